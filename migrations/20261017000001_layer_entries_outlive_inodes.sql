@@ -0,0 +1,7 @@
+-- Keep layer entries after their inode is deleted
+-- Delete entries (and the history of removed files) must survive the inode
+-- they describe, so layer_entries.inode_id becomes a plain historical reference.
+
+ALTER TABLE layer_entries DROP CONSTRAINT IF EXISTS layer_entries_tenant_id_inode_id_fkey;
+
+COMMENT ON COLUMN layer_entries.inode_id IS 'Inode the change applied to; may refer to an inode that has since been deleted';
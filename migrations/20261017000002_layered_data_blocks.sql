@@ -0,0 +1,22 @@
+-- Layer-aware binary blocks
+-- A binary write stores only the blocks that differ from what the file
+-- inherits through its layer chain. Blocks written before this migration
+-- keep a NULL layer_id and stay visible from every layer.
+
+ALTER TABLE data_blocks
+    ADD COLUMN layer_id UUID REFERENCES layers(layer_id) ON DELETE CASCADE;
+
+ALTER TABLE data_blocks DROP CONSTRAINT IF EXISTS data_blocks_tenant_id_inode_id_block_index_key;
+
+CREATE UNIQUE INDEX idx_blocks_layer_block
+    ON data_blocks(tenant_id, inode_id, layer_id, block_index)
+    WHERE layer_id IS NOT NULL;
+CREATE UNIQUE INDEX idx_blocks_unlayered_block
+    ON data_blocks(tenant_id, inode_id, block_index)
+    WHERE layer_id IS NULL;
+
+COMMENT ON COLUMN data_blocks.layer_id IS 'Layer that stored this block version; NULL for blocks that predate layered storage';
+
+ALTER TABLE layer_entries ADD COLUMN block_changes JSONB;
+
+COMMENT ON COLUMN layer_entries.block_changes IS 'Binary block-level changes: block size, block count and overridden block indices';
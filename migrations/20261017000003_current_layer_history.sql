@@ -0,0 +1,38 @@
+-- Record every change of a tenant's current layer
+-- Lets reads be answered "as of" a point in time: the layer that was current
+-- at T is the target of the last switch at or before T.
+
+CREATE TABLE tenant_current_layer_history (
+    history_id BIGSERIAL PRIMARY KEY,
+    tenant_id UUID NOT NULL REFERENCES tenants(tenant_id) ON DELETE CASCADE,
+    layer_id UUID NOT NULL REFERENCES layers(layer_id) ON DELETE CASCADE,
+    switched_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_current_layer_history_time
+    ON tenant_current_layer_history(tenant_id, switched_at DESC, history_id DESC);
+
+-- Every writer of tenant_current_layer (switch, checkpoint, squash) is covered
+CREATE OR REPLACE FUNCTION record_current_layer_switch()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' AND OLD.current_layer_id = NEW.current_layer_id THEN
+        RETURN NEW;
+    END IF;
+
+    INSERT INTO tenant_current_layer_history (tenant_id, layer_id, switched_at)
+    VALUES (NEW.tenant_id, NEW.current_layer_id, NEW.updated_at);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER trigger_record_current_layer_switch
+    AFTER INSERT OR UPDATE OF current_layer_id ON tenant_current_layer
+    FOR EACH ROW
+    EXECUTE FUNCTION record_current_layer_switch();
+
+-- Tenants that already exist start their history at the last switch
+INSERT INTO tenant_current_layer_history (tenant_id, layer_id, switched_at)
+SELECT tenant_id, current_layer_id, updated_at FROM tenant_current_layer;
+
+COMMENT ON TABLE tenant_current_layer_history IS 'Audit of current-layer switches, used for point-in-time reads';
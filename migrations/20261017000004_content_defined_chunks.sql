@@ -0,0 +1,33 @@
+-- Content-defined chunk storage for binary files
+-- Chunks are variable-length and stored once per tenant, keyed by content
+-- hash. Each layer that writes a chunked file records the file's full chunk
+-- map; identical chunks across versions and files share one row.
+
+CREATE TABLE content_chunks (
+    tenant_id UUID NOT NULL REFERENCES tenants(tenant_id) ON DELETE CASCADE,
+    content_hash VARCHAR(64) NOT NULL,
+    data BYTEA NOT NULL,
+    size INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (tenant_id, content_hash)
+);
+
+CREATE TABLE file_chunks (
+    tenant_id UUID NOT NULL,
+    inode_id BIGINT NOT NULL,
+    layer_id UUID NOT NULL REFERENCES layers(layer_id) ON DELETE CASCADE,
+    chunk_index INTEGER NOT NULL,
+    chunk_offset BIGINT NOT NULL,
+    size INTEGER NOT NULL,
+    content_hash VARCHAR(64) NOT NULL,
+
+    PRIMARY KEY (tenant_id, inode_id, layer_id, chunk_index),
+    FOREIGN KEY (tenant_id, inode_id) REFERENCES inodes(tenant_id, inode_id) ON DELETE CASCADE,
+    FOREIGN KEY (tenant_id, content_hash) REFERENCES content_chunks(tenant_id, content_hash)
+);
+
+CREATE INDEX idx_file_chunks_hash ON file_chunks(tenant_id, content_hash);
+
+COMMENT ON TABLE content_chunks IS 'Deduplicated variable-length chunks of binary files';
+COMMENT ON TABLE file_chunks IS 'Per-layer chunk map of a content-defined chunked file';
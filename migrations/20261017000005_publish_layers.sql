@@ -0,0 +1,27 @@
+-- Let a tenant publish one of its layers directly, without a mount entry
+-- Only working-layer publishes follow a mount entry's current layer; a
+-- published layer is a fixed snapshot identified by layer_id alone.
+
+ALTER TABLE published_mounts ALTER COLUMN mount_entry_id DROP NOT NULL;
+
+ALTER TABLE published_mounts ADD CONSTRAINT working_layer_has_mount CHECK (
+    target_type <> 'working_layer' OR mount_entry_id IS NOT NULL
+);
+
+-- A published layer can't be dropped out from under its readers
+ALTER TABLE published_mounts
+    ADD CONSTRAINT published_mounts_layer_id_fkey
+    FOREIGN KEY (layer_id) REFERENCES layers(layer_id);
+
+-- Publishes go with the tenant that owns them
+ALTER TABLE published_mounts DROP CONSTRAINT published_mounts_tenant_id_fkey;
+ALTER TABLE published_mounts
+    ADD CONSTRAINT published_mounts_tenant_id_fkey
+    FOREIGN KEY (tenant_id) REFERENCES tenants(tenant_id) ON DELETE CASCADE;
+
+-- Rows are read back as UTC instants like every other table
+ALTER TABLE published_mounts
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING created_at AT TIME ZONE 'UTC',
+    ALTER COLUMN updated_at TYPE TIMESTAMPTZ USING updated_at AT TIME ZONE 'UTC';
+
+COMMENT ON COLUMN published_mounts.mount_entry_id IS 'Mount entry a working_layer publish follows; NULL for layers published directly';
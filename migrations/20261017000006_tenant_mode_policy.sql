@@ -0,0 +1,11 @@
+-- Per-tenant defaults for the mode of new files and directories
+-- The umask is applied to every created entry, including modes given by the
+-- caller; the defaults are used when the caller gives none.
+
+ALTER TABLE tenants
+    ADD COLUMN default_file_mode INTEGER NOT NULL DEFAULT 420  -- 0o644
+        CHECK (default_file_mode BETWEEN 0 AND 4095),
+    ADD COLUMN default_dir_mode INTEGER NOT NULL DEFAULT 493   -- 0o755
+        CHECK (default_dir_mode BETWEEN 0 AND 4095),
+    ADD COLUMN umask INTEGER NOT NULL DEFAULT 18               -- 0o022
+        CHECK (umask BETWEEN 0 AND 511);
@@ -0,0 +1,13 @@
+-- Scope the names of mount layers to their mount
+-- A batch snapshot names every mount's snapshot the same, which a
+-- tenant-wide unique name rules out. Tenant-level layers keep unique names.
+
+ALTER TABLE layers DROP CONSTRAINT layers_tenant_id_layer_name_key;
+
+CREATE UNIQUE INDEX idx_layers_tenant_name
+    ON layers(tenant_id, layer_name)
+    WHERE mount_entry_id IS NULL;
+
+CREATE UNIQUE INDEX idx_layers_mount_name
+    ON layers(mount_entry_id, layer_name)
+    WHERE mount_entry_id IS NOT NULL;
@@ -0,0 +1,7 @@
+-- Attribute audit entries to the agent acting for the caller
+-- uid/gid say which local user made a call; agents (CI jobs, AI sessions)
+-- often share one uid, so mounts can tag their entries with an agent id.
+
+ALTER TABLE audit_logs ADD COLUMN agent_id TEXT;
+
+COMMENT ON COLUMN audit_logs.agent_id IS 'Agent the caller acted for, from the mount or API request; NULL when unknown';
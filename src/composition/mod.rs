@@ -3,5 +3,5 @@ pub mod publisher;
 pub mod resolver;
 
 pub use layer_chain::{LayerChain, LayerChainManager, SnapshotResult};
-pub use publisher::LayerPublisher;
+pub use publisher::{ExportSummary, LayerPublisher};
 pub use resolver::{PathResolver, ResolvedPath, ResolvedSource};
@@ -1,17 +1,39 @@
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow};
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use uuid::Uuid;
 
+use crate::layer::{CowHandler, UnionView};
+use crate::storage::models::Inode;
+
 use crate::storage::models::mount_entry::MountSource;
 use crate::storage::models::published_mount::{
-    PublishMountInput, PublishedMount, ResolvedPublished,
+    PublishLayerInput, PublishMountInput, PublishScope, PublishedMount, ResolvedPublished,
 };
 use crate::storage::traits::{MountEntryRepository, PublishedMountRepository};
+use crate::storage::{
+    BlockOperations, DatabaseTransaction, InodeOperations, InodeType, PgMountEntryRepository,
+    PgPublishedMountRepository, TenantOperations, TenantRepository,
+};
+use crate::types::{LayerId, TenantId};
 
 /// Layer publisher service
 pub struct LayerPublisher {
     pub published_mount_repo: Arc<dyn PublishedMountRepository>,
     pub mount_entry_repo: Arc<dyn MountEntryRepository>,
+    /// Needed to read layer contents for `export_tar`
+    pool: Option<PgPool>,
+}
+
+/// Counts of what `export_tar` wrote
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    pub directories: usize,
+    pub files: usize,
+    pub symlinks: usize,
+    pub bytes: u64,
 }
 
 impl LayerPublisher {
@@ -19,7 +41,21 @@ impl LayerPublisher {
         published_mount_repo: Arc<dyn PublishedMountRepository>,
         mount_entry_repo: Arc<dyn MountEntryRepository>,
     ) -> Self {
-        Self { published_mount_repo, mount_entry_repo }
+        Self { published_mount_repo, mount_entry_repo, pool: None }
+    }
+
+    /// Publisher backed by the Postgres repositories, able to export layers
+    pub fn from_pool(pool: PgPool) -> Self {
+        Self::new(
+            Arc::new(PgPublishedMountRepository::new(pool.clone())),
+            Arc::new(PgMountEntryRepository::new(pool.clone())),
+        )
+        .with_pool(pool)
+    }
+
+    pub fn with_pool(mut self, pool: PgPool) -> Self {
+        self.pool = Some(pool);
+        self
     }
 
     /// Publish a mount point
@@ -87,6 +123,38 @@ impl LayerPublisher {
         Ok(())
     }
 
+    /// Publish one of the tenant's layers under a global name
+    ///
+    /// The layer is marked read-only so everyone mounting it sees the same
+    /// content. The tenant's current layer can't be published; checkpoint
+    /// first so the layer to share stops taking writes.
+    pub async fn publish_layer(
+        &self,
+        tenant_id: Uuid,
+        layer_id: Uuid,
+        publish_name: &str,
+    ) -> Result<PublishedMount> {
+        self.published_mount_repo
+            .publish_layer(PublishLayerInput {
+                tenant_id,
+                layer_id,
+                publish_name: publish_name.to_string(),
+                description: None,
+                scope: PublishScope::Public,
+            })
+            .await
+    }
+
+    /// Remove a publish made by this tenant
+    ///
+    /// The layer stays read-only; it is part of the tenant's history.
+    pub async fn unpublish_by_name(&self, tenant_id: Uuid, publish_name: &str) -> Result<()> {
+        if !self.published_mount_repo.unpublish_by_name(tenant_id, publish_name).await? {
+            return Err(anyhow!("'{}' is not published by this tenant", publish_name));
+        }
+        Ok(())
+    }
+
     /// Resolve a published mount to actual layer
     ///
     /// For working_layer type, returns the current working layer ID
@@ -97,6 +165,173 @@ impl LayerPublisher {
     ) -> Result<ResolvedPublished> {
         self.published_mount_repo.resolve_published(publish_name, accessor_tenant_id).await
     }
+
+    /// Stream the tree visible at `layer_id` into `writer` as a tar archive
+    ///
+    /// Files come from the union view at that layer, so later layers and
+    /// whiteouts are not visible. Directories are emitted ahead of their
+    /// children with the metadata of the inode currently at that path, or
+    /// 0755 if it has since been removed. Files whose inode was deleted in a
+    /// later layer are skipped. All reads share one transaction so the archive
+    /// is a consistent snapshot.
+    pub async fn export_tar<W>(
+        &self,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+        writer: &mut W,
+    ) -> Result<ExportSummary>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let pool = self.pool.as_ref().ok_or_else(|| anyhow!("Layer export requires a pool"))?;
+        let tenant = TenantOperations::new(pool)
+            .get_by_id(tenant_id)
+            .await?
+            .ok_or_else(|| anyhow!("Tenant {} not found", tenant_id))?;
+
+        let view = UnionView::from_layer(pool, tenant_id, layer_id).await?;
+        if view.current_layer_id() != Some(layer_id) {
+            return Err(anyhow!("Layer {} not found", layer_id));
+        }
+        let files = view.list_all().await?;
+
+        let inode_ops = InodeOperations::new(pool);
+        let block_ops = BlockOperations::new(pool);
+        let cow = CowHandler::new(pool, tenant_id, layer_id);
+        let mut tx = pool.begin().await?;
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut summary = ExportSummary::default();
+        let mut emitted_dirs = HashSet::new();
+        let mut dir_cache = HashMap::new();
+
+        for (path, entry) in files {
+            let Some(inode) = inode_ops.get_in_tx(&mut tx, tenant_id, entry.inode_id).await? else {
+                tracing::warn!(path = %path, "Skipping export of file whose inode is gone");
+                continue;
+            };
+
+            for dir in ancestors(&path) {
+                if !emitted_dirs.insert(dir.to_string()) {
+                    continue;
+                }
+                let dir_inode = resolve_dir(
+                    &inode_ops,
+                    &mut tx,
+                    tenant_id,
+                    tenant.root_inode_id,
+                    dir,
+                    &mut dir_cache,
+                )
+                .await?;
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Directory);
+                set_metadata(&mut header, dir_inode.as_ref(), 0o755);
+                header.set_size(0);
+                builder.append_data(
+                    &mut header,
+                    format!("{}/", relative(dir)),
+                    std::io::empty(),
+                )?;
+                summary.directories += 1;
+            }
+
+            // Text files live per layer; anything else is binary data
+            let data =
+                match cow.read_text_file_in_tx(&mut tx, inode.inode_id, entry.layer_id).await? {
+                    Some(text) => text.into_bytes(),
+                    None => {
+                        block_ops
+                            .read_visible_in_tx(
+                                &mut tx,
+                                tenant_id,
+                                inode.inode_id,
+                                layer_id,
+                                inode.size,
+                            )
+                            .await?
+                    }
+                };
+
+            let mut header = tar::Header::new_gnu();
+            set_metadata(&mut header, Some(&inode), 0o644);
+            if inode.inode_type == InodeType::Symlink {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                let target = String::from_utf8_lossy(&data).into_owned();
+                builder.append_link(&mut header, relative(&path), target)?;
+                summary.symlinks += 1;
+            } else {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(data.len() as u64);
+                builder.append_data(&mut header, relative(&path), data.as_slice())?;
+                summary.files += 1;
+                summary.bytes += data.len() as u64;
+            }
+
+            // Hand each entry to the writer as soon as it is encoded
+            let chunk = std::mem::take(builder.get_mut());
+            writer.write_all(&chunk).await.context("Failed to write tar entry")?;
+        }
+
+        tx.commit().await?;
+        let trailer = builder.into_inner()?;
+        writer.write_all(&trailer).await.context("Failed to write tar trailer")?;
+        writer.flush().await?;
+        Ok(summary)
+    }
+}
+
+/// Ancestor directories of an absolute path, outermost first, excluding `/`
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/').skip(1).map(move |(idx, _)| &path[..idx])
+}
+
+/// Archive member name for an absolute path
+fn relative(path: &str) -> &str {
+    path.trim_start_matches('/')
+}
+
+fn set_metadata(header: &mut tar::Header, inode: Option<&Inode>, default_mode: u32) {
+    match inode {
+        Some(inode) => {
+            header.set_mode(inode.mode as u32 & 0o7777);
+            header.set_uid(inode.uid.max(0) as u64);
+            header.set_gid(inode.gid.max(0) as u64);
+            header.set_mtime(inode.mtime.timestamp().max(0) as u64);
+        }
+        None => header.set_mode(default_mode),
+    }
+}
+
+/// Look up the directory inode at `path` by walking down from the root
+async fn resolve_dir(
+    inode_ops: &InodeOperations<'_>,
+    tx: &mut DatabaseTransaction<'_>,
+    tenant_id: TenantId,
+    root_inode_id: crate::types::InodeId,
+    path: &str,
+    cache: &mut HashMap<String, Option<Inode>>,
+) -> Result<Option<Inode>> {
+    if let Some(inode) = cache.get(path) {
+        return Ok(inode.clone());
+    }
+
+    let mut parent_id = root_inode_id;
+    let mut found = None;
+    for name in relative(path).split('/') {
+        found = inode_ops.get_by_parent_and_name_in_tx(tx, tenant_id, parent_id, name).await?;
+        match &found {
+            Some(inode) if inode.inode_type == InodeType::Dir => parent_id = inode.inode_id,
+            _ => {
+                found = None;
+                break;
+            }
+        }
+    }
+
+    cache.insert(path.to_string(), found.clone());
+    Ok(found)
 }
 
 #[cfg(test)]
@@ -145,7 +380,7 @@ mod tests {
         mock_publish_repo.expect_publish_mount().times(1).returning(|input| {
             Ok(PublishedMount {
                 publish_id: Uuid::new_v4(),
-                mount_entry_id: input.mount_entry_id,
+                mount_entry_id: Some(input.mount_entry_id),
                 tenant_id: Uuid::new_v4(),
                 publish_name: input.publish_name,
                 description: input.description,
@@ -276,7 +511,7 @@ mod tests {
         let mut mock_publish_repo = MockPublishedMountRepository::new();
         mock_publish_repo.expect_resolve_published().times(1).returning(|_, _| {
             Ok(ResolvedPublished {
-                mount_entry_id: Uuid::new_v4(),
+                mount_entry_id: Some(Uuid::new_v4()),
                 owner_tenant_id: Uuid::new_v4(),
                 layer_id: Uuid::new_v4(),
                 is_working_layer: true,
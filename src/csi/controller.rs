@@ -5,14 +5,14 @@ use crate::csi::proto::{
     DeleteSnapshotResponse, DeleteVolumeRequest, DeleteVolumeResponse, ListSnapshotsRequest,
     ListSnapshotsResponse, ListVolumesRequest, ListVolumesResponse, Snapshot,
     ValidateVolumeCapabilitiesRequest, ValidateVolumeCapabilitiesResponse, Volume,
-    controller_server::Controller,
+    controller_server::Controller, volume_content_source::Type as ContentSourceType,
 };
 use crate::csi::{SnapshotManager, TenantMapper};
-use crate::storage::TenantOperations;
-use crate::storage::traits::TenantRepository;
+use crate::layer::LayerManagerError;
+use crate::storage::traits::{TenantRepository, UsageRepository};
+use crate::storage::{Layer, TenantOperations};
 use std::sync::Arc;
 use tonic::{Request, Response, Status};
-use uuid::Uuid;
 
 /// Controller Service implementation
 ///
@@ -22,6 +22,14 @@ pub struct ControllerService {
     tenant_mapper: Arc<TenantMapper<'static>>,
     snapshot_manager: Arc<SnapshotManager<'static>>,
     tenant_ops: Arc<TenantOperations<'static>>,
+    capacity: Option<Capacity>,
+}
+
+/// Storage pool the controller reports capacity against
+#[derive(Clone)]
+struct Capacity {
+    usage: Arc<dyn UsageRepository>,
+    ceiling_bytes: i64,
 }
 
 impl ControllerService {
@@ -30,7 +38,16 @@ impl ControllerService {
         snapshot_manager: Arc<SnapshotManager<'static>>,
         tenant_ops: Arc<TenantOperations<'static>>,
     ) -> Self {
-        Self { tenant_mapper, snapshot_manager, tenant_ops }
+        Self { tenant_mapper, snapshot_manager, tenant_ops, capacity: None }
+    }
+
+    /// Report capacity as `ceiling_bytes` minus the database size.
+    ///
+    /// Tarbox has no per-tenant quotas, so the pool ceiling is the only limit.
+    /// Without one, GetCapacity reports unlimited capacity.
+    pub fn with_capacity(mut self, usage: Arc<dyn UsageRepository>, ceiling_bytes: i64) -> Self {
+        self.capacity = Some(Capacity { usage, ceiling_bytes });
+        self
     }
 
     fn extract_pvc_info(
@@ -46,6 +63,22 @@ impl ControllerService {
             .unwrap_or_else(|| "unknown".to_string());
         (namespace, pvc_name)
     }
+
+    /// Describe a snapshot layer for CSI. Snapshot layers are frozen when
+    /// created, so they are always ready to use.
+    fn to_csi_snapshot(layer: &Layer) -> Snapshot {
+        Snapshot {
+            snapshot_id: SnapshotManager::snapshot_id(layer.tenant_id, layer.layer_id),
+            source_volume_id: layer.tenant_id.to_string(),
+            creation_time: Some(prost_types::Timestamp {
+                seconds: layer.created_at.timestamp(),
+                nanos: 0,
+            }),
+            ready_to_use: true,
+            size_bytes: SnapshotManager::snapshot_size(layer),
+            group_snapshot_id: String::new(),
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -66,19 +99,31 @@ impl Controller for ControllerService {
             .and_then(|r| r.required_bytes.into())
             .unwrap_or(1024 * 1024 * 1024); // 1GB default
 
-        // Create tenant
-        let tenant_id = self
-            .tenant_mapper
-            .create_tenant_from_pvc(&namespace, &pvc_name, capacity_bytes)
-            .await
-            .map_err(|e| Status::internal(format!("Failed to create tenant: {}", e)))?;
+        // Create tenant, cloning the source volume if one was given
+        let tenant_id = match req.volume_content_source.as_ref().and_then(|s| s.r#type.as_ref()) {
+            Some(ContentSourceType::Volume(source)) => self
+                .tenant_mapper
+                .clone_tenant_from_volume(&source.volume_id, &namespace, &pvc_name, capacity_bytes)
+                .await
+                .map_err(|e| Status::internal(format!("Failed to clone volume: {:#}", e)))?,
+            Some(ContentSourceType::Snapshot(_)) => {
+                return Err(Status::invalid_argument(
+                    "Creating volumes from snapshots is not supported",
+                ));
+            }
+            None => self
+                .tenant_mapper
+                .create_tenant_from_pvc(&namespace, &pvc_name, capacity_bytes)
+                .await
+                .map_err(|e| Status::internal(format!("Failed to create tenant: {}", e)))?,
+        };
 
         // Create volume response
         let volume = Volume {
             volume_id: tenant_id.to_string(),
             capacity_bytes,
             volume_context: req.parameters,
-            content_source: None,
+            content_source: req.volume_content_source,
             accessible_topology: vec![],
         };
 
@@ -183,9 +228,21 @@ impl Controller for ControllerService {
         &self,
         _request: Request<crate::csi::proto::GetCapacityRequest>,
     ) -> Result<Response<crate::csi::proto::GetCapacityResponse>, Status> {
-        // Return unlimited capacity
+        // All volumes share one database, so topology and parameters don't
+        // change the answer
+        let available_capacity = match &self.capacity {
+            Some(capacity) => {
+                let used =
+                    capacity.usage.database_size().await.map_err(|e| {
+                        Status::internal(format!("Failed to get database size: {}", e))
+                    })?;
+                capacity.ceiling_bytes.saturating_sub(used).max(0)
+            }
+            None => i64::MAX,
+        };
+
         Ok(Response::new(crate::csi::proto::GetCapacityResponse {
-            available_capacity: i64::MAX,
+            available_capacity,
             maximum_volume_size: None,
             minimum_volume_size: None,
         }))
@@ -223,6 +280,10 @@ impl Controller for ControllerService {
     ) -> Result<Response<CreateSnapshotResponse>, Status> {
         let req = request.into_inner();
 
+        if req.name.is_empty() {
+            return Err(Status::invalid_argument("Snapshot name is required"));
+        }
+
         // Parse volume ID
         let tenant_id = self
             .tenant_mapper
@@ -234,21 +295,9 @@ impl Controller for ControllerService {
             .snapshot_manager
             .create_snapshot(tenant_id, &req.name)
             .await
-            .map_err(|e| Status::internal(format!("Failed to create snapshot: {}", e)))?;
+            .map_err(|e| Status::internal(format!("Failed to create snapshot: {:#}", e)))?;
 
-        let snapshot = Snapshot {
-            snapshot_id: layer.layer_id.to_string(),
-            source_volume_id: req.source_volume_id,
-            creation_time: Some(prost_types::Timestamp {
-                seconds: layer.created_at.timestamp(),
-                nanos: 0,
-            }),
-            ready_to_use: true,
-            size_bytes: 0, // TODO: calculate actual size
-            group_snapshot_id: String::new(),
-        };
-
-        Ok(Response::new(CreateSnapshotResponse { snapshot: Some(snapshot) }))
+        Ok(Response::new(CreateSnapshotResponse { snapshot: Some(Self::to_csi_snapshot(&layer)) }))
     }
 
     async fn delete_snapshot(
@@ -258,14 +307,18 @@ impl Controller for ControllerService {
         let req = request.into_inner();
 
         // Parse snapshot ID
-        let _snapshot_id = Uuid::parse_str(&req.snapshot_id)
-            .map_err(|e| Status::invalid_argument(format!("Invalid snapshot_id: {}", e)))?;
-
-        // We need tenant_id, which we don't have in the request
-        // For now, we'll try to find it from the snapshot
-        // In production, we'd need to store snapshot metadata separately
-
-        // TODO: Implement proper snapshot deletion with tenant lookup
+        let (tenant_id, layer_id) = SnapshotManager::parse_snapshot_id(&req.snapshot_id)
+            .map_err(|e| Status::invalid_argument(format!("Invalid snapshot_id: {:#}", e)))?;
+
+        self.snapshot_manager.delete_snapshot(tenant_id, layer_id).await.map_err(|e| {
+            match e.downcast_ref::<LayerManagerError>() {
+                Some(LayerManagerError::HasChildLayers(_)) => Status::failed_precondition(format!(
+                    "Snapshot {} has dependent layers",
+                    req.snapshot_id
+                )),
+                _ => Status::internal(format!("Failed to delete snapshot: {:#}", e)),
+            }
+        })?;
 
         Ok(Response::new(DeleteSnapshotResponse {}))
     }
@@ -276,41 +329,54 @@ impl Controller for ControllerService {
     ) -> Result<Response<ListSnapshotsResponse>, Status> {
         let req = request.into_inner();
 
-        // If source_volume_id is provided, list snapshots for that volume
-        if !req.source_volume_id.is_empty() {
+        let layers = if !req.snapshot_id.is_empty() {
+            // An unparseable ID names no snapshot
+            match SnapshotManager::parse_snapshot_id(&req.snapshot_id) {
+                Ok((tenant_id, layer_id)) => self
+                    .snapshot_manager
+                    .get_snapshot(tenant_id, layer_id)
+                    .await
+                    .map_err(|e| Status::internal(format!("Failed to get snapshot: {}", e)))?
+                    .into_iter()
+                    .collect(),
+                Err(_) => vec![],
+            }
+        } else if !req.source_volume_id.is_empty() {
             let tenant_id = self
                 .tenant_mapper
                 .parse_volume_id(&req.source_volume_id)
                 .map_err(|e| Status::invalid_argument(format!("Invalid volume_id: {}", e)))?;
 
-            let layers = self
-                .snapshot_manager
+            self.snapshot_manager
                 .list_snapshots(tenant_id)
                 .await
-                .map_err(|e| Status::internal(format!("Failed to list snapshots: {}", e)))?;
-
-            let entries: Vec<_> = layers
-                .into_iter()
-                .map(|layer| crate::csi::proto::list_snapshots_response::Entry {
-                    snapshot: Some(Snapshot {
-                        snapshot_id: layer.layer_id.to_string(),
-                        source_volume_id: req.source_volume_id.clone(),
-                        creation_time: Some(prost_types::Timestamp {
-                            seconds: layer.created_at.timestamp(),
-                            nanos: 0,
-                        }),
-                        ready_to_use: true,
-                        size_bytes: 0,
-                        group_snapshot_id: String::new(),
-                    }),
-                })
-                .collect();
-
-            return Ok(Response::new(ListSnapshotsResponse { entries, next_token: String::new() }));
-        }
+                .map_err(|e| Status::internal(format!("Failed to list snapshots: {}", e)))?
+        } else {
+            let tenants = self
+                .tenant_ops
+                .list()
+                .await
+                .map_err(|e| Status::internal(format!("Failed to list tenants: {}", e)))?;
+
+            let mut layers = Vec::new();
+            for tenant in tenants {
+                layers.extend(
+                    self.snapshot_manager.list_snapshots(tenant.tenant_id).await.map_err(|e| {
+                        Status::internal(format!("Failed to list snapshots: {}", e))
+                    })?,
+                );
+            }
+            layers
+        };
 
-        // List all snapshots (not implemented for now)
-        Ok(Response::new(ListSnapshotsResponse { entries: vec![], next_token: String::new() }))
+        let entries = layers
+            .iter()
+            .map(|layer| crate::csi::proto::list_snapshots_response::Entry {
+                snapshot: Some(Self::to_csi_snapshot(layer)),
+            })
+            .collect();
+
+        Ok(Response::new(ListSnapshotsResponse { entries, next_token: String::new() }))
     }
 
     async fn controller_expand_volume(
@@ -364,12 +430,19 @@ impl Controller for ControllerService {
         let req = request.into_inner();
 
         // Parse snapshot ID
-        let _snapshot_id = uuid::Uuid::parse_str(&req.snapshot_id)
-            .map_err(|e| Status::invalid_argument(format!("Invalid snapshot_id: {}", e)))?;
+        let (tenant_id, layer_id) = SnapshotManager::parse_snapshot_id(&req.snapshot_id)
+            .map_err(|e| Status::invalid_argument(format!("Invalid snapshot_id: {:#}", e)))?;
+
+        let layer = self
+            .snapshot_manager
+            .get_snapshot(tenant_id, layer_id)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to get snapshot: {}", e)))?
+            .ok_or_else(|| Status::not_found("Snapshot not found"))?;
 
-        // We need tenant_id but don't have it in the request
-        // This is a limitation - in production we'd need snapshot metadata table
-        Err(Status::unimplemented("GetSnapshot requires tenant context"))
+        Ok(Response::new(crate::csi::proto::GetSnapshotResponse {
+            snapshot: Some(Self::to_csi_snapshot(&layer)),
+        }))
     }
 }
 
@@ -395,4 +468,58 @@ mod tests {
         assert_eq!(ns, "default");
         assert_eq!(name, "unknown");
     }
+
+    fn controller_with_usage(used: i64, ceiling: i64) -> ControllerService {
+        // Lazy pool: GetCapacity never touches it
+        let pool: &'static sqlx::PgPool =
+            Box::leak(Box::new(sqlx::PgPool::connect_lazy("postgres://localhost/none").unwrap()));
+        let mut usage = crate::storage::traits::MockUsageRepository::new();
+        usage.expect_database_size().returning(move || Ok(used));
+
+        ControllerService::new(
+            Arc::new(TenantMapper::new(
+                Arc::new(TenantOperations::new(pool)),
+                Arc::new(crate::storage::LayerOperations::new(pool)),
+            )),
+            Arc::new(SnapshotManager::new(pool)),
+            Arc::new(TenantOperations::new(pool)),
+        )
+        .with_capacity(Arc::new(usage), ceiling)
+    }
+
+    #[tokio::test]
+    async fn test_get_capacity_subtracts_database_size() {
+        let controller = controller_with_usage(300, 1000);
+
+        let plain = controller
+            .get_capacity(Request::new(crate::csi::proto::GetCapacityRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(plain.available_capacity, 700);
+
+        let mut parameters = std::collections::HashMap::new();
+        parameters.insert("tier".to_string(), "fast".to_string());
+        let with_topology = controller
+            .get_capacity(Request::new(crate::csi::proto::GetCapacityRequest {
+                parameters,
+                accessible_topology: Some(crate::csi::proto::Topology::default()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(with_topology.available_capacity, 700);
+    }
+
+    #[tokio::test]
+    async fn test_get_capacity_never_negative() {
+        let controller = controller_with_usage(5000, 1000);
+        let response = controller
+            .get_capacity(Request::new(crate::csi::proto::GetCapacityRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.available_capacity, 0);
+    }
 }
@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tokio::sync::Notify;
+
+/// Drain state of a CSI node
+///
+/// Before a node is evicted the plugin is switched into drain mode: new
+/// volumes are refused, while operations on volumes already on the node
+/// (unpublish, unstage, stats) keep working so pods can unmount cleanly. The
+/// node reports drained once no operation is in flight.
+#[derive(Debug, Default)]
+pub struct DrainState {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+/// Marks one node operation as in flight until dropped
+#[derive(Debug)]
+pub struct OperationGuard<'a> {
+    state: &'a DrainState,
+}
+
+impl DrainState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop accepting new volumes
+    pub fn start_drain(&self) {
+        if !self.draining.swap(true, Ordering::SeqCst) {
+            tracing::info!(in_flight = self.in_flight(), "CSI node entering drain mode");
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Draining and no operation left in flight
+    pub fn is_drained(&self) -> bool {
+        self.is_draining() && self.in_flight() == 0
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Admit an operation that brings a new volume onto the node
+    ///
+    /// Returns `None` once the node is draining.
+    pub fn admit(&self) -> Option<OperationGuard<'_>> {
+        let guard = self.track();
+        if self.is_draining() {
+            return None;
+        }
+        Some(guard)
+    }
+
+    /// Track an operation on a volume already on the node; always allowed
+    pub fn track(&self) -> OperationGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        OperationGuard { state: self }
+    }
+
+    /// Wait until no operation is in flight
+    pub async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.in_flight() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    /// Enter drain mode on SIGUSR1, then write `marker` once idle
+    ///
+    /// Meant for a preStop hook: `kill -USR1 1` and wait for the marker file.
+    pub fn listen_for_signal(self: Arc<Self>, marker: PathBuf) -> Result<()> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut drain_signal =
+            signal(SignalKind::user_defined1()).context("Failed to install SIGUSR1 handler")?;
+        tokio::spawn(async move {
+            if drain_signal.recv().await.is_none() {
+                return;
+            }
+            self.start_drain();
+            self.wait_idle().await;
+            tracing::info!(marker = %marker.display(), "CSI node drained");
+            if let Some(parent) = marker.parent() {
+                let _ = tokio::fs::create_dir_all(parent).await;
+            }
+            if let Err(e) = tokio::fs::write(&marker, b"drained\n").await {
+                tracing::warn!("Failed to write drain marker {}: {}", marker.display(), e);
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for OperationGuard<'_> {
+    fn drop(&mut self) {
+        if self.state.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.state.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_admit_until_draining() {
+        let state = DrainState::new();
+        assert!(!state.is_draining());
+
+        let guard = state.admit().expect("admitted before drain");
+        assert_eq!(state.in_flight(), 1);
+
+        state.start_drain();
+        assert!(state.admit().is_none());
+        assert_eq!(state.in_flight(), 1, "rejected operations are not counted");
+        assert!(!state.is_drained());
+
+        // Existing volumes can still be operated on
+        let tracked = state.track();
+        assert_eq!(state.in_flight(), 2);
+
+        drop(guard);
+        drop(tracked);
+        assert!(state.is_drained());
+    }
+
+    #[tokio::test]
+    async fn test_wait_idle_waits_for_in_flight() {
+        let state = Arc::new(DrainState::new());
+        state.start_drain();
+
+        let waiter = {
+            let state = state.clone();
+            let guard_state = state.clone();
+            let guard = guard_state.track();
+            let handle = tokio::spawn(async move { state.wait_idle().await });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(!handle.is_finished());
+            drop(guard);
+            handle
+        };
+
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+        assert!(state.is_drained());
+    }
+}
@@ -1,4 +1,5 @@
 pub mod controller;
+pub mod drain;
 pub mod identity;
 pub mod metrics;
 pub mod mount_manager;
@@ -8,6 +9,7 @@ pub mod snapshot;
 pub mod tenant_mapping;
 
 pub use controller::ControllerService;
+pub use drain::DrainState;
 pub use identity::IdentityService;
 pub use mount_manager::MountManager;
 pub use node::NodeService;
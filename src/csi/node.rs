@@ -6,13 +6,17 @@ use crate::csi::proto::{
     NodeUnpublishVolumeRequest, NodeUnpublishVolumeResponse, NodeUnstageVolumeRequest,
     NodeUnstageVolumeResponse, node_server::Node,
 };
-use crate::csi::{MountManager, TenantMapper};
+use crate::csi::{DrainState, MountManager, TenantMapper};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tonic::{Request, Response, Status};
 
 const NODE_ID: &str = "tarbox-node";
 
+fn draining() -> Status {
+    Status::unavailable("Node is draining; not accepting new volumes")
+}
+
 /// Node Service implementation
 ///
 /// Handles node-specific operations: mount/unmount, stats
@@ -21,6 +25,7 @@ pub struct NodeService {
     tenant_mapper: Arc<TenantMapper<'static>>,
     mount_manager: Arc<MountManager<'static>>,
     node_id: String,
+    drain: Arc<DrainState>,
 }
 
 impl NodeService {
@@ -36,7 +41,12 @@ impl NodeService {
         mount_manager: Arc<MountManager<'static>>,
         node_id: String,
     ) -> Self {
-        Self { tenant_mapper, mount_manager, node_id }
+        Self { tenant_mapper, mount_manager, node_id, drain: Arc::new(DrainState::new()) }
+    }
+
+    /// Drain state shared with whatever triggers the drain (e.g. a signal handler)
+    pub fn drain_state(&self) -> Arc<DrainState> {
+        self.drain.clone()
     }
 }
 
@@ -46,6 +56,7 @@ impl Node for NodeService {
         &self,
         request: Request<NodeStageVolumeRequest>,
     ) -> Result<Response<NodeStageVolumeResponse>, Status> {
+        let _op = self.drain.admit().ok_or_else(draining)?;
         let req = request.into_inner();
 
         // Parse volume ID (tenant ID)
@@ -80,6 +91,7 @@ impl Node for NodeService {
         &self,
         request: Request<NodeUnstageVolumeRequest>,
     ) -> Result<Response<NodeUnstageVolumeResponse>, Status> {
+        let _op = self.drain.track();
         let req = request.into_inner();
 
         self.mount_manager
@@ -94,6 +106,7 @@ impl Node for NodeService {
         &self,
         request: Request<NodePublishVolumeRequest>,
     ) -> Result<Response<NodePublishVolumeResponse>, Status> {
+        let _op = self.drain.admit().ok_or_else(draining)?;
         let req = request.into_inner();
 
         // Bind mount from staging to target
@@ -130,6 +143,7 @@ impl Node for NodeService {
         &self,
         request: Request<NodeUnpublishVolumeRequest>,
     ) -> Result<Response<NodeUnpublishVolumeResponse>, Status> {
+        let _op = self.drain.track();
         let req = request.into_inner();
 
         let target_path = PathBuf::from(&req.target_path);
@@ -152,6 +166,7 @@ impl Node for NodeService {
         &self,
         request: Request<NodeGetVolumeStatsRequest>,
     ) -> Result<Response<NodeGetVolumeStatsResponse>, Status> {
+        let _op = self.drain.track();
         let req = request.into_inner();
 
         // Get mount path
@@ -3,7 +3,14 @@ use crate::csi::proto::{
 };
 use crate::csi::{ControllerService, IdentityService, NodeService};
 use anyhow::{Context, Result};
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
 use tonic::transport::Server;
+use tonic::transport::server::Router;
+
+/// How long in-flight RPCs get to finish once shutdown starts
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
 
 /// CSI gRPC server
 #[allow(dead_code)] // Fields used in serve methods
@@ -22,114 +29,157 @@ impl CsiServer {
         Self { identity, controller, node }
     }
 
-    /// Serve controller service
+    /// Resolves on the first SIGTERM or SIGINT
+    pub async fn shutdown_signal() {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Ok(sigterm) => sigterm,
+            Err(e) => {
+                tracing::warn!("Failed to install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = sigterm.recv() => tracing::info!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => tracing::info!("Received SIGINT"),
+        }
+    }
+
+    /// Serve controller service until `shutdown` resolves
     pub async fn serve_controller(
         identity: IdentityService,
         controller: ControllerService,
         addr: String,
+        shutdown: impl Future<Output = ()> + Send,
     ) -> Result<()> {
-        let addr_parsed =
-            addr.strip_prefix("unix://").context("Address must start with unix://")?;
-
-        // Remove existing socket if it exists
-        if std::path::Path::new(addr_parsed).exists() {
-            std::fs::remove_file(addr_parsed).context("Failed to remove existing socket")?;
-        }
-
-        // Create parent directory
-        if let Some(parent) = std::path::Path::new(addr_parsed).parent() {
-            std::fs::create_dir_all(parent).context("Failed to create socket directory")?;
-        }
-
-        // Create UDS listener
-        let uds =
-            tokio::net::UnixListener::bind(addr_parsed).context("Failed to bind Unix socket")?;
-        let uds_stream = tokio_stream::wrappers::UnixListenerStream::new(uds);
-
-        tracing::info!("CSI Controller listening on {}", addr);
-
-        Server::builder()
+        let router = Server::builder()
             .add_service(IdentityServer::new(identity))
-            .add_service(ControllerServer::new(controller))
-            .serve_with_incoming(uds_stream)
-            .await
-            .context("gRPC server error")
+            .add_service(ControllerServer::new(controller));
+        Self::serve_router(router, "Controller", &addr, shutdown).await
     }
 
-    /// Serve node service
+    /// Serve node service until `shutdown` resolves
     pub async fn serve_node(
         identity: IdentityService,
         node: NodeService,
         addr: String,
+        shutdown: impl Future<Output = ()> + Send,
     ) -> Result<()> {
-        let addr_parsed =
-            addr.strip_prefix("unix://").context("Address must start with unix://")?;
-
-        // Remove existing socket if it exists
-        if std::path::Path::new(addr_parsed).exists() {
-            std::fs::remove_file(addr_parsed).context("Failed to remove existing socket")?;
-        }
-
-        // Create parent directory
-        if let Some(parent) = std::path::Path::new(addr_parsed).parent() {
-            std::fs::create_dir_all(parent).context("Failed to create socket directory")?;
-        }
-
-        // Create UDS listener
-        let uds =
-            tokio::net::UnixListener::bind(addr_parsed).context("Failed to bind Unix socket")?;
-        let uds_stream = tokio_stream::wrappers::UnixListenerStream::new(uds);
-
-        tracing::info!("CSI Node listening on {}", addr);
-
-        Server::builder()
+        let router = Server::builder()
             .add_service(IdentityServer::new(identity))
-            .add_service(NodeServer::new(node))
-            .serve_with_incoming(uds_stream)
-            .await
-            .context("gRPC server error")
+            .add_service(NodeServer::new(node));
+        Self::serve_router(router, "Node", &addr, shutdown).await
     }
 
-    /// Serve both controller and node services
+    /// Serve both controller and node services until `shutdown` resolves
     pub async fn serve_all(
         identity: IdentityService,
         controller: ControllerService,
         node: NodeService,
         addr: String,
+        shutdown: impl Future<Output = ()> + Send,
     ) -> Result<()> {
-        let addr_parsed =
-            addr.strip_prefix("unix://").context("Address must start with unix://")?;
+        let router = Server::builder()
+            .add_service(IdentityServer::new(identity))
+            .add_service(ControllerServer::new(controller))
+            .add_service(NodeServer::new(node));
+        Self::serve_router(router, "Controller+Node", &addr, shutdown).await
+    }
+
+    /// Remove a socket left behind by a previous run
+    ///
+    /// A socket something still accepts connections on belongs to a live
+    /// process and is left alone, as is anything that isn't a socket.
+    fn remove_stale_socket(socket_path: &Path) -> Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let metadata = match std::fs::symlink_metadata(socket_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context("Failed to inspect existing socket"),
+        };
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", socket_path.display());
+        }
 
-        // Remove existing socket if it exists
-        if std::path::Path::new(addr_parsed).exists() {
-            std::fs::remove_file(addr_parsed).context("Failed to remove existing socket")?;
+        match std::os::unix::net::UnixStream::connect(socket_path) {
+            Ok(_) => anyhow::bail!("{} is in use by another process", socket_path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                tracing::info!("Removing stale socket {}", socket_path.display());
+                std::fs::remove_file(socket_path).context("Failed to remove stale socket")
+            }
+            Err(e) => Err(e).context("Failed to probe existing socket"),
         }
+    }
+
+    /// Serve on a unix socket until `shutdown` resolves
+    ///
+    /// Once it does, new connections are refused and in-flight RPCs get
+    /// `SHUTDOWN_GRACE` to finish before the server is dropped. The socket
+    /// file is removed either way.
+    async fn serve_router(
+        router: Router,
+        role: &str,
+        addr: &str,
+        shutdown: impl Future<Output = ()> + Send,
+    ) -> Result<()> {
+        let socket_path =
+            addr.strip_prefix("unix://").context("Address must start with unix://")?;
+        let socket_path = Path::new(socket_path);
+
+        Self::remove_stale_socket(socket_path)?;
 
         // Create parent directory
-        if let Some(parent) = std::path::Path::new(addr_parsed).parent() {
+        if let Some(parent) = socket_path.parent() {
             std::fs::create_dir_all(parent).context("Failed to create socket directory")?;
         }
 
         // Create UDS listener
         let uds =
-            tokio::net::UnixListener::bind(addr_parsed).context("Failed to bind Unix socket")?;
+            tokio::net::UnixListener::bind(socket_path).context("Failed to bind Unix socket")?;
         let uds_stream = tokio_stream::wrappers::UnixListenerStream::new(uds);
 
-        tracing::info!("CSI Controller+Node listening on {}", addr);
+        tracing::info!("CSI {} listening on {}", role, addr);
 
-        Server::builder()
-            .add_service(IdentityServer::new(identity))
-            .add_service(ControllerServer::new(controller))
-            .add_service(NodeServer::new(node))
-            .serve_with_incoming(uds_stream)
-            .await
-            .context("gRPC server error")
+        let (stopping_tx, stopping_rx) = tokio::sync::oneshot::channel();
+        let server = router.serve_with_incoming_shutdown(uds_stream, async move {
+            shutdown.await;
+            let _ = stopping_tx.send(());
+        });
+        tokio::pin!(server);
+
+        let result = tokio::select! {
+            result = &mut server => result,
+            _ = stopping_rx => {
+                tracing::info!("CSI {} shutting down, draining in-flight RPCs", role);
+                match tokio::time::timeout(SHUTDOWN_GRACE, &mut server).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        tracing::warn!("RPCs still in flight after {:?}, exiting", SHUTDOWN_GRACE);
+                        Ok(())
+                    }
+                }
+            }
+        };
+
+        if let Err(e) = std::fs::remove_file(socket_path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            tracing::warn!("Failed to remove socket {}: {}", socket_path.display(), e);
+        }
+
+        result.context("gRPC server error")
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::csi::{SnapshotManager, TenantMapper};
+    use crate::storage::{LayerOperations, TenantOperations};
+    use std::sync::Arc;
 
     #[test]
     fn test_csi_server_creation() {
@@ -138,4 +188,95 @@ mod tests {
         assert!(server.controller.is_none());
         assert!(server.node.is_none());
     }
+
+    fn controller() -> ControllerService {
+        // Lazy pool: no RPC is made, so it never connects
+        let pool: &'static sqlx::PgPool =
+            Box::leak(Box::new(sqlx::PgPool::connect_lazy("postgres://localhost/none").unwrap()));
+        let tenant_ops = Arc::new(TenantOperations::new(pool));
+        ControllerService::new(
+            Arc::new(TenantMapper::new(tenant_ops.clone(), Arc::new(LayerOperations::new(pool)))),
+            Arc::new(SnapshotManager::new(pool)),
+            tenant_ops,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_removes_socket() {
+        let controller = controller();
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("csi.sock");
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(CsiServer::serve_controller(
+            IdentityService::new(),
+            controller,
+            format!("unix://{}", socket.display()),
+            async move {
+                let _ = shutdown_rx.await;
+            },
+        ));
+
+        for _ in 0..100 {
+            if socket.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(socket.exists(), "server never bound its socket");
+        tokio::net::UnixStream::connect(&socket).await.unwrap();
+
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+        assert!(!socket.exists());
+    }
+
+    #[tokio::test]
+    async fn test_binds_over_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("csi.sock");
+        // A listener that went away without unlinking, as after a crash
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        assert!(socket.exists());
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(CsiServer::serve_controller(
+            IdentityService::new(),
+            controller(),
+            format!("unix://{}", socket.display()),
+            async move {
+                let _ = shutdown_rx.await;
+            },
+        ));
+
+        let mut connected = false;
+        for _ in 0..100 {
+            if tokio::net::UnixStream::connect(&socket).await.is_ok() {
+                connected = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(connected, "server never accepted on the stale socket path");
+
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refuses_socket_of_live_listener() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("csi.sock");
+        let _live = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+
+        let result = CsiServer::serve_controller(
+            IdentityService::new(),
+            controller(),
+            format!("unix://{}", socket.display()),
+            std::future::pending(),
+        )
+        .await;
+
+        assert!(result.unwrap_err().to_string().contains("in use"));
+        assert!(socket.exists());
+    }
 }
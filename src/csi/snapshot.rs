@@ -1,89 +1,140 @@
+use crate::layer::LayerManager;
 use crate::storage::traits::LayerRepository;
-use crate::storage::{CreateLayerInput, Layer, LayerOperations};
+use crate::storage::{InodeOperations, Layer, LayerOperations};
 use anyhow::{Context, Result};
-use std::sync::Arc;
+use sqlx::PgPool;
 use uuid::Uuid;
 
+/// Tag marking a layer as the CSI snapshot with the given name
+const SNAPSHOT_TAG_PREFIX: &str = "csi-snapshot:";
+
+/// Tag recording the volume's size in bytes when the snapshot was taken.
+/// The layer's own stats only count what changed in that layer.
+const SIZE_TAG_PREFIX: &str = "csi-snapshot-size:";
+
 /// Manages volume snapshots using Tarbox layers
+///
+/// A snapshot is the tenant's current layer, frozen read-only and tagged with
+/// the CSI snapshot name; writes continue in a new layer on top of it.
 #[derive(Clone)]
 pub struct SnapshotManager<'a> {
-    layer_ops: Arc<LayerOperations<'a>>,
+    pool: &'a PgPool,
 }
 
 impl<'a> SnapshotManager<'a> {
-    pub fn new(layer_ops: Arc<LayerOperations<'a>>) -> Self {
-        Self { layer_ops }
+    pub fn new(pool: &'a PgPool) -> Self {
+        Self { pool }
+    }
+
+    fn layer_ops(&self) -> LayerOperations<'a> {
+        LayerOperations::new(self.pool)
+    }
+
+    /// CSI snapshot ID for a layer. It carries the tenant so requests that
+    /// only name the snapshot can still be scoped to one tenant.
+    pub fn snapshot_id(tenant_id: Uuid, layer_id: Uuid) -> String {
+        format!("{}:{}", tenant_id, layer_id)
+    }
+
+    /// Split a CSI snapshot ID into `(tenant_id, layer_id)`
+    pub fn parse_snapshot_id(snapshot_id: &str) -> Result<(Uuid, Uuid)> {
+        let (tenant, layer) = snapshot_id
+            .split_once(':')
+            .context("Invalid snapshot_id format, expected <tenant>:<layer>")?;
+        Ok((
+            Uuid::parse_str(tenant).context("Invalid tenant in snapshot_id")?,
+            Uuid::parse_str(layer).context("Invalid layer in snapshot_id")?,
+        ))
+    }
+
+    /// CSI name of the snapshot a layer holds, if it is one
+    pub fn snapshot_name(layer: &Layer) -> Option<String> {
+        LayerManager::tags_of(layer)
+            .into_iter()
+            .find_map(|tag| tag.strip_prefix(SNAPSHOT_TAG_PREFIX).map(String::from))
+    }
+
+    /// Size of the volume when the snapshot was taken, or 0 if unknown
+    pub fn snapshot_size(layer: &Layer) -> i64 {
+        LayerManager::tags_of(layer)
+            .iter()
+            .find_map(|tag| tag.strip_prefix(SIZE_TAG_PREFIX)?.parse().ok())
+            .unwrap_or(0)
     }
 
     /// Create snapshot for a tenant
     ///
-    /// Creates a new layer on top of current layer
+    /// Freezes the current layer as the snapshot and moves writes to a new
+    /// layer on top. Creating a snapshot under a name that already exists
+    /// returns the existing one, as CSI requires.
     pub async fn create_snapshot(&self, tenant_id: Uuid, snapshot_name: &str) -> Result<Layer> {
-        // Get current layer
-        let current_layer_id = self
-            .layer_ops
-            .get_current_layer(tenant_id)
-            .await?
-            .context("No current layer found for tenant")?;
-
-        // Create new layer as snapshot
-        let snapshot_layer = self
-            .layer_ops
-            .create(CreateLayerInput {
-                tenant_id,
-                parent_layer_id: Some(current_layer_id),
-                layer_name: snapshot_name.to_string(),
-                description: Some(format!("Snapshot: {}", snapshot_name)),
-                tags: None,
-                created_by: "csi-driver".to_string(),
-                mount_entry_id: None,
-                is_working: false,
-            })
+        if let Some(existing) = self.find_by_name(tenant_id, snapshot_name).await? {
+            return Ok(existing);
+        }
+
+        let manager = LayerManager::new(self.pool, tenant_id);
+        let frozen = manager.get_current_layer().await.context("No current layer for tenant")?;
+        manager
+            .create_checkpoint(
+                &format!("after-{}", snapshot_name),
+                Some(&format!("Writes after snapshot {}", snapshot_name)),
+            )
             .await
             .context("Failed to create snapshot layer")?;
 
-        Ok(snapshot_layer)
+        let size = InodeOperations::new(self.pool).total_file_size(tenant_id).await?;
+        let mut tags = LayerManager::tags_of(&frozen);
+        tags.push(format!("{}{}", SNAPSHOT_TAG_PREFIX, snapshot_name));
+        tags.push(format!("{}{}", SIZE_TAG_PREFIX, size));
+        Ok(manager.set_tags(frozen.layer_id, &tags).await?)
     }
 
     /// Delete snapshot
+    ///
+    /// Fails with `LayerManagerError::HasChildLayers` while other layers are
+    /// built on top of the snapshot. Deleting a missing snapshot succeeds.
     pub async fn delete_snapshot(&self, tenant_id: Uuid, snapshot_id: Uuid) -> Result<()> {
-        let _deleted = self
-            .layer_ops
-            .delete(tenant_id, snapshot_id)
-            .await
-            .context("Failed to delete snapshot layer")?;
+        if self.get_snapshot(tenant_id, snapshot_id).await?.is_none() {
+            return Ok(());
+        }
 
+        LayerManager::new(self.pool, tenant_id).delete_layer(snapshot_id).await?;
         Ok(())
     }
 
-    /// List all snapshots (layers) for a tenant
+    /// List all snapshots for a tenant
     pub async fn list_snapshots(&self, tenant_id: Uuid) -> Result<Vec<Layer>> {
-        self.layer_ops.list(tenant_id).await.context("Failed to list snapshots")
+        let layers = self.layer_ops().list(tenant_id).await.context("Failed to list snapshots")?;
+        Ok(layers.into_iter().filter(|layer| Self::snapshot_name(layer).is_some()).collect())
     }
 
     /// Restore from snapshot
     ///
     /// Sets the specified layer as current layer
     pub async fn restore_from_snapshot(&self, tenant_id: Uuid, snapshot_id: Uuid) -> Result<()> {
+        let layer_ops = self.layer_ops();
+
         // Verify snapshot exists
-        self.layer_ops.get(tenant_id, snapshot_id).await?.context("Snapshot not found")?;
+        layer_ops.get(tenant_id, snapshot_id).await?.context("Snapshot not found")?;
 
         // Set as current layer
-        self.layer_ops
+        layer_ops
             .set_current_layer(tenant_id, snapshot_id)
             .await
             .context("Failed to restore from snapshot")
     }
 
-    /// Get snapshot by ID (snapshot is just a layer)
-    pub async fn get_snapshot(
-        &self,
-        _tenant_id: Uuid,
-        _snapshot_id: Uuid,
-    ) -> Result<Option<Layer>> {
-        // LayerRepository::get requires tenant_id and layer_id
-        // But we can't implement this without proper storage access
-        Err(anyhow::anyhow!("get_snapshot not implemented - requires LayerRepository::get"))
+    /// Get snapshot by ID. Layers that are not snapshots are not returned.
+    pub async fn get_snapshot(&self, tenant_id: Uuid, snapshot_id: Uuid) -> Result<Option<Layer>> {
+        let layer = self.layer_ops().get(tenant_id, snapshot_id).await?;
+        Ok(layer.filter(|layer| Self::snapshot_name(layer).is_some()))
+    }
+
+    async fn find_by_name(&self, tenant_id: Uuid, snapshot_name: &str) -> Result<Option<Layer>> {
+        let snapshots = self.list_snapshots(tenant_id).await?;
+        Ok(snapshots
+            .into_iter()
+            .find(|layer| Self::snapshot_name(layer).as_deref() == Some(snapshot_name)))
     }
 }
 
@@ -102,7 +153,21 @@ mod tests {
             return; // Skip if no DB available
         }
         let pool = pool_result.unwrap();
-        let layer_ops = Arc::new(LayerOperations::new(&pool));
-        let _manager = SnapshotManager::new(layer_ops);
+        let _manager = SnapshotManager::new(&pool);
+    }
+
+    #[test]
+    fn test_snapshot_id_roundtrip() {
+        let tenant_id = Uuid::new_v4();
+        let layer_id = Uuid::new_v4();
+        let id = SnapshotManager::snapshot_id(tenant_id, layer_id);
+        assert_eq!(SnapshotManager::parse_snapshot_id(&id).unwrap(), (tenant_id, layer_id));
+    }
+
+    #[test]
+    fn test_parse_snapshot_id_invalid() {
+        assert!(SnapshotManager::parse_snapshot_id("not-a-snapshot").is_err());
+        assert!(SnapshotManager::parse_snapshot_id(&Uuid::new_v4().to_string()).is_err());
+        assert!(SnapshotManager::parse_snapshot_id(&format!("{}:x", Uuid::new_v4())).is_err());
     }
 }
@@ -1,6 +1,9 @@
+use crate::fs::FileSystem;
+use crate::fs::ingest::{IngestEntry, IngestOptions, ingest};
 use crate::storage::traits::{LayerRepository, TenantRepository};
-use crate::storage::{LayerOperations, TenantOperations};
+use crate::storage::{InodeType, LayerOperations, TenantOperations};
 use anyhow::{Context, Result};
+use sqlx::PgPool;
 use std::sync::Arc;
 use uuid::Uuid;
 
@@ -12,11 +15,19 @@ use uuid::Uuid;
 pub struct TenantMapper<'a> {
     tenant_ops: Arc<TenantOperations<'a>>,
     layer_ops: Arc<LayerOperations<'a>>,
+    /// Needed to copy file contents when cloning volumes
+    pool: Option<&'a PgPool>,
 }
 
 impl<'a> TenantMapper<'a> {
     pub fn new(tenant_ops: Arc<TenantOperations<'a>>, layer_ops: Arc<LayerOperations<'a>>) -> Self {
-        Self { tenant_ops, layer_ops }
+        Self { tenant_ops, layer_ops, pool: None }
+    }
+
+    /// Allow volumes to be cloned, copying file contents through `pool`
+    pub fn with_pool(mut self, pool: &'a PgPool) -> Self {
+        self.pool = Some(pool);
+        self
     }
 
     /// Create tenant from PVC
@@ -43,7 +54,8 @@ impl<'a> TenantMapper<'a> {
             .context("Failed to create tenant")?;
 
         // Create base layer for tenant
-        self.layer_ops
+        let base = self
+            .layer_ops
             .create(crate::storage::CreateLayerInput {
                 tenant_id: tenant.tenant_id,
                 parent_layer_id: None,
@@ -56,10 +68,76 @@ impl<'a> TenantMapper<'a> {
             })
             .await
             .context("Failed to create base layer")?;
+        self.layer_ops
+            .set_current_layer(tenant.tenant_id, base.layer_id)
+            .await
+            .context("Failed to set base layer as current")?;
 
         Ok(tenant.tenant_id)
     }
 
+    /// Create tenant for a PVC cloned from another volume
+    ///
+    /// Tenants never share inodes, so the clone's base layer gets its own
+    /// copy of the source's current tree; text lines are content-addressed
+    /// and stay shared. Writes to either volume never reach the other.
+    /// Cloning into a PVC that already has a tenant returns that tenant.
+    pub async fn clone_tenant_from_volume(
+        &self,
+        source_volume_id: &str,
+        namespace: &str,
+        pvc_name: &str,
+        capacity_bytes: i64,
+    ) -> Result<Uuid> {
+        let pool = self.pool.context("Volume cloning requires a database pool")?;
+        let source_id = self.parse_volume_id(source_volume_id)?;
+        self.tenant_ops.get_by_id(source_id).await?.context("Source volume not found")?;
+
+        let tenant_name = Self::format_tenant_name(namespace, pvc_name);
+        if let Some(existing) = self.tenant_ops.get_by_name(&tenant_name).await? {
+            return Ok(existing.tenant_id);
+        }
+
+        let tenant_id = self.create_tenant_from_pvc(namespace, pvc_name, capacity_bytes).await?;
+        if let Err(e) = Self::copy_tree(pool, source_id, tenant_id).await {
+            // Don't leave a half-copied volume behind for a retry to pick up
+            self.tenant_ops.delete(tenant_id).await.context("Failed to remove partial clone")?;
+            return Err(e.context(format!("Failed to clone volume {}", source_volume_id)));
+        }
+
+        Ok(tenant_id)
+    }
+
+    async fn copy_tree(pool: &PgPool, source_id: Uuid, tenant_id: Uuid) -> Result<()> {
+        let source = FileSystem::new(pool, source_id).await?;
+        let mut entries = Vec::new();
+        let mut pending = vec!["/".to_string()];
+        while let Some(dir) = pending.pop() {
+            for child in source.list_directory(&dir).await? {
+                let path = if dir == "/" {
+                    format!("/{}", child.name)
+                } else {
+                    format!("{}/{}", dir, child.name)
+                };
+                match child.inode_type {
+                    InodeType::Dir => {
+                        entries.push(IngestEntry::Directory { path: path.clone() });
+                        pending.push(path);
+                    }
+                    InodeType::File => {
+                        let data = source.read_file(&path).await?;
+                        entries.push(IngestEntry::File { path, data });
+                    }
+                    InodeType::Symlink => {}
+                }
+            }
+        }
+
+        let clone = FileSystem::new(pool, tenant_id).await?;
+        ingest(&clone, entries, IngestOptions { atomic: true, ..Default::default() }).await?;
+        Ok(())
+    }
+
     /// Delete tenant for volume
     pub async fn delete_tenant_for_volume(&self, volume_id: &str) -> Result<()> {
         let tenant_id =
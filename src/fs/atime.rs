@@ -0,0 +1,118 @@
+//! When reads update a file's access time, modelled on the Linux mount options.
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::storage::Inode;
+
+/// Policy for bumping `atime` when a file is read.
+///
+/// Every update is a write to the primary, so the default leaves `atime`
+/// alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AtimeMode {
+    /// Reads never touch `atime`.
+    #[default]
+    Noatime,
+    /// Update `atime` only if it is not newer than `mtime` or `ctime`, or is
+    /// more than a day old.
+    Relatime,
+    /// Update `atime` on every read.
+    Strictatime,
+}
+
+/// How stale `atime` may get under `Relatime` before a read refreshes it
+const RELATIME_INTERVAL_HOURS: i64 = 24;
+
+impl AtimeMode {
+    /// Whether reading `inode` at `now` should set its `atime` to `now`.
+    pub fn should_update(&self, inode: &Inode, now: DateTime<Utc>) -> bool {
+        match self {
+            AtimeMode::Noatime => false,
+            AtimeMode::Strictatime => true,
+            AtimeMode::Relatime => {
+                inode.atime <= inode.mtime
+                    || inode.atime <= inode.ctime
+                    || now - inode.atime >= Duration::hours(RELATIME_INTERVAL_HOURS)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for AtimeMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AtimeMode::Noatime => write!(f, "noatime"),
+            AtimeMode::Relatime => write!(f, "relatime"),
+            AtimeMode::Strictatime => write!(f, "strictatime"),
+        }
+    }
+}
+
+impl std::str::FromStr for AtimeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "noatime" => Ok(AtimeMode::Noatime),
+            "relatime" => Ok(AtimeMode::Relatime),
+            "strictatime" => Ok(AtimeMode::Strictatime),
+            _ => Err(format!("Invalid atime mode: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InodeType;
+    use uuid::Uuid;
+
+    fn inode(atime: DateTime<Utc>, mtime: DateTime<Utc>) -> Inode {
+        Inode {
+            inode_id: 2,
+            tenant_id: Uuid::new_v4(),
+            parent_id: Some(1),
+            name: "f.txt".into(),
+            inode_type: InodeType::File,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime,
+            mtime,
+            ctime: mtime,
+        }
+    }
+
+    #[test]
+    fn test_relatime_updates_after_modification_or_a_day() {
+        let now = Utc::now();
+        let mode = AtimeMode::Relatime;
+
+        // Read since the last change: left alone
+        assert!(!mode.should_update(&inode(now, now - Duration::hours(1)), now));
+        // Changed since the last read
+        assert!(
+            mode.should_update(&inode(now - Duration::hours(2), now - Duration::hours(1)), now)
+        );
+        // Read recently but more than a day ago
+        let day_old = now - Duration::hours(RELATIME_INTERVAL_HOURS);
+        assert!(mode.should_update(&inode(day_old, day_old - Duration::hours(1)), now));
+    }
+
+    #[test]
+    fn test_noatime_and_strictatime() {
+        let now = Utc::now();
+        let fresh = inode(now, now - Duration::hours(1));
+        assert!(!AtimeMode::Noatime.should_update(&inode(now - Duration::days(7), now), now));
+        assert!(AtimeMode::Strictatime.should_update(&fresh, now));
+    }
+
+    #[test]
+    fn test_atime_mode_parse() {
+        assert_eq!("relatime".parse::<AtimeMode>(), Ok(AtimeMode::Relatime));
+        assert_eq!("StrictAtime".parse::<AtimeMode>(), Ok(AtimeMode::Strictatime));
+        assert_eq!(AtimeMode::Noatime.to_string().parse::<AtimeMode>(), Ok(AtimeMode::Noatime));
+        assert!("atime".parse::<AtimeMode>().is_err());
+    }
+}
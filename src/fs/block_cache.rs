@@ -0,0 +1,335 @@
+//! Cache of fixed-size file blocks for ranged reads.
+//!
+//! FUSE reads a file in windows of up to 128 KiB that rarely line up with
+//! block boundaries, so neighbouring windows need some of the same blocks.
+//! Blocks are kept by inode, the layer they were read through and index, so a
+//! sequential read fetches each block from the database once. Writes through a
+//! `FileSystem` drop the written inode's blocks; changes made elsewhere are
+//! only seen once the owner drops them too (see `invalidate_all`).
+//!
+//! With read-ahead on (see `with_readahead`), a read that picks up where the
+//! previous one on the same file left off also fetches the next few blocks,
+//! in the same query as the ones it needs, so a sequential reader finds them
+//! cached. Reads elsewhere in the file fetch only what they cover.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use bytes::Bytes;
+use moka::future::Cache;
+use moka::policy::EvictionPolicy;
+
+use crate::layer::BLOCK_SIZE;
+use crate::storage::BlockRepository;
+use crate::types::{InodeId, LayerId, TenantId};
+
+/// Bytes of block data kept by `BlockCache::default`.
+pub const DEFAULT_BLOCK_CACHE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Files whose last read block is remembered for read-ahead.
+const TRACKED_READERS: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BlockKey {
+    inode_id: InodeId,
+    layer_id: LayerId,
+    index: i32,
+}
+
+/// Recently read blocks, least recently used evicted first. Clones share
+/// the same cache.
+#[derive(Clone)]
+pub struct BlockCache {
+    blocks: Cache<BlockKey, Bytes>,
+    /// Last block read of each recently read file, by inode and layer
+    last_read: Cache<(InodeId, LayerId), i32>,
+    /// Blocks fetched past a sequential read; 0 turns read-ahead off.
+    readahead_blocks: u32,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_BLOCK_CACHE_BYTES)
+    }
+}
+
+impl BlockCache {
+    /// Build a cache holding at most `capacity` bytes of block data.
+    pub fn new(capacity: u64) -> Self {
+        let blocks = Cache::builder()
+            .max_capacity(capacity)
+            // Holes are cached as empty blocks; they still take a slot
+            .weigher(|_, data: &Bytes| data.len().max(1) as u32)
+            .eviction_policy(EvictionPolicy::lru())
+            .support_invalidation_closures()
+            .build();
+        let last_read = Cache::builder().max_capacity(TRACKED_READERS).build();
+        Self { blocks, last_read, readahead_blocks: 0 }
+    }
+
+    /// Fetch up to `blocks` blocks past each sequential read.
+    pub fn with_readahead(mut self, blocks: u32) -> Self {
+        self.readahead_blocks = blocks;
+        self
+    }
+
+    /// Read `len` bytes at `offset` of the `size`-byte file `inode_id` as
+    /// seen from `layer_id`. Blocks not cached yet are fetched from `repo`,
+    /// one query per run of consecutive missing blocks, along with those of
+    /// the read-ahead window if the read follows on from the previous one.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn read(
+        &self,
+        repo: &dyn BlockRepository,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        size: u64,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        let end = offset.saturating_add(len).min(size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        let block_size = BLOCK_SIZE as u64;
+        let first = (offset / block_size) as i32;
+        let last = ((end - 1) / block_size) as i32;
+        let key = |index| BlockKey { inode_id, layer_id, index };
+
+        let mut blocks = Vec::with_capacity((last - first + 1) as usize);
+        let mut missing = Vec::new();
+        for index in first..=last {
+            let cached = self.blocks.get(&key(index)).await;
+            if cached.is_none() {
+                missing.push(index);
+            }
+            blocks.push(cached);
+        }
+        if let Some(ahead) = self.readahead(inode_id, layer_id, size, first, last).await {
+            missing.extend(ahead.filter(|&index| !self.blocks.contains_key(&key(index))));
+        }
+
+        for (from, count) in runs(&missing) {
+            let mut fetched: HashMap<i32, Bytes> = repo
+                .list_visible_range(tenant_id, inode_id, layer_id, from, count)
+                .await?
+                .into_iter()
+                .map(|block| (block.block_index, Bytes::from(block.data)))
+                .collect();
+            for index in from..from + count {
+                // An index without a block is a hole and reads as zeros
+                let data = fetched.remove(&index).unwrap_or_default();
+                self.blocks.insert(key(index), data.clone()).await;
+                if index <= last {
+                    blocks[(index - first) as usize] = Some(data);
+                }
+            }
+        }
+
+        let mut out = vec![0u8; (end - offset) as usize];
+        for (i, data) in blocks.into_iter().enumerate() {
+            let data = data.unwrap_or_default();
+            let start = (first as u64 + i as u64) * block_size;
+            let (from, to) = (start.max(offset), (start + data.len() as u64).min(end));
+            if from < to {
+                out[(from - offset) as usize..(to - offset) as usize]
+                    .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Blocks to fetch ahead of a read of blocks `first..=last`: the next
+    /// `readahead_blocks` within the file if the previous read of the file
+    /// ended at or just before `first`.
+    async fn readahead(
+        &self,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        size: u64,
+        first: i32,
+        last: i32,
+    ) -> Option<std::ops::RangeInclusive<i32>> {
+        if self.readahead_blocks == 0 {
+            return None;
+        }
+        let previous = self.last_read.get(&(inode_id, layer_id)).await;
+        self.last_read.insert((inode_id, layer_id), last).await;
+        // FUSE windows rarely end on a block boundary, so the next one
+        // usually starts in the block the last one ended in
+        if !previous.is_some_and(|previous| first == previous || first == previous + 1) {
+            return None;
+        }
+        let blocks = i32::try_from(self.readahead_blocks).unwrap_or(i32::MAX);
+        let end_block = ((size - 1) / BLOCK_SIZE as u64) as i32;
+        Some(last + 1..=last.saturating_add(blocks).min(end_block))
+    }
+
+    /// Forget the blocks of `inode_id`, e.g. because it was just written.
+    pub fn invalidate_inode(&self, inode_id: InodeId) {
+        // Only fails if closures aren't supported, and they are enabled in `new`
+        let _ = self.blocks.invalidate_entries_if(move |key, _| key.inode_id == inode_id);
+    }
+
+    /// Forget every block, e.g. after another process changed some file.
+    pub fn invalidate_all(&self) {
+        self.blocks.invalidate_all();
+    }
+}
+
+/// Split sorted block indices into `(first, count)` runs of consecutive ones.
+fn runs(indices: &[i32]) -> Vec<(i32, i32)> {
+    let mut runs: Vec<(i32, i32)> = Vec::new();
+    for &index in indices {
+        match runs.last_mut() {
+            Some((first, count)) if *first + *count == index => *count += 1,
+            _ => runs.push((index, 1)),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::storage::DataBlock;
+    use crate::storage::traits::MockBlockRepository;
+
+    /// A repository holding `blocks` full blocks of one file, recording each
+    /// block index it returns
+    fn counting_repo(blocks: i32, fetched: Arc<Mutex<Vec<i32>>>) -> MockBlockRepository {
+        let mut repo = MockBlockRepository::new();
+        repo.expect_list_visible_range().returning(
+            move |tenant_id, inode_id, layer_id, first, count| {
+                let indices: Vec<i32> = (first..first + count).filter(|&i| i < blocks).collect();
+                fetched.lock().unwrap().extend(&indices);
+                Ok(indices
+                    .into_iter()
+                    .map(|index| DataBlock {
+                        block_id: uuid::Uuid::new_v4(),
+                        tenant_id,
+                        inode_id,
+                        block_index: index,
+                        data: vec![index as u8; BLOCK_SIZE],
+                        size: BLOCK_SIZE as i32,
+                        content_hash: String::new(),
+                        created_at: chrono::Utc::now(),
+                        layer_id: Some(layer_id),
+                    })
+                    .collect())
+            },
+        );
+        repo
+    }
+
+    fn expected(range: std::ops::Range<usize>) -> Vec<u8> {
+        range.map(|at| (at / BLOCK_SIZE) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_reads_fetch_each_block_once() {
+        let fetched = Arc::new(Mutex::new(Vec::new()));
+        let repo = counting_repo(8, fetched.clone());
+        let cache = BlockCache::default();
+        let (tenant_id, layer_id) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        let size = 8 * BLOCK_SIZE as u64;
+
+        // Two windows sharing blocks 2 to 4, neither aligned to a block
+        let first = cache.read(&repo, tenant_id, 7, layer_id, size, 100, 4 * 4096).await.unwrap();
+        assert_eq!(first, expected(100..100 + 4 * 4096));
+        let second = cache.read(&repo, tenant_id, 7, layer_id, size, 9000, 4 * 4096).await.unwrap();
+        assert_eq!(second, expected(9000..9000 + 4 * 4096));
+
+        let mut fetched = fetched.lock().unwrap().clone();
+        let total = fetched.len();
+        fetched.sort();
+        fetched.dedup();
+        assert_eq!(total, fetched.len(), "a block was fetched twice");
+        assert_eq!(fetched, (0..7).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_and_holes() {
+        let fetched = Arc::new(Mutex::new(Vec::new()));
+        let repo = counting_repo(2, fetched.clone());
+        let cache = BlockCache::default();
+        let (tenant_id, layer_id) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+
+        // Past the stored blocks the file is a hole
+        let size = 3 * BLOCK_SIZE as u64;
+        let data = cache.read(&repo, tenant_id, 1, layer_id, size, 0, size).await.unwrap();
+        assert_eq!(&data[..2 * BLOCK_SIZE], expected(0..2 * BLOCK_SIZE));
+        assert!(data[2 * BLOCK_SIZE..].iter().all(|&b| b == 0));
+        cache.read(&repo, tenant_id, 1, layer_id, size, 0, size).await.unwrap();
+        assert_eq!(fetched.lock().unwrap().len(), 2);
+
+        // Reads are clamped to the file size
+        let tail = cache.read(&repo, tenant_id, 1, layer_id, size, size - 10, 100).await.unwrap();
+        assert_eq!(tail.len(), 10);
+        assert!(cache.read(&repo, tenant_id, 1, layer_id, size, size, 1).await.unwrap().is_empty());
+
+        cache.invalidate_inode(1);
+        cache.read(&repo, tenant_id, 1, layer_id, size, 0, 1).await.unwrap();
+        assert_eq!(fetched.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_sequential_reads_fetch_ahead() {
+        let fetched = Arc::new(Mutex::new(Vec::new()));
+        let repo = counting_repo(16, fetched.clone());
+        let cache = BlockCache::default().with_readahead(4);
+        let (tenant_id, layer_id) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        // Stored blocks past the end of the file must not be fetched
+        let size = 12 * BLOCK_SIZE as u64;
+
+        // Windows of two blocks, as `cat` would issue them; note how far
+        // fetching had got when each window was asked for
+        let window = 2 * BLOCK_SIZE as u64;
+        let mut fetched_before = Vec::new();
+        for offset in (0..size).step_by(window as usize) {
+            fetched_before.push(fetched.lock().unwrap().len());
+            let data =
+                cache.read(&repo, tenant_id, 3, layer_id, size, offset, window).await.unwrap();
+            assert_eq!(data, expected(offset as usize..(offset + window) as usize));
+        }
+
+        // The first read can't tell it's sequential; from the second on,
+        // every window after it finds its blocks already fetched
+        let fetched = fetched.lock().unwrap().clone();
+        assert_eq!(fetched, (0..12).collect::<Vec<_>>());
+        for (window, &before) in fetched_before.iter().enumerate().skip(2) {
+            let needed = 2 * window + 2;
+            assert!(before >= needed, "window {} waited on a fetch", window);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_random_reads_fetch_only_what_they_cover() {
+        let fetched = Arc::new(Mutex::new(Vec::new()));
+        let repo = counting_repo(16, fetched.clone());
+        let cache = BlockCache::default().with_readahead(4);
+        let (tenant_id, layer_id) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        let size = 16 * BLOCK_SIZE as u64;
+
+        for index in [10u64, 3, 7, 12, 1] {
+            let offset = index * BLOCK_SIZE as u64 + 10;
+            cache.read(&repo, tenant_id, 3, layer_id, size, offset, 100).await.unwrap();
+        }
+        assert_eq!(*fetched.lock().unwrap(), vec![10, 3, 7, 12, 1]);
+
+        // A window ending mid-block followed by one starting in that block
+        // counts as sequential; block 10 is still cached from before
+        cache.read(&repo, tenant_id, 3, layer_id, size, 5 * 4096 + 4000, 200).await.unwrap();
+        cache.read(&repo, tenant_id, 3, layer_id, size, 6 * 4096 + 104, 4096).await.unwrap();
+        assert_eq!(fetched.lock().unwrap()[5..], [5, 6, 8, 9, 11]);
+    }
+
+    #[test]
+    fn test_runs() {
+        assert_eq!(runs(&[]), vec![]);
+        assert_eq!(runs(&[1, 2, 3, 7, 9, 10]), vec![(1, 3), (7, 1), (9, 2)]);
+    }
+}
@@ -0,0 +1,46 @@
+//! Who a filesystem operation is performed for, as recorded in audit logs.
+
+/// The principal behind an operation.
+///
+/// FUSE callers are identified by the uid and gid of the calling process;
+/// several agents often share one uid, so an agent id can be attached too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallerContext {
+    pub uid: u32,
+    pub gid: u32,
+    /// Agent acting for the user, from a mount option or API header.
+    pub agent_id: Option<String>,
+}
+
+impl CallerContext {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self { uid, gid, agent_id: None }
+    }
+
+    /// Attribute the caller's operations to `agent_id` as well.
+    pub fn with_agent(mut self, agent_id: Option<String>) -> Self {
+        self.agent_id = agent_id;
+        self
+    }
+
+    /// The effective user and group of this process: whoever mounted the
+    /// filesystem, which stands in for callers that aren't known.
+    pub fn current_process() -> Self {
+        // SAFETY: geteuid and getegid always succeed and touch no memory
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        Self::new(uid, gid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caller_context_agent() {
+        let caller = CallerContext::new(1000, 100).with_agent(Some("ci-runner".into()));
+        assert_eq!((caller.uid, caller.gid), (1000, 100));
+        assert_eq!(caller.agent_id.as_deref(), Some("ci-runner"));
+        assert_eq!(CallerContext::new(0, 0).with_agent(None).agent_id, None);
+    }
+}
@@ -28,6 +28,18 @@ pub enum FsError {
     #[error("Filename too long: {0} bytes (max 255)")]
     FilenameTooLong(usize),
 
+    #[error("No space left on device: {0}")]
+    NoSpace(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Too many levels of symbolic links: {0}")]
+    SymlinkLoop(String),
+
+    #[error("Invalid pattern: {0}")]
+    InvalidPattern(String),
+
     #[error("Storage error: {0}")]
     Storage(#[from] anyhow::Error),
 }
@@ -84,6 +96,14 @@ mod tests {
         assert_eq!(err.to_string(), "Filename too long: 300 bytes (max 255)");
     }
 
+    #[test]
+    fn test_no_space_and_quota_errors() {
+        let err = FsError::NoSpace("/big.bin".to_string());
+        assert_eq!(err.to_string(), "No space left on device: /big.bin");
+        let err = FsError::QuotaExceeded("/big.bin".to_string());
+        assert_eq!(err.to_string(), "Quota exceeded: /big.bin");
+    }
+
     #[test]
     fn test_fs_result_ok() {
         fn get_value() -> FsResult<i32> {
@@ -0,0 +1,163 @@
+//! Shell-style path patterns, as matched by `FileSystem::glob`.
+
+use crate::fs::error::FsResult;
+use crate::fs::path::normalize_path;
+
+/// A path pattern, split at its first wildcard.
+///
+/// `*` matches any run of characters within a name and `?` exactly one
+/// character. A `**` component matches any number of directories, none
+/// included. Relative patterns are taken from the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobPattern {
+    /// Leading components without wildcards: the directory searched
+    base: Vec<String>,
+    /// The rest, matched against paths below `base`
+    rest: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// `**`
+    AnyDirs,
+    Name(String),
+}
+
+impl GlobPattern {
+    pub fn parse(pattern: &str) -> FsResult<Self> {
+        let absolute = if pattern.starts_with('/') {
+            normalize_path(pattern)?
+        } else {
+            normalize_path(&format!("/{}", pattern))?
+        };
+
+        let mut base = Vec::new();
+        let mut rest = Vec::new();
+        for component in absolute.split('/').filter(|c| !c.is_empty()) {
+            if rest.is_empty() && !has_wildcard(component) {
+                base.push(component.to_string());
+            } else if component == "**" {
+                // Consecutive `**` match the same as one
+                if rest.last() != Some(&Segment::AnyDirs) {
+                    rest.push(Segment::AnyDirs);
+                }
+            } else {
+                rest.push(Segment::Name(component.to_string()));
+            }
+        }
+
+        Ok(Self { base, rest })
+    }
+
+    /// The directory below which matches are found, or the only match of a
+    /// pattern without wildcards.
+    pub fn base(&self) -> String {
+        format!("/{}", self.base.join("/"))
+    }
+
+    /// Whether the pattern has no wildcards and so names a single path.
+    pub fn is_literal(&self) -> bool {
+        self.rest.is_empty()
+    }
+
+    /// How many levels below `base` matches can be; `None` if `**` makes it
+    /// unbounded.
+    pub fn max_depth(&self) -> Option<usize> {
+        if self.rest.contains(&Segment::AnyDirs) { None } else { Some(self.rest.len()) }
+    }
+
+    /// Whether the path below `base` made of `names` matches.
+    pub fn matches(&self, names: &[&str]) -> bool {
+        match_segments(&self.rest, names)
+    }
+}
+
+fn has_wildcard(component: &str) -> bool {
+    component.contains(['*', '?'])
+}
+
+fn match_segments(segments: &[Segment], names: &[&str]) -> bool {
+    match segments.split_first() {
+        None => names.is_empty(),
+        Some((Segment::AnyDirs, rest)) => {
+            (0..=names.len()).any(|skip| match_segments(rest, &names[skip..]))
+        }
+        Some((Segment::Name(pattern), rest)) => match names.split_first() {
+            Some((name, names)) => match_name(pattern, name) && match_segments(rest, names),
+            None => false,
+        },
+    }
+}
+
+/// Match a single name against `*` and `?`, backtracking to the last `*`.
+fn match_name(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*`, and where in `name` it started matching
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(&c) if c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the `*` take one more character
+                Some((after, start)) => {
+                    p = after;
+                    n = start + 1;
+                    star = Some((after, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_name_wildcards() {
+        assert!(match_name("*.txt", "notes.txt"));
+        assert!(match_name("*.txt", ".txt"));
+        assert!(!match_name("*.txt", "notes.txt.bak"));
+        assert!(match_name("a?c*", "abcdef"));
+        assert!(!match_name("a?c", "ac"));
+        assert!(match_name("*a*b*", "xxaybzz"));
+        assert!(match_name("**", ""));
+    }
+
+    #[test]
+    fn test_glob_pattern_split_and_match() {
+        let pattern = GlobPattern::parse("/src/**/*.rs").unwrap();
+        assert_eq!(pattern.base(), "/src");
+        assert_eq!(pattern.max_depth(), None);
+        assert!(pattern.matches(&["main.rs"]));
+        assert!(pattern.matches(&["fs", "glob", "mod.rs"]));
+        assert!(!pattern.matches(&["fs", "README.md"]));
+
+        let pattern = GlobPattern::parse("docs/*/?.md").unwrap();
+        assert_eq!((pattern.base().as_str(), pattern.max_depth()), ("/docs", Some(2)));
+        assert!(pattern.matches(&["guide", "a.md"]));
+        assert!(!pattern.matches(&["a.md"]));
+
+        let pattern = GlobPattern::parse("/etc/hosts").unwrap();
+        assert!(pattern.is_literal());
+        assert_eq!(pattern.base(), "/etc/hosts");
+        assert!(GlobPattern::parse("/../x").is_err());
+    }
+}
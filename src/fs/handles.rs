@@ -0,0 +1,163 @@
+//! Open file handle tracking.
+//!
+//! A handle refers to an inode, not a path: editors commonly keep a file open,
+//! rename it and continue writing, and those writes must land in the renamed
+//! file. The path stored with each handle is only kept for change recording
+//! and diagnostics, and is fixed up whenever a rename moves it.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::types::InodeId;
+
+/// Identifier returned by `open` and passed back on every handle operation.
+pub type FileHandle = u64;
+
+/// State kept for an open handle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenFile {
+    pub inode_id: InodeId,
+    /// Current path of the inode, updated on rename.
+    pub path: String,
+    /// Flags the file was opened with (`O_RDONLY`, `O_WRONLY`, ...).
+    pub flags: i32,
+    /// User that opened the file.
+    pub uid: u32,
+    /// Position just past the last read or write through the handle.
+    pub offset: u64,
+}
+
+/// Table of open handles, shared by every `FileSystem` of a mount.
+#[derive(Debug)]
+pub struct HandleTable {
+    next_handle: AtomicU64,
+    open: Mutex<HashMap<FileHandle, OpenFile>>,
+}
+
+impl Default for HandleTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        // 0 is what FUSE reports when no handle was allocated
+        Self { next_handle: AtomicU64::new(1), open: Mutex::new(HashMap::new()) }
+    }
+
+    /// Register a new open handle for `inode_id`, opened by `uid`.
+    pub fn insert(&self, inode_id: InodeId, path: &str, flags: i32, uid: u32) -> FileHandle {
+        let fh = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        let file = OpenFile { inode_id, path: path.to_string(), flags, uid, offset: 0 };
+        self.open.lock().unwrap().insert(fh, file);
+        fh
+    }
+
+    pub fn get(&self, fh: FileHandle) -> Option<OpenFile> {
+        self.open.lock().unwrap().get(&fh).cloned()
+    }
+
+    /// Forget a handle. Returns its state if it was open.
+    pub fn remove(&self, fh: FileHandle) -> Option<OpenFile> {
+        self.open.lock().unwrap().remove(&fh)
+    }
+
+    /// Record that I/O through `fh` ended at `offset`. Unknown handles are
+    /// ignored.
+    pub fn set_offset(&self, fh: FileHandle, offset: u64) {
+        if let Some(file) = self.open.lock().unwrap().get_mut(&fh) {
+            file.offset = offset;
+        }
+    }
+
+    /// All open handles, ordered by handle number.
+    pub fn list(&self) -> Vec<(FileHandle, OpenFile)> {
+        let mut handles: Vec<_> =
+            self.open.lock().unwrap().iter().map(|(fh, file)| (*fh, file.clone())).collect();
+        handles.sort_by_key(|(fh, _)| *fh);
+        handles
+    }
+
+    pub fn len(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Rewrite the paths of handles at or below `from` to live under `to`.
+    ///
+    /// The inode of each handle is unchanged, so I/O through it is unaffected.
+    pub fn rename(&self, from: &str, to: &str) {
+        let prefix = format!("{}/", from);
+        for file in self.open.lock().unwrap().values_mut() {
+            if file.path == from || file.path.starts_with(&prefix) {
+                file.path = format!("{}{}", to, &file.path[from.len()..]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_get_remove() {
+        let table = HandleTable::new();
+        let fh = table.insert(42, "/a.txt", libc::O_RDWR, 1000);
+        assert_ne!(fh, 0);
+
+        let file = table.get(fh).unwrap();
+        assert_eq!(file.inode_id, 42);
+        assert_eq!(file.path, "/a.txt");
+        assert_eq!(file.flags, libc::O_RDWR);
+        assert_eq!(file.uid, 1000);
+        assert_eq!(file.offset, 0);
+
+        assert_eq!(table.remove(fh), Some(file));
+        assert!(table.get(fh).is_none());
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_handles_are_unique() {
+        let table = HandleTable::new();
+        let a = table.insert(1, "/a", 0, 0);
+        let b = table.insert(1, "/a", 0, 0);
+        assert_ne!(a, b);
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.list().iter().map(|(fh, _)| *fh).collect::<Vec<_>>(), vec![a, b]);
+    }
+
+    #[test]
+    fn test_set_offset() {
+        let table = HandleTable::new();
+        let fh = table.insert(1, "/a", 0, 0);
+        table.set_offset(fh, 4096);
+        assert_eq!(table.get(fh).unwrap().offset, 4096);
+
+        // Released handles stay gone
+        table.remove(fh);
+        table.set_offset(fh, 8192);
+        assert!(table.get(fh).is_none());
+    }
+
+    #[test]
+    fn test_rename_fixes_up_paths() {
+        let table = HandleTable::new();
+        let file = table.insert(1, "/dir/file", 0, 0);
+        let nested = table.insert(2, "/dir/sub/file", 0, 0);
+        let sibling = table.insert(3, "/dir2/file", 0, 0);
+
+        table.rename("/dir", "/moved");
+
+        assert_eq!(table.get(file).unwrap().path, "/moved/file");
+        assert_eq!(table.get(nested).unwrap().path, "/moved/sub/file");
+        assert_eq!(table.get(sibling).unwrap().path, "/dir2/file");
+        assert_eq!(table.get(file).unwrap().inode_id, 1);
+    }
+}
@@ -0,0 +1,290 @@
+//! Bulk ingestion of file trees.
+//!
+//! Imports (e.g. from a tar archive) can contain thousands of entries. Writing
+//! them all at once would exhaust the connection pool, so files are written
+//! through a bounded pipeline that keeps at most `max_in_flight` writes
+//! outstanding. Per-entry failures are collected instead of aborting the
+//! import, unless atomic mode is requested.
+
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::stream::{self, StreamExt};
+use tracing::{debug, info};
+
+use crate::fs::error::{FsError, FsResult};
+use crate::fs::operations::FileSystem;
+use crate::fs::path::normalize_path;
+use crate::storage::{DatabaseTransaction, InodeType};
+
+/// Default number of concurrent file writes during ingestion.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 8;
+
+/// Options controlling bulk ingestion.
+#[derive(Debug, Clone, Copy)]
+pub struct IngestOptions {
+    /// Maximum number of file writes outstanding at once (at least 1).
+    pub max_in_flight: usize,
+    /// Apply every entry in a single transaction; any failure rolls back the
+    /// whole import. Entries are then written one at a time.
+    pub atomic: bool,
+}
+
+impl Default for IngestOptions {
+    fn default() -> Self {
+        Self { max_in_flight: DEFAULT_MAX_IN_FLIGHT, atomic: false }
+    }
+}
+
+/// A single entry to ingest.
+#[derive(Debug, Clone)]
+pub enum IngestEntry {
+    Directory { path: String },
+    File { path: String, data: Vec<u8> },
+}
+
+impl IngestEntry {
+    pub fn path(&self) -> &str {
+        match self {
+            IngestEntry::Directory { path } | IngestEntry::File { path, .. } => path,
+        }
+    }
+}
+
+/// An entry that could not be ingested.
+#[derive(Debug)]
+pub struct IngestFailure {
+    pub path: String,
+    pub error: FsError,
+}
+
+/// Outcome of a bulk ingestion.
+#[derive(Debug, Default)]
+pub struct IngestReport {
+    pub directories_created: usize,
+    pub files_written: usize,
+    pub failures: Vec<IngestFailure>,
+    /// Highest number of file writes observed in flight at once.
+    pub peak_in_flight: usize,
+}
+
+impl IngestReport {
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Ingest `entries` into `fs`.
+///
+/// Directories (including missing parents of files) are created first, in
+/// path order, then files are written with at most `max_in_flight` writes in
+/// flight. Existing files are overwritten and existing directories reused.
+///
+/// In non-atomic mode failures are reported per entry and the rest of the
+/// import continues; the returned error is reserved for setup problems. In
+/// atomic mode the first failure is returned and nothing is kept.
+pub async fn ingest(
+    fs: &FileSystem<'_>,
+    entries: Vec<IngestEntry>,
+    options: IngestOptions,
+) -> FsResult<IngestReport> {
+    let (directories, files) = plan(entries);
+
+    if options.atomic {
+        return ingest_atomic(fs, directories, files).await;
+    }
+
+    let mut report = IngestReport::default();
+
+    for dir in directories {
+        match ensure_directory(fs, &dir).await {
+            Ok(true) => report.directories_created += 1,
+            Ok(false) => {}
+            Err(error) => report.failures.push(IngestFailure { path: dir, error }),
+        }
+    }
+
+    let in_flight = AtomicUsize::new(0);
+    let peak = AtomicUsize::new(0);
+    let limit = options.max_in_flight.max(1);
+
+    let results: Vec<(String, FsResult<()>)> = stream::iter(files)
+        .map(|(path, data)| {
+            let (in_flight, peak) = (&in_flight, &peak);
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                let result = write_entry(fs, &path, &data).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                (path, result)
+            }
+        })
+        .buffer_unordered(limit)
+        .collect()
+        .await;
+
+    for (path, result) in results {
+        match result {
+            Ok(()) => report.files_written += 1,
+            Err(error) => {
+                debug!(path = %path, error = %error, "Ingest entry failed");
+                report.failures.push(IngestFailure { path, error });
+            }
+        }
+    }
+    report.peak_in_flight = peak.load(Ordering::SeqCst);
+
+    info!(
+        directories = report.directories_created,
+        files = report.files_written,
+        failures = report.failures.len(),
+        max_in_flight = limit,
+        "Ingestion finished"
+    );
+
+    Ok(report)
+}
+
+async fn ingest_atomic(
+    fs: &FileSystem<'_>,
+    directories: Vec<String>,
+    files: Vec<(String, Vec<u8>)>,
+) -> FsResult<IngestReport> {
+    let mut report = IngestReport::default();
+    let mut tx = fs.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+
+    for dir in directories {
+        if ensure_directory_in_tx(fs, &mut tx, &dir).await? {
+            report.directories_created += 1;
+        }
+    }
+
+    for (path, data) in files {
+        write_entry_in_tx(fs, &mut tx, &path, &data).await?;
+        report.files_written += 1;
+    }
+    report.peak_in_flight = usize::from(report.files_written > 0);
+
+    tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+
+    info!(
+        directories = report.directories_created,
+        files = report.files_written,
+        "Atomic ingestion committed"
+    );
+
+    Ok(report)
+}
+
+/// Read the directories and regular files of a tar archive as ingest entries
+/// rooted at `dest`. Other entry types (links, devices) are skipped.
+pub fn entries_from_tar<R: Read>(reader: R, dest: &str) -> anyhow::Result<Vec<IngestEntry>> {
+    let dest = normalize_path(dest)?;
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let rel = entry.path()?.to_string_lossy().trim_start_matches("./").to_string();
+        let rel = rel.trim_matches('/');
+        if rel.is_empty() {
+            continue;
+        }
+        let path = if dest == "/" { format!("/{}", rel) } else { format!("{}/{}", dest, rel) };
+
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => entries.push(IngestEntry::Directory { path }),
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let mut data = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut data)?;
+                entries.push(IngestEntry::File { path, data });
+            }
+            other => debug!(path = %path, entry_type = ?other, "Skipping unsupported tar entry"),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Split entries into the directories to ensure (parents first) and the files
+/// to write. Invalid paths are kept so they surface as per-entry failures.
+fn plan(entries: Vec<IngestEntry>) -> (Vec<String>, Vec<(String, Vec<u8>)>) {
+    // Sorted order puts every parent before its children
+    let mut directories = BTreeSet::new();
+    let mut files = Vec::new();
+
+    for entry in entries {
+        let path = normalize_path(entry.path()).unwrap_or_else(|_| entry.path().to_string());
+        let mut ancestor = path.as_str();
+        while let Some(idx) = ancestor.rfind('/') {
+            ancestor = &ancestor[..idx];
+            if ancestor.is_empty() {
+                break;
+            }
+            directories.insert(ancestor.to_string());
+        }
+        match entry {
+            IngestEntry::Directory { .. } => {
+                if path != "/" {
+                    directories.insert(path);
+                }
+            }
+            IngestEntry::File { data, .. } => files.push((path, data)),
+        }
+    }
+
+    (directories.into_iter().collect(), files)
+}
+
+/// Create `path` as a directory if missing. Returns whether it was created.
+pub(crate) async fn ensure_directory(fs: &FileSystem<'_>, path: &str) -> FsResult<bool> {
+    match fs.create_directory(path).await {
+        Ok(_) => Ok(true),
+        Err(FsError::AlreadyExists(_)) => match fs.resolve_path(path).await? {
+            inode if inode.inode_type == InodeType::Dir => Ok(false),
+            _ => Err(FsError::NotDirectory(path.to_string())),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Write `data` to the file at `path`, creating it if missing.
+pub(crate) async fn write_entry(fs: &FileSystem<'_>, path: &str, data: &[u8]) -> FsResult<()> {
+    match fs.create_file(path).await {
+        Ok(_) | Err(FsError::AlreadyExists(_)) => {}
+        Err(e) => return Err(e),
+    }
+    fs.write_file(path, data).await
+}
+
+/// `ensure_directory` as part of a caller-managed transaction.
+pub(crate) async fn ensure_directory_in_tx(
+    fs: &FileSystem<'_>,
+    tx: &mut DatabaseTransaction<'_>,
+    path: &str,
+) -> FsResult<bool> {
+    match fs.resolve_path_in_tx(tx, path).await {
+        Ok(inode) if inode.inode_type == InodeType::Dir => Ok(false),
+        Ok(_) => Err(FsError::NotDirectory(path.to_string())),
+        Err(FsError::PathNotFound(_)) => {
+            fs.create_directory_in_tx(tx, path).await?;
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// `write_entry` as part of a caller-managed transaction.
+pub(crate) async fn write_entry_in_tx(
+    fs: &FileSystem<'_>,
+    tx: &mut DatabaseTransaction<'_>,
+    path: &str,
+    data: &[u8],
+) -> FsResult<()> {
+    match fs.create_file_in_tx(tx, path).await {
+        Ok(_) | Err(FsError::AlreadyExists(_)) => {}
+        Err(e) => return Err(e),
+    }
+    fs.write_file_in_tx(tx, path, data).await
+}
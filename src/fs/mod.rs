@@ -1,6 +1,24 @@
+pub mod atime;
+pub mod block_cache;
+pub mod caller;
 pub mod error;
+pub mod glob;
+pub mod handles;
+pub mod ingest;
 pub mod operations;
 pub mod path;
+pub mod search;
+pub mod storage_mode;
+pub mod watch;
 
+pub use atime::AtimeMode;
+pub use block_cache::BlockCache;
+pub use caller::CallerContext;
 pub use error::{FsError, FsResult};
+pub use glob::GlobPattern;
+pub use handles::{FileHandle, HandleTable, OpenFile};
+pub use ingest::{IngestEntry, IngestOptions, IngestReport};
 pub use operations::FileSystem;
+pub use search::{SearchMatch, SearchOptions};
+pub use storage_mode::StorageMode;
+pub use watch::{ChangeEvent, ChangeFeed};
@@ -1,24 +1,211 @@
-use sqlx::PgPool;
-use tracing::{debug, info};
-
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use sqlx::{PgExecutor, PgPool};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+use crate::fs::atime::AtimeMode;
+use crate::fs::block_cache::BlockCache;
+use crate::fs::caller::CallerContext;
 use crate::fs::error::{FsError, FsResult};
-use crate::fs::path::{normalize_path, path_components, split_path};
-use crate::layer::{CowHandler, LayerManager};
+use crate::fs::glob::GlobPattern;
+use crate::fs::handles::{FileHandle, HandleTable};
+use crate::fs::path::{join_link_target, normalize_path, path_components, split_path};
+use crate::fs::search::{LineMatcher, SearchMatch, SearchOptions};
+use crate::fs::storage_mode::StorageMode;
+use crate::fs::watch::{ChangeEvent, ChangeFeed};
+use crate::layer::{
+    BLOCK_SIZE, BlockChanges, ChunkingMode, CowHandler, CowResult, DetectionConfig, DirectoryEntry,
+    FileState, LayerManager, LayerManagerError, LineEnding, TARBOX_HOOK_PATH, UnionView,
+};
 use crate::storage::{
-    BlockOperations, CreateInodeInput, Inode, InodeOperations, InodeType, TenantOperations,
-    TenantRepository, UpdateInodeInput,
+    AuditLogOperations, AuditLogRepository, BlockOperations, ChangeType, ChunkOperations,
+    CreateAuditLogInput, CreateInodeInput, DatabaseTransaction, Inode, InodeOperations, InodeType,
+    LayerOperations, NotifyOperations, Tenant, TenantOperations, TenantRepository,
+    TextBlockOperations, UpdateInodeInput, WriteSession, begin_snapshot,
 };
 use crate::types::{InodeId, LayerId, TenantId};
 
-// Note: BLOCK_SIZE is defined here for future use if needed
-// const BLOCK_SIZE: usize = 4096;
+/// The path of `relative` below the directory `base`
+fn join_below(base: &str, relative: &str) -> String {
+    if base == "/" { format!("/{}", relative) } else { format!("{}/{}", base, relative) }
+}
+
+/// Whether `path` is `/.tarbox` or inside it
+fn is_hook_path(path: &str) -> bool {
+    path.strip_prefix(TARBOX_HOOK_PATH).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Symlinks followed while resolving one path before giving up, as on Linux
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// The path of each leading run of `components`: `/a`, `/a/b`, ...
+fn path_prefixes(components: &[String]) -> Vec<String> {
+    let mut prefixes = Vec::with_capacity(components.len());
+    let mut prefix = String::new();
+    for component in components {
+        prefix.push('/');
+        prefix.push_str(component);
+        prefixes.push(prefix.clone());
+    }
+    prefixes
+}
+
+/// Where a walk that reached `components[..depth]` at `inode` stopped: at
+/// the end of the path, or at a symlink with components left to resolve
+/// through it. A regular file with components left below it fails with
+/// `NotDirectory`; short of a directory, the next component is missing.
+fn stopped_at(
+    (depth, inode): (usize, Inode),
+    components: &[String],
+    prefixes: &[String],
+) -> FsResult<Option<(Inode, String, Vec<String>)>> {
+    if depth == components.len() || inode.inode_type == InodeType::Symlink {
+        Ok(Some((inode, prefixes[depth - 1].clone(), components[depth..].to_vec())))
+    } else if inode.inode_type == InodeType::File {
+        Err(FsError::NotDirectory(prefixes[depth - 1].clone()))
+    } else {
+        Ok(None)
+    }
+}
 
 pub struct FileSystem<'a> {
     pub(crate) pool: &'a PgPool,
+    /// Pool for read-only operations; the primary unless a replica is set.
+    read_pool: &'a PgPool,
+    session: WriteSession,
     pub(crate) tenant_id: TenantId,
     pub(crate) root_inode_id: InodeId,
     layer_manager: LayerManager<'a>,
     current_layer_id: LayerId,
+    /// Line ending to present text files with on read (storage is unchanged).
+    read_eol: Option<LineEnding>,
+    /// How binary files are split when written
+    chunking: ChunkingMode,
+    /// Open handles; shared across instances when set via `with_handles`.
+    handles: Arc<HandleTable>,
+    /// Directories this instance has resolved, by normalized path. Entries
+    /// may go stale if another instance changes the tree; see `resolve_path_in_tx`.
+    dir_cache: Mutex<HashMap<String, InodeId>>,
+    /// Whether reads update `atime`
+    atime: AtimeMode,
+    /// The tenant as loaded; its mode policy applies to new entries.
+    tenant: Tenant,
+    /// Whether changes are recorded in `audit_logs`
+    audit: bool,
+    /// Who changes are attributed to in the audit log
+    caller: CallerContext,
+    /// Watchers of committed changes; shared when set via `with_change_feed`.
+    changes: ChangeFeed,
+    /// Blocks of recently read files; see `read_range`.
+    block_cache: Option<BlockCache>,
+    /// Inodes written by the operation in progress; see `forget_blocks`.
+    written: Mutex<Vec<InodeId>>,
+}
+
+/// A change made through a `FileSystem`, to record in the audit log and
+/// announce to watchers
+struct Change<'p> {
+    operation: &'static str,
+    path: &'p str,
+    /// Second path of a rename, copy or symlink
+    target: Option<&'p str>,
+    bytes_written: Option<i64>,
+}
+
+impl<'p> Change<'p> {
+    fn new(operation: &'static str, path: &'p str) -> Self {
+        Self { operation, path, target: None, bytes_written: None }
+    }
+
+    fn target(mut self, target: &'p str) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    fn bytes(mut self, len: usize) -> Self {
+        self.bytes_written = Some(len as i64);
+        self
+    }
+
+    /// What watchers are told once the change is committed
+    fn event(&self) -> ChangeEvent {
+        // Only valid paths get this far
+        let normalize = |path: &str| normalize_path(path).unwrap_or_else(|_| path.to_string());
+        let path = normalize(self.path);
+        let target = self.target.map(normalize);
+        match (self.operation, target) {
+            ("rename", Some(to)) => ChangeEvent::Renamed { from: path, to },
+            ("copy", Some(to)) => ChangeEvent::Created { path: to },
+            ("mkdir" | "create" | "symlink", _) => ChangeEvent::Created { path },
+            ("rmdir" | "delete", _) => ChangeEvent::Deleted { path },
+            _ => ChangeEvent::Modified { path },
+        }
+    }
+}
+
+/// A change to part of a file's contents
+enum Edit<'d> {
+    /// Put `data` at `offset`, growing the file if it reaches past the end
+    Write { offset: u64, data: &'d [u8] },
+    /// Set the length, dropping the tail or padding it with zeros
+    Resize { size: u64 },
+    /// Grow to at least `size` bytes
+    Extend { size: u64 },
+    /// Zero a range, without changing the length
+    Zero { offset: u64, len: u64 },
+}
+
+impl Edit<'_> {
+    /// Length of a file of `size` bytes after the edit
+    fn new_size(&self, size: u64) -> u64 {
+        match *self {
+            Edit::Write { offset, data } => size.max(offset + data.len() as u64),
+            Edit::Resize { size } => size,
+            Edit::Extend { size: min } => size.max(min),
+            Edit::Zero { .. } => size,
+        }
+    }
+
+    /// Apply the edit to a file's whole contents. Returns false if that left
+    /// them unchanged.
+    fn apply(&self, content: &mut Vec<u8>) -> bool {
+        let new_size = self.new_size(content.len() as u64) as usize;
+        match *self {
+            Edit::Write { offset, data } => {
+                let (start, end) = (offset as usize, offset as usize + data.len());
+                if content.len() < end {
+                    content.resize(end, 0);
+                } else if content[start..end] == *data {
+                    return false;
+                }
+                content[start..end].copy_from_slice(data);
+            }
+            Edit::Resize { .. } | Edit::Extend { .. } => {
+                if content.len() == new_size {
+                    return false;
+                }
+                content.resize(new_size, 0);
+            }
+            Edit::Zero { offset, len } => {
+                let start = usize::try_from(offset).unwrap_or(usize::MAX).min(content.len());
+                let end = usize::try_from(offset.saturating_add(len))
+                    .unwrap_or(usize::MAX)
+                    .min(content.len());
+                if content[start..end].iter().all(|&b| b == 0) {
+                    return false;
+                }
+                content[start..end].fill(0);
+            }
+        }
+        true
+    }
 }
 
 impl<'a> FileSystem<'a> {
@@ -46,73 +233,505 @@ impl<'a> FileSystem<'a> {
 
         Ok(Self {
             pool,
+            read_pool: pool,
+            session: WriteSession::new(),
             tenant_id,
             root_inode_id: tenant.root_inode_id,
             layer_manager,
             current_layer_id: current_layer.layer_id,
+            read_eol: None,
+            chunking: ChunkingMode::default(),
+            handles: Arc::new(HandleTable::new()),
+            dir_cache: Mutex::new(HashMap::new()),
+            atime: AtimeMode::default(),
+            tenant,
+            audit: false,
+            caller: CallerContext::current_process(),
+            changes: ChangeFeed::new(),
+            block_cache: None,
+            written: Mutex::new(Vec::new()),
         })
     }
 
+    /// Convert line endings of text files to `eol` when they are read.
+    ///
+    /// Only the bytes returned by `read_file` and the size reported by `stat`
+    /// are affected; stored content keeps its original line endings.
+    pub fn with_read_eol(mut self, eol: Option<LineEnding>) -> Self {
+        self.read_eol = eol;
+        self
+    }
+
+    /// Store binary files written through this instance as `chunking`
+    /// dictates. Files already stored either way stay readable.
+    pub fn with_chunking(mut self, chunking: ChunkingMode) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    /// Track open handles in `handles` instead of a table private to this
+    /// instance, so handles outlive it (the FUSE backend builds a
+    /// `FileSystem` per request).
+    pub fn with_handles(mut self, handles: Arc<HandleTable>) -> Self {
+        self.handles = handles;
+        self
+    }
+
+    pub fn handles(&self) -> &HandleTable {
+        &self.handles
+    }
+
+    /// Serve stat, read and list from `read_pool` (typically a replica)
+    /// until this session writes; writes always go to the primary.
+    pub fn with_read_pool(mut self, read_pool: &'a PgPool) -> Self {
+        self.read_pool = read_pool;
+        self
+    }
+
+    /// Share read-your-writes state with other instances of the same session.
+    pub fn with_write_session(mut self, session: WriteSession) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Update `atime` on reads as `atime` dictates; by default it is never
+    /// updated.
+    pub fn with_atime(mut self, atime: AtimeMode) -> Self {
+        self.atime = atime;
+        self
+    }
+
+    /// Record every change made through this instance in the audit log.
+    ///
+    /// Entries go to the partition for the current month, which must exist.
+    pub fn with_audit(mut self, enabled: bool) -> Self {
+        self.audit = enabled;
+        self
+    }
+
+    /// Attribute changes to `caller`; by default they are attributed to the
+    /// user running this process.
+    pub fn with_caller(mut self, caller: CallerContext) -> Self {
+        self.caller = caller;
+        self
+    }
+
+    pub fn caller(&self) -> &CallerContext {
+        &self.caller
+    }
+
+    /// Announce changes to the watchers of `changes` instead of a feed
+    /// private to this instance, so watches see changes made by other
+    /// instances of the same mount.
+    pub fn with_change_feed(mut self, changes: ChangeFeed) -> Self {
+        self.changes = changes;
+        self
+    }
+
+    /// Serve `read_range` of block-stored files from `cache`, which is
+    /// usually shared by every instance of a mount.
+    pub fn with_block_cache(mut self, cache: BlockCache) -> Self {
+        self.block_cache = Some(cache);
+        self
+    }
+
+    /// Write to `layer_id` instead of the layer that was current when this
+    /// instance was created, for callers making it current in a transaction
+    /// they have not committed yet.
+    pub(crate) fn with_current_layer(mut self, layer_id: LayerId) -> Self {
+        self.current_layer_id = layer_id;
+        self
+    }
+
+    /// Refuse to change a layer that has been made read-only, such as one a
+    /// checkpoint has sealed. `executor` is the transaction the change is
+    /// made in, so a layer the caller has not committed yet is seen too.
+    async fn ensure_layer_writable<'e, E: PgExecutor<'e>>(&self, executor: E) -> FsResult<()> {
+        let layer = LayerOperations::get_with(executor, self.tenant_id, self.current_layer_id)
+            .await
+            .map_err(FsError::Storage)?;
+        if layer.is_some_and(|l| l.is_readonly) {
+            return Err(FsError::Storage(
+                LayerManagerError::ReadonlyLayer(self.current_layer_id).into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Changes at or below `prefix` from now on, as they are committed.
+    ///
+    /// Changes made through this instance or any sharing its feed are seen;
+    /// those made in a caller-managed transaction (the `*_in_tx` methods) are
+    /// not. A watcher that falls far behind skips the events it missed.
+    pub fn watch(&self, prefix: &str) -> FsResult<impl Stream<Item = ChangeEvent> + use<>> {
+        let prefix = normalize_path(prefix)?;
+        let receiver = self.changes.subscribe();
+        Ok(futures::stream::unfold(receiver, move |mut receiver| {
+            let prefix = prefix.clone();
+            async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) if event.is_under(&prefix) => return Some((event, receiver)),
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!(prefix = %prefix, missed, "Watcher fell behind; changes skipped");
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Run `op`, which makes `change`, then tell watchers if it succeeded
+    /// and record it in the audit log if enabled.
+    async fn tracked<T>(
+        &self,
+        change: Change<'_>,
+        op: impl Future<Output = FsResult<T>>,
+    ) -> FsResult<T> {
+        let started = Instant::now();
+        let result = op.await;
+        let written = std::mem::take(&mut *self.written.lock().unwrap());
+        for inode_id in written {
+            self.forget_blocks(inode_id);
+        }
+        self.finish_change(change, started, result.as_ref().err()).await;
+        result
+    }
+
+    async fn finish_change(&self, change: Change<'_>, started: Instant, error: Option<&FsError>) {
+        if error.is_none() {
+            let event = change.event();
+            self.announce(&event).await;
+            self.changes.publish(|| event);
+        }
+        self.record_audit(change, started, error).await;
+    }
+
+    /// Tell other processes serving this tenant which paths changed, so they
+    /// drop what they cached about them. Failing to is logged, not returned:
+    /// the change is already committed.
+    async fn announce(&self, event: &ChangeEvent) {
+        let notify_ops = NotifyOperations::new(self.pool);
+        let result = notify_ops
+            .notify_invalidate(self.tenant_id, &event.paths(), |pid| self.changes.add_notifier(pid))
+            .await;
+        if let Err(e) = result {
+            warn!(paths = ?event.paths(), "Failed to announce change: {}", e);
+        }
+    }
+
+    /// Record an operation begun at `started` that failed with `error`, or
+    /// succeeded if `None`.
+    ///
+    /// A failed insert is logged rather than failing an operation that has
+    /// already taken effect.
+    async fn record_audit(&self, change: Change<'_>, started: Instant, error: Option<&FsError>) {
+        if !self.audit {
+            return;
+        }
+        let input = CreateAuditLogInput {
+            tenant_id: self.tenant_id,
+            inode_id: None,
+            operation: change.operation.to_string(),
+            uid: self.caller.uid as i32,
+            gid: self.caller.gid as i32,
+            pid: None,
+            path: change.path.to_string(),
+            success: error.is_none(),
+            error_code: None,
+            error_message: error.map(|e| e.to_string()),
+            bytes_read: None,
+            bytes_written: change.bytes_written,
+            duration_ms: Some(started.elapsed().as_millis().min(i32::MAX as u128) as i32),
+            text_changes: None,
+            is_native_mount: false,
+            native_source_path: None,
+            metadata: change.target.map(|target| serde_json::json!({ "target": target })),
+            agent_id: self.caller.agent_id.clone(),
+        };
+        if let Err(e) = AuditLogOperations::new(self.pool).create(input).await {
+            warn!(operation = change.operation, path = %change.path, "Failed to record audit entry: {}", e);
+        }
+    }
+
+    /// Pool to use for a read-only operation.
+    fn reader(&self) -> &'a PgPool {
+        if self.session.has_written() { self.pool } else { self.read_pool }
+    }
+
     pub async fn resolve_path(&self, path: &str) -> FsResult<Inode> {
+        self.resolve(path, true).await
+    }
+
+    pub async fn resolve_path_nofollow(&self, path: &str) -> FsResult<Inode> {
+        self.resolve(path, false).await
+    }
+
+    /// Resolve `path` with plain lookups on the pool. Only a path through a
+    /// symlink, whose target has to be read, is resolved again in a
+    /// transaction.
+    async fn resolve(&self, path: &str, follow_final: bool) -> FsResult<Inode> {
         let normalized = normalize_path(path)?;
+        match self.lookup(&normalized).await? {
+            None => Err(FsError::PathNotFound(normalized)),
+            Some((inode, _, rest))
+                if inode.inode_type != InodeType::Symlink || (rest.is_empty() && !follow_final) =>
+            {
+                Ok(inode)
+            }
+            Some(_) => {
+                let mut tx = self.reader().begin().await.map_err(|e| FsError::Storage(e.into()))?;
+                let inode = self.resolve_in_tx(&mut tx, &normalized, follow_final).await?;
+                tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+                Ok(inode)
+            }
+        }
+    }
 
-        if normalized == "/" {
-            let inode_ops = InodeOperations::new(self.pool);
-            return inode_ops
-                .get(self.tenant_id, self.root_inode_id)
+    /// Resolve a path within a caller-managed transaction, following
+    /// symlinks in every component.
+    ///
+    /// More than `MAX_SYMLINK_HOPS` links along the way, as in a loop,
+    /// fail with `SymlinkLoop`.
+    pub async fn resolve_path_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        path: &str,
+    ) -> FsResult<Inode> {
+        self.resolve_in_tx(tx, path, true).await
+    }
+
+    /// Resolve like `resolve_path_in_tx`, except that a symlink in the final
+    /// component is returned itself rather than followed (`O_NOFOLLOW`).
+    pub async fn resolve_path_nofollow_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        path: &str,
+    ) -> FsResult<Inode> {
+        self.resolve_in_tx(tx, path, false).await
+    }
+
+    async fn resolve_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        path: &str,
+        follow_final: bool,
+    ) -> FsResult<Inode> {
+        let normalized = normalize_path(path)?;
+        let mut current = normalized.clone();
+
+        for _ in 0..=MAX_SYMLINK_HOPS {
+            let (inode, at, rest) = self
+                .lookup_in_tx(tx, &current)
                 .await?
-                .ok_or_else(|| FsError::PathNotFound("/".to_string()));
+                .ok_or_else(|| FsError::PathNotFound(normalized.clone()))?;
+            if inode.inode_type != InodeType::Symlink || (rest.is_empty() && !follow_final) {
+                return Ok(inode);
+            }
+
+            let target = self.link_target_in_tx(tx, &inode, &at).await?;
+            current = join_link_target(&at, &target);
+            for component in rest {
+                current.push('/');
+                current.push_str(&component);
+            }
         }
 
-        let components = path_components(&normalized)?;
-        let mut current_inode_id = self.root_inode_id;
+        Err(FsError::SymlinkLoop(normalized))
+    }
+
+    /// Walk `path` without following symlinks. Returns the inode reached,
+    /// its path and the components left below it: none once the whole path
+    /// resolved, the rest of the path if the walk stopped at a symlink.
+    /// `None` if a component is missing.
+    ///
+    /// The walk starts below the deepest directory this instance has already
+    /// resolved. A miss directly under that directory checks whether it still
+    /// exists; if it was removed since (or its creating transaction rolled
+    /// back) the path is walked again from the root, refreshing the cache.
+    async fn lookup_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        path: &str,
+    ) -> FsResult<Option<(Inode, String, Vec<String>)>> {
+        let normalized = normalize_path(path)?;
         let inode_ops = InodeOperations::new(self.pool);
 
-        for component in components {
-            let inode = inode_ops
-                .get_by_parent_and_name(self.tenant_id, current_inode_id, &component)
-                .await?
-                .ok_or_else(|| FsError::PathNotFound(normalized.clone()))?;
+        if normalized == "/" {
+            let root = inode_ops.get_in_tx(tx, self.tenant_id, self.root_inode_id).await?;
+            return Ok(root.map(|root| (root, normalized, Vec::new())));
+        }
 
-            current_inode_id = inode.inode_id;
+        let components = path_components(&normalized)?;
+        let prefixes = path_prefixes(&components);
+        if let Some((from, dir)) = self.cached_dir(&prefixes) {
+            match self.walk_in_tx(tx, &components, &prefixes, from, dir).await? {
+                Some(reached) => return stopped_at(reached, &components, &prefixes),
+                None if from < components.len()
+                    && inode_ops.get_in_tx(tx, self.tenant_id, dir).await?.is_some() =>
+                {
+                    return Ok(None);
+                }
+                None => {}
+            }
         }
 
-        inode_ops
-            .get(self.tenant_id, current_inode_id)
-            .await?
-            .ok_or_else(|| FsError::PathNotFound(normalized))
+        let reached = self.walk_in_tx(tx, &components, &prefixes, 0, self.root_inode_id).await?;
+        match reached {
+            Some(reached) => stopped_at(reached, &components, &prefixes),
+            None => Ok(None),
+        }
     }
 
-    pub async fn create_directory(&self, path: &str) -> FsResult<Inode> {
-        let (parent_path, dirname) = split_path(path)?;
+    /// `lookup_in_tx` with plain queries on the pool, outside a transaction.
+    async fn lookup(&self, path: &str) -> FsResult<Option<(Inode, String, Vec<String>)>> {
+        let normalized = normalize_path(path)?;
+        let inode_ops = InodeOperations::new(self.reader());
 
-        let parent = self.resolve_path(&parent_path).await?;
-        if parent.inode_type != InodeType::Dir {
-            return Err(FsError::NotDirectory(parent_path));
+        if normalized == "/" {
+            let root = inode_ops.get(self.tenant_id, self.root_inode_id).await?;
+            return Ok(root.map(|root| (root, normalized, Vec::new())));
+        }
+
+        let components = path_components(&normalized)?;
+        let prefixes = path_prefixes(&components);
+        if let Some((from, dir)) = self.cached_dir(&prefixes) {
+            match self.walk(&components, &prefixes, from, dir).await? {
+                Some(reached) => return stopped_at(reached, &components, &prefixes),
+                None if from < components.len()
+                    && inode_ops.get(self.tenant_id, dir).await?.is_some() =>
+                {
+                    return Ok(None);
+                }
+                None => {}
+            }
         }
 
+        match self.walk(&components, &prefixes, 0, self.root_inode_id).await? {
+            Some(reached) => stopped_at(reached, &components, &prefixes),
+            None => Ok(None),
+        }
+    }
+
+    /// The deepest cached directory among `prefixes`, and how many
+    /// components lead to it.
+    fn cached_dir(&self, prefixes: &[String]) -> Option<(usize, InodeId)> {
+        let cache = self.dir_cache.lock().unwrap();
+        prefixes.iter().enumerate().rev().find_map(|(i, p)| cache.get(p).map(|&id| (i + 1, id)))
+    }
+
+    /// Look up `components[from..]` below `dir` in one query, caching the
+    /// directories passed on the way. `prefixes[i]` is the path of
+    /// `components[..=i]`. Returns the deepest inode reached below `dir` and
+    /// how many components lead to it; `dir` itself if nothing is left.
+    async fn walk_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        components: &[String],
+        prefixes: &[String],
+        from: usize,
+        dir: InodeId,
+    ) -> FsResult<Option<(usize, Inode)>> {
         let inode_ops = InodeOperations::new(self.pool);
-        if inode_ops
-            .get_by_parent_and_name(self.tenant_id, parent.inode_id, &dirname)
-            .await?
-            .is_some()
+        if from == components.len() {
+            let inode = inode_ops.get_in_tx(tx, self.tenant_id, dir).await?;
+            return Ok(inode.map(|inode| (from, inode)));
+        }
+
+        let chain = inode_ops.walk_in_tx(tx, self.tenant_id, dir, &components[from..]).await?;
+        Ok(self.walked(chain, &prefixes[from..], from))
+    }
+
+    /// `walk_in_tx` with a plain query on the pool.
+    async fn walk(
+        &self,
+        components: &[String],
+        prefixes: &[String],
+        from: usize,
+        dir: InodeId,
+    ) -> FsResult<Option<(usize, Inode)>> {
+        let inode_ops = InodeOperations::new(self.reader());
+        if from == components.len() {
+            let inode = inode_ops.get(self.tenant_id, dir).await?;
+            return Ok(inode.map(|inode| (from, inode)));
+        }
+
+        let chain = inode_ops.walk(self.tenant_id, dir, &components[from..]).await?;
+        Ok(self.walked(chain, &prefixes[from..], from))
+    }
+
+    /// Cache the directories on `chain`, walked from `from` components
+    /// down, and return the last inode reached.
+    fn walked(
+        &self,
+        chain: Vec<Inode>,
+        prefixes: &[String],
+        from: usize,
+    ) -> Option<(usize, Inode)> {
+        let resolved = from + chain.len();
         {
-            return Err(FsError::AlreadyExists(path.to_string()));
+            let mut cache = self.dir_cache.lock().unwrap();
+            for (inode, prefix) in chain.iter().zip(prefixes) {
+                if inode.inode_type == InodeType::Dir {
+                    cache.insert(prefix.clone(), inode.inode_id);
+                }
+            }
         }
+        chain.into_iter().last().map(|inode| (resolved, inode))
+    }
 
-        let inode = inode_ops
-            .create(CreateInodeInput {
-                tenant_id: self.tenant_id,
-                parent_id: Some(parent.inode_id),
-                name: dirname,
-                inode_type: InodeType::Dir,
-                mode: 0o755,
-                uid: 0,
-                gid: 0,
-            })
-            .await?;
+    /// A symlink's target, as stored
+    async fn link_target_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        link: &Inode,
+        path: &str,
+    ) -> FsResult<String> {
+        let data = self.read_inode_in_tx(tx, link, path).await?;
+        String::from_utf8(data)
+            .map_err(|_| FsError::InvalidPath(format!("symlink target is not UTF-8: {}", path)))
+    }
 
-        Ok(inode)
+    /// Drop every cached directory resolution, e.g. after a rename moved
+    /// directories to new paths.
+    fn forget_dirs(&self) {
+        self.dir_cache.lock().unwrap().clear();
+    }
+
+    pub async fn create_directory(&self, path: &str) -> FsResult<Inode> {
+        self.create_directory_with_mode(path, None).await
+    }
+
+    /// Create a directory with `mode`, or the tenant's default directory
+    /// mode if `None`. The tenant's umask is applied either way.
+    pub async fn create_directory_with_mode(
+        &self,
+        path: &str,
+        mode: Option<i32>,
+    ) -> FsResult<Inode> {
+        self.tracked(Change::new("mkdir", path), async {
+            self.session.mark_written();
+            self.ensure_layer_writable(self.pool).await?;
+            let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+            let mode = self.tenant.dir_mode(mode);
+            let inode = self.create_entry_in_tx(&mut tx, path, InodeType::Dir, mode).await?;
+            tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+            Ok(inode)
+        })
+        .await
+    }
+
+    /// Create a directory as part of a caller-managed transaction.
+    pub async fn create_directory_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        path: &str,
+    ) -> FsResult<Inode> {
+        self.session.mark_written();
+        self.ensure_layer_writable(&mut **tx).await?;
+        self.create_entry_in_tx(tx, path, InodeType::Dir, self.tenant.dir_mode(None)).await
     }
 
     pub async fn list_directory(&self, path: &str) -> FsResult<Vec<Inode>> {
@@ -122,42 +741,281 @@ impl<'a> FileSystem<'a> {
             return Err(FsError::NotDirectory(path.to_string()));
         }
 
-        let inode_ops = InodeOperations::new(self.pool);
+        let inode_ops = InodeOperations::new(self.reader());
         let children = inode_ops.list_children(self.tenant_id, dir_inode.inode_id).await?;
 
         Ok(children)
     }
 
-    pub async fn remove_directory(&self, path: &str) -> FsResult<()> {
-        let dir_inode = self.resolve_path(path).await?;
+    /// List a directory's children with their attributes as `stat` reports
+    /// them, so callers can answer follow-up lookups without a query each.
+    pub async fn list_directory_with_attrs(&self, path: &str) -> FsResult<Vec<Inode>> {
+        let children = self.list_directory(path).await?;
+        self.with_read_eol_sizes(children).await
+    }
 
-        if dir_inode.inode_type != InodeType::Dir {
-            return Err(FsError::NotDirectory(path.to_string()));
+    /// Every path matching `pattern` (`*`, `?` and `**`; see `GlobPattern`)
+    /// with its inode, sorted by path.
+    ///
+    /// Only the tree below the pattern's leading directories is fetched, and
+    /// no deeper than the pattern reaches unless it has `**`. Symlinks are
+    /// matched but not followed. `/.tarbox` is skipped unless the pattern
+    /// starts inside it.
+    pub async fn glob(&self, pattern: &str) -> FsResult<Vec<(String, Inode)>> {
+        let pattern = GlobPattern::parse(pattern)?;
+        let base = pattern.base();
+
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let dir = match self.resolve_path_nofollow_in_tx(&mut tx, &base).await {
+            Ok(inode) => inode,
+            Err(FsError::PathNotFound(_) | FsError::NotDirectory(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        if pattern.is_literal() {
+            return Ok(vec![(base, dir)]);
+        }
+        if dir.inode_type != InodeType::Dir {
+            return Ok(Vec::new());
         }
 
-        let inode_ops = InodeOperations::new(self.pool);
-        let children = inode_ops.list_children(self.tenant_id, dir_inode.inode_id).await?;
+        let max_depth = pattern.max_depth().map(|depth| depth as i32);
+        let tree = InodeOperations::new(self.pool)
+            .list_tree_in_tx(&mut tx, self.tenant_id, dir.inode_id, max_depth)
+            .await?;
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+
+        let into_hooks = is_hook_path(&base);
+        let mut matches: Vec<_> = tree
+            .into_iter()
+            .filter(|(relative, _)| pattern.matches(&relative.split('/').collect::<Vec<_>>()))
+            .map(|(relative, inode)| (join_below(&base, &relative), inode))
+            .filter(|(path, _)| into_hooks || !is_hook_path(path))
+            .collect();
+        matches.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(matches)
+    }
 
-        if !children.is_empty() {
-            return Err(FsError::DirectoryNotEmpty(path.to_string()));
+    /// Lines of text files matching `query`, by path and line number.
+    ///
+    /// The query is a regular expression unless `opts.fixed_strings` is set,
+    /// in which case the database does the matching. Binary files are
+    /// skipped, and so is `/.tarbox` unless the search starts inside it.
+    pub async fn search(&self, query: &str, opts: &SearchOptions) -> FsResult<Vec<SearchMatch>> {
+        let matcher = LineMatcher::new(query, opts)?;
+        let base = normalize_path(opts.path.as_deref().unwrap_or("/"))?;
+
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let root = self.resolve_path_in_tx(&mut tx, &base).await?;
+        let files: HashMap<InodeId, String> = match root.inode_type {
+            InodeType::File => HashMap::from([(root.inode_id, base)]),
+            InodeType::Symlink => HashMap::new(),
+            InodeType::Dir => {
+                let into_hooks = is_hook_path(&base);
+                InodeOperations::new(self.pool)
+                    .list_tree_in_tx(&mut tx, self.tenant_id, root.inode_id, None)
+                    .await?
+                    .into_iter()
+                    .filter(|(_, inode)| inode.inode_type == InodeType::File)
+                    .map(|(relative, inode)| (inode.inode_id, join_below(&base, &relative)))
+                    .filter(|(_, path)| into_hooks || !is_hook_path(path))
+                    .collect()
+            }
+        };
+
+        let inode_ids: Vec<InodeId> = files.keys().copied().collect();
+        let lines = TextBlockOperations::new(self.pool)
+            .search_lines_in_tx(
+                &mut tx,
+                self.tenant_id,
+                self.current_layer_id,
+                &inode_ids,
+                matcher.needle(),
+                opts.ignore_case,
+            )
+            .await?;
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+
+        // Stored lines keep the `\r` of a CRLF ending; it is not part of the text
+        let mut matches: Vec<SearchMatch> = lines
+            .into_iter()
+            .map(|(inode_id, line_number, mut line)| {
+                if line.ends_with('\r') {
+                    line.pop();
+                }
+                (inode_id, line_number, line)
+            })
+            .filter(|(_, _, line)| matcher.is_match(line))
+            .map(|(inode_id, line_number, line)| SearchMatch {
+                path: files[&inode_id].clone(),
+                line_number: line_number as usize + 1,
+                line,
+            })
+            .collect();
+        matches.sort_by(|a, b| (&a.path, a.line_number).cmp(&(&b.path, b.line_number)));
+        if let Some(max) = opts.max_matches {
+            matches.truncate(max);
         }
+        Ok(matches)
+    }
 
-        inode_ops.delete(self.tenant_id, dir_inode.inode_id).await?;
+    pub async fn remove_directory(&self, path: &str) -> FsResult<()> {
+        self.tracked(Change::new("rmdir", path), async {
+            self.session.mark_written();
+            self.ensure_layer_writable(self.pool).await?;
 
-        Ok(())
+            let dir_inode = self.resolve_path_nofollow(path).await?;
+
+            if dir_inode.inode_type != InodeType::Dir {
+                return Err(FsError::NotDirectory(path.to_string()));
+            }
+
+            let inode_ops = InodeOperations::new(self.pool);
+            let children = inode_ops.list_children(self.tenant_id, dir_inode.inode_id).await?;
+
+            if !children.is_empty() {
+                return Err(FsError::DirectoryNotEmpty(path.to_string()));
+            }
+
+            inode_ops.delete(self.tenant_id, dir_inode.inode_id).await?;
+
+            self.layer_manager
+                .record_change(dir_inode.inode_id, path, ChangeType::Delete, None, None)
+                .await
+                .map_err(|e| FsError::Storage(e.into()))?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Remove a directory and everything below it.
+    ///
+    /// Children are deleted depth-first in a single transaction, freeing their
+    /// blocks and recording a `Delete` layer entry for every removed path. A
+    /// failure part-way through leaves the tree untouched. Returns the number
+    /// of removed entries, including the directory itself.
+    pub async fn remove_directory_recursive(&self, path: &str) -> FsResult<usize> {
+        self.tracked(Change::new("rmdir", path), async {
+            self.session.mark_written();
+            self.ensure_layer_writable(self.pool).await?;
+
+            let normalized = normalize_path(path)?;
+
+            if normalized == "/" {
+                return Err(FsError::InvalidPath("cannot remove root directory".to_string()));
+            }
+            if is_hook_path(&normalized) {
+                return Err(FsError::InvalidPath(format!("cannot remove {}", normalized)));
+            }
+
+            let dir_inode = self.resolve_path_nofollow(&normalized).await?;
+            if dir_inode.inode_type != InodeType::Dir {
+                return Err(FsError::NotDirectory(normalized));
+            }
+
+            let inode_ops = InodeOperations::new(self.pool);
+            let removed = inode_ops
+                .delete_tree(
+                    self.tenant_id,
+                    dir_inode.inode_id,
+                    &normalized,
+                    Some(self.current_layer_id),
+                )
+                .await?;
+
+            info!(path = %normalized, removed = removed.len(), "Removed directory tree");
+
+            Ok(removed.len())
+        })
+        .await
     }
 
     pub async fn create_file(&self, path: &str) -> FsResult<Inode> {
-        let (parent_path, filename) = split_path(path)?;
+        self.create_file_with_mode(path, None).await
+    }
+
+    /// Create an empty file with `mode`, or the tenant's default file mode
+    /// if `None`. The tenant's umask is applied either way.
+    pub async fn create_file_with_mode(&self, path: &str, mode: Option<i32>) -> FsResult<Inode> {
+        self.tracked(Change::new("create", path), async {
+            self.session.mark_written();
+            self.ensure_layer_writable(self.pool).await?;
+            let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+            let mode = self.tenant.file_mode(mode);
+            let inode = self.create_entry_in_tx(&mut tx, path, InodeType::File, mode).await?;
+            tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+            Ok(inode)
+        })
+        .await
+    }
+
+    /// Create a file as part of a caller-managed transaction.
+    pub async fn create_file_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        path: &str,
+    ) -> FsResult<Inode> {
+        self.session.mark_written();
+        self.ensure_layer_writable(&mut **tx).await?;
+        self.create_entry_in_tx(tx, path, InodeType::File, self.tenant.file_mode(None)).await
+    }
+
+    /// Create a symlink at `link` pointing to `target`.
+    ///
+    /// The target is stored as the link's contents and is not checked; it
+    /// may be relative to the link's directory, and may dangle.
+    pub async fn create_symlink(&self, target: &str, link: &str) -> FsResult<Inode> {
+        self.tracked(Change::new("symlink", link).target(target), async {
+            self.session.mark_written();
+            self.ensure_layer_writable(self.pool).await?;
+
+            if target.is_empty() || target.contains('\0') {
+                return Err(FsError::InvalidPath(format!("invalid symlink target: {:?}", target)));
+            }
+
+            let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+            let mut inode =
+                self.create_entry_in_tx(&mut tx, link, InodeType::Symlink, 0o777).await?;
+            self.store_inode_in_tx(&mut tx, &inode, link, target.as_bytes()).await?;
+            tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+
+            inode.size = target.len() as i64;
+            Ok(inode)
+        })
+        .await
+    }
 
-        let parent = self.resolve_path(&parent_path).await?;
+    /// The target of the symlink at `path`.
+    pub async fn read_link(&self, path: &str) -> FsResult<String> {
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let inode = self.resolve_path_nofollow_in_tx(&mut tx, path).await?;
+        if inode.inode_type != InodeType::Symlink {
+            return Err(FsError::InvalidPath(format!("not a symlink: {}", path)));
+        }
+        let target = self.link_target_in_tx(&mut tx, &inode, path).await?;
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        Ok(target)
+    }
+
+    /// Create an empty inode of `inode_type` at `path`, whose parent must
+    /// be an existing directory.
+    async fn create_entry_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        path: &str,
+        inode_type: InodeType,
+        mode: i32,
+    ) -> FsResult<Inode> {
+        let (parent_path, name) = split_path(path)?;
+
+        let parent = self.resolve_path_in_tx(tx, &parent_path).await?;
         if parent.inode_type != InodeType::Dir {
             return Err(FsError::NotDirectory(parent_path));
         }
 
         let inode_ops = InodeOperations::new(self.pool);
         if inode_ops
-            .get_by_parent_and_name(self.tenant_id, parent.inode_id, &filename)
+            .get_by_parent_and_name_in_tx(tx, self.tenant_id, parent.inode_id, &name)
             .await?
             .is_some()
         {
@@ -165,180 +1023,755 @@ impl<'a> FileSystem<'a> {
         }
 
         let inode = inode_ops
-            .create(CreateInodeInput {
-                tenant_id: self.tenant_id,
-                parent_id: Some(parent.inode_id),
-                name: filename,
-                inode_type: InodeType::File,
-                mode: 0o644,
-                uid: 0,
-                gid: 0,
-            })
+            .create_in_tx(
+                tx,
+                CreateInodeInput {
+                    tenant_id: self.tenant_id,
+                    parent_id: Some(parent.inode_id),
+                    name,
+                    inode_type,
+                    mode,
+                    uid: 0,
+                    gid: 0,
+                },
+            )
             .await?;
 
         Ok(inode)
     }
 
+    /// Write a file's full contents.
+    ///
+    /// Blocks, the layer entry and the inode size are updated in one
+    /// transaction, so a failure leaves the previous contents intact.
     pub async fn write_file(&self, path: &str, data: &[u8]) -> FsResult<()> {
-        let inode = self.resolve_path(path).await?;
+        self.tracked(Change::new("write", path).bytes(data.len()), self.store_file(path, data))
+            .await
+    }
+
+    /// Write `data` into a file at `offset`, keeping the bytes around it. A
+    /// gap between the old end and `offset` reads as zeros.
+    ///
+    /// For files stored as fixed-size blocks only the blocks the write covers
+    /// are rewritten, so appending to a large file costs what it appends.
+    pub async fn write_at(&self, path: &str, offset: u64, data: &[u8]) -> FsResult<()> {
+        self.tracked(Change::new("write", path).bytes(data.len()), async {
+            Self::byte_len(path, offset.saturating_add(data.len() as u64))?;
+            self.edit_file(path, Edit::Write { offset, data }).await
+        })
+        .await
+    }
+
+    /// `write_file` without the audit entry, for writes audited by their caller.
+    async fn store_file(&self, path: &str, data: &[u8]) -> FsResult<()> {
+        let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+        self.write_file_in_tx(&mut tx, path, data).await?;
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        Ok(())
+    }
+
+    /// Write a file's full contents as part of a caller-managed transaction.
+    ///
+    /// Nothing is visible to other readers until the caller commits.
+    pub async fn write_file_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        path: &str,
+        data: &[u8],
+    ) -> FsResult<()> {
+        self.session.mark_written();
+        self.ensure_layer_writable(&mut **tx).await?;
+
+        let inode = self.resolve_path_in_tx(tx, path).await?;
+        self.write_inode_in_tx(tx, &inode, path, data).await
+    }
+
+    /// Set a file's length to `size`, dropping data past it or padding the
+    /// end with zeros. Growing a block-stored file leaves a hole.
+    pub async fn truncate(&self, path: &str, size: u64) -> FsResult<()> {
+        self.tracked(Change::new("truncate", path), async {
+            Self::byte_len(path, size)?;
+            self.edit_file(path, Edit::Resize { size }).await
+        })
+        .await
+    }
+
+    /// Make sure `path` is at least `offset + len` bytes long. The new range
+    /// reads as zeros and, being a hole, takes no space.
+    pub async fn allocate(&self, path: &str, offset: u64, len: u64) -> FsResult<()> {
+        self.tracked(Change::new("fallocate", path), async {
+            let size = offset.saturating_add(len);
+            Self::byte_len(path, size)?;
+            self.edit_file(path, Edit::Extend { size }).await
+        })
+        .await
+    }
+
+    /// Zero `len` bytes of `path` from `offset` without changing its size.
+    /// Whole blocks in the range are dropped rather than stored as zeros.
+    pub async fn punch_hole(&self, path: &str, offset: u64, len: u64) -> FsResult<()> {
+        self.tracked(Change::new("punch_hole", path), async {
+            self.edit_file(path, Edit::Zero { offset, len }).await
+        })
+        .await
+    }
+
+    fn byte_len(path: &str, len: u64) -> FsResult<usize> {
+        usize::try_from(len).map_err(|_| FsError::NoSpace(format!("{} bytes for {}", len, path)))
+    }
 
+    /// Apply `edit` to a file, holding its lock from the read to the write so
+    /// a concurrent write isn't lost.
+    ///
+    /// Block-stored files have only the blocks the edit touches rewritten;
+    /// new space past the old end is left as holes. Text and chunk-mapped
+    /// files are edited whole and go through the same COW write as
+    /// `write_file`, which also decides the type of a file's first contents.
+    /// Once such a file would outgrow the text size limit it is moved to
+    /// blocks first, so its size never has to fit in memory.
+    async fn edit_file(&self, path: &str, edit: Edit<'_>) -> FsResult<()> {
+        self.session.mark_written();
+        self.ensure_layer_writable(self.pool).await?;
+
+        let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+        let inode = self.resolve_path_in_tx(&mut tx, path).await?;
         if inode.inode_type != InodeType::File {
             return Err(FsError::IsDirectory(path.to_string()));
         }
+        InodeOperations::new(self.pool).lock_in_tx(&mut tx, self.tenant_id, inode.inode_id).await?;
+
+        let old_size = inode.size.max(0) as u64;
+        let new_size = edit.new_size(old_size);
+        let as_blocks = self.stored_as_blocks_in_tx(&mut tx, &inode).await?;
+        let max_text_size = DetectionConfig::default().max_text_file_size as u64;
+        if (!as_blocks || old_size == 0) && new_size <= max_text_size {
+            // Edit what is stored, so read-time line ending conversion isn't saved
+            let text_layer_id = self.text_layer_in_tx(&mut tx, &inode).await?;
+            let (mut data, _) = self
+                .read_stored_at_layer_in_tx(
+                    &mut tx,
+                    &inode,
+                    path,
+                    text_layer_id,
+                    self.current_layer_id,
+                )
+                .await?;
+            if !edit.apply(&mut data) {
+                return Ok(());
+            }
+            self.write_inode_in_tx(&mut tx, &inode, path, &data).await?;
+        } else {
+            let mut touched = BTreeSet::new();
+            if !as_blocks {
+                touched.extend(self.move_to_blocks_in_tx(&mut tx, &inode, path, old_size).await?);
+            }
+            touched.extend(self.edit_blocks_in_tx(&mut tx, inode.inode_id, old_size, &edit).await?);
+            if touched.is_empty() && new_size == old_size {
+                return Ok(());
+            }
+            self.record_block_edit_in_tx(&mut tx, &inode, path, old_size, new_size, touched)
+                .await?;
+        }
 
-        debug!(
-            path = %path,
-            size = data.len(),
-            inode_id = inode.inode_id,
-            layer_id = %self.current_layer_id,
-            "Writing file via COW"
-        );
-
-        // Read old data for diff calculation
-        let old_data = self.read_file_internal(inode.inode_id).await.ok();
-        // Treat empty data as None (file just created)
-        let old_data_opt = old_data.as_ref().filter(|d| !d.is_empty());
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        Ok(())
+    }
 
-        // Use CowHandler to write file
-        let cow = CowHandler::new(self.pool, self.tenant_id, self.current_layer_id);
-        let result = cow
-            .write_file(inode.inode_id, data, old_data_opt.map(|v| v.as_slice()))
+    /// Restore a text or chunk-mapped file's contents as fixed-size blocks in
+    /// the current layer. Returns the indices of the blocks stored.
+    async fn move_to_blocks_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode: &Inode,
+        path: &str,
+        size: u64,
+    ) -> FsResult<Vec<i32>> {
+        let text_layer_id = self.text_layer_in_tx(tx, inode).await?;
+        let (data, _) = self
+            .read_stored_at_layer_in_tx(tx, inode, path, text_layer_id, self.current_layer_id)
+            .await?;
+        let blocks = data.chunks(BLOCK_SIZE).map(|block| Ok(block.to_vec())).collect::<Vec<_>>();
+        let result = CowHandler::new(self.pool, self.tenant_id, self.current_layer_id)
+            .write_binary_blocks_in_tx(
+                tx,
+                inode.inode_id,
+                futures::stream::iter(blocks),
+                false,
+                size as usize,
+            )
             .await
             .map_err(FsError::Storage)?;
+        Ok(result.block_changes.map(|changes| changes.changed_blocks).unwrap_or_default())
+    }
 
-        info!(
-            path = %path,
-            is_text = result.is_text,
-            change_type = ?result.change_type,
-            size_delta = result.size_delta,
-            "File written via COW"
-        );
+    /// Apply `edit` to the blocks of a block-stored file of `old_size` bytes,
+    /// storing the new versions in the current layer. Returns the indices of
+    /// the blocks it stored or dropped.
+    async fn edit_blocks_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        old_size: u64,
+        edit: &Edit<'_>,
+    ) -> FsResult<BTreeSet<i32>> {
+        let new_size = edit.new_size(old_size);
+        let mut touched = BTreeSet::new();
+        if new_size > old_size {
+            self.grow_blocks_in_tx(tx, inode_id, old_size, new_size, &mut touched).await?;
+        } else if new_size < old_size {
+            self.shrink_blocks_in_tx(tx, inode_id, new_size, &mut touched).await?;
+        }
 
-        // Record change to current layer
-        self.layer_manager
-            .record_change(
-                inode.inode_id,
-                path,
-                result.change_type,
-                Some(result.size_delta),
-                result.text_changes.map(|tc| tc.to_json()),
-            )
-            .await
-            .map_err(|e| FsError::Storage(e.into()))?;
+        let block_size = BLOCK_SIZE as u64;
+        match *edit {
+            Edit::Write { offset, data } => {
+                let end = offset + data.len() as u64;
+                for index in offset / block_size..end.div_ceil(block_size) {
+                    let start = index * block_size;
+                    let mut block = self.visible_block_in_tx(tx, inode_id, index).await?;
+                    block.truncate(old_size.saturating_sub(start).min(block_size) as usize);
+                    block.resize(new_size.saturating_sub(start).min(block_size) as usize, 0);
+                    let (from, to) = (offset.max(start), end.min(start + block_size));
+                    let source = &data[(from - offset) as usize..(to - offset) as usize];
+                    let target = &mut block[(from - start) as usize..(to - start) as usize];
+                    if target == source {
+                        continue;
+                    }
+                    target.copy_from_slice(source);
+                    self.store_block_in_tx(tx, inode_id, index, &block).await?;
+                    touched.insert(index as i32);
+                }
+            }
+            Edit::Zero { offset, len } => {
+                let end = offset.saturating_add(len).min(old_size);
+                for index in offset / block_size..end.div_ceil(block_size) {
+                    let start = index * block_size;
+                    let mut block = self.visible_block_in_tx(tx, inode_id, index).await?;
+                    block.truncate(old_size.saturating_sub(start).min(block_size) as usize);
+                    let from = offset.max(start) - start;
+                    let to = (end - start).min(block.len() as u64);
+                    if from >= to || block[from as usize..to as usize].iter().all(|&b| b == 0) {
+                        continue;
+                    }
+                    block[from as usize..to as usize].fill(0);
+                    self.store_block_in_tx(tx, inode_id, index, &block).await?;
+                    touched.insert(index as i32);
+                }
+            }
+            Edit::Resize { .. } | Edit::Extend { .. } => {}
+        }
+        Ok(touched)
+    }
 
-        // Update inode metadata
-        let inode_ops = InodeOperations::new(self.pool);
-        inode_ops
-            .update(
+    /// Extend a block-stored file from `old_size` to `new_size` bytes without
+    /// storing the new range: versions that lower layers still hold there,
+    /// from before the file last shrank, are trimmed or masked so it reads
+    /// as zeros.
+    async fn grow_blocks_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        old_size: u64,
+        new_size: u64,
+        touched: &mut BTreeSet<i32>,
+    ) -> FsResult<()> {
+        let block_size = BLOCK_SIZE as u64;
+        let first = old_size / block_size;
+        let until = i32::try_from(new_size.div_ceil(block_size)).unwrap_or(i32::MAX);
+        let stale = BlockOperations::new(self.pool)
+            .list_visible_range_in_tx(
+                tx,
                 self.tenant_id,
-                inode.inode_id,
-                UpdateInodeInput {
-                    size: Some(data.len() as i64),
-                    mode: None,
-                    uid: None,
-                    gid: None,
-                    atime: None,
-                    mtime: Some(chrono::Utc::now()),
-                    ctime: None,
-                },
+                inode_id,
+                self.current_layer_id,
+                first as i32,
+                until,
             )
             .await?;
-
+        for block in stale {
+            let kept = old_size.saturating_sub(block.block_index as u64 * block_size) as usize;
+            if block.data.len() <= kept {
+                continue;
+            }
+            let data = &block.data[..kept];
+            self.store_block_in_tx(tx, inode_id, block.block_index as u64, data).await?;
+            touched.insert(block.block_index);
+        }
         Ok(())
     }
 
-    /// Internal helper to read file data without path resolution
-    async fn read_file_internal(&self, inode_id: InodeId) -> FsResult<Vec<u8>> {
-        // Try reading as text file first
-        let cow = CowHandler::new(self.pool, self.tenant_id, self.current_layer_id);
-        if let Ok(Some(text_content)) = cow.read_text_file(inode_id, self.current_layer_id).await {
-            return Ok(text_content.into_bytes());
+    /// Cut a block-stored file down to `new_size` bytes: the current layer's
+    /// blocks past it are deleted and the new last block is trimmed.
+    async fn shrink_blocks_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        new_size: u64,
+        touched: &mut BTreeSet<i32>,
+    ) -> FsResult<()> {
+        let block_size = BLOCK_SIZE as u64;
+        let kept = new_size.div_ceil(block_size) as i32;
+        BlockOperations::new(self.pool)
+            .delete_layer_range_in_tx(
+                tx,
+                self.tenant_id,
+                inode_id,
+                self.current_layer_id,
+                kept,
+                None,
+            )
+            .await?;
+
+        let tail = (new_size % block_size) as usize;
+        if tail > 0 {
+            let index = new_size / block_size;
+            let block = self.visible_block_in_tx(tx, inode_id, index).await?;
+            if block.len() > tail {
+                self.store_block_in_tx(tx, inode_id, index, &block[..tail]).await?;
+                touched.insert(index as i32);
+            }
         }
+        Ok(())
+    }
 
-        // Fall back to binary blocks
-        let block_ops = BlockOperations::new(self.pool);
-        let blocks = block_ops.list(self.tenant_id, inode_id).await?;
+    /// The data of the block at `index` as seen from the current layer,
+    /// empty for a hole.
+    async fn visible_block_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        index: u64,
+    ) -> FsResult<Vec<u8>> {
+        let index = index as i32;
+        let block = BlockOperations::new(self.pool)
+            .list_visible_range_in_tx(
+                tx,
+                self.tenant_id,
+                inode_id,
+                self.current_layer_id,
+                index,
+                index + 1,
+            )
+            .await?
+            .pop();
+        Ok(block.map(|block| block.data).unwrap_or_default())
+    }
 
-        let mut data = Vec::new();
-        for block in blocks {
-            data.extend_from_slice(&block.data);
+    /// Store `data` as the block at `index` in the current layer. All-zero
+    /// data is stored as a hole: the layer's version is dropped, and an empty
+    /// block masks any version a lower layer still shows.
+    async fn store_block_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        index: u64,
+        data: &[u8],
+    ) -> FsResult<()> {
+        let blocks = BlockOperations::new(self.pool);
+        let index = index as i32;
+        if data.iter().any(|&b| b != 0) {
+            blocks
+                .put_layer_block_in_tx(
+                    tx,
+                    self.tenant_id,
+                    inode_id,
+                    self.current_layer_id,
+                    index,
+                    data,
+                )
+                .await?;
+            return Ok(());
         }
 
-        Ok(data)
+        blocks
+            .delete_layer_range_in_tx(
+                tx,
+                self.tenant_id,
+                inode_id,
+                self.current_layer_id,
+                index,
+                Some(index + 1),
+            )
+            .await?;
+        if !self.visible_block_in_tx(tx, inode_id, index as u64).await?.is_empty() {
+            blocks
+                .put_layer_block_in_tx(
+                    tx,
+                    self.tenant_id,
+                    inode_id,
+                    self.current_layer_id,
+                    index,
+                    &[],
+                )
+                .await?;
+        }
+        Ok(())
     }
 
-    pub async fn read_file(&self, path: &str) -> FsResult<Vec<u8>> {
-        let inode = self.resolve_path(path).await?;
+    /// Record a block-level edit of `path` in the current layer, merged with
+    /// what the layer already recorded for it, and set the inode's new size.
+    async fn record_block_edit_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode: &Inode,
+        path: &str,
+        old_size: u64,
+        new_size: u64,
+        touched: BTreeSet<i32>,
+    ) -> FsResult<()> {
+        let entry = LayerOperations::new(self.pool)
+            .get_entry_in_tx(tx, self.tenant_id, self.current_layer_id, path)
+            .await?;
+        let change_type = match &entry {
+            Some(entry) if entry.change_type == ChangeType::Add => ChangeType::Add,
+            _ => ChangeType::Modify,
+        };
+        let block_count = i32::try_from(new_size.div_ceil(BLOCK_SIZE as u64)).unwrap_or(i32::MAX);
+        let mut changed_blocks: BTreeSet<i32> = entry
+            .and_then(|entry| entry.block_changes)
+            .and_then(|value| BlockChanges::from_json(&value))
+            .map(|changes| changes.changed_blocks.into_iter().collect())
+            .unwrap_or_default();
+        changed_blocks.extend(touched);
+        changed_blocks.retain(|&index| index < block_count);
+
+        let result = CowResult {
+            change_type,
+            size_delta: new_size as i64 - old_size as i64,
+            text_changes: None,
+            block_changes: Some(BlockChanges {
+                block_size: BLOCK_SIZE as i32,
+                block_count,
+                changed_blocks: changed_blocks.into_iter().collect(),
+                file_size: Some(new_size as i64),
+            }),
+            is_text: false,
+        };
+        debug!(path = %path, old_size, new_size, "File edited in place");
+        self.record_write_in_tx(tx, inode, path, &result, new_size as i64).await
+    }
+
+    /// Write a file's full contents from a stream of byte chunks.
+    ///
+    /// Chunks are buffered only until the content is sure to be stored as
+    /// binary (it holds a NUL byte or outgrows the text size limit); from then
+    /// on it is written block by block as it arrives. Text is buffered whole,
+    /// as it is stored by line, and so is everything under content-defined
+    /// chunking. As with `write_file`, it is all one transaction. Returns the
+    /// number of bytes written.
+    pub async fn write_file_stream<S>(&self, path: &str, stream: S) -> FsResult<u64>
+    where
+        S: Stream<Item = FsResult<Bytes>>,
+    {
+        let started = Instant::now();
+        let result = self.store_file_stream(path, stream).await;
+        let mut change = Change::new("write", path);
+        if let Ok(written) = &result {
+            change = change.bytes(*written as usize);
+        }
+        self.finish_change(change, started, result.as_ref().err()).await;
+        result
+    }
+
+    async fn store_file_stream<S>(&self, path: &str, stream: S) -> FsResult<u64>
+    where
+        S: Stream<Item = FsResult<Bytes>>,
+    {
+        let mut stream = std::pin::pin!(stream);
+        let max_text_size = DetectionConfig::default().max_text_file_size;
+
+        // Both conditions make FileTypeDetector::detect answer binary
+        let mut head = BytesMut::new();
+        let mut binary = false;
+        while !binary {
+            let Some(chunk) = stream.next().await else {
+                self.store_file(path, &head).await?;
+                return Ok(head.len() as u64);
+            };
+            let chunk = chunk?;
+            binary = chunk.contains(&0) || head.len() + chunk.len() > max_text_size;
+            head.extend_from_slice(&chunk);
+        }
+
+        if self.chunking == ChunkingMode::ContentDefined {
+            while let Some(chunk) = stream.next().await {
+                head.extend_from_slice(&chunk?);
+            }
+            self.store_file(path, &head).await?;
+            return Ok(head.len() as u64);
+        }
 
+        self.session.mark_written();
+        self.ensure_layer_writable(self.pool).await?;
+        let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+        let inode = self.resolve_path_in_tx(&mut tx, path).await?;
         if inode.inode_type != InodeType::File {
             return Err(FsError::IsDirectory(path.to_string()));
         }
+        InodeOperations::new(self.pool).lock_in_tx(&mut tx, self.tenant_id, inode.inode_id).await?;
 
-        debug!(
-            path = %path,
-            inode_id = inode.inode_id,
-            layer_id = %self.current_layer_id,
-            "Reading file"
-        );
+        let old_size = inode.size.max(0) as usize;
+        let blocks = std::pin::pin!(into_blocks(head, stream));
+        let result = CowHandler::new(self.pool, self.tenant_id, self.current_layer_id)
+            .write_binary_blocks_in_tx(&mut tx, inode.inode_id, blocks, old_size == 0, old_size)
+            .await
+            .map_err(FsError::Storage)?;
+        let size = old_size as i64 + result.size_delta;
+        debug!(path = %path, size, "File written from stream");
+
+        self.record_write_in_tx(&mut tx, &inode, path, &result, size).await?;
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        Ok(size as u64)
+    }
+
+    /// Copy a file to `dst` without moving its bytes through the client.
+    ///
+    /// The copy shares the source's text lines or content-defined chunks, so
+    /// it takes no extra space until one side is modified. Fails if `dst`
+    /// already exists.
+    pub async fn copy_file(&self, src: &str, dst: &str) -> FsResult<Inode> {
+        self.tracked(Change::new("copy", src).target(dst), async {
+            self.session.mark_written();
+            self.ensure_layer_writable(self.pool).await?;
+
+            let src = normalize_path(src)?;
+            let dst = normalize_path(dst)?;
+            let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+
+            let source = self.resolve_path_in_tx(&mut tx, &src).await?;
+            if source.inode_type != InodeType::File {
+                return Err(FsError::IsDirectory(src));
+            }
+            let target = self.create_file_in_tx(&mut tx, &dst).await?;
+            let target = self
+                .copy_content_in_tx(&mut tx, &src, &source, &dst, &target, ChangeType::Add)
+                .await?;
+
+            tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+            Ok(target)
+        })
+        .await
+    }
+
+    /// Replace the contents of the existing file `dst` with those of `src`,
+    /// sharing storage as `copy_file` does. Returns the number of bytes copied.
+    pub async fn copy_into(&self, src: &str, dst: &str) -> FsResult<u64> {
+        self.tracked(Change::new("copy", src).target(dst), async {
+            self.session.mark_written();
+            self.ensure_layer_writable(self.pool).await?;
+
+            let src = normalize_path(src)?;
+            let dst = normalize_path(dst)?;
+            let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+
+            let source = self.resolve_path_in_tx(&mut tx, &src).await?;
+            if source.inode_type != InodeType::File {
+                return Err(FsError::IsDirectory(src));
+            }
+            let target = self.resolve_path_in_tx(&mut tx, &dst).await?;
+            if target.inode_type != InodeType::File {
+                return Err(FsError::IsDirectory(dst));
+            }
+            InodeOperations::new(self.pool)
+                .lock_in_tx(&mut tx, self.tenant_id, target.inode_id)
+                .await?;
+            self.copy_content_in_tx(&mut tx, &src, &source, &dst, &target, ChangeType::Modify)
+                .await?;
+
+            tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+            Ok(source.size as u64)
+        })
+        .await
+    }
+
+    /// Give `target` the content and size of `source` and record the change
+    /// in the current layer. A newly created target takes the source's mode
+    /// too; an existing one keeps its own.
+    async fn copy_content_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        src: &str,
+        source: &Inode,
+        dst: &str,
+        target: &Inode,
+        change_type: ChangeType,
+    ) -> FsResult<Inode> {
+        // Text content stays in the layer that last wrote it
+        let view = UnionView::from_layer(self.pool, self.tenant_id, self.current_layer_id).await?;
+        let src_text_layer_id = match view.lookup_file(src).await? {
+            FileState::Exists { layer_id, .. } => layer_id,
+            _ => self.current_layer_id,
+        };
 
-        // Try reading as text file first
         let cow = CowHandler::new(self.pool, self.tenant_id, self.current_layer_id);
-        if let Ok(Some(text_content)) =
-            cow.read_text_file(inode.inode_id, self.current_layer_id).await
-        {
-            debug!(path = %path, size = text_content.len(), "Read from text_blocks");
-            return Ok(text_content.into_bytes());
-        }
+        let is_text = cow
+            .copy_file_in_tx(tx, source.inode_id, src_text_layer_id, target.inode_id)
+            .await
+            .map_err(FsError::Storage)?;
+        debug!(src = %src, dst = %dst, is_text, "Copied file");
+        self.forget_written_blocks(target.inode_id);
 
-        // Fall back to binary blocks
-        let block_ops = BlockOperations::new(self.pool);
-        let blocks = block_ops.list(self.tenant_id, inode.inode_id).await?;
+        self.layer_manager
+            .record_change_in_tx(
+                tx,
+                target.inode_id,
+                dst,
+                change_type,
+                Some(source.size - target.size),
+                None,
+            )
+            .await
+            .map_err(|e| FsError::Storage(e.into()))?;
 
-        let mut data = Vec::with_capacity(inode.size as usize);
-        for block in blocks {
-            data.extend_from_slice(&block.data);
-        }
+        let now = chrono::Utc::now();
+        let target = InodeOperations::new(self.pool)
+            .update_in_tx(
+                tx,
+                self.tenant_id,
+                target.inode_id,
+                UpdateInodeInput {
+                    size: Some(source.size),
+                    mode: (change_type == ChangeType::Add).then_some(source.mode),
+                    uid: None,
+                    gid: None,
+                    atime: None,
+                    mtime: Some(now),
+                    ctime: Some(now),
+                },
+            )
+            .await?;
+        Ok(target)
+    }
 
-        debug!(path = %path, size = data.len(), "Read from data_blocks");
-        Ok(data)
+    /// Drop `inode_id`'s cached blocks because its content is changing.
+    fn forget_blocks(&self, inode_id: InodeId) {
+        if let Some(cache) = &self.block_cache {
+            cache.invalidate_inode(inode_id);
+        }
     }
 
-    pub async fn delete_file(&self, path: &str) -> FsResult<()> {
-        let inode = self.resolve_path(path).await?;
+    /// `forget_blocks` now and again once the current operation is over, in
+    /// case a concurrent read cached the old content before the commit.
+    fn forget_written_blocks(&self, inode_id: InodeId) {
+        if self.block_cache.is_some() {
+            self.forget_blocks(inode_id);
+            self.written.lock().unwrap().push(inode_id);
+        }
+    }
 
-        if inode.inode_type == InodeType::Dir {
+    /// Replace the contents of `inode`, recording the change under `path`.
+    async fn write_inode_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode: &Inode,
+        path: &str,
+        data: &[u8],
+    ) -> FsResult<()> {
+        if inode.inode_type != InodeType::File {
             return Err(FsError::IsDirectory(path.to_string()));
         }
+        self.store_inode_in_tx(tx, inode, path, data).await
+    }
 
-        let block_ops = BlockOperations::new(self.pool);
-        block_ops.delete(self.tenant_id, inode.inode_id).await?;
+    /// Store `data` as the contents of `inode`, whatever its type.
+    async fn store_inode_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode: &Inode,
+        path: &str,
+        data: &[u8],
+    ) -> FsResult<()> {
+        debug!(
+            path = %path,
+            size = data.len(),
+            inode_id = inode.inode_id,
+            layer_id = %self.current_layer_id,
+            "Writing file via COW"
+        );
 
-        let inode_ops = InodeOperations::new(self.pool);
-        inode_ops.delete(self.tenant_id, inode.inode_id).await?;
+        // Concurrent writers to the same file take turns, so neither sees or
+        // overwrites a half-applied version of the other
+        InodeOperations::new(self.pool).lock_in_tx(tx, self.tenant_id, inode.inode_id).await?;
 
-        Ok(())
-    }
+        // Read old data for diff calculation, from the same transaction
+        let old_data = self.read_inode_in_tx(tx, inode, path).await.ok();
+        // Treat empty data as None (file just created)
+        let old_data_opt = old_data.as_ref().filter(|d| !d.is_empty());
 
-    pub async fn stat(&self, path: &str) -> FsResult<Inode> {
-        self.resolve_path(path).await
+        // Use CowHandler to write file
+        let cow = CowHandler::new(self.pool, self.tenant_id, self.current_layer_id)
+            .with_chunking(self.chunking);
+        let result = cow
+            .write_file_in_tx(tx, inode.inode_id, data, old_data_opt.map(|v| v.as_slice()))
+            .await
+            .map_err(FsError::Storage)?;
+
+        info!(
+            path = %path,
+            is_text = result.is_text,
+            change_type = ?result.change_type,
+            size_delta = result.size_delta,
+            "File written via COW"
+        );
+
+        self.record_write_in_tx(tx, inode, path, &result, data.len() as i64).await
     }
 
-    pub async fn chmod(&self, path: &str, mode: i32) -> FsResult<()> {
-        let inode = self.resolve_path(path).await?;
+    /// Record a COW write in the current layer and set the inode's new size.
+    async fn record_write_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode: &Inode,
+        path: &str,
+        result: &CowResult,
+        size: i64,
+    ) -> FsResult<()> {
+        // Record change to current layer
+        let recorded = match &result.block_changes {
+            Some(block_changes) => {
+                self.layer_manager
+                    .record_binary_change_in_tx(
+                        tx,
+                        inode.inode_id,
+                        path,
+                        result.change_type,
+                        Some(result.size_delta),
+                        block_changes,
+                    )
+                    .await
+            }
+            None => {
+                self.layer_manager
+                    .record_change_in_tx(
+                        tx,
+                        inode.inode_id,
+                        path,
+                        result.change_type,
+                        Some(result.size_delta),
+                        result.text_changes.as_ref().map(|tc| tc.to_json()),
+                    )
+                    .await
+            }
+        };
+        recorded.map_err(|e| FsError::Storage(e.into()))?;
+        self.forget_written_blocks(inode.inode_id);
 
+        // Update inode metadata
+        let now = chrono::Utc::now();
         let inode_ops = InodeOperations::new(self.pool);
         inode_ops
-            .update(
+            .update_in_tx(
+                tx,
                 self.tenant_id,
                 inode.inode_id,
                 UpdateInodeInput {
-                    size: None,
-                    mode: Some(mode),
+                    size: Some(size),
+                    mode: None,
                     uid: None,
                     gid: None,
                     atime: None,
-                    mtime: None,
-                    ctime: Some(chrono::Utc::now()),
+                    mtime: Some(now),
+                    ctime: Some(now),
                 },
             )
             .await?;
@@ -346,26 +1779,1037 @@ impl<'a> FileSystem<'a> {
         Ok(())
     }
 
-    pub async fn chown(&self, path: &str, uid: i32, gid: i32) -> FsResult<()> {
-        let inode = self.resolve_path(path).await?;
+    /// Read a file's full contents.
+    ///
+    /// Path resolution and the content read share one snapshot, so a file
+    /// replaced concurrently (e.g. by `rename`) is seen either before or after
+    /// the swap, never half-way.
+    pub async fn read_file(&self, path: &str) -> FsResult<Vec<u8>> {
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let inode = self.resolve_path_in_tx(&mut tx, path).await?;
+        let data = self.read_inode_in_tx(&mut tx, &inode, path).await?;
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        self.touch_atime(&inode).await?;
+        Ok(data)
+    }
 
+    /// Read up to `len` bytes of a file from `offset`.
+    ///
+    /// With a block cache set, files stored as fixed-size blocks fetch only
+    /// the blocks the range covers that aren't cached yet, so sequential
+    /// windows over the same file read each block from the database once.
+    /// Text and chunk-mapped files are read whole, as by `read_file`.
+    pub async fn read_range(&self, path: &str, offset: u64, len: u64) -> FsResult<Vec<u8>> {
+        let window = |data: Vec<u8>| {
+            let start = usize::try_from(offset).unwrap_or(usize::MAX).min(data.len());
+            let end = start.saturating_add(usize::try_from(len).unwrap_or(usize::MAX));
+            data[start..end.min(data.len())].to_vec()
+        };
+        let Some(cache) = &self.block_cache else {
+            return self.read_file(path).await.map(window);
+        };
+
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let inode = self.resolve_path_in_tx(&mut tx, path).await?;
+        let stored_as_blocks = inode.inode_type == InodeType::File
+            && self.stored_as_blocks_in_tx(&mut tx, &inode).await?;
+        if !stored_as_blocks {
+            let data = self.read_inode_in_tx(&mut tx, &inode, path).await?;
+            tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+            self.touch_atime(&inode).await?;
+            return Ok(window(data));
+        }
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        self.touch_atime(&inode).await?;
+
+        let blocks = BlockOperations::new(self.reader());
+        Ok(cache
+            .read(
+                &blocks,
+                self.tenant_id,
+                inode.inode_id,
+                self.current_layer_id,
+                inode.size.max(0) as u64,
+                offset,
+                len,
+            )
+            .await?)
+    }
+
+    /// Offset of the first data at or after `offset`, as lseek(2)'s
+    /// `SEEK_DATA` finds it. `None` if `offset` is at or past the end of the
+    /// file, or only holes follow it.
+    pub async fn seek_data(&self, path: &str, offset: u64) -> FsResult<Option<u64>> {
+        let (size, blocks) = self.stored_blocks(path).await?;
+        if offset >= size {
+            return Ok(None);
+        }
+        let Some(blocks) = blocks else {
+            return Ok(Some(offset));
+        };
+        let block_size = BLOCK_SIZE as u64;
+        Ok(blocks
+            .range(offset / block_size..)
+            .next()
+            .map(|&index| (index * block_size).max(offset))
+            .filter(|&at| at < size))
+    }
+
+    /// Offset of the first hole at or after `offset`, as lseek(2)'s
+    /// `SEEK_HOLE` finds it; the end of the file counts as a hole. `None` if
+    /// `offset` is at or past the end of the file.
+    pub async fn seek_hole(&self, path: &str, offset: u64) -> FsResult<Option<u64>> {
+        let (size, blocks) = self.stored_blocks(path).await?;
+        if offset >= size {
+            return Ok(None);
+        }
+        let Some(blocks) = blocks else {
+            return Ok(Some(size));
+        };
+        let block_size = BLOCK_SIZE as u64;
+        let mut index = offset / block_size;
+        while blocks.contains(&index) {
+            index += 1;
+        }
+        Ok(Some((index * block_size).max(offset).min(size)))
+    }
+
+    /// Size of the file at `path` and the indices of the blocks it has
+    /// stored. Only files stored as fixed-size blocks can have holes; for
+    /// text and chunk-mapped files the indices are `None`.
+    async fn stored_blocks(&self, path: &str) -> FsResult<(u64, Option<BTreeSet<u64>>)> {
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let inode = self.resolve_path_in_tx(&mut tx, path).await?;
+        if inode.inode_type != InodeType::File {
+            return Err(FsError::IsDirectory(path.to_string()));
+        }
+        let size = inode.size.max(0) as u64;
+
+        let blocks = if !self.stored_as_blocks_in_tx(&mut tx, &inode).await? {
+            None
+        } else {
+            let indices = BlockOperations::new(self.pool)
+                .list_visible_indices_in_tx(
+                    &mut tx,
+                    self.tenant_id,
+                    inode.inode_id,
+                    self.current_layer_id,
+                )
+                .await?;
+            Some(indices.into_iter().map(|index| index as u64).collect())
+        };
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        Ok((size, blocks))
+    }
+
+    /// Whether a file's contents are fixed-size blocks, rather than text
+    /// lines or a content-defined chunk map.
+    async fn stored_as_blocks_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode: &Inode,
+    ) -> FsResult<bool> {
+        let text_layer_id = self.text_layer_in_tx(tx, inode).await?;
+        Ok(TextBlockOperations::new(self.pool)
+            .get_metadata_in_tx(tx, self.tenant_id, inode.inode_id, text_layer_id)
+            .await?
+            .is_none()
+            && ChunkOperations::new(self.pool)
+                .visible_map_layer_in_tx(tx, self.tenant_id, inode.inode_id, self.current_layer_id)
+                .await?
+                .is_none())
+    }
+
+    /// Stream a file's contents in pieces of at most `BLOCK_SIZE` bytes.
+    ///
+    /// Binary files stored as blocks are fetched `STREAM_PAGE_BLOCKS` at a
+    /// time, so only one page is held in memory. Text files and chunk-mapped
+    /// files are read whole and then handed out piecewise. Everything comes
+    /// from one snapshot, as with `read_file`; a missing path or a directory
+    /// fails before any data is returned.
+    pub async fn read_file_stream(
+        &self,
+        path: &str,
+    ) -> FsResult<impl Stream<Item = FsResult<Bytes>> + 'a> {
+        self.read_range_stream(path, 0, None).await
+    }
+
+    /// Stream up to `length` bytes of a file starting at `offset`, or
+    /// everything from `offset` on when `length` is `None`.
+    ///
+    /// Block pages are fetched from the block holding `offset`, so the part
+    /// before it is never read. A range starting at or past EOF yields
+    /// nothing.
+    pub async fn read_range_stream(
+        &self,
+        path: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> FsResult<impl Stream<Item = FsResult<Bytes>> + 'a> {
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let inode = self.resolve_path_in_tx(&mut tx, path).await?;
+        if inode.inode_type != InodeType::File {
+            return Err(FsError::IsDirectory(path.to_string()));
+        }
+        self.touch_atime(&inode).await?;
+
+        let text_layer_id = self.text_layer_in_tx(&mut tx, &inode).await?;
+        let cow = CowHandler::new(self.pool, self.tenant_id, self.current_layer_id);
+        let text = cow
+            .read_text_file_in_tx(&mut tx, inode.inode_id, text_layer_id)
+            .await
+            .map_err(FsError::Storage)?;
+        let chunk_ops = ChunkOperations::new(self.pool);
+        let whole = match text {
+            Some(text) => Some(self.apply_read_eol(text.into_bytes())),
+            None => match chunk_ops
+                .visible_map_layer_in_tx(
+                    &mut tx,
+                    self.tenant_id,
+                    inode.inode_id,
+                    self.current_layer_id,
+                )
+                .await?
+            {
+                Some(map_layer) => {
+                    let mut data = chunk_ops
+                        .read_map_in_tx(&mut tx, self.tenant_id, inode.inode_id, map_layer)
+                        .await?;
+                    data.truncate(inode.size.max(0) as usize);
+                    Some(data)
+                }
+                None => None,
+            },
+        };
+
+        let mut state = ReadStreamState {
+            tx: Some(tx),
+            block_ops: BlockOperations::new(self.pool),
+            tenant_id: self.tenant_id,
+            inode_id: inode.inode_id,
+            layer_id: self.current_layer_id,
+            pending: VecDeque::new(),
+            next_block: None,
+            position: 0,
+            size: 0,
+        };
+        let paged = whole.is_none();
+        let size = match whole {
+            Some(data) => {
+                let data = Bytes::from(data);
+                state.pending = (0..data.len())
+                    .step_by(BLOCK_SIZE)
+                    .map(|at| (at, data.slice(at..(at + BLOCK_SIZE).min(data.len()))))
+                    .collect();
+                data.len()
+            }
+            None => inode.size.max(0) as usize,
+        };
+        let start = usize::try_from(offset).unwrap_or(usize::MAX).min(size);
+        state.position = start;
+        state.size = match length {
+            Some(length) => start.saturating_add(usize::try_from(length).unwrap_or(usize::MAX)),
+            None => size,
+        }
+        .min(size);
+        if paged {
+            state.next_block = Some((start / BLOCK_SIZE) as i32);
+        }
+
+        Ok(futures::stream::try_unfold(state, ReadStreamState::next))
+    }
+
+    /// Read a file as it was at `at`.
+    ///
+    /// Uses the layer that was current at that time and resolves the path
+    /// through that layer's union view, so later writes and deletions are not
+    /// visible. Files whose inode has since been deleted can no longer be read.
+    pub async fn read_file_at_time(&self, path: &str, at: DateTime<Utc>) -> FsResult<Vec<u8>> {
+        let normalized = normalize_path(path)?;
+        let layer = self
+            .layer_manager
+            .layer_at_time(at)
+            .await
+            .map_err(|e| FsError::Storage(e.into()))?
+            .ok_or_else(|| FsError::PathNotFound(normalized.clone()))?;
+        self.read_file_at_layer(&normalized, layer.layer_id).await
+    }
+
+    /// Read a file as `layer_id` sees it, resolving the path through that
+    /// layer's union view rather than the live tree.
+    pub async fn read_file_at_layer(&self, path: &str, layer_id: LayerId) -> FsResult<Vec<u8>> {
+        let normalized = normalize_path(path)?;
+        let view = UnionView::from_layer(self.reader(), self.tenant_id, layer_id).await?;
+        let state = view.lookup_file(&normalized).await?;
+
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let (inode, text_layer_id) = match state {
+            FileState::Exists { layer_id, inode_id } => {
+                let inode = InodeOperations::new(self.pool)
+                    .get_in_tx(&mut tx, self.tenant_id, inode_id)
+                    .await?
+                    .ok_or_else(|| FsError::PathNotFound(normalized.clone()))?;
+                (inode, layer_id)
+            }
+            FileState::Deleted { .. } => return Err(FsError::PathNotFound(normalized)),
+            // No layer recorded the path; it predates layering
+            FileState::NotFound => (self.resolve_path_in_tx(&mut tx, &normalized).await?, layer_id),
+        };
+        if inode.inode_type == InodeType::Dir {
+            return Err(FsError::IsDirectory(normalized));
+        }
+        let inode = self.with_size_at_layer_in_tx(&mut tx, inode, layer_id).await?;
+
+        let data = self
+            .read_inode_at_layer_in_tx(&mut tx, &inode, &normalized, text_layer_id, layer_id)
+            .await?;
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        Ok(data)
+    }
+
+    /// Stat a path as `layer_id` sees it.
+    ///
+    /// Only files carry layer entries, so a directory exists in the layer if
+    /// a visible file lies below it; its attributes are the live directory's,
+    /// or the root's if it has since been removed. A file's size is that of
+    /// its content in the layer, the other attributes are current. Files
+    /// whose inode has since been deleted are reported missing.
+    pub async fn stat_at_layer(&self, path: &str, layer_id: LayerId) -> FsResult<Inode> {
+        let normalized = normalize_path(path)?;
+        let view = UnionView::from_layer(self.reader(), self.tenant_id, layer_id).await?;
+        let files = view.list_all().await?;
+
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let inode = self.inode_at_layer_in_tx(&mut tx, &files, &normalized, layer_id).await?;
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        Ok(inode)
+    }
+
+    /// List a directory as `layer_id` sees it, with attributes as
+    /// `stat_at_layer` reports them. Entries are sorted by name.
+    pub async fn list_directory_at_layer(
+        &self,
+        path: &str,
+        layer_id: LayerId,
+    ) -> FsResult<Vec<Inode>> {
+        let normalized = normalize_path(path)?;
+        let view = UnionView::from_layer(self.reader(), self.tenant_id, layer_id).await?;
+        let files = view.list_all().await?;
+
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let dir = self.inode_at_layer_in_tx(&mut tx, &files, &normalized, layer_id).await?;
+        if dir.inode_type != InodeType::Dir {
+            return Err(FsError::NotDirectory(normalized));
+        }
+
+        let prefix = if normalized == "/" { "/".to_string() } else { format!("{}/", normalized) };
+        let names: std::collections::BTreeSet<&str> = files
+            .keys()
+            .filter_map(|file| file.strip_prefix(&prefix))
+            .filter_map(|rest| rest.split('/').next())
+            .collect();
+
+        let mut children = Vec::with_capacity(names.len());
+        for name in names {
+            let child = format!("{}{}", prefix, name);
+            match self.inode_at_layer_in_tx(&mut tx, &files, &child, layer_id).await {
+                Ok(inode) => children.push(inode),
+                Err(FsError::PathNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        Ok(children)
+    }
+
+    /// Give a binary file the length it had in `layer_id`, if a write recorded
+    /// it; the inode itself only knows the current length.
+    async fn with_size_at_layer_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        mut inode: Inode,
+        layer_id: LayerId,
+    ) -> FsResult<Inode> {
+        if inode.inode_type == InodeType::File
+            && let Some(size) = LayerOperations::new(self.pool)
+                .last_file_size_in_chain_in_tx(tx, self.tenant_id, layer_id, inode.inode_id)
+                .await?
+        {
+            inode.size = size;
+        }
+        Ok(inode)
+    }
+
+    /// `stat_at_layer` against an already listed view
+    async fn inode_at_layer_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        files: &BTreeMap<String, DirectoryEntry>,
+        path: &str,
+        layer_id: LayerId,
+    ) -> FsResult<Inode> {
         let inode_ops = InodeOperations::new(self.pool);
-        inode_ops
+        if let Some(entry) = files.get(path) {
+            let mut inode = inode_ops
+                .get_in_tx(tx, self.tenant_id, entry.inode_id)
+                .await?
+                .ok_or_else(|| FsError::PathNotFound(path.to_string()))?;
+            inode.name = entry.name.clone();
+            let mut inode = self.with_size_at_layer_in_tx(tx, inode, layer_id).await?;
+            if inode.inode_type == InodeType::File {
+                let cow = CowHandler::new(self.pool, self.tenant_id, layer_id);
+                if let Some(text) = cow
+                    .read_text_file_in_tx(tx, inode.inode_id, entry.layer_id)
+                    .await
+                    .map_err(FsError::Storage)?
+                {
+                    inode.size = self.apply_read_eol(text.into_bytes()).len() as i64;
+                }
+            }
+            return Ok(inode);
+        }
+
+        let below = format!("{}/", path.trim_end_matches('/'));
+        if path != "/" && !files.keys().any(|file| file.starts_with(&below)) {
+            return Err(FsError::PathNotFound(path.to_string()));
+        }
+        match self.resolve_path_in_tx(tx, path).await {
+            Ok(dir) if dir.inode_type == InodeType::Dir => Ok(dir),
+            Ok(_) | Err(FsError::PathNotFound(_) | FsError::NotDirectory(_)) => {
+                let mut dir = inode_ops
+                    .get_in_tx(tx, self.tenant_id, self.root_inode_id)
+                    .await?
+                    .ok_or_else(|| FsError::PathNotFound("/".to_string()))?;
+                dir.name = path.rsplit('/').next().unwrap_or_default().to_string();
+                Ok(dir)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Record a read of `inode` if the atime policy asks for it. The update
+    /// goes to the primary but doesn't count as a write of this session, so
+    /// reads stay on the replica.
+    async fn touch_atime(&self, inode: &Inode) -> FsResult<()> {
+        let now = Utc::now();
+        if !self.atime.should_update(inode, now) {
+            return Ok(());
+        }
+        InodeOperations::new(self.pool)
             .update(
                 self.tenant_id,
                 inode.inode_id,
                 UpdateInodeInput {
                     size: None,
                     mode: None,
-                    uid: Some(uid),
-                    gid: Some(gid),
-                    atime: None,
+                    uid: None,
+                    gid: None,
+                    atime: Some(now),
                     mtime: None,
-                    ctime: Some(chrono::Utc::now()),
+                    ctime: None,
                 },
             )
             .await?;
+        Ok(())
+    }
+
+    async fn read_inode_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode: &Inode,
+        path: &str,
+    ) -> FsResult<Vec<u8>> {
+        let text_layer_id = self.text_layer_in_tx(tx, inode).await?;
+        self.read_inode_at_layer_in_tx(tx, inode, path, text_layer_id, self.current_layer_id).await
+    }
+
+    /// Layer holding `inode`'s text content. Text stays in the layer that last
+    /// wrote the file, which is an ancestor of the current layer if the file
+    /// is unchanged since a checkpoint.
+    async fn text_layer_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode: &Inode,
+    ) -> FsResult<LayerId> {
+        Ok(LayerOperations::new(self.pool)
+            .last_change_in_chain_in_tx(tx, self.tenant_id, self.current_layer_id, inode.inode_id)
+            .await?
+            .unwrap_or(self.current_layer_id))
+    }
+
+    /// Read an inode's contents as seen from `layer_id`. Text content lives in
+    /// the layer that last wrote it (`text_layer_id`); binary blocks are
+    /// overlaid along the chain ending at `layer_id`.
+    async fn read_inode_at_layer_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode: &Inode,
+        path: &str,
+        text_layer_id: LayerId,
+        layer_id: LayerId,
+    ) -> FsResult<Vec<u8>> {
+        let (data, is_text) =
+            self.read_stored_at_layer_in_tx(tx, inode, path, text_layer_id, layer_id).await?;
+        Ok(if is_text { self.apply_read_eol(data) } else { data })
+    }
+
+    /// Read an inode's contents as stored, before any read-time line ending
+    /// conversion, and whether they are text.
+    async fn read_stored_at_layer_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode: &Inode,
+        path: &str,
+        text_layer_id: LayerId,
+        layer_id: LayerId,
+    ) -> FsResult<(Vec<u8>, bool)> {
+        // A symlink's content is its target
+        if inode.inode_type == InodeType::Dir {
+            return Err(FsError::IsDirectory(path.to_string()));
+        }
+
+        debug!(path = %path, inode_id = inode.inode_id, layer_id = %layer_id, "Reading file");
+
+        // Try reading as text file first
+        let cow = CowHandler::new(self.pool, self.tenant_id, layer_id);
+        if let Some(text_content) = cow
+            .read_text_file_in_tx(tx, inode.inode_id, text_layer_id)
+            .await
+            .map_err(FsError::Storage)?
+        {
+            debug!(path = %path, size = text_content.len(), "Read from text_blocks");
+            return Ok((text_content.into_bytes(), true));
+        }
+
+        // Fall back to binary storage: chunk map or per-layer blocks
+        let block_ops = BlockOperations::new(self.pool);
+        let data = block_ops
+            .read_visible_in_tx(tx, self.tenant_id, inode.inode_id, layer_id, inode.size)
+            .await?;
+
+        debug!(path = %path, size = data.len(), "Read binary data");
+        Ok((data, false))
+    }
+
+    pub async fn delete_file(&self, path: &str) -> FsResult<()> {
+        self.tracked(Change::new("delete", path), async {
+            let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+            self.delete_file_in_tx(&mut tx, path).await?;
+            tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Delete a file or symlink as part of a caller-managed transaction.
+    pub async fn delete_file_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        path: &str,
+    ) -> FsResult<()> {
+        self.session.mark_written();
+        self.ensure_layer_writable(&mut **tx).await?;
+
+        let inode = self.resolve_path_nofollow_in_tx(tx, path).await?;
+
+        if inode.inode_type == InodeType::Dir {
+            return Err(FsError::IsDirectory(path.to_string()));
+        }
+
+        let block_ops = BlockOperations::new(self.pool);
+        block_ops.delete_in_tx(tx, self.tenant_id, inode.inode_id).await?;
+
+        let inode_ops = InodeOperations::new(self.pool);
+        inode_ops.delete_in_tx(tx, self.tenant_id, inode.inode_id).await?;
+
+        self.layer_manager
+            .record_change_in_tx(
+                tx,
+                inode.inode_id,
+                path,
+                ChangeType::Delete,
+                Some(-inode.size),
+                None,
+            )
+            .await
+            .map_err(|e| FsError::Storage(e.into()))?;
 
         Ok(())
     }
+
+    /// Rename `from` to `to`, replacing `to` if it exists.
+    ///
+    /// The source inode is re-linked under the new name and any existing
+    /// target is freed in the same transaction, so readers of `to` see either
+    /// the old or the new content. This supports the write-temp-then-rename
+    /// pattern used for config files.
+    pub async fn rename(&self, from: &str, to: &str) -> FsResult<()> {
+        self.tracked(Change::new("rename", from).target(to), async {
+            self.session.mark_written();
+            self.ensure_layer_writable(self.pool).await?;
+
+            let from = normalize_path(from)?;
+            let to = normalize_path(to)?;
+
+            if from == "/" || to == "/" {
+                return Err(FsError::InvalidPath("cannot rename the root directory".to_string()));
+            }
+            if from == to {
+                self.resolve_path_nofollow(&from).await?;
+                return Ok(());
+            }
+            if to.starts_with(&format!("{}/", from)) {
+                return Err(FsError::InvalidPath(format!(
+                    "cannot move '{}' into its own subdirectory '{}'",
+                    from, to
+                )));
+            }
+
+            let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+
+            let source = self.resolve_path_nofollow_in_tx(&mut tx, &from).await?;
+            let (parent_path, new_name) = split_path(&to)?;
+            let parent = self.resolve_path_in_tx(&mut tx, &parent_path).await?;
+            if parent.inode_type != InodeType::Dir {
+                return Err(FsError::NotDirectory(parent_path));
+            }
+
+            let inode_ops = InodeOperations::new(self.pool);
+            let target = inode_ops
+                .get_by_parent_and_name_in_tx(&mut tx, self.tenant_id, parent.inode_id, &new_name)
+                .await?;
+
+            if let Some(target) = &target {
+                match (source.inode_type, target.inode_type) {
+                    (InodeType::Dir, InodeType::Dir) => {
+                        let children = inode_ops
+                            .list_children_in_tx(&mut tx, self.tenant_id, target.inode_id)
+                            .await?;
+                        if !children.is_empty() {
+                            return Err(FsError::DirectoryNotEmpty(to));
+                        }
+                    }
+                    (InodeType::Dir, _) => return Err(FsError::NotDirectory(to)),
+                    (_, InodeType::Dir) => return Err(FsError::IsDirectory(to)),
+                    _ => {}
+                }
+
+                // Blocks and text data of the old target go with the inode
+                inode_ops.delete_in_tx(&mut tx, self.tenant_id, target.inode_id).await?;
+            }
+
+            // Everything below a directory moves with it and gets entries too
+            let subtree = match source.inode_type {
+                InodeType::Dir => {
+                    inode_ops
+                        .list_tree_in_tx(&mut tx, self.tenant_id, source.inode_id, None)
+                        .await?
+                }
+                _ => Vec::new(),
+            };
+
+            // Text lives in the layer that last changed the file, which the entries
+            // below make this one; copy it up if it was inherited
+            let cow = CowHandler::new(self.pool, self.tenant_id, self.current_layer_id);
+            for inode in std::iter::once(&source).chain(subtree.iter().map(|(_, inode)| inode)) {
+                if inode.inode_type == InodeType::File {
+                    let text_layer_id = self.text_layer_in_tx(&mut tx, inode).await?;
+                    cow.copy_up_text_in_tx(&mut tx, inode.inode_id, text_layer_id)
+                        .await
+                        .map_err(FsError::Storage)?;
+                }
+            }
+
+            inode_ops
+                .move_in_tx(&mut tx, self.tenant_id, source.inode_id, parent.inode_id, &new_name)
+                .await?;
+
+            self.layer_manager
+                .record_change_in_tx(
+                    &mut tx,
+                    source.inode_id,
+                    &from,
+                    ChangeType::Delete,
+                    Some(-source.size),
+                    None,
+                )
+                .await
+                .map_err(|e| FsError::Storage(e.into()))?;
+
+            let (change_type, size_delta) = match &target {
+                Some(target) => (ChangeType::Modify, source.size - target.size),
+                None => (ChangeType::Add, source.size),
+            };
+            self.layer_manager
+                .record_change_in_tx(
+                    &mut tx,
+                    source.inode_id,
+                    &to,
+                    change_type,
+                    Some(size_delta),
+                    None,
+                )
+                .await
+                .map_err(|e| FsError::Storage(e.into()))?;
+
+            for (relative, inode) in &subtree {
+                for (path, change_type, size_delta) in [
+                    (join_below(&from, relative), ChangeType::Delete, -inode.size),
+                    (join_below(&to, relative), ChangeType::Add, inode.size),
+                ] {
+                    self.layer_manager
+                        .record_change_in_tx(
+                            &mut tx,
+                            inode.inode_id,
+                            &path,
+                            change_type,
+                            Some(size_delta),
+                            None,
+                        )
+                        .await
+                        .map_err(|e| FsError::Storage(e.into()))?;
+                }
+            }
+
+            tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+
+            // Directories at or under `from` moved; cached ids would resolve the old paths
+            self.forget_dirs();
+
+            // Handles follow the inode; only their recorded path changes
+            self.handles.rename(&from, &to);
+
+            debug!(from = %from, to = %to, inode_id = source.inode_id, "Renamed");
+            Ok(())
+        })
+        .await
+    }
+
+    /// Move a regular file from `from` to `to` without disturbing handles
+    /// already open on it.
+    ///
+    /// Editors keep a file open, rename it and carry on writing; reads and
+    /// writes through those handles keep addressing the same inode and are
+    /// recorded under the new path.
+    pub async fn move_file_preserving_handles(&self, from: &str, to: &str) -> FsResult<()> {
+        // The entry itself moves, so a symlink is not followed, as in `rename`
+        let inode = self.resolve_path_nofollow(from).await?;
+        if inode.inode_type == InodeType::Dir {
+            return Err(FsError::IsDirectory(from.to_string()));
+        }
+        self.rename(from, to).await
+    }
+
+    /// Open the file at `path` for `uid` and return a handle bound to its inode.
+    ///
+    /// A final symlink is followed unless `flags` has `O_NOFOLLOW`, in which
+    /// case opening it fails with `SymlinkLoop` (ELOOP), as open(2) does.
+    pub async fn open(&self, path: &str, flags: i32, uid: u32) -> FsResult<FileHandle> {
+        let path = normalize_path(path)?;
+        let inode = if flags & libc::O_NOFOLLOW != 0 {
+            let inode = self.resolve_path_nofollow(&path).await?;
+            if inode.inode_type == InodeType::Symlink {
+                return Err(FsError::SymlinkLoop(path));
+            }
+            inode
+        } else {
+            self.resolve_path(&path).await?
+        };
+        if inode.inode_type == InodeType::Dir {
+            return Err(FsError::IsDirectory(path));
+        }
+
+        let fh = self.handles.insert(inode.inode_id, &path, flags, uid);
+        debug!(path = %path, inode_id = inode.inode_id, fh, "Opened file handle");
+        Ok(fh)
+    }
+
+    /// Close a handle returned by `open`. Unknown handles are ignored.
+    pub fn release(&self, fh: FileHandle) {
+        if let Some(file) = self.handles.remove(fh) {
+            debug!(path = %file.path, fh, "Released file handle");
+        }
+    }
+
+    /// Read the full contents of the file behind `fh`.
+    pub async fn read_handle(&self, fh: FileHandle) -> FsResult<Vec<u8>> {
+        let file = self.handles.get(fh).ok_or_else(|| invalid_handle(fh))?;
+
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let inode = InodeOperations::new(self.pool)
+            .get_in_tx(&mut tx, self.tenant_id, file.inode_id)
+            .await?
+            .ok_or_else(|| FsError::PathNotFound(file.path.clone()))?;
+        let data = self.read_inode_in_tx(&mut tx, &inode, &file.path).await?;
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        self.touch_atime(&inode).await?;
+        Ok(data)
+    }
+
+    /// Replace the contents of the file behind `fh`, wherever it now lives.
+    pub async fn write_handle(&self, fh: FileHandle, data: &[u8]) -> FsResult<()> {
+        self.session.mark_written();
+
+        let file = self.handles.get(fh).ok_or_else(|| invalid_handle(fh))?;
+
+        self.tracked(Change::new("write", &file.path).bytes(data.len()), async {
+            self.ensure_layer_writable(self.pool).await?;
+            let mut tx = self.pool.begin().await.map_err(|e| FsError::Storage(e.into()))?;
+            let inode = InodeOperations::new(self.pool)
+                .get_in_tx(&mut tx, self.tenant_id, file.inode_id)
+                .await?
+                .ok_or_else(|| FsError::PathNotFound(file.path.clone()))?;
+            self.write_inode_in_tx(&mut tx, &inode, &file.path, data).await?;
+            tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Metadata of the entry at `path`; a symlink is reported itself, as
+    /// `lstat` does.
+    pub async fn stat(&self, path: &str) -> FsResult<Inode> {
+        let inode = self.resolve_path_nofollow(path).await?;
+        let mut adjusted = self.with_read_eol_sizes(vec![inode]).await?;
+        Ok(adjusted.remove(0))
+    }
+
+    /// How the content of the file at `path` is stored. Symlinks are
+    /// followed; directories have no content and fail with `IsDirectory`.
+    pub async fn storage_mode(&self, path: &str) -> FsResult<StorageMode> {
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let inode = self.resolve_path_in_tx(&mut tx, path).await?;
+        if inode.inode_type == InodeType::Dir {
+            return Err(FsError::IsDirectory(path.to_string()));
+        }
+
+        let text_layer_id = self.text_layer_in_tx(&mut tx, &inode).await?;
+        let metadata = TextBlockOperations::new(self.pool)
+            .get_metadata_in_tx(&mut tx, self.tenant_id, inode.inode_id, text_layer_id)
+            .await?;
+        let mode = match metadata {
+            Some(metadata) => {
+                StorageMode::Text { encoding: metadata.encoding, line_ending: metadata.line_ending }
+            }
+            None => match ChunkOperations::new(self.pool)
+                .visible_map_layer_in_tx(
+                    &mut tx,
+                    self.tenant_id,
+                    inode.inode_id,
+                    self.current_layer_id,
+                )
+                .await?
+            {
+                Some(_) => StorageMode::Chunked,
+                None => StorageMode::Binary,
+            },
+        };
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+        Ok(mode)
+    }
+
+    /// Report the converted length of text files so readers don't stop short
+    /// of the data. The text is read as `read_file` finds it, from the layer
+    /// that last wrote each file, with one query for all of `inodes`.
+    async fn with_read_eol_sizes(&self, mut inodes: Vec<Inode>) -> FsResult<Vec<Inode>> {
+        if self.read_eol.is_none() {
+            return Ok(inodes);
+        }
+        let files: Vec<InodeId> = inodes
+            .iter()
+            .filter(|inode| inode.inode_type == InodeType::File)
+            .map(|inode| inode.inode_id)
+            .collect();
+        if files.is_empty() {
+            return Ok(inodes);
+        }
+
+        let mut tx = begin_snapshot(self.reader()).await?;
+        let texts: HashMap<InodeId, String> = TextBlockOperations::new(self.pool)
+            .visible_texts_in_tx(&mut tx, self.tenant_id, self.current_layer_id, &files)
+            .await?
+            .into_iter()
+            .collect();
+        tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+
+        for inode in &mut inodes {
+            if let Some(text) = texts.get(&inode.inode_id) {
+                inode.size = self.apply_read_eol(text.as_bytes().to_vec()).len() as i64;
+            }
+        }
+        Ok(inodes)
+    }
+
+    /// Apply the configured read-time line ending conversion to text content
+    fn apply_read_eol(&self, data: Vec<u8>) -> Vec<u8> {
+        match self.read_eol {
+            Some(eol) => eol.convert(&data),
+            None => data,
+        }
+    }
+
+    pub async fn chmod(&self, path: &str, mode: i32) -> FsResult<()> {
+        self.tracked(Change::new("chmod", path), async {
+            self.session.mark_written();
+            self.ensure_layer_writable(self.pool).await?;
+
+            let inode = self.resolve_path(path).await?;
+
+            let inode_ops = InodeOperations::new(self.pool);
+            inode_ops
+                .update(
+                    self.tenant_id,
+                    inode.inode_id,
+                    UpdateInodeInput {
+                        size: None,
+                        mode: Some(mode),
+                        uid: None,
+                        gid: None,
+                        atime: None,
+                        mtime: None,
+                        ctime: Some(chrono::Utc::now()),
+                    },
+                )
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn chown(&self, path: &str, uid: i32, gid: i32) -> FsResult<()> {
+        self.tracked(Change::new("chown", path), async {
+            self.session.mark_written();
+            self.ensure_layer_writable(self.pool).await?;
+
+            let inode = self.resolve_path(path).await?;
+
+            let inode_ops = InodeOperations::new(self.pool);
+            inode_ops
+                .update(
+                    self.tenant_id,
+                    inode.inode_id,
+                    UpdateInodeInput {
+                        size: None,
+                        mode: None,
+                        uid: Some(uid),
+                        gid: Some(gid),
+                        atime: None,
+                        mtime: None,
+                        ctime: Some(chrono::Utc::now()),
+                    },
+                )
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Blocks of binary content fetched per query by `read_file_stream`
+const STREAM_PAGE_BLOCKS: i64 = 256;
+
+/// Progress of a `read_file_stream`
+struct ReadStreamState<'a> {
+    /// The snapshot everything is read from; committed once exhausted
+    tx: Option<DatabaseTransaction<'a>>,
+    block_ops: BlockOperations<'a>,
+    tenant_id: TenantId,
+    inode_id: InodeId,
+    layer_id: LayerId,
+    /// Fetched pieces with their byte offsets, in order
+    pending: VecDeque<(usize, Bytes)>,
+    /// Block index the next page starts at; `None` once no pages are left
+    next_block: Option<i32>,
+    /// Offset of the next byte to hand out
+    position: usize,
+    /// Offset the stream ends at: the end of the range, at most the file size
+    size: usize,
+}
+
+impl<'a> ReadStreamState<'a> {
+    async fn next(mut self) -> FsResult<Option<(Bytes, Self)>> {
+        loop {
+            if self.position >= self.size {
+                if let Some(tx) = self.tx.take() {
+                    tx.commit().await.map_err(|e| FsError::Storage(e.into()))?;
+                }
+                return Ok(None);
+            }
+
+            let hole_end = match self.pending.pop_front() {
+                Some((offset, piece)) if offset > self.position => {
+                    self.pending.push_front((offset, piece));
+                    offset
+                }
+                Some((offset, mut piece)) => {
+                    // A range may start part-way into a piece
+                    let skip = (self.position - offset).min(piece.len());
+                    piece = piece.slice(skip..);
+                    // Same truncation as a whole read: an inherited block may
+                    // hold bytes past the current end of the file
+                    piece.truncate(self.size - self.position);
+                    self.position += piece.len();
+                    if piece.is_empty() {
+                        continue;
+                    }
+                    return Ok(Some((piece, self)));
+                }
+                None => match (self.next_block, self.tx.as_mut()) {
+                    (Some(from), Some(tx)) => {
+                        let page = self
+                            .block_ops
+                            .list_visible_page_in_tx(
+                                tx,
+                                self.tenant_id,
+                                self.inode_id,
+                                self.layer_id,
+                                from,
+                                STREAM_PAGE_BLOCKS,
+                            )
+                            .await?;
+                        self.next_block = page.last().map(|block| block.block_index + 1);
+                        self.pending.extend(page.into_iter().map(|block| {
+                            (block.block_index as usize * BLOCK_SIZE, Bytes::from(block.data))
+                        }));
+                        continue;
+                    }
+                    _ => self.size,
+                },
+            };
+
+            // Blocks never stored are holes and read as zeros
+            let len = (hole_end.min(self.size) - self.position).min(BLOCK_SIZE);
+            self.position += len;
+            return Ok(Some((Bytes::from(vec![0u8; len]), self)));
+        }
+    }
+}
+
+/// Re-cut `head` followed by `rest` into `BLOCK_SIZE` blocks
+fn into_blocks<S>(head: BytesMut, rest: S) -> impl Stream<Item = anyhow::Result<Vec<u8>>>
+where
+    S: Stream<Item = FsResult<Bytes>> + Unpin,
+{
+    futures::stream::try_unfold(
+        (head, rest, false),
+        |(mut buffer, mut rest, mut done)| async move {
+            while !done && buffer.len() < BLOCK_SIZE {
+                match rest.next().await {
+                    Some(chunk) => buffer.extend_from_slice(&chunk?),
+                    None => done = true,
+                }
+            }
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+            let block = buffer.split_to(buffer.len().min(BLOCK_SIZE)).to_vec();
+            Ok(Some((block, (buffer, rest, done))))
+        },
+    )
+}
+
+fn invalid_handle(fh: FileHandle) -> FsError {
+    FsError::InvalidPath(format!("invalid file handle: {}", fh))
 }
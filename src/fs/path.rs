@@ -3,6 +3,11 @@ use crate::fs::error::{FsError, FsResult};
 const MAX_PATH_LENGTH: usize = 4096;
 const MAX_FILENAME_LENGTH: usize = 255;
 
+/// Canonical form of an absolute path.
+///
+/// Collapses `.`, `..` and repeated or trailing slashes, so `/a/./b/../c`
+/// and `//a//c/` both become `/a/c`. A `..` that would climb above `/` is
+/// rejected rather than clamped to the root.
 pub fn normalize_path(path: &str) -> FsResult<String> {
     if path.is_empty() {
         return Err(FsError::InvalidPath("Empty path".to_string()));
@@ -24,11 +29,19 @@ pub fn normalize_path(path: &str) -> FsResult<String> {
         return Ok("/".to_string());
     }
 
-    let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
-
-    for part in &parts {
-        if part.len() > MAX_FILENAME_LENGTH {
-            return Err(FsError::FilenameTooLong(part.len()));
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                if parts.pop().is_none() {
+                    return Err(FsError::InvalidPath(format!("Path escapes root: {}", path)));
+                }
+            }
+            _ if part.len() > MAX_FILENAME_LENGTH => {
+                return Err(FsError::FilenameTooLong(part.len()));
+            }
+            _ => parts.push(part),
         }
     }
 
@@ -68,10 +81,40 @@ pub fn path_components(path: &str) -> FsResult<Vec<String>> {
     Ok(normalized.split('/').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect())
 }
 
+/// Resolve a symlink's `target` against the directory holding `link`,
+/// collapsing `.` and `..` components
+pub fn join_link_target(link: &str, target: &str) -> String {
+    let base = if target.starts_with('/') {
+        ""
+    } else {
+        link.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+    };
+
+    let mut parts: Vec<&str> = Vec::new();
+    for component in base.split('/').chain(target.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            name => parts.push(name),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_join_link_target() {
+        assert_eq!(join_link_target("/a/link", "file"), "/a/file");
+        assert_eq!(join_link_target("/a/link", "../b/./file"), "/b/file");
+        assert_eq!(join_link_target("/a/link", "/abs/file"), "/abs/file");
+        assert_eq!(join_link_target("/link", "../../file"), "/file");
+    }
+
     #[test]
     fn test_normalize_path_root() {
         assert_eq!(normalize_path("/").unwrap(), "/");
@@ -97,6 +140,42 @@ mod tests {
         assert_eq!(normalize_path("//data//files//").unwrap(), "/data/files");
     }
 
+    #[test]
+    fn test_normalize_path_dot_segments() {
+        assert_eq!(normalize_path("/a/./b/../c").unwrap(), "/a/c");
+        assert_eq!(normalize_path("/a/b/..").unwrap(), "/a");
+        assert_eq!(normalize_path("/a/..").unwrap(), "/");
+        assert_eq!(normalize_path("/./").unwrap(), "/");
+        assert_eq!(normalize_path("//a//./b/").unwrap(), "/a/b");
+        // Only whole `.`/`..` components are special
+        assert_eq!(normalize_path("/a/...").unwrap(), "/a/...");
+        assert_eq!(normalize_path("/a/..b/.c").unwrap(), "/a/..b/.c");
+    }
+
+    #[test]
+    fn test_normalize_path_rejects_escaping_root() {
+        assert!(normalize_path("/..").is_err());
+        assert!(normalize_path("/a/../..").is_err());
+        assert!(normalize_path("/../etc").is_err());
+    }
+
+    #[test]
+    fn test_normalize_path_hook_traversal() {
+        // Leaving the hook directory lands on an ordinary path...
+        assert_eq!(normalize_path("/.tarbox/../etc").unwrap(), "/etc");
+        // ...and entering it through a detour lands on the hook path
+        assert_eq!(normalize_path("/foo/../.tarbox/layers").unwrap(), "/.tarbox/layers");
+        assert!(normalize_path("/.tarbox/../../etc").is_err());
+    }
+
+    #[test]
+    fn test_split_path_collapses_dots() {
+        let (parent, name) = split_path("/a/./b/../c.txt").unwrap();
+        assert_eq!(parent, "/a");
+        assert_eq!(name, "c.txt");
+        assert!(split_path("/a/..").is_err());
+    }
+
     #[test]
     fn test_normalize_path_empty() {
         assert!(normalize_path("").is_err());
@@ -0,0 +1,86 @@
+//! Searching text file contents line by line, as `FileSystem::search` does.
+
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+use crate::fs::error::{FsError, FsResult};
+
+/// Where `FileSystem::search` looks and how it reads the query.
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// Directory, or single file, to search; the whole tree if `None`
+    pub path: Option<String>,
+    /// Take the query as a plain string instead of a regular expression
+    pub fixed_strings: bool,
+    pub ignore_case: bool,
+    /// Stop after this many matching lines
+    pub max_matches: Option<usize>,
+}
+
+/// A line of a text file that matched.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SearchMatch {
+    pub path: String,
+    /// 1-based, as editors and grep count
+    pub line_number: usize,
+    /// The line, without its line ending
+    pub line: String,
+}
+
+/// Decides which lines match a query.
+///
+/// Plain strings are matched by the database, which then returns only
+/// matching lines; regular expressions are matched here, over every line.
+pub(crate) enum LineMatcher {
+    Fixed(String),
+    Regex(Regex),
+}
+
+impl LineMatcher {
+    pub(crate) fn new(query: &str, opts: &SearchOptions) -> FsResult<Self> {
+        if opts.fixed_strings {
+            return Ok(Self::Fixed(query.to_string()));
+        }
+        RegexBuilder::new(query)
+            .case_insensitive(opts.ignore_case)
+            .build()
+            .map(Self::Regex)
+            .map_err(|e| FsError::InvalidPattern(e.to_string()))
+    }
+
+    /// The substring lines must contain, for the database to filter on.
+    pub(crate) fn needle(&self) -> Option<&str> {
+        match self {
+            Self::Fixed(needle) => Some(needle),
+            Self::Regex(_) => None,
+        }
+    }
+
+    /// Whether `line`, as returned by the database, matches.
+    pub(crate) fn is_match(&self, line: &str) -> bool {
+        match self {
+            Self::Fixed(_) => true,
+            Self::Regex(regex) => regex.is_match(line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_matcher() {
+        let opts = SearchOptions { ignore_case: true, ..Default::default() };
+        let matcher = LineMatcher::new(r"fn \w+\(", &opts).unwrap();
+        assert_eq!(matcher.needle(), None);
+        assert!(matcher.is_match("pub FN main() {"));
+        assert!(!matcher.is_match("let f = 1;"));
+
+        let opts = SearchOptions { fixed_strings: true, ..Default::default() };
+        assert_eq!(LineMatcher::new("a.b(", &opts).unwrap().needle(), Some("a.b("));
+
+        let invalid = LineMatcher::new("(", &SearchOptions::default());
+        assert!(matches!(invalid, Err(FsError::InvalidPattern(_))));
+    }
+}
@@ -0,0 +1,28 @@
+//! How a file's content is laid out in the database.
+
+use serde::Serialize;
+
+/// The representation a file was last written in. It follows from what
+/// `FileTypeDetector` made of the content and from the chunking mode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StorageMode {
+    /// Deduplicated lines, so layers record line-level changes.
+    Text { encoding: String, line_ending: String },
+    /// Fixed-size blocks, so layers record the blocks that changed.
+    Binary,
+    /// Content-defined chunks, shared with other files holding the same data.
+    Chunked,
+}
+
+impl std::fmt::Display for StorageMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageMode::Text { encoding, line_ending } => {
+                write!(f, "text({}, {})", encoding, line_ending.to_lowercase())
+            }
+            StorageMode::Binary => write!(f, "binary"),
+            StorageMode::Chunked => write!(f, "binary(chunked)"),
+        }
+    }
+}
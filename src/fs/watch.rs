@@ -0,0 +1,135 @@
+//! Change notifications for `FileSystem::watch`.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events a watcher may fall behind by before it starts missing them
+const CHANGE_FEED_CAPACITY: usize = 1024;
+
+/// A committed change to the tree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChangeEvent {
+    Created {
+        path: String,
+    },
+    /// Contents or attributes changed
+    Modified {
+        path: String,
+    },
+    Deleted {
+        path: String,
+    },
+    Renamed {
+        from: String,
+        to: String,
+    },
+    /// Changed by another process, which only said where
+    Invalidated {
+        path: String,
+    },
+}
+
+impl ChangeEvent {
+    /// The paths the change touches: both ends of a rename, one otherwise.
+    pub fn paths(&self) -> Vec<&str> {
+        match self {
+            Self::Created { path }
+            | Self::Modified { path }
+            | Self::Deleted { path }
+            | Self::Invalidated { path } => vec![path],
+            Self::Renamed { from, to } => vec![from, to],
+        }
+    }
+
+    /// Whether the change touches `prefix` or anything below it.
+    pub fn is_under(&self, prefix: &str) -> bool {
+        self.paths().into_iter().any(|path| {
+            prefix == "/"
+                || path
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+}
+
+/// Fans changes out to watchers. Clones share the same watchers, so every
+/// `FileSystem` of a mount can be given one feed.
+#[derive(Debug, Clone)]
+pub struct ChangeFeed {
+    sender: broadcast::Sender<ChangeEvent>,
+    /// Database sessions changes were announced to other processes from;
+    /// see `is_own_notifier`.
+    notifiers: Arc<Mutex<HashSet<u32>>>,
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CHANGE_FEED_CAPACITY).0,
+            notifiers: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Send `event` to current watchers; it is built only if there are any.
+    pub fn publish(&self, event: impl FnOnce() -> ChangeEvent) {
+        if self.sender.receiver_count() > 0 {
+            // Fails only if every watcher went away in the meantime
+            let _ = self.sender.send(event());
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Remember that changes published here were also announced from the
+    /// server process `pid`.
+    pub fn add_notifier(&self, pid: u32) {
+        self.notifiers.lock().unwrap().insert(pid);
+    }
+
+    /// Whether an invalidation sent from `pid` echoes a change this feed has
+    /// already published. Pool connections are long-lived, so the set stays
+    /// as small as the pool.
+    pub fn is_own_notifier(&self, pid: u32) -> bool {
+        self.notifiers.lock().unwrap().contains(&pid)
+    }
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_event_is_under() {
+        let created = ChangeEvent::Created { path: "/src/main.rs".into() };
+        assert!(created.is_under("/"));
+        assert!(created.is_under("/src"));
+        assert!(created.is_under("/src/main.rs"));
+        assert!(!created.is_under("/sr"));
+
+        let renamed = ChangeEvent::Renamed { from: "/tmp/x".into(), to: "/src/x".into() };
+        assert!(renamed.is_under("/tmp") && renamed.is_under("/src"));
+        assert!(!renamed.is_under("/docs"));
+    }
+
+    #[test]
+    fn test_change_feed_publishes_only_to_watchers() {
+        let feed = ChangeFeed::new();
+        feed.publish(|| unreachable!("no watchers yet"));
+
+        let mut rx = feed.clone().subscribe();
+        feed.publish(|| ChangeEvent::Deleted { path: "/a".into() });
+        assert_eq!(rx.try_recv().unwrap(), ChangeEvent::Deleted { path: "/a".into() });
+    }
+}
@@ -12,13 +12,24 @@
 // without creating a new runtime, which is essential because resources like database
 // connection pools are bound to the runtime that created them.
 
-use super::interface::{FileAttr, FilesystemInterface, FsError, SetAttr};
+use super::backend::as_caller;
+use super::interface::{
+    DirEntry, FileAttr, FileType, FilesystemInterface, FsError, FsResult, SetAttr,
+};
+use super::negative_cache::NegativeCache;
+use crate::config::CacheConfig;
+use crate::fs::caller::CallerContext;
+use crate::fs::path::join_link_target;
 use fuser::{
     FileType as FuseFileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyLseek, ReplyOpen, ReplyStatfs, ReplyWrite,
+    Request, TimeOrNow, consts,
 };
+use moka::future::Cache;
 use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::runtime::Handle;
@@ -39,6 +50,27 @@ pub struct FuseAdapter {
     /// Inode to path mapping
     /// FUSE uses inodes, but our backend uses paths
     inode_map: Arc<RwLock<InodeMap>>,
+
+    /// Backend invalidation generation the inode map was built under
+    generation: AtomicU64,
+
+    /// Attributes fetched alongside the last directory listings, keyed by
+    /// path. Each entry answers one `lookup`/`getattr` within `attr_ttl`.
+    /// `None` when `attr_ttl` or the capacity is zero.
+    listed_attrs: Option<Cache<String, FileAttr>>,
+
+    /// Paths a lookup found missing. Each answers lookups as a negative
+    /// entry within `entry_ttl`.
+    missing: NegativeCache,
+
+    /// How long the kernel may cache attributes we reply with
+    attr_ttl: Duration,
+
+    /// How long the kernel may cache name lookups we reply with
+    entry_ttl: Duration,
+
+    /// Most paths each of the caches above holds
+    max_cached_entries: usize,
 }
 
 /// Manages inode <-> path bidirectional mapping
@@ -96,6 +128,36 @@ impl InodeMap {
             self.path_to_inode.remove(&path);
         }
     }
+
+    /// Drop every mapping except the root. Inode numbers are not reused, so
+    /// the kernel's stale inodes fail with ENOENT instead of aliasing new paths.
+    fn clear(&mut self) {
+        self.inode_to_path.clear();
+        self.path_to_inode.clear();
+        self.insert(1, "/".to_string());
+    }
+
+    /// Re-key `from` (and everything below it) to `to`, dropping any mapping
+    /// that `to` replaced. The moved entries keep their inode numbers.
+    fn rename(&mut self, from: &str, to: &str) {
+        if let Some(replaced) = self.path_to_inode.get(to).copied() {
+            self.remove(replaced);
+        }
+
+        let prefix = format!("{}/", from);
+        let moved: Vec<(String, u64)> = self
+            .path_to_inode
+            .iter()
+            .filter(|(path, _)| path.as_str() == from || path.starts_with(&prefix))
+            .map(|(path, &inode)| (path.clone(), inode))
+            .collect();
+
+        for (old_path, inode) in moved {
+            self.path_to_inode.remove(&old_path);
+            let new_path = format!("{}{}", to, &old_path[from.len()..]);
+            self.insert(inode, new_path);
+        }
+    }
 }
 
 impl FuseAdapter {
@@ -107,21 +169,237 @@ impl FuseAdapter {
     /// # Panics
     /// Panics if called outside of a tokio runtime context.
     pub fn new(backend: Arc<dyn FilesystemInterface>) -> Self {
-        let runtime = Handle::current();
-        Self { backend, runtime, inode_map: Arc::new(RwLock::new(InodeMap::new())) }
+        Self::with_runtime(backend, Handle::current())
     }
 
     /// Create a new FUSE adapter with a provided runtime handle
     pub fn with_runtime(backend: Arc<dyn FilesystemInterface>, runtime: Handle) -> Self {
-        Self { backend, runtime, inode_map: Arc::new(RwLock::new(InodeMap::new())) }
+        let generation = AtomicU64::new(backend.invalidation_generation());
+        let max_cached_entries = CacheConfig::default().max_entries;
+        Self {
+            backend,
+            runtime,
+            inode_map: Arc::new(RwLock::new(InodeMap::new())),
+            generation,
+            listed_attrs: Self::attr_cache(DEFAULT_ATTR_TTL, max_cached_entries),
+            missing: NegativeCache::with_ttl(DEFAULT_ENTRY_TTL, max_cached_entries),
+            attr_ttl: DEFAULT_ATTR_TTL,
+            entry_ttl: DEFAULT_ENTRY_TTL,
+            max_cached_entries,
+        }
+    }
+
+    /// Let the kernel cache attributes for `attr_ttl` and lookups for
+    /// `entry_ttl`. Zero makes it ask again every time.
+    pub fn with_ttls(mut self, attr_ttl: Duration, entry_ttl: Duration) -> Self {
+        self.attr_ttl = attr_ttl;
+        self.entry_ttl = entry_ttl;
+        self.build_caches();
+        self
+    }
+
+    /// Remember attributes and misses for at most `max_entries` paths each.
+    pub fn with_max_cached_entries(mut self, max_entries: usize) -> Self {
+        self.max_cached_entries = max_entries;
+        self.build_caches();
+        self
+    }
+
+    fn build_caches(&mut self) {
+        self.listed_attrs = Self::attr_cache(self.attr_ttl, self.max_cached_entries);
+        self.missing = NegativeCache::with_ttl(self.entry_ttl, self.max_cached_entries);
+    }
+
+    fn attr_cache(ttl: Duration, max_entries: usize) -> Option<Cache<String, FileAttr>> {
+        if ttl.is_zero() || max_entries == 0 {
+            return None;
+        }
+        Some(Cache::builder().max_capacity(max_entries as u64).time_to_live(ttl).build())
+    }
+
+    /// Reset the inode map if the backend invalidated everything since it
+    /// was built, so paths are resolved fresh.
+    fn sync_generation(&self) {
+        let current = self.backend.invalidation_generation();
+        if self.generation.swap(current, Ordering::SeqCst) != current {
+            tracing::info!(generation = current, "Backend invalidated paths, clearing inode map");
+            self.inode_map.write().unwrap().clear();
+            self.forget_cached_attrs();
+        }
+    }
+
+    /// Drop attributes remembered from directory listings and paths known to
+    /// be missing; called whenever this mount changes something they might
+    /// describe
+    fn forget_cached_attrs(&self) {
+        if let Some(listed) = &self.listed_attrs {
+            listed.invalidate_all();
+        }
+        self.missing.invalidate_all();
+    }
+
+    /// Attributes of `path`, served from the last listing of its directory
+    /// when still fresh and asked from the backend otherwise
+    fn path_attr(&self, path: &str) -> FsResult<FileAttr> {
+        // Each listed attribute answers once
+        let listed = self.listed_attrs.as_ref().and_then(|listed| {
+            self.block_on(async {
+                let attr = listed.get(path).await;
+                listed.invalidate(path).await;
+                attr
+            })
+        });
+        match listed {
+            Some(attr) => Ok(attr),
+            None => self.block_on(self.backend.get_attr(path)),
+        }
+    }
+
+    /// Answer a `lookup` of `name` in `parent`: the entry's attributes, mapped
+    /// to a FUSE inode, and how long the kernel may cache them
+    fn lookup_entry(
+        &self,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<(Duration, fuser::FileAttr), libc::c_int> {
+        let name = name.to_str().ok_or(libc::EINVAL)?;
+        let parent_path = self.get_path(parent)?;
+
+        // Construct child path
+        let path = if parent_path == "/" {
+            format!("/{}", name)
+        } else {
+            format!("{}/{}", parent_path, name)
+        };
+
+        if self.missing.is_missing(&path) {
+            return Ok((self.entry_ttl, Self::negative_entry()));
+        }
+
+        match self.path_attr(&path) {
+            Ok(mut attr) => {
+                attr.inode = self.inode_map.write().unwrap().get_or_create(&path);
+                Ok((self.entry_ttl, Self::to_fuse_attr(&attr, self.entry_ttl)))
+            }
+            // With a zero TTL the kernel would forget the entry at once, so
+            // it is told plain ENOENT
+            Err(FsError::PathNotFound(_)) if !self.entry_ttl.is_zero() => {
+                self.block_on(self.missing.insert_missing(&path));
+                Ok((self.entry_ttl, Self::negative_entry()))
+            }
+            Err(e) => Err(Self::error_to_errno(e)),
+        }
+    }
+
+    /// Answer a `getattr` of `ino`, with how long the kernel may cache it
+    fn inode_attr(&self, ino: u64) -> Result<(Duration, fuser::FileAttr), libc::c_int> {
+        let path = self.get_path(ino)?;
+        let mut attr = self.path_attr(&path).map_err(Self::error_to_errno)?;
+        attr.inode = ino; // Use FUSE inode
+        Ok((self.attr_ttl, Self::to_fuse_attr(&attr, self.attr_ttl)))
+    }
+
+    /// List the directory at `path` with attributes, mapping each entry to a
+    /// FUSE inode. The returned attributes already carry that inode.
+    fn list_dir_with_attrs(&self, path: &str) -> FsResult<Vec<(String, DirEntry, FileAttr)>> {
+        let listed = self.block_on(self.backend.read_dir_with_attrs(path))?;
+        let mut map = self.inode_map.write().unwrap();
+        Ok(listed
+            .into_iter()
+            .map(|(entry, mut attr)| {
+                let entry_path = if path == "/" {
+                    format!("/{}", entry.name)
+                } else {
+                    format!("{}/{}", path, entry.name)
+                };
+                attr.inode = map.get_or_create(&entry_path);
+                (entry_path, entry, attr)
+            })
+            .collect())
+    }
+
+    /// List the directory at `path`, remembering each entry's attributes for
+    /// the lookups that usually follow a plain `readdir`
+    fn list_dir(&self, path: &str) -> FsResult<Vec<(u64, DirEntry)>> {
+        let listed = self.list_dir_with_attrs(path)?;
+        if let Some(attrs) = &self.listed_attrs {
+            self.block_on(async {
+                for (entry_path, _, attr) in &listed {
+                    attrs.insert(entry_path.clone(), attr.clone()).await;
+                }
+            });
+        }
+        Ok(listed.into_iter().map(|(_, entry, attr)| (attr.inode, entry)).collect())
     }
 
     /// Get path from inode
     fn get_path(&self, inode: u64) -> Result<String, libc::c_int> {
+        self.sync_generation();
         let map = self.inode_map.read().unwrap();
         map.get_path(inode).map(|s| s.to_string()).ok_or(libc::ENOENT)
     }
 
+    /// Flush the file or directory behind `inode` through the backend
+    fn sync_inode(&self, inode: u64) -> Result<(), libc::c_int> {
+        let path = self.get_path(inode)?;
+        self.block_on(self.backend.fsync(&path)).map_err(Self::error_to_errno)
+    }
+
+    /// Resolve a symlink in the final component of `path` the way open(2)
+    /// does: with `O_NOFOLLOW` it is refused with ELOOP, otherwise its target
+    /// (and the target's target) is used instead. Missing paths pass through
+    /// unchanged so `create` can make them.
+    async fn follow_final_symlink(&self, path: &str, flags: i32) -> FsResult<String> {
+        let mut current = path.to_string();
+        for _ in 0..MAX_SYMLINK_HOPS {
+            match self.backend.get_attr(&current).await {
+                Ok(attr) if attr.kind == FileType::Symlink => {
+                    if flags & libc::O_NOFOLLOW != 0 {
+                        return Err(FsError::SymlinkLoop(current));
+                    }
+                    let target = self.backend.read_symlink(&current).await?;
+                    current = join_link_target(&current, &target);
+                }
+                Ok(_) | Err(FsError::PathNotFound(_)) => return Ok(current),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(FsError::SymlinkLoop(path.to_string()))
+    }
+
+    /// Open `path` for `uid`/`gid` through the backend, following a final
+    /// symlink unless `flags` has `O_NOFOLLOW`
+    fn open_path(&self, path: &str, flags: i32, uid: u32, gid: u32) -> Result<u64, libc::c_int> {
+        self.block_on(async {
+            let target = self.follow_final_symlink(path, flags).await?;
+            self.backend.authorize(&target, uid, gid, open_mask(flags)).await?;
+            self.backend.open(&target, flags, uid).await
+        })
+        .map_err(Self::error_to_errno)
+    }
+
+    /// Fail unless the caller of `req` may access `path` as `mask`
+    fn authorize(&self, req: &Request, path: &str, mask: i32) -> Result<(), libc::c_int> {
+        self.block_on(self.backend.authorize(path, req.uid(), req.gid(), mask))
+            .map_err(Self::error_to_errno)
+    }
+
+    /// Hand a new entry at `path` to the caller of `req`; the backend
+    /// creates everything as root
+    fn give_to_caller(
+        &self,
+        req: &Request,
+        path: &str,
+        attr: &mut FileAttr,
+    ) -> Result<(), libc::c_int> {
+        if (attr.uid, attr.gid) != (req.uid(), req.gid()) {
+            self.block_on_for(req, self.backend.chown(path, req.uid(), req.gid()))
+                .map_err(Self::error_to_errno)?;
+            (attr.uid, attr.gid) = (req.uid(), req.gid());
+        }
+        Ok(())
+    }
+
     /// Execute async operation in tokio runtime using block_in_place
     ///
     /// This uses block_in_place to allow blocking on the current runtime,
@@ -136,11 +414,41 @@ impl FuseAdapter {
         tokio::task::block_in_place(|| self.runtime.block_on(future))
     }
 
+    /// `block_on`, attributing the changes `future` makes to the caller of `req`
+    fn block_on_for<F, T>(&self, req: &Request, future: F) -> T
+    where
+        F: std::future::Future<Output = T>,
+    {
+        self.block_on(as_caller(CallerContext::new(req.uid(), req.gid()), future))
+    }
+
     /// Convert FsError to errno
     fn error_to_errno(error: FsError) -> libc::c_int {
         error.to_errno()
     }
 
+    /// An entry with inode 0, which tells the kernel the name doesn't exist
+    /// and may be cached as such
+    fn negative_entry() -> fuser::FileAttr {
+        fuser::FileAttr {
+            ino: 0,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FuseFileType::RegularFile,
+            perm: 0,
+            nlink: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 0,
+            flags: 0,
+        }
+    }
+
     /// Convert our FileAttr to fuser FileAttr
     fn to_fuse_attr(attr: &FileAttr, _ttl: Duration) -> fuser::FileAttr {
         fuser::FileAttr {
@@ -178,19 +486,41 @@ fn systemtime_to_datetime(st: SystemTime) -> chrono::DateTime<chrono::Utc> {
     chrono::DateTime::from_timestamp(duration.as_secs() as i64, 0).unwrap_or_else(chrono::Utc::now)
 }
 
+/// Symlinks followed before open gives up with ELOOP, matching Linux
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Access to a directory needed to add or remove entries in it
+const DIR_CHANGE: i32 = libc::W_OK | libc::X_OK;
+
+/// Access an open(2) with `flags` needs
+fn open_mask(flags: i32) -> i32 {
+    let mask = match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => libc::W_OK,
+        libc::O_RDWR => libc::R_OK | libc::W_OK,
+        _ => libc::R_OK,
+    };
+    if flags & libc::O_TRUNC != 0 { mask | libc::W_OK } else { mask }
+}
+
 /// Default TTL for file attributes (1 second)
-const ATTR_TTL: Duration = Duration::from_secs(1);
+pub(crate) const DEFAULT_ATTR_TTL: Duration = Duration::from_secs(1);
 
 /// Default TTL for directory entries (1 second)
-const ENTRY_TTL: Duration = Duration::from_secs(1);
+pub(crate) const DEFAULT_ENTRY_TTL: Duration = Duration::from_secs(1);
 
 impl Filesystem for FuseAdapter {
     /// Initialize filesystem
     fn init(
         &mut self,
         _req: &Request,
-        _config: &mut fuser::KernelConfig,
+        config: &mut fuser::KernelConfig,
     ) -> Result<(), libc::c_int> {
+        // Older kernels without readdirplus keep using readdir + lookup
+        if let Err(missing) =
+            config.add_capabilities(consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO)
+        {
+            tracing::info!(missing, "Kernel lacks readdirplus, falling back to readdir");
+        }
         tracing::info!("FUSE filesystem initialized");
         Ok(())
     }
@@ -202,81 +532,24 @@ impl Filesystem for FuseAdapter {
 
     /// Look up a directory entry by name
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let name = match name.to_str() {
-            Some(n) => n,
-            None => {
-                reply.error(libc::EINVAL);
-                return;
-            }
-        };
-
-        let parent_path = match self.get_path(parent) {
-            Ok(p) => p,
-            Err(e) => {
-                reply.error(e);
-                return;
-            }
-        };
-
-        // Construct child path
-        let path = if parent_path == "/" {
-            format!("/{}", name)
-        } else {
-            format!("{}/{}", parent_path, name)
-        };
-
-        // Get file attributes
-        let result = self.block_on(self.backend.get_attr(&path));
-
-        match result {
-            Ok(attr) => {
-                // Update inode mapping
-                let inode = {
-                    let mut map = self.inode_map.write().unwrap();
-                    map.get_or_create(&path)
-                };
-
-                // Update attr with mapped inode
-                let mut attr = attr;
-                attr.inode = inode;
-
-                let fuse_attr = Self::to_fuse_attr(&attr, ENTRY_TTL);
-                reply.entry(&ENTRY_TTL, &fuse_attr, 0);
-            }
-            Err(e) => {
-                reply.error(Self::error_to_errno(e));
-            }
+        match self.lookup_entry(parent, name) {
+            Ok((ttl, fuse_attr)) => reply.entry(&ttl, &fuse_attr, 0),
+            Err(errno) => reply.error(errno),
         }
     }
 
     /// Get file attributes
     fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        let path = match self.get_path(ino) {
-            Ok(p) => p,
-            Err(e) => {
-                reply.error(e);
-                return;
-            }
-        };
-
-        let result = self.block_on(self.backend.get_attr(&path));
-
-        match result {
-            Ok(mut attr) => {
-                attr.inode = ino; // Use FUSE inode
-                let fuse_attr = Self::to_fuse_attr(&attr, ATTR_TTL);
-                reply.attr(&ATTR_TTL, &fuse_attr);
-            }
-            Err(e) => {
-                reply.error(Self::error_to_errno(e));
-            }
+        match self.inode_attr(ino) {
+            Ok((ttl, fuse_attr)) => reply.attr(&ttl, &fuse_attr),
+            Err(errno) => reply.error(errno),
         }
     }
 
     /// Set file attributes
     fn setattr(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -313,13 +586,21 @@ impl Filesystem for FuseAdapter {
 
         let set_attr = SetAttr { mode, uid, gid, size, atime: atime_dt, mtime: mtime_dt };
 
-        let result = self.block_on(self.backend.set_attr(&path, set_attr));
+        if let Err(e) =
+            self.block_on(self.backend.authorize_set_attr(&path, req.uid(), req.gid(), &set_attr))
+        {
+            reply.error(Self::error_to_errno(e));
+            return;
+        }
+
+        self.forget_cached_attrs();
+        let result = self.block_on_for(req, self.backend.set_attr(&path, set_attr));
 
         match result {
             Ok(mut attr) => {
                 attr.inode = ino;
-                let fuse_attr = Self::to_fuse_attr(&attr, ATTR_TTL);
-                reply.attr(&ATTR_TTL, &fuse_attr);
+                let fuse_attr = Self::to_fuse_attr(&attr, self.attr_ttl);
+                reply.attr(&self.attr_ttl, &fuse_attr);
             }
             Err(e) => {
                 reply.error(Self::error_to_errno(e));
@@ -332,7 +613,7 @@ impl Filesystem for FuseAdapter {
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
@@ -351,6 +632,7 @@ impl Filesystem for FuseAdapter {
 
         match result {
             Ok(data) => {
+                self.backend.record_handle_offset(fh, offset as u64 + data.len() as u64);
                 reply.data(&data);
             }
             Err(e) => {
@@ -362,9 +644,9 @@ impl Filesystem for FuseAdapter {
     /// Write data to file
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _write_flags: u32,
@@ -380,10 +662,13 @@ impl Filesystem for FuseAdapter {
             }
         };
 
-        let result = self.block_on(self.backend.write_file(&path, offset as u64, data));
+        self.forget_cached_attrs();
+        let result =
+            self.block_on_for(req, self.backend.write_handle(fh, &path, offset as u64, data));
 
         match result {
             Ok(written) => {
+                self.backend.record_handle_offset(fh, offset as u64 + written as u64);
                 reply.written(written);
             }
             Err(e) => {
@@ -395,7 +680,7 @@ impl Filesystem for FuseAdapter {
     /// Create a directory
     fn mkdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
@@ -424,7 +709,19 @@ impl Filesystem for FuseAdapter {
             format!("{}/{}", parent_path, name)
         };
 
-        let result = self.block_on(self.backend.create_dir(&path, mode));
+        if let Err(e) = self.authorize(req, &parent_path, DIR_CHANGE) {
+            reply.error(e);
+            return;
+        }
+
+        self.forget_cached_attrs();
+        let result = self
+            .block_on_for(req, self.backend.create_dir(&path, mode))
+            .map_err(Self::error_to_errno);
+        let result = result.and_then(|mut attr| {
+            self.give_to_caller(req, &path, &mut attr)?;
+            Ok(attr)
+        });
 
         match result {
             Ok(attr) => {
@@ -436,17 +733,17 @@ impl Filesystem for FuseAdapter {
                 let mut attr = attr;
                 attr.inode = inode;
 
-                let fuse_attr = Self::to_fuse_attr(&attr, ENTRY_TTL);
-                reply.entry(&ENTRY_TTL, &fuse_attr, 0);
+                let fuse_attr = Self::to_fuse_attr(&attr, self.entry_ttl);
+                reply.entry(&self.entry_ttl, &fuse_attr, 0);
             }
             Err(e) => {
-                reply.error(Self::error_to_errno(e));
+                reply.error(e);
             }
         }
     }
 
     /// Remove a directory
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let name = match name.to_str() {
             Some(n) => n,
             None => {
@@ -469,7 +766,13 @@ impl Filesystem for FuseAdapter {
             format!("{}/{}", parent_path, name)
         };
 
-        let result = self.block_on(self.backend.remove_dir(&path));
+        if let Err(e) = self.authorize(req, &parent_path, DIR_CHANGE) {
+            reply.error(e);
+            return;
+        }
+
+        self.forget_cached_attrs();
+        let result = self.block_on_for(req, self.backend.remove_dir(&path));
 
         match result {
             Ok(_) => {
@@ -504,7 +807,7 @@ impl Filesystem for FuseAdapter {
             }
         };
 
-        let result = self.block_on(self.backend.read_dir(&path));
+        let result = self.list_dir(&path);
 
         match result {
             Ok(entries) => {
@@ -515,17 +818,8 @@ impl Filesystem for FuseAdapter {
                 ];
 
                 // Add actual entries
-                for entry in &entries {
-                    let inode = {
-                        let entry_path = if path == "/" {
-                            format!("/{}", entry.name)
-                        } else {
-                            format!("{}/{}", path, entry.name)
-                        };
-                        let mut map = self.inode_map.write().unwrap();
-                        map.get_or_create(&entry_path)
-                    };
-
+                for (inode, entry) in &entries {
+                    let inode = *inode;
                     let kind = match entry.kind {
                         super::interface::FileType::RegularFile => FuseFileType::RegularFile,
                         super::interface::FileType::Directory => FuseFileType::Directory,
@@ -552,15 +846,196 @@ impl Filesystem for FuseAdapter {
         }
     }
 
+    /// Reserve space in or punch holes into a file
+    fn fallocate(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        if offset < 0 || length <= 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        let path = match self.get_path(ino) {
+            Ok(p) => p,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.authorize(req, &path, libc::W_OK) {
+            reply.error(e);
+            return;
+        }
+
+        self.forget_cached_attrs();
+        match self
+            .block_on_for(req, self.backend.fallocate(&path, offset as u64, length as u64, mode))
+        {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Self::error_to_errno(e)),
+        }
+    }
+
+    /// Find the next data or hole for `SEEK_DATA`/`SEEK_HOLE`, so tools like
+    /// `cp --sparse` and `tar -S` skip holes instead of reading zeros. The
+    /// kernel handles the other `whence` values itself.
+    fn lseek(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        if offset < 0 {
+            reply.error(libc::ENXIO);
+            return;
+        }
+
+        let path = match self.get_path(ino) {
+            Ok(p) => p,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        match self.block_on_for(req, self.backend.seek(&path, offset as u64, whence)) {
+            Ok(found) => reply.offset(found as i64),
+            Err(e) => reply.error(Self::error_to_errno(e)),
+        }
+    }
+
+    /// Copy between two open files without the data passing through the
+    /// caller, e.g. for `cp --reflink=auto`
+    fn copy_file_range(
+        &mut self,
+        req: &Request,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        if offset_in < 0 || offset_out < 0 || flags != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        let (src, dst) = match (self.get_path(ino_in), self.get_path(ino_out)) {
+            (Ok(src), Ok(dst)) => (src, dst),
+            (Err(e), _) | (_, Err(e)) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        if let Err(e) = self
+            .authorize(req, &src, libc::R_OK)
+            .and_then(|()| self.authorize(req, &dst, libc::W_OK))
+        {
+            reply.error(e);
+            return;
+        }
+
+        // The reply carries a u32, so never copy more than it can report
+        let len = len.min(u32::MAX as u64);
+        self.forget_cached_attrs();
+        let result = self.block_on_for(
+            req,
+            self.backend.copy_file_range(&src, offset_in as u64, &dst, offset_out as u64, len),
+        );
+
+        match result {
+            Ok(copied) => {
+                self.backend.record_handle_offset(fh_out, offset_out as u64 + copied);
+                reply.written(copied as u32);
+            }
+            Err(e) => reply.error(Self::error_to_errno(e)),
+        }
+    }
+
+    /// Read directory entries together with their attributes, sparing the
+    /// kernel a `lookup` per entry
+    fn readdirplus(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let path = match self.get_path(ino) {
+            Ok(p) => p,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        let entries = match self.list_dir_with_attrs(&path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                reply.error(Self::error_to_errno(e));
+                return;
+            }
+        };
+
+        // The kernel ignores the attributes of . and .., so they get a
+        // placeholder with a zero TTL instead of another query
+        let now = chrono::Utc::now();
+        let dot_attr = FileAttr {
+            inode: ino,
+            kind: FileType::Directory,
+            size: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            nlinks: 2,
+        };
+        let mut all_entries = vec![
+            (".", dot_attr.clone(), Duration::ZERO),
+            ("..", dot_attr, Duration::ZERO), // TODO: get parent inode
+        ];
+        for (_, entry, attr) in &entries {
+            all_entries.push((entry.name.as_str(), attr.clone(), self.entry_ttl));
+        }
+
+        for (i, (name, attr, ttl)) in all_entries.iter().enumerate().skip(offset as usize) {
+            let fuse_attr = Self::to_fuse_attr(attr, *ttl);
+            if reply.add(attr.inode, (i + 1) as i64, name, ttl, &fuse_attr, 0) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
     /// Create and open a file
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
         _umask: u32,
-        _flags: i32,
+        flags: i32,
         reply: ReplyCreate,
     ) {
         let name = match name.to_str() {
@@ -585,10 +1060,33 @@ impl Filesystem for FuseAdapter {
             format!("{}/{}", parent_path, name)
         };
 
-        let result = self.block_on(self.backend.create_file(&path, mode));
+        if let Err(e) = self.authorize(req, &parent_path, DIR_CHANGE) {
+            reply.error(e);
+            return;
+        }
+
+        self.forget_cached_attrs();
+        // A dangling symlink is created through, as open(O_CREAT) would
+        let result = self
+            .block_on_for(req, async {
+                let target = self.follow_final_symlink(&path, flags).await?;
+                let attr = self.backend.create_file(&target, mode).await?;
+                // The new file is open too, so its writes go through a handle
+                let fh = self.backend.open(&target, flags, req.uid()).await?;
+                Ok((attr, target, fh))
+            })
+            .map_err(Self::error_to_errno)
+            .and_then(|(mut attr, target, fh)| {
+                if let Err(e) = self.give_to_caller(req, &target, &mut attr) {
+                    // The kernel never learns of the handle, so never releases it
+                    let _ = self.block_on(self.backend.release(fh));
+                    return Err(e);
+                }
+                Ok((attr, fh))
+            });
 
         match result {
-            Ok(attr) => {
+            Ok((attr, fh)) => {
                 let inode = {
                     let mut map = self.inode_map.write().unwrap();
                     map.get_or_create(&path)
@@ -597,17 +1095,92 @@ impl Filesystem for FuseAdapter {
                 let mut attr = attr;
                 attr.inode = inode;
 
-                let fuse_attr = Self::to_fuse_attr(&attr, ENTRY_TTL);
-                reply.created(&ENTRY_TTL, &fuse_attr, 0, 0, 0);
+                let fuse_attr = Self::to_fuse_attr(&attr, self.entry_ttl);
+                reply.created(&self.entry_ttl, &fuse_attr, 0, fh, 0);
             }
             Err(e) => {
-                reply.error(Self::error_to_errno(e));
+                reply.error(e);
+            }
+        }
+    }
+
+    /// Create a symbolic link
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let (Some(name), Some(target)) = (link_name.to_str(), target.to_str()) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+
+        let parent_path = match self.get_path(parent) {
+            Ok(p) => p,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        let path = if parent_path == "/" {
+            format!("/{}", name)
+        } else {
+            format!("{}/{}", parent_path, name)
+        };
+
+        if let Err(e) = self.authorize(req, &parent_path, DIR_CHANGE) {
+            reply.error(e);
+            return;
+        }
+
+        self.forget_cached_attrs();
+        match self.block_on_for(req, self.backend.create_symlink(target, &path)) {
+            Ok(mut attr) => {
+                attr.inode = self.inode_map.write().unwrap().get_or_create(&path);
+                reply.entry(&self.entry_ttl, &Self::to_fuse_attr(&attr, self.entry_ttl), 0);
+            }
+            Err(e) => reply.error(Self::error_to_errno(e)),
+        }
+    }
+
+    /// Check file access permissions for the calling user
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let path = match self.get_path(ino) {
+            Ok(p) => p,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        match self.block_on(self.backend.check_access(&path, req.uid(), req.gid(), mask)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Self::error_to_errno(e)),
+        }
+    }
+
+    /// Read the target of a symbolic link
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let path = match self.get_path(ino) {
+            Ok(p) => p,
+            Err(e) => {
+                reply.error(e);
+                return;
             }
+        };
+
+        match self.block_on(self.backend.read_symlink(&path)) {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(e) => reply.error(Self::error_to_errno(e)),
         }
     }
 
     /// Remove a file
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         eprintln!("FUSE unlink: parent={}, name={:?}", parent, name);
         let name = match name.to_str() {
             Some(n) => n,
@@ -631,7 +1204,13 @@ impl Filesystem for FuseAdapter {
             format!("{}/{}", parent_path, name)
         };
 
-        let result = self.block_on(self.backend.delete_file(&path));
+        if let Err(e) = self.authorize(req, &parent_path, DIR_CHANGE) {
+            reply.error(e);
+            return;
+        }
+
+        self.forget_cached_attrs();
+        let result = self.block_on_for(req, self.backend.delete_file(&path));
 
         match result {
             Ok(_) => {
@@ -649,11 +1228,84 @@ impl Filesystem for FuseAdapter {
         }
     }
 
+    /// Rename a file or directory, replacing the target if it exists
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        // RENAME_NOREPLACE / RENAME_EXCHANGE are not supported
+        if flags != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        let (name, newname) = match (name.to_str(), newname.to_str()) {
+            (Some(n), Some(nn)) => (n, nn),
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        let (parent_path, new_parent_path) = match (self.get_path(parent), self.get_path(newparent))
+        {
+            (Ok(p), Ok(np)) => (p, np),
+            (Err(e), _) | (_, Err(e)) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        let from = if parent_path == "/" {
+            format!("/{}", name)
+        } else {
+            format!("{}/{}", parent_path, name)
+        };
+        let to = if new_parent_path == "/" {
+            format!("/{}", newname)
+        } else {
+            format!("{}/{}", new_parent_path, newname)
+        };
+
+        for dir in [&parent_path, &new_parent_path] {
+            if let Err(e) = self.authorize(req, dir, DIR_CHANGE) {
+                reply.error(e);
+                return;
+            }
+        }
+
+        self.forget_cached_attrs();
+        match self.block_on_for(req, self.backend.rename(&from, &to)) {
+            Ok(()) => {
+                self.inode_map.write().unwrap().rename(&from, &to);
+                reply.ok();
+            }
+            Err(e) => {
+                reply.error(Self::error_to_errno(e));
+            }
+        }
+    }
+
     /// Open a file
-    fn open(&mut self, _req: &Request, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        // For now, we don't maintain file handles
-        // Just return a dummy file handle
-        reply.opened(0, 0);
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        let path = match self.get_path(ino) {
+            Ok(p) => p,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        match self.open_path(&path, flags, req.uid(), req.gid()) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e),
+        }
     }
 
     /// Release (close) a file
@@ -661,14 +1313,32 @@ impl Filesystem for FuseAdapter {
         &mut self,
         _req: &Request,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        // Nothing to do for now
-        reply.ok();
+        match self.block_on(self.backend.release(fh)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Self::error_to_errno(e)),
+        }
+    }
+
+    /// Synchronize file contents
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.sync_inode(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Synchronize directory contents
+    fn fsyncdir(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.sync_inode(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
     /// Get filesystem statistics
@@ -697,6 +1367,7 @@ impl Filesystem for FuseAdapter {
 
 #[cfg(test)]
 mod tests {
+    use super::super::interface::{DirEntry, StatFs};
     use super::*;
 
     #[test]
@@ -730,6 +1401,297 @@ mod tests {
         assert_eq!(map.get_path(ino), None);
     }
 
+    #[test]
+    fn test_inode_map_rename() {
+        let mut map = InodeMap::new();
+        let dir = map.get_or_create("/dir");
+        let child = map.get_or_create("/dir/a.txt");
+        let other = map.get_or_create("/dirx");
+        let replaced = map.get_or_create("/new");
+
+        map.rename("/dir", "/new");
+
+        assert_eq!(map.get_path(dir), Some("/new"));
+        assert_eq!(map.get_path(child), Some("/new/a.txt"));
+        assert_eq!(map.get_path(other), Some("/dirx"));
+        assert_eq!(map.get_path(replaced), None);
+        assert_eq!(map.path_to_inode.get("/dir"), None);
+    }
+
+    #[test]
+    fn test_inode_map_clear_keeps_root() {
+        let mut map = InodeMap::new();
+        let ino = map.get_or_create("/a.txt");
+        map.clear();
+        assert_eq!(map.get_path(1), Some("/"));
+        assert_eq!(map.get_path(ino), None);
+        // Fresh lookups get fresh inode numbers
+        assert_ne!(map.get_or_create("/a.txt"), ino);
+    }
+
+    /// Backend that only tracks invalidation, fsync, open and metadata calls.
+    /// Paths in `links` are symlinks to their value; every other path is a
+    /// file. `dirs` maps a directory to the names listed in it. Other
+    /// operations fail with `NotSupported`.
+    #[derive(Default)]
+    struct StubBackend {
+        generation: AtomicU64,
+        synced: std::sync::Mutex<Vec<String>>,
+        links: HashMap<String, String>,
+        opened: std::sync::Mutex<Vec<String>>,
+        dirs: HashMap<String, Vec<String>>,
+        queries: AtomicU64,
+        /// Paths `get_attr` reports as not found
+        missing: Vec<String>,
+    }
+
+    impl StubBackend {
+        fn attr_of(&self, path: &str) -> FileAttr {
+            let now = chrono::Utc::now();
+            let kind = if self.links.contains_key(path) {
+                FileType::Symlink
+            } else {
+                FileType::RegularFile
+            };
+            FileAttr {
+                inode: 0,
+                kind,
+                size: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+                nlinks: 1,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl FilesystemInterface for StubBackend {
+        async fn read_file(&self, _: &str, _: u64, _: u32) -> FsResult<Vec<u8>> {
+            Err(FsError::NotSupported("read_file".to_string()))
+        }
+        async fn write_file(&self, _: &str, _: u64, _: &[u8]) -> FsResult<u32> {
+            Err(FsError::NotSupported("write_file".to_string()))
+        }
+        async fn create_file(&self, _: &str, _: u32) -> FsResult<FileAttr> {
+            Err(FsError::NotSupported("create_file".to_string()))
+        }
+        async fn delete_file(&self, _: &str) -> FsResult<()> {
+            Err(FsError::NotSupported("delete_file".to_string()))
+        }
+        async fn truncate(&self, _: &str, _: u64) -> FsResult<()> {
+            Err(FsError::NotSupported("truncate".to_string()))
+        }
+        async fn create_dir(&self, _: &str, _: u32) -> FsResult<FileAttr> {
+            Err(FsError::NotSupported("create_dir".to_string()))
+        }
+        async fn read_dir(&self, _: &str) -> FsResult<Vec<DirEntry>> {
+            Err(FsError::NotSupported("read_dir".to_string()))
+        }
+        async fn read_dir_with_attrs(&self, path: &str) -> FsResult<Vec<(DirEntry, FileAttr)>> {
+            let names = self.dirs.get(path).ok_or(FsError::PathNotFound(path.to_string()))?;
+            let mut listed = Vec::new();
+            for (i, name) in names.iter().enumerate() {
+                let mut attr = self.attr_of(name);
+                attr.inode = 100 + i as u64;
+                let entry = DirEntry { inode: attr.inode, name: name.clone(), kind: attr.kind };
+                listed.push((entry, attr));
+            }
+            // The whole listing is a single query, not one per entry
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            Ok(listed)
+        }
+        async fn remove_dir(&self, _: &str) -> FsResult<()> {
+            Err(FsError::NotSupported("remove_dir".to_string()))
+        }
+        async fn get_attr(&self, path: &str) -> FsResult<FileAttr> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            if self.missing.iter().any(|missing| missing == path) {
+                return Err(FsError::PathNotFound(path.to_string()));
+            }
+            Ok(self.attr_of(path))
+        }
+        async fn read_symlink(&self, path: &str) -> FsResult<String> {
+            Ok(self.links[path].clone())
+        }
+        async fn open(&self, path: &str, _: i32, _: u32) -> FsResult<u64> {
+            let mut opened = self.opened.lock().unwrap();
+            opened.push(path.to_string());
+            Ok(opened.len() as u64)
+        }
+        async fn set_attr(&self, _: &str, _: SetAttr) -> FsResult<FileAttr> {
+            Err(FsError::NotSupported("set_attr".to_string()))
+        }
+        async fn chmod(&self, _: &str, _: u32) -> FsResult<()> {
+            Err(FsError::NotSupported("chmod".to_string()))
+        }
+        async fn chown(&self, _: &str, _: u32, _: u32) -> FsResult<()> {
+            Err(FsError::NotSupported("chown".to_string()))
+        }
+        async fn statfs(&self) -> FsResult<StatFs> {
+            Err(FsError::NotSupported("statfs".to_string()))
+        }
+        async fn fsync(&self, path: &str) -> FsResult<()> {
+            self.synced.lock().unwrap().push(path.to_string());
+            Ok(())
+        }
+        fn invalidation_generation(&self) -> u64 {
+            self.generation.load(Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backend_invalidation_clears_inode_map() {
+        let backend = Arc::new(StubBackend { generation: AtomicU64::new(3), ..Default::default() });
+        let adapter = FuseAdapter::new(backend.clone());
+        let ino = adapter.inode_map.write().unwrap().get_or_create("/data/model.bin");
+        assert_eq!(adapter.get_path(ino), Ok("/data/model.bin".to_string()));
+
+        // The path was replaced out-of-band and the backend asked for a refresh
+        backend.generation.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(adapter.get_path(ino), Err(libc::ENOENT));
+        assert_eq!(adapter.get_path(1), Ok("/".to_string()));
+
+        let fresh = adapter.inode_map.write().unwrap().get_or_create("/data/model.bin");
+        assert_ne!(fresh, ino);
+        assert_eq!(adapter.get_path(fresh), Ok("/data/model.bin".to_string()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fsync_flushes_through_backend() {
+        let backend = Arc::new(StubBackend::default());
+        let adapter = FuseAdapter::new(backend.clone());
+        let ino = adapter.inode_map.write().unwrap().get_or_create("/db.sqlite");
+
+        assert_eq!(adapter.sync_inode(ino), Ok(()));
+        assert_eq!(adapter.sync_inode(1), Ok(()));
+        assert_eq!(adapter.sync_inode(999), Err(libc::ENOENT));
+        assert_eq!(*backend.synced.lock().unwrap(), vec!["/db.sqlite", "/"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_open_nofollow_refuses_final_symlink() {
+        let links = HashMap::from([
+            ("/current".to_string(), "models/v2.bin".to_string()),
+            ("/models/latest".to_string(), "../current".to_string()),
+            ("/loop".to_string(), "/loop".to_string()),
+        ]);
+        let backend = Arc::new(StubBackend { links, ..Default::default() });
+        let adapter = FuseAdapter::new(backend.clone());
+
+        assert_eq!(
+            adapter.open_path("/current", libc::O_RDONLY | libc::O_NOFOLLOW, 0, 0),
+            Err(libc::ELOOP)
+        );
+        assert!(backend.opened.lock().unwrap().is_empty());
+
+        // Without the flag the chain is followed to the file
+        assert_eq!(adapter.open_path("/current", libc::O_RDONLY, 0, 0), Ok(1));
+        assert_eq!(adapter.open_path("/models/latest", libc::O_RDONLY, 0, 0), Ok(2));
+        // O_NOFOLLOW only concerns symlinks; plain files still open
+        assert_eq!(adapter.open_path("/models/v2.bin", libc::O_NOFOLLOW, 0, 0), Ok(3));
+        assert_eq!(
+            *backend.opened.lock().unwrap(),
+            vec!["/models/v2.bin", "/models/v2.bin", "/models/v2.bin"]
+        );
+
+        assert_eq!(adapter.open_path("/loop", libc::O_RDONLY, 0, 0), Err(libc::ELOOP));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lookups_after_readdir_reuse_listed_attrs() {
+        let names: Vec<String> = (0..5).map(|i| format!("file{}.txt", i)).collect();
+        let dirs = HashMap::from([("/data".to_string(), names)]);
+        let backend = Arc::new(StubBackend { dirs, ..Default::default() });
+        let adapter = FuseAdapter::new(backend.clone());
+
+        // `ls -l`: one listing, then a lookup per entry
+        let listed = adapter.list_dir("/data").unwrap();
+        assert_eq!(listed.len(), 5);
+        for (inode, entry) in &listed {
+            let path = format!("/data/{}", entry.name);
+            assert_eq!(adapter.get_path(*inode), Ok(path.clone()));
+            assert_eq!(adapter.path_attr(&path).unwrap().inode, *inode);
+        }
+        assert_eq!(backend.queries.load(Ordering::SeqCst), 1);
+
+        // A listed attribute answers once; later calls go to the backend
+        adapter.path_attr("/data/file0.txt").unwrap();
+        assert_eq!(backend.queries.load(Ordering::SeqCst), 2);
+
+        // Changes made through the mount drop what the listing remembered
+        adapter.list_dir("/data").unwrap();
+        adapter.forget_cached_attrs();
+        adapter.path_attr("/data/file1.txt").unwrap();
+        assert_eq!(backend.queries.load(Ordering::SeqCst), 4);
+
+        // With no room for them, listed attributes are not kept at all
+        let adapter = FuseAdapter::new(backend.clone()).with_max_cached_entries(0);
+        adapter.list_dir("/data").unwrap();
+        adapter.path_attr("/data/file2.txt").unwrap();
+        assert_eq!(backend.queries.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replies_carry_configured_ttls() {
+        let backend = Arc::new(StubBackend::default());
+        let adapter = FuseAdapter::new(backend.clone())
+            .with_ttls(Duration::from_secs(30), Duration::from_secs(60));
+
+        let (ttl, entry) = adapter.lookup_entry(1, OsStr::new("notes.txt")).unwrap();
+        assert_eq!(ttl, Duration::from_secs(60));
+        assert_eq!(adapter.get_path(entry.ino), Ok("/notes.txt".to_string()));
+        let (ttl, attr) = adapter.inode_attr(entry.ino).unwrap();
+        assert_eq!((ttl, attr.ino), (Duration::from_secs(30), entry.ino));
+
+        // Zero TTLs leave nothing cached, not even listed attributes
+        let dirs = HashMap::from([("/data".to_string(), vec!["a.txt".to_string()])]);
+        let backend = Arc::new(StubBackend { dirs, ..Default::default() });
+        let adapter = FuseAdapter::new(backend.clone()).with_ttls(Duration::ZERO, Duration::ZERO);
+        adapter.list_dir("/data").unwrap();
+        let data = adapter.inode_map.write().unwrap().get_or_create("/data");
+        let (ttl, _) = adapter.lookup_entry(data, OsStr::new("a.txt")).unwrap();
+        assert_eq!(ttl, Duration::ZERO);
+        assert_eq!(backend.queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_missing_path_lookups_reply_negative_entries() {
+        let backend =
+            Arc::new(StubBackend { missing: vec!["/bin/cc".to_string()], ..Default::default() });
+        let adapter = FuseAdapter::new(backend.clone());
+        let bin = adapter.inode_map.write().unwrap().get_or_create("/bin");
+
+        // A PATH search: the first miss asks the backend, the rest don't
+        for _ in 0..5 {
+            let (ttl, entry) = adapter.lookup_entry(bin, OsStr::new("cc")).unwrap();
+            assert_eq!((ttl, entry.ino), (DEFAULT_ENTRY_TTL, 0));
+        }
+        assert_eq!(backend.queries.load(Ordering::SeqCst), 1);
+
+        // Creating anything through the mount forgets the miss
+        adapter.forget_cached_attrs();
+        adapter.lookup_entry(bin, OsStr::new("cc")).unwrap();
+        assert_eq!(backend.queries.load(Ordering::SeqCst), 2);
+
+        // Without entry caching a miss is a plain error
+        let adapter = FuseAdapter::new(backend.clone()).with_ttls(DEFAULT_ATTR_TTL, Duration::ZERO);
+        let bin = adapter.inode_map.write().unwrap().get_or_create("/bin");
+        assert_eq!(adapter.lookup_entry(bin, OsStr::new("cc")), Err(libc::ENOENT));
+        assert_eq!(adapter.lookup_entry(bin, OsStr::new("cc")), Err(libc::ENOENT));
+        assert_eq!(backend.queries.load(Ordering::SeqCst), 4);
+
+        // Nor are misses remembered with no room for them
+        let adapter = FuseAdapter::new(backend.clone()).with_max_cached_entries(0);
+        let bin = adapter.inode_map.write().unwrap().get_or_create("/bin");
+        adapter.lookup_entry(bin, OsStr::new("cc")).unwrap();
+        adapter.lookup_entry(bin, OsStr::new("cc")).unwrap();
+        assert_eq!(backend.queries.load(Ordering::SeqCst), 6);
+    }
+
     #[test]
     fn test_datetime_conversion() {
         let dt = chrono::Utc::now();
@@ -1,15 +1,52 @@
 // TarboxBackend - Core filesystem implementation with layer support
 
 use super::interface::*;
+use super::negative_cache::NegativeCache;
+use super::write_buffer::{PendingWrite, WriteBuffer};
+use crate::config::{CacheConfig, LayerConfig};
+use crate::fs::atime::AtimeMode;
+use crate::fs::block_cache::BlockCache;
+use crate::fs::caller::CallerContext;
 use crate::fs::error::FsError as CoreFsError;
+use crate::fs::handles::{FileHandle, HandleTable, OpenFile};
 use crate::fs::operations::FileSystem;
-use crate::layer::{HookError, HookFileAttr, HookResult, HooksHandler, TARBOX_HOOK_PATH};
-use crate::storage::{InodeType, TenantOperations, TenantRepository};
-use crate::types::{InodeId, TenantId};
+use crate::fs::watch::{ChangeEvent, ChangeFeed};
+use crate::layer::{
+    HookError, HookFileAttr, HookResult, HooksHandler, LineEnding, TARBOX_HOOK_PATH, paths,
+};
+use crate::storage::{
+    InodeType, InvalidationListener, LayerOperations, LayerRepository, PgPublishedMountRepository,
+    PublishedMountError, PublishedMountRepository, TenantOperations, TenantRepository,
+    UsageOperations, WriteSession,
+};
+use crate::types::{InodeId, LayerId, TenantId};
 use chrono::Utc;
 use sqlx::PgPool;
+use std::future::Future;
 use std::sync::Arc;
-use tracing::debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// Block size reported by `statfs`
+const STATFS_BLOCK_SIZE: u32 = 4096;
+
+/// Capacity reported by `statfs`, in blocks and inodes. PostgreSQL sets no
+/// fixed limit, so these are nominal ceilings that usage is subtracted from.
+const STATFS_BLOCKS: u64 = 1_000_000_000;
+const STATFS_FILES: u64 = 10_000_000;
+
+tokio::task_local! {
+    /// Caller of the request being served; see `as_caller`.
+    static REQUEST_CALLER: CallerContext;
+}
+
+/// Run `future` on behalf of `caller`, so the changes it makes through a
+/// backend are audited as theirs. The FUSE adapter wraps each mutating
+/// callback in this with the uid and gid of the calling process.
+pub async fn as_caller<F: Future>(caller: CallerContext, future: F) -> F::Output {
+    REQUEST_CALLER.scope(caller, future).await
+}
 
 /// Convert fs::FsError to fuse::FsError with proper error mapping
 fn map_fs_error(e: CoreFsError) -> FsError {
@@ -24,15 +61,72 @@ fn map_fs_error(e: CoreFsError) -> FsError {
         CoreFsError::FilenameTooLong(n) => {
             FsError::InvalidPath(format!("filename too long: {} bytes", n))
         }
+        CoreFsError::NoSpace(p) => FsError::NoSpace(p),
+        CoreFsError::QuotaExceeded(p) => FsError::QuotaExceeded(p),
+        CoreFsError::SymlinkLoop(p) => FsError::SymlinkLoop(p),
+        CoreFsError::InvalidPattern(p) => FsError::InvalidPath(format!("invalid pattern: {}", p)),
+        CoreFsError::Storage(e) if is_disk_full(&e) => FsError::NoSpace(e.to_string()),
         CoreFsError::Storage(e) => FsError::IoError(e.to_string()),
     }
 }
 
+/// The part of `data` a read of `size` bytes at `offset` returns: empty when
+/// `offset` is at or past EOF, and only the available tail when the read
+/// straddles EOF. Offsets beyond `usize` are treated as past EOF.
+fn read_window(data: &[u8], offset: u64, size: u32) -> &[u8] {
+    let Some(start) = usize::try_from(offset).ok().filter(|&s| s < data.len()) else {
+        return &[];
+    };
+    let end = start.saturating_add(size as usize).min(data.len());
+    &data[start..end]
+}
+
+/// Whether a storage error is PostgreSQL running out of disk (SQLSTATE 53100)
+fn is_disk_full(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| match cause.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db)) => db.code().as_deref() == Some("53100"),
+        _ => false,
+    })
+}
+
 pub struct TarboxBackend {
     pool: Arc<PgPool>,
+    /// Pool for read-only operations; the primary unless a replica is set.
+    read_pool: Arc<PgPool>,
+    /// The mount is one session: after any write, reads stay on the primary.
+    session: WriteSession,
     tenant_id: TenantId,
     #[allow(dead_code)]
     root_inode_id: InodeId,
+    read_eol: Option<LineEnding>,
+    /// Whether reads update `atime`; never on a read-only mount.
+    atime: AtimeMode,
+    handles: Arc<HandleTable>,
+    /// Paths recently found missing, answered without a database query.
+    negative: NegativeCache,
+    /// Blocks of files recently read through the mount.
+    block_cache: BlockCache,
+    /// Small writes through open handles, not stored yet.
+    writes: WriteBuffer,
+    /// Chain depth limit for checkpoints taken through the hooks.
+    layer_config: LayerConfig,
+    /// Bumped by `/.tarbox/refresh`; see `invalidation_generation`.
+    generation: AtomicU64,
+    /// Reject every change, including layer hooks; see `with_read_only`.
+    read_only: bool,
+    /// Deny callers what the mode bits deny; see `with_permission_checks`.
+    permission_checks: bool,
+    /// Layer served instead of the current one; see `new_at_layer`.
+    layer: Option<LayerId>,
+    /// Expose `/.tarbox`; off for published layers, whose hooks would
+    /// report on the owning tenant.
+    hooks: bool,
+    /// Record changes in the audit log; see `with_audit`.
+    audit: bool,
+    /// Agent changes are attributed to, for every caller of the mount
+    agent_id: Option<String>,
+    /// Watchers of changes made through the mount
+    changes: ChangeFeed,
 }
 
 impl TarboxBackend {
@@ -44,12 +138,192 @@ impl TarboxBackend {
             .map_err(|e| FsError::IoError(e.to_string()))?
             .ok_or_else(|| FsError::PathNotFound("tenant not found".to_string()))?;
 
-        Ok(Self { pool, tenant_id, root_inode_id: tenant.root_inode_id })
+        Ok(Self {
+            read_pool: pool.clone(),
+            pool,
+            session: WriteSession::new(),
+            tenant_id,
+            root_inode_id: tenant.root_inode_id,
+            read_eol: None,
+            atime: AtimeMode::default(),
+            handles: Arc::new(HandleTable::new()),
+            negative: NegativeCache::new(&CacheConfig::default()),
+            block_cache: BlockCache::default(),
+            writes: WriteBuffer::new(),
+            layer_config: LayerConfig::default(),
+            generation: AtomicU64::new(0),
+            read_only: false,
+            permission_checks: true,
+            layer: None,
+            hooks: true,
+            audit: false,
+            agent_id: None,
+            changes: ChangeFeed::new(),
+        })
+    }
+
+    /// Serve the tenant as `layer_id` sees it, read-only.
+    ///
+    /// Paths resolve through the layer's union view, so an old checkpoint can
+    /// be inspected while work continues on the current layer. Hooks still
+    /// report on the tenant's current layer.
+    pub async fn new_at_layer(
+        pool: Arc<PgPool>,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+    ) -> Result<Self, FsError> {
+        LayerOperations::new(&pool)
+            .get(tenant_id, layer_id)
+            .await
+            .map_err(|e| FsError::IoError(e.to_string()))?
+            .ok_or_else(|| FsError::PathNotFound(format!("layer not found: {}", layer_id)))?;
+
+        let mut backend = Self::new(pool, tenant_id).await?.with_read_only(true);
+        backend.layer = Some(layer_id);
+        Ok(backend)
+    }
+
+    /// Serve a layer another tenant published, read-only.
+    ///
+    /// `accessor_tenant_id` must be allowed by the publish's scope. The
+    /// owner's layer is read in place through its union view, nothing is
+    /// copied, and `/.tarbox` is hidden so the owner's other layers and
+    /// usage stay private.
+    pub async fn new_from_published(
+        pool: Arc<PgPool>,
+        accessor_tenant_id: TenantId,
+        published_name: &str,
+    ) -> Result<Self, FsError> {
+        let resolved = PgPublishedMountRepository::new((*pool).clone())
+            .resolve_published(published_name, accessor_tenant_id)
+            .await
+            .map_err(|e| match e.downcast_ref::<PublishedMountError>() {
+                Some(PublishedMountError::AccessDenied(_)) => {
+                    FsError::PermissionDenied(published_name.to_string())
+                }
+                Some(PublishedMountError::NotFound(_)) => {
+                    FsError::PathNotFound(format!("published mount {}", published_name))
+                }
+                None => FsError::IoError(format!("published mount {}: {}", published_name, e)),
+            })?;
+
+        let mut backend =
+            Self::new_at_layer(pool, resolved.owner_tenant_id, resolved.layer_id).await?;
+        backend.hooks = false;
+        Ok(backend)
+    }
+
+    /// Size the lookup caches and set read-ahead from configuration.
+    pub fn with_cache_config(mut self, config: &CacheConfig) -> Self {
+        self.negative = NegativeCache::new(config);
+        self.block_cache = BlockCache::default().with_readahead(config.readahead_blocks);
+        self
+    }
+
+    /// Check checkpoints taken through the hooks against the chain depth limit.
+    pub fn with_layer_config(mut self, config: &LayerConfig) -> Self {
+        self.layer_config = config.clone();
+        self
+    }
+
+    /// Route stat, read and list to a read replica while the mount hasn't written.
+    pub fn with_read_pool(mut self, read_pool: Arc<PgPool>) -> Self {
+        self.read_pool = read_pool;
+        self
+    }
+
+    /// Present text files with the given line ending on read (e.g. CRLF for Windows agents)
+    pub fn with_read_eol(mut self, eol: Option<LineEnding>) -> Self {
+        self.read_eol = eol;
+        self
+    }
+
+    /// Update `atime` on reads as `atime` dictates (like the `relatime` mount
+    /// option). Ignored when the mount is read-only.
+    pub fn with_atime(mut self, atime: AtimeMode) -> Self {
+        self.atime = atime;
+        self
+    }
+
+    /// Serve the tenant read-only: every mutating call and every hook write
+    /// other than `/.tarbox/refresh` fails with `EROFS`.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Check callers against mode, uid and gid (on by default). Single-user
+    /// mounts can turn this off so every caller is treated as the owner.
+    pub fn with_permission_checks(mut self, enabled: bool) -> Self {
+        self.permission_checks = enabled;
+        self
+    }
+
+    /// Record every change in the audit log, attributed to the caller set by
+    /// `as_caller` or, outside of one, to the user running the mount.
+    pub fn with_audit(mut self, enabled: bool) -> Self {
+        self.audit = enabled;
+        self
+    }
+
+    /// Tag audited changes with `agent_id` (the `--agent-id` mount option).
+    pub fn with_agent_id(mut self, agent_id: Option<String>) -> Self {
+        self.agent_id = agent_id;
+        self
+    }
+
+    /// Whoever the current request is served for.
+    fn caller(&self) -> CallerContext {
+        let caller = REQUEST_CALLER
+            .try_with(CallerContext::clone)
+            .unwrap_or_else(|_| CallerContext::current_process());
+        match caller.agent_id {
+            Some(_) => caller,
+            None => caller.with_agent(self.agent_id.clone()),
+        }
+    }
+
+    /// Changes made through the mount, for `FileSystem::watch`-style
+    /// subscribers outside of it.
+    pub fn change_feed(&self) -> &ChangeFeed {
+        &self.changes
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Store every write still held back for an open handle, e.g. before
+    /// unmounting.
+    pub async fn flush_writes(&self) -> FsResult<()> {
+        self.store_pending(self.writes.take_all()).await
+    }
+
+    fn ensure_writable(&self, path: &str) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly(path.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Files currently open through this mount, ordered by handle.
+    pub fn list_open_handles(&self) -> Vec<(FileHandle, OpenFile)> {
+        self.handles.list()
     }
 
     async fn fs(&self) -> Result<FileSystem<'_>, FsError> {
         // Create FileSystem with layer initialization
-        FileSystem::new(&self.pool, self.tenant_id).await.map_err(map_fs_error)
+        let fs = FileSystem::new(&self.pool, self.tenant_id).await.map_err(map_fs_error)?;
+        Ok(fs
+            .with_read_eol(self.read_eol)
+            .with_atime(if self.read_only { AtimeMode::Noatime } else { self.atime })
+            .with_handles(self.handles.clone())
+            .with_read_pool(&self.read_pool)
+            .with_write_session(self.session.clone())
+            .with_audit(self.audit)
+            .with_caller(self.caller())
+            .with_change_feed(self.changes.clone())
+            .with_block_cache(self.block_cache.clone()))
     }
 
     fn inode_type_to_file_type(inode_type: &InodeType) -> FileType {
@@ -80,6 +354,11 @@ impl TarboxBackend {
         HooksHandler::is_hook_path(path)
     }
 
+    /// Whether `path` is answered by the layer hooks on this mount
+    fn serves_hook(&self, path: &str) -> bool {
+        self.hooks && Self::is_hook_path(path)
+    }
+
     /// Convert hook file attributes to FileAttr
     fn hook_attr_to_file_attr(path: &str, hook_attr: &HookFileAttr) -> FileAttr {
         // Use a consistent inode for hook paths based on hash
@@ -118,9 +397,74 @@ impl TarboxBackend {
         }
     }
 
+    /// Forget every cached path, here and in the adapter on its next call.
+    ///
+    /// For recovery after bulk changes made outside this mount, such as an
+    /// import or sync apply.
+    pub fn invalidate_all(&self) {
+        self.negative.invalidate_all();
+        self.block_cache.invalidate_all();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Forget what this mount cached about `path`, which another process
+    /// changed, and tell its watchers.
+    pub async fn invalidate_path(&self, path: &str) {
+        self.negative.invalidate(path).await;
+        // Cached blocks are kept by inode, which `path` no longer tells
+        self.block_cache.invalidate_all();
+        self.changes.publish(|| ChangeEvent::Invalidated { path: path.to_string() });
+    }
+
+    /// Follow changes other processes announce for this tenant (see
+    /// `storage::notify`) from a background task, until it is aborted or
+    /// the listener fails. Announcements of this mount's own changes are
+    /// ignored. Returns once listening, so nothing announced later is missed.
+    pub async fn listen_for_invalidations(self: &Arc<Self>) -> Result<JoinHandle<()>, FsError> {
+        let mut listener = InvalidationListener::connect(&self.pool)
+            .await
+            .map_err(|e| FsError::IoError(e.to_string()))?;
+        let backend = Arc::clone(self);
+        Ok(tokio::spawn(async move {
+            loop {
+                match listener.recv().await {
+                    Ok(invalidation)
+                        if invalidation.tenant_id == backend.tenant_id
+                            && !backend.changes.is_own_notifier(invalidation.sender_pid) =>
+                    {
+                        debug!(path = %invalidation.path, "Path changed elsewhere");
+                        backend.invalidate_path(&invalidation.path).await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("Stopped following changes from other processes: {}", e);
+                        return;
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Store writes `write_handle` held back.
+    async fn store_pending(&self, pending: Vec<PendingWrite>) -> FsResult<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let fs = self.fs().await?;
+        for write in pending {
+            debug!(path = %write.path, offset = write.offset, size = write.data.len(), "Storing buffered writes");
+            fs.write_at(&write.path, write.offset, &write.data).await.map_err(map_fs_error)?;
+        }
+        Ok(())
+    }
+
     /// Get hooks handler
     fn hooks_handler(&self) -> HooksHandler<'_> {
         HooksHandler::new(&self.pool, self.tenant_id)
+            .with_read_pool(&self.read_pool)
+            .with_write_session(self.session.clone())
+            .with_handles(self.handles.clone())
+            .with_layer_config(&self.layer_config)
     }
 }
 
@@ -128,30 +472,24 @@ impl TarboxBackend {
 impl FilesystemInterface for TarboxBackend {
     async fn read_file(&self, path: &str, offset: u64, size: u32) -> FsResult<Vec<u8>> {
         // Handle hook paths
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             let handler = self.hooks_handler();
-            let result = handler.handle_read(path).await;
-            let data = match result {
-                HookResult::Content(s) => s.into_bytes(),
-                HookResult::WriteSuccess { message } => message.into_bytes(),
+            let data = match handler.handle_read(path).await {
                 HookResult::Error(e) => return Err(Self::hook_error_to_fs_error(e)),
-                HookResult::NotAHook => Vec::new(),
+                result => result.render().unwrap_or_default().into_bytes(),
             };
-            let start = offset as usize;
-            let end = std::cmp::min(start + size as usize, data.len());
-            if start >= data.len() {
-                return Ok(Vec::new());
-            }
-            return Ok(data[start..end].to_vec());
+            return Ok(read_window(&data, offset, size).to_vec());
         }
 
-        let data = self.fs().await?.read_file(path).await.map_err(map_fs_error)?;
-        let start = offset as usize;
-        let end = std::cmp::min(start + size as usize, data.len());
-        if start >= data.len() {
-            return Ok(Vec::new());
+        self.store_pending(self.writes.take_path(path)).await?;
+        let fs = self.fs().await?;
+        match self.layer {
+            Some(layer_id) => {
+                let data = fs.read_file_at_layer(path, layer_id).await.map_err(map_fs_error)?;
+                Ok(read_window(&data, offset, size).to_vec())
+            }
+            None => fs.read_range(path, offset, size as u64).await.map_err(map_fs_error),
         }
-        Ok(data[start..end].to_vec())
     }
 
     async fn write_file(&self, path: &str, offset: u64, data: &[u8]) -> FsResult<u32> {
@@ -164,130 +502,271 @@ impl FilesystemInterface for TarboxBackend {
         );
 
         // Handle hook paths
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
+            // Refreshing only drops caches, so it is allowed on read-only mounts
+            if HooksHandler::canonical_hook_path(path).as_deref() != Some(paths::REFRESH) {
+                self.ensure_writable(path)?;
+            }
             if offset != 0 {
                 return Err(FsError::NotSupported(
                     "Offset writes not supported for hook paths".to_string(),
                 ));
             }
+            // Checkpoints and rollbacks have to see what was written so far
+            self.store_pending(self.writes.take_all()).await?;
             let handler = self.hooks_handler();
             let result = handler.handle_write(path, data).await;
+            // Hooks can switch layers or roll files back, changing which
+            // paths exist and what they hold
+            self.negative.invalidate_all();
+            self.block_cache.invalidate_all();
+            if HooksHandler::canonical_hook_path(path).as_deref() == Some(paths::REFRESH)
+                && matches!(result, HookResult::WriteSuccess { .. })
+            {
+                self.invalidate_all();
+            }
             return match result {
-                HookResult::WriteSuccess { .. } | HookResult::Content(_) => Ok(data.len() as u32),
                 HookResult::Error(e) => Err(Self::hook_error_to_fs_error(e)),
-                HookResult::NotAHook => Ok(data.len() as u32),
+                _ => Ok(data.len() as u32),
             };
         }
 
-        if offset != 0 {
-            return Err(FsError::NotSupported("Offset writes not supported yet".to_string()));
+        self.ensure_writable(path)?;
+        self.store_pending(self.writes.take_path(path)).await?;
+        let fs = self.fs().await?;
+        fs.write_at(path, offset, data).await.map_err(map_fs_error)?;
+        Ok(data.len() as u32)
+    }
+
+    async fn write_handle(&self, fh: u64, path: &str, offset: u64, data: &[u8]) -> FsResult<u32> {
+        if fh == 0 || self.serves_hook(path) || !WriteBuffer::accepts(data.len()) {
+            self.store_pending(self.writes.take(fh).into_iter().collect()).await?;
+            return self.write_file(path, offset, data).await;
         }
-        self.fs().await?.write_file(path, data).await.map_err(map_fs_error)?;
+
+        self.ensure_writable(path)?;
+        self.store_pending(self.writes.append(fh, path, offset, data)).await?;
         Ok(data.len() as u32)
     }
 
-    async fn create_file(&self, path: &str, _mode: u32) -> FsResult<FileAttr> {
+    async fn copy_file_range(
+        &self,
+        src: &str,
+        src_offset: u64,
+        dst: &str,
+        dst_offset: u64,
+        len: u64,
+    ) -> FsResult<u64> {
+        self.ensure_writable(dst)?;
+        self.store_pending(self.writes.take_path(src)).await?;
+        self.store_pending(self.writes.take_path(dst)).await?;
+
+        // A whole-file copy over no more than it replaces can share the
+        // source's storage; anything else goes through read and write
+        if src_offset == 0
+            && dst_offset == 0
+            && src != dst
+            && !self.serves_hook(src)
+            && !self.serves_hook(dst)
+        {
+            let fs = self.fs().await?;
+            let source = fs.stat(src).await.map_err(map_fs_error)?;
+            let target = fs.stat(dst).await.map_err(map_fs_error)?;
+            if len >= source.size as u64 && target.size <= source.size {
+                debug!(src = %src, dst = %dst, size = source.size, "FUSE copy_file_range in storage");
+                return fs.copy_into(src, dst).await.map_err(map_fs_error);
+            }
+        }
+        copy_range_by_reading(self, src, src_offset, dst, dst_offset, len).await
+    }
+
+    async fn seek(&self, path: &str, offset: u64, whence: i32) -> FsResult<u64> {
+        // Hook files and layer views are reported without holes
+        if self.serves_hook(path) || self.layer.is_some() {
+            return seek_without_holes(self, path, offset, whence).await;
+        }
+
+        self.store_pending(self.writes.take_path(path)).await?;
+        let fs = self.fs().await?;
+        let found = match whence {
+            libc::SEEK_DATA => fs.seek_data(path, offset).await,
+            libc::SEEK_HOLE => fs.seek_hole(path, offset).await,
+            _ => {
+                return Err(FsError::InvalidPath(format!(
+                    "unsupported whence {} for {}",
+                    whence, path
+                )));
+            }
+        };
+        found
+            .map_err(map_fs_error)?
+            .ok_or_else(|| FsError::NoSuchOffset(format!("{} at {}", path, offset)))
+    }
+
+    async fn create_file(&self, path: &str, mode: u32) -> FsResult<FileAttr> {
+        self.ensure_writable(path)?;
+
         // Hook paths cannot be created
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             return Err(FsError::PermissionDenied("Cannot create files in /.tarbox/".to_string()));
         }
 
-        let inode = self.fs().await?.create_file(path).await.map_err(map_fs_error)?;
+        let fs = self.fs().await?;
+        let inode =
+            fs.create_file_with_mode(path, Some(mode as i32)).await.map_err(map_fs_error)?;
+        self.negative.invalidate(path).await;
         Ok(Self::inode_to_attr(&inode))
     }
 
     async fn delete_file(&self, path: &str) -> FsResult<()> {
+        self.ensure_writable(path)?;
+
         // Hook paths cannot be deleted
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             return Err(FsError::PermissionDenied("Cannot delete files in /.tarbox/".to_string()));
         }
 
+        // Whatever is still buffered would only land in the deleted file
+        self.writes.take_path(path);
         self.fs().await?.delete_file(path).await.map_err(map_fs_error)
     }
 
     async fn truncate(&self, path: &str, size: u64) -> FsResult<()> {
+        self.ensure_writable(path)?;
+
         // Hook paths cannot be truncated
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             return Err(FsError::PermissionDenied(
                 "Cannot truncate files in /.tarbox/".to_string(),
             ));
         }
 
-        if size != 0 {
-            return Err(FsError::NotSupported("Non-zero truncate not supported yet".to_string()));
+        self.store_pending(self.writes.take_path(path)).await?;
+        self.fs().await?.truncate(path, size).await.map_err(map_fs_error)
+    }
+
+    async fn fallocate(&self, path: &str, offset: u64, length: u64, mode: i32) -> FsResult<()> {
+        self.ensure_writable(path)?;
+
+        if self.serves_hook(path) {
+            return Err(FsError::PermissionDenied(
+                "Cannot allocate space in /.tarbox/".to_string(),
+            ));
         }
-        self.fs().await?.write_file(path, &[]).await.map_err(map_fs_error)
+
+        self.store_pending(self.writes.take_path(path)).await?;
+        let fs = self.fs().await?;
+        let result = match mode {
+            // Grow to cover the range; the new bytes are a hole until written
+            0 => fs.allocate(path, offset, length).await,
+            // Space is never reserved ahead, so only check the file is there
+            libc::FALLOC_FL_KEEP_SIZE => fs.stat(path).await.and_then(|inode| {
+                if inode.inode_type == InodeType::File {
+                    Ok(())
+                } else {
+                    Err(CoreFsError::IsDirectory(path.to_string()))
+                }
+            }),
+            m if m == libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE => {
+                fs.punch_hole(path, offset, length).await
+            }
+            _ => {
+                return Err(FsError::NotSupported(format!(
+                    "fallocate mode {:#x} not supported: {}",
+                    mode, path
+                )));
+            }
+        };
+        result.map_err(map_fs_error)
     }
 
-    async fn create_dir(&self, path: &str, _mode: u32) -> FsResult<FileAttr> {
+    async fn create_dir(&self, path: &str, mode: u32) -> FsResult<FileAttr> {
+        self.ensure_writable(path)?;
+
         // Hook paths cannot be created
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             return Err(FsError::PermissionDenied(
                 "Cannot create directories in /.tarbox/".to_string(),
             ));
         }
 
-        let inode = self.fs().await?.create_directory(path).await.map_err(map_fs_error)?;
+        let fs = self.fs().await?;
+        let inode =
+            fs.create_directory_with_mode(path, Some(mode as i32)).await.map_err(map_fs_error)?;
+        self.negative.invalidate(path).await;
         Ok(Self::inode_to_attr(&inode))
     }
 
+    async fn create_symlink(&self, target: &str, link: &str) -> FsResult<FileAttr> {
+        self.ensure_writable(link)?;
+
+        if self.serves_hook(link) {
+            return Err(FsError::PermissionDenied("Cannot create links in /.tarbox/".to_string()));
+        }
+
+        let inode = self.fs().await?.create_symlink(target, link).await.map_err(map_fs_error)?;
+        self.negative.invalidate(link).await;
+        Ok(Self::inode_to_attr(&inode))
+    }
+
+    async fn read_symlink(&self, path: &str) -> FsResult<String> {
+        if self.serves_hook(path) {
+            return Err(FsError::InvalidPath(format!("not a symlink: {}", path)));
+        }
+
+        let fs = self.fs().await?;
+        match self.layer {
+            Some(layer_id) => {
+                let inode = fs.stat_at_layer(path, layer_id).await.map_err(map_fs_error)?;
+                if inode.inode_type != InodeType::Symlink {
+                    return Err(FsError::InvalidPath(format!("not a symlink: {}", path)));
+                }
+                let target = fs.read_file_at_layer(path, layer_id).await.map_err(map_fs_error)?;
+                Ok(String::from_utf8_lossy(&target).into_owned())
+            }
+            None => fs.read_link(path).await.map_err(map_fs_error),
+        }
+    }
+
     async fn read_dir(&self, path: &str) -> FsResult<Vec<DirEntry>> {
         // Handle hook paths
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             let handler = self.hooks_handler();
             let result = handler.read_dir(path).await;
             return match result {
-                HookResult::Content(content) => {
-                    // Content is newline-separated list of entries
-                    let entries: Vec<DirEntry> = content
-                        .lines()
-                        .filter(|l| !l.is_empty())
-                        .map(|name| {
-                            // Generate consistent inodes for hook entries
-                            use std::collections::hash_map::DefaultHasher;
-                            use std::hash::{Hash, Hasher};
-                            let mut hasher = DefaultHasher::new();
-                            format!("{}/{}", path, name).hash(&mut hasher);
-                            let inode =
-                                0x8000_0000_0000_0000 | (hasher.finish() & 0x7FFF_FFFF_FFFF_FFFF);
-
-                            // Determine if directory based on known paths
-                            let is_dir = matches!(
-                                name,
-                                "layers"
-                                    | "snapshots"
-                                    | "stats"
-                                    | "current"
-                                    | "list"
-                                    | "new"
-                                    | "switch"
-                                    | "drop"
-                                    | "tree"
-                                    | "diff"
-                                    | "usage"
-                            ) && (path == TARBOX_HOOK_PATH
-                                || path == "/.tarbox/layers"
-                                || path == "/.tarbox/stats");
-
-                            DirEntry {
-                                inode,
-                                name: name.to_string(),
-                                kind: if is_dir {
-                                    FileType::Directory
-                                } else {
-                                    FileType::RegularFile
-                                },
-                            }
-                        })
-                        .collect();
-                    Ok(entries)
-                }
+                HookResult::DirListing(entries) => Ok(entries
+                    .into_iter()
+                    .map(|entry| {
+                        // Generate consistent inodes for hook entries
+                        use std::collections::hash_map::DefaultHasher;
+                        use std::hash::{Hash, Hasher};
+                        let mut hasher = DefaultHasher::new();
+                        format!("{}/{}", path, entry.name).hash(&mut hasher);
+                        let inode =
+                            0x8000_0000_0000_0000 | (hasher.finish() & 0x7FFF_FFFF_FFFF_FFFF);
+
+                        DirEntry {
+                            inode,
+                            name: entry.name,
+                            kind: if entry.is_dir {
+                                FileType::Directory
+                            } else {
+                                FileType::RegularFile
+                            },
+                        }
+                    })
+                    .collect()),
                 HookResult::Error(e) => Err(Self::hook_error_to_fs_error(e)),
                 _ => Ok(vec![]),
             };
         }
 
-        let entries = self.fs().await?.list_directory(path).await.map_err(map_fs_error)?;
+        let fs = self.fs().await?;
+        let entries = match self.layer {
+            Some(layer_id) => fs.list_directory_at_layer(path, layer_id).await,
+            None => fs.list_directory(path).await,
+        }
+        .map_err(map_fs_error)?;
         let mut result: Vec<DirEntry> = entries
             .into_iter()
             .map(|inode| DirEntry {
@@ -298,7 +777,7 @@ impl FilesystemInterface for TarboxBackend {
             .collect();
 
         // If this is the root directory, add .tarbox virtual entry
-        if path == "/" {
+        if self.hooks && path == "/" {
             use std::collections::hash_map::DefaultHasher;
             use std::hash::{Hash, Hasher};
             let mut hasher = DefaultHasher::new();
@@ -311,9 +790,47 @@ impl FilesystemInterface for TarboxBackend {
         Ok(result)
     }
 
+    async fn read_dir_with_attrs(&self, path: &str) -> FsResult<Vec<(DirEntry, FileAttr)>> {
+        // Hook entries and the root's .tarbox entry are answered in memory
+        let virtual_entries = if self.serves_hook(path) {
+            self.read_dir(path).await?
+        } else if path == "/" {
+            self.read_dir(path).await?.into_iter().filter(|entry| entry.name == ".tarbox").collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut listed = Vec::new();
+        if !self.serves_hook(path) {
+            let fs = self.fs().await?;
+            let inodes = match self.layer {
+                Some(layer_id) => fs.list_directory_at_layer(path, layer_id).await,
+                None => fs.list_directory_with_attrs(path).await,
+            }
+            .map_err(map_fs_error)?;
+            for inode in inodes {
+                let attr = Self::inode_to_attr(&inode);
+                let entry = DirEntry { inode: attr.inode, name: inode.name, kind: attr.kind };
+                listed.push((entry, attr));
+            }
+        }
+        for entry in virtual_entries {
+            let child = if path == "/" {
+                format!("/{}", entry.name)
+            } else {
+                format!("{}/{}", path, entry.name)
+            };
+            let attr = self.get_attr(&child).await?;
+            listed.push((entry, attr));
+        }
+        Ok(listed)
+    }
+
     async fn remove_dir(&self, path: &str) -> FsResult<()> {
+        self.ensure_writable(path)?;
+
         // Hook paths cannot be removed
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             return Err(FsError::PermissionDenied(
                 "Cannot remove directories in /.tarbox/".to_string(),
             ));
@@ -322,9 +839,63 @@ impl FilesystemInterface for TarboxBackend {
         self.fs().await?.remove_directory(path).await.map_err(map_fs_error)
     }
 
+    async fn open(&self, path: &str, flags: i32, uid: u32) -> FsResult<u64> {
+        // Hook files are regenerated on every read; nothing to track
+        if self.serves_hook(path) {
+            return Ok(0);
+        }
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            self.ensure_writable(path)?;
+        }
+
+        let fs = self.fs().await?;
+        if let Some(layer_id) = self.layer {
+            // Handles track live files; a layer view only needs the path to exist there
+            fs.stat_at_layer(path, layer_id).await.map_err(map_fs_error)?;
+            return Ok(0);
+        }
+        fs.open(path, flags, uid).await.map_err(map_fs_error)
+    }
+
+    fn record_handle_offset(&self, fh: u64, offset: u64) {
+        self.handles.set_offset(fh, offset);
+    }
+
+    async fn release(&self, fh: u64) -> FsResult<()> {
+        let stored = self.store_pending(self.writes.take(fh).into_iter().collect()).await;
+        self.handles.remove(fh);
+        stored
+    }
+
+    async fn fsync(&self, path: &str) -> FsResult<()> {
+        self.store_pending(self.writes.take_path(path)).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> FsResult<()> {
+        self.ensure_writable(from)?;
+
+        // Hook entries are virtual and cannot be moved in or out of /.tarbox/
+        if self.serves_hook(from) || self.serves_hook(to) {
+            return Err(FsError::PermissionDenied(
+                "Cannot rename entries in /.tarbox/".to_string(),
+            ));
+        }
+
+        // Buffered writes name their file by path, which may be under `from`
+        self.store_pending(self.writes.take_all()).await?;
+        self.fs().await?.rename(from, to).await.map_err(map_fs_error)?;
+        // A renamed directory brings its whole subtree into existence at `to`
+        self.negative.invalidate_all();
+        Ok(())
+    }
+
+    fn invalidation_generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
     async fn get_attr(&self, path: &str) -> FsResult<FileAttr> {
         // Handle hook paths
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             let handler = self.hooks_handler();
             match handler.get_attr(path) {
                 Some(hook_attr) => return Ok(Self::hook_attr_to_file_attr(path, &hook_attr)),
@@ -332,13 +903,86 @@ impl FilesystemInterface for TarboxBackend {
             }
         }
 
-        let inode = self.fs().await?.stat(path).await.map_err(map_fs_error)?;
-        Ok(Self::inode_to_attr(&inode))
+        if self.negative.is_missing(path) {
+            return Err(FsError::PathNotFound(path.to_string()));
+        }
+        let fs = self.fs().await?;
+        let inode = match self.layer {
+            Some(layer_id) => fs.stat_at_layer(path, layer_id).await,
+            None => fs.stat(path).await,
+        };
+        match inode.map_err(map_fs_error) {
+            Ok(inode) => {
+                let mut attr = Self::inode_to_attr(&inode);
+                // Writes still buffered count towards the size already
+                if let Some(end) = self.writes.end_of(path) {
+                    attr.size = attr.size.max(end);
+                }
+                Ok(attr)
+            }
+            Err(FsError::PathNotFound(p)) => {
+                self.negative.insert_missing(path).await;
+                Err(FsError::PathNotFound(p))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn check_access(&self, path: &str, uid: u32, gid: u32, mask: i32) -> FsResult<()> {
+        let attr = self.get_attr(path).await?;
+        // access(2) reports EROFS for writes no matter what the mode allows;
+        // hooks decide for themselves which writes a read-only mount takes
+        if mask & libc::W_OK != 0 && !self.serves_hook(path) {
+            self.ensure_writable(path)?;
+        }
+        check_mode(&attr, path, uid, gid, mask)
+    }
+
+    async fn authorize(&self, path: &str, uid: u32, gid: u32, mask: i32) -> FsResult<()> {
+        // Hook files are owned by root; the hooks decide what callers may do
+        if !self.permission_checks || uid == 0 || self.serves_hook(path) {
+            return Ok(());
+        }
+        let attr = self.get_attr(path).await?;
+        check_mode(&attr, path, uid, gid, mask)
+    }
+
+    async fn authorize_set_attr(
+        &self,
+        path: &str,
+        uid: u32,
+        gid: u32,
+        attr: &SetAttr,
+    ) -> FsResult<()> {
+        if !self.permission_checks || uid == 0 || self.serves_hook(path) {
+            return Ok(());
+        }
+        let current = self.get_attr(path).await?;
+        // Handing a file to its current owner or group changes nothing
+        if attr.uid.is_some_and(|u| u != current.uid) || attr.gid.is_some_and(|g| g != current.gid)
+        {
+            return Err(FsError::NotPermitted(format!(
+                "only root may change the owner of {}",
+                path
+            )));
+        }
+        if attr.mode.is_some() && uid != current.uid {
+            return Err(FsError::NotPermitted(format!(
+                "only the owner may change the mode of {}",
+                path
+            )));
+        }
+        if attr.size.is_some() {
+            check_mode(&current, path, uid, gid, libc::W_OK)?;
+        }
+        Ok(())
     }
 
     async fn set_attr(&self, path: &str, attr: SetAttr) -> FsResult<FileAttr> {
+        self.ensure_writable(path)?;
+
         // Hook paths cannot have attributes changed
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             return Err(FsError::PermissionDenied(
                 "Cannot change attributes of /.tarbox/ entries".to_string(),
             ));
@@ -359,8 +1003,10 @@ impl FilesystemInterface for TarboxBackend {
     }
 
     async fn chmod(&self, path: &str, mode: u32) -> FsResult<()> {
+        self.ensure_writable(path)?;
+
         // Hook paths cannot have permissions changed
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             return Err(FsError::PermissionDenied(
                 "Cannot change permissions of /.tarbox/ entries".to_string(),
             ));
@@ -370,8 +1016,10 @@ impl FilesystemInterface for TarboxBackend {
     }
 
     async fn chown(&self, path: &str, uid: u32, gid: u32) -> FsResult<()> {
+        self.ensure_writable(path)?;
+
         // Hook paths cannot have ownership changed
-        if Self::is_hook_path(path) {
+        if self.serves_hook(path) {
             return Err(FsError::PermissionDenied(
                 "Cannot change ownership of /.tarbox/ entries".to_string(),
             ));
@@ -381,24 +1029,60 @@ impl FilesystemInterface for TarboxBackend {
     }
 
     async fn statfs(&self) -> FsResult<StatFs> {
-        Ok(StatFs {
-            blocks: 1_000_000_000,
-            bfree: 500_000_000,
-            bavail: 500_000_000,
-            files: 10_000_000,
-            ffree: 9_000_000,
-            bsize: 4096,
-            namelen: 255,
-        })
+        tenant_statfs(&self.read_pool, self.tenant_id).await
     }
 }
 
+/// Filesystem statistics of a tenant, as `statfs` reports them for a mount.
+pub async fn tenant_statfs(pool: &PgPool, tenant_id: TenantId) -> FsResult<StatFs> {
+    // Only stored bytes count as used, so holes in sparse files are free
+    let usage = UsageOperations::new(pool)
+        .list_tenant_usage(Some(&[tenant_id]))
+        .await
+        .map_err(|e| map_fs_error(CoreFsError::Storage(e)))?;
+    let (used_bytes, files) = usage
+        .first()
+        .map(|u| (u.physical_bytes.max(0) as u64, u.files.max(0) as u64))
+        .unwrap_or_default();
+    let bfree = STATFS_BLOCKS.saturating_sub(used_bytes.div_ceil(STATFS_BLOCK_SIZE as u64));
+    Ok(StatFs {
+        blocks: STATFS_BLOCKS,
+        bfree,
+        bavail: bfree,
+        files: STATFS_FILES,
+        ffree: STATFS_FILES.saturating_sub(files),
+        bsize: STATFS_BLOCK_SIZE,
+        namelen: 255,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::storage::InodeType;
     use chrono::Utc;
 
+    #[test]
+    fn test_map_fs_error_space_errnos() {
+        assert_eq!(map_fs_error(CoreFsError::NoSpace("/f".into())).to_errno(), libc::ENOSPC);
+        assert_eq!(map_fs_error(CoreFsError::QuotaExceeded("/f".into())).to_errno(), libc::EDQUOT);
+        let other = CoreFsError::Storage(anyhow::anyhow!("connection reset"));
+        assert_eq!(map_fs_error(other).to_errno(), libc::EIO);
+    }
+
+    #[test]
+    fn test_read_window_bounds() {
+        let data = b"0123456789";
+        assert_eq!(read_window(data, 0, 4), b"0123");
+        assert_eq!(read_window(data, 9, 4), b"9");
+        assert_eq!(read_window(data, 6, 4), b"6789");
+        assert_eq!(read_window(data, 10, 4), b"");
+        assert_eq!(read_window(data, 11, 4), b"");
+        assert_eq!(read_window(data, u64::MAX, u32::MAX), b"");
+        assert_eq!(read_window(data, 5, 0), b"");
+        assert_eq!(read_window(b"", 0, 16), b"");
+    }
+
     #[test]
     fn test_inode_type_conversion() {
         assert_eq!(TarboxBackend::inode_type_to_file_type(&InodeType::File), FileType::RegularFile);
@@ -706,6 +1390,23 @@ mod tests {
         assert!(!TarboxBackend::is_hook_path("/data/.tarbox"));
     }
 
+    #[test]
+    fn test_is_hook_path_classifies_traversal_like_filesystem() {
+        // Whatever the spelling, a path is a hook exactly when its
+        // normalized form, which the filesystem would resolve, is one
+        for (path, hook) in [
+            ("/foo/../.tarbox/layers/drop", true),
+            ("/.tarbox/./layers/drop", true),
+            ("/.tarbox/../realfile", false),
+            ("/.tarbox/layers/../../data/file", false),
+            ("/.tarboxfile", false),
+        ] {
+            assert_eq!(TarboxBackend::is_hook_path(path), hook, "{}", path);
+            let normalized = crate::fs::path::normalize_path(path).unwrap();
+            assert_eq!(TarboxBackend::is_hook_path(&normalized), hook, "{}", normalized);
+        }
+    }
+
     #[test]
     fn test_hook_attr_to_file_attr_directory() {
         let hook_attr = HookFileAttr { is_dir: true, mode: 0o755, size: 4096 };
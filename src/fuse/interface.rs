@@ -33,15 +33,38 @@ pub enum FsError {
     #[error("Permission denied: {0}")]
     PermissionDenied(String),
 
+    #[error("Operation not permitted: {0}")]
+    NotPermitted(String),
+
     #[error("Not supported: {0}")]
     NotSupported(String),
 
+    #[error("Too many levels of symbolic links: {0}")]
+    SymlinkLoop(String),
+
+    #[error("No space left on device: {0}")]
+    NoSpace(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Read-only file system: {0}")]
+    ReadOnly(String),
+
+    #[error("No data or hole at or after offset: {0}")]
+    NoSuchOffset(String),
+
     #[error("IO error: {0}")]
     IoError(String),
 }
 
 impl FsError {
     /// Convert to POSIX errno
+    ///
+    /// `NotSupported` is used for arguments an operation can't handle (a
+    /// fallocate mode, a write offset), so it is EOPNOTSUPP. ENOSYS would
+    /// tell the kernel the whole operation is missing, and it stops sending
+    /// some of them for good.
     pub fn to_errno(&self) -> i32 {
         match self {
             FsError::PathNotFound(_) => libc::ENOENT,
@@ -51,7 +74,13 @@ impl FsError {
             FsError::DirectoryNotEmpty(_) => libc::ENOTEMPTY,
             FsError::InvalidPath(_) => libc::EINVAL,
             FsError::PermissionDenied(_) => libc::EACCES,
-            FsError::NotSupported(_) => libc::ENOSYS,
+            FsError::NotPermitted(_) => libc::EPERM,
+            FsError::NotSupported(_) => libc::EOPNOTSUPP,
+            FsError::SymlinkLoop(_) => libc::ELOOP,
+            FsError::NoSpace(_) => libc::ENOSPC,
+            FsError::QuotaExceeded(_) => libc::EDQUOT,
+            FsError::ReadOnly(_) => libc::EROFS,
+            FsError::NoSuchOffset(_) => libc::ENXIO,
             FsError::IoError(_) => libc::EIO,
         }
     }
@@ -100,6 +129,85 @@ pub struct SetAttr {
     pub gid: Option<u32>,
 }
 
+/// Check `mask` (`R_OK`, `W_OK`, `X_OK` or `F_OK`) against `attr` for a
+/// caller with `uid` and primary group `gid`, as access(2) does. Exactly one
+/// class of mode bits applies: owner, else group, else other. Root passes
+/// every check except `X_OK` on a non-directory with no execute bit set.
+/// Supplementary groups are not known here and never match.
+pub fn check_mode(attr: &FileAttr, path: &str, uid: u32, gid: u32, mask: i32) -> FsResult<()> {
+    let wanted = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u32;
+    if uid == 0 {
+        let executable = attr.kind == FileType::Directory || attr.mode & 0o111 != 0;
+        return if mask & libc::X_OK == 0 || executable {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied(format!("{} (mask {:#o}, uid 0)", path, mask)))
+        };
+    }
+    if wanted == 0 {
+        return Ok(());
+    }
+
+    let granted = if uid == attr.uid {
+        attr.mode >> 6
+    } else if gid == attr.gid {
+        attr.mode >> 3
+    } else {
+        attr.mode
+    } & 0o7;
+    if granted & wanted == wanted {
+        Ok(())
+    } else {
+        Err(FsError::PermissionDenied(format!("{} (mask {:#o}, uid {})", path, mask, uid)))
+    }
+}
+
+/// Most bytes one `copy_range_by_reading` call moves; callers of
+/// copy_file_range(2) loop on short copies.
+pub const COPY_RANGE_CHUNK: u64 = 1 << 20;
+
+/// Copy part of `src` to `dst` by reading it and writing it back, for
+/// copies a backend cannot do in storage. Copies at most `COPY_RANGE_CHUNK`
+/// bytes and nothing past the end of `src`.
+pub async fn copy_range_by_reading<F: FilesystemInterface + ?Sized>(
+    fs: &F,
+    src: &str,
+    src_offset: u64,
+    dst: &str,
+    dst_offset: u64,
+    len: u64,
+) -> FsResult<u64> {
+    let size = len.min(COPY_RANGE_CHUNK) as u32;
+    let data = fs.read_file(src, src_offset, size).await?;
+    if data.is_empty() {
+        return Ok(0);
+    }
+    Ok(fs.write_file(dst, dst_offset, &data).await? as u64)
+}
+
+/// Answer a `SEEK_DATA` or `SEEK_HOLE` seek in `path` as if it had no
+/// holes: data runs from `offset` to the end of the file, where the only
+/// hole is. Offsets at or past the end fail with `NoSuchOffset`.
+pub async fn seek_without_holes<F: FilesystemInterface + ?Sized>(
+    fs: &F,
+    path: &str,
+    offset: u64,
+    whence: i32,
+) -> FsResult<u64> {
+    let attr = fs.get_attr(path).await?;
+    if attr.kind == FileType::Directory {
+        return Err(FsError::IsDirectory(path.to_string()));
+    }
+    if offset >= attr.size {
+        return Err(FsError::NoSuchOffset(format!("{} at {}", path, offset)));
+    }
+    match whence {
+        libc::SEEK_DATA => Ok(offset),
+        libc::SEEK_HOLE => Ok(attr.size),
+        _ => Err(FsError::InvalidPath(format!("unsupported whence {} for {}", whence, path))),
+    }
+}
+
 /// Unified filesystem interface
 ///
 /// This trait defines the common operations that all filesystem interfaces
@@ -114,17 +222,130 @@ pub trait FilesystemInterface: Send + Sync {
     async fn delete_file(&self, path: &str) -> FsResult<()>;
     async fn truncate(&self, path: &str, size: u64) -> FsResult<()>;
 
+    /// Reserve or release space in `path` as fallocate(2) does. `mode` takes
+    /// the `FALLOC_FL_*` flags; backends reject modes they don't implement
+    /// with `NotSupported`.
+    async fn fallocate(&self, path: &str, offset: u64, length: u64, mode: i32) -> FsResult<()> {
+        Err(FsError::NotSupported(format!(
+            "fallocate mode {:#x} not supported: {} {}+{}",
+            mode, path, offset, length
+        )))
+    }
+
+    /// Copy up to `len` bytes from `src` at `src_offset` to `dst` at
+    /// `dst_offset`, as copy_file_range(2) does, and return how many were
+    /// copied. The default moves the bytes through `read_file` and
+    /// `write_file`; backends that can share storage override it.
+    async fn copy_file_range(
+        &self,
+        src: &str,
+        src_offset: u64,
+        dst: &str,
+        dst_offset: u64,
+        len: u64,
+    ) -> FsResult<u64> {
+        copy_range_by_reading(self, src, src_offset, dst, dst_offset, len).await
+    }
+
+    /// Find the next data or hole in `path` at or after `offset`, as lseek(2)
+    /// does for `whence` `SEEK_DATA` or `SEEK_HOLE`. The default treats the
+    /// whole file as data; backends that store sparse files override it.
+    async fn seek(&self, path: &str, offset: u64, whence: i32) -> FsResult<u64> {
+        seek_without_holes(self, path, offset, whence).await
+    }
+
+    /// Open `path` on behalf of `uid` and return a handle that stays bound
+    /// to the file across renames. Backends without handle tracking return 0.
+    async fn open(&self, _path: &str, _flags: i32, _uid: u32) -> FsResult<u64> {
+        Ok(0)
+    }
+
+    /// Note that a read or write through `fh` ended at `offset`, for
+    /// diagnostics.
+    fn record_handle_offset(&self, _fh: u64, _offset: u64) {}
+
+    /// Write `data` at `offset` through the handle `fh` returned by `open`.
+    /// Backends may hold small writes back until `fsync` or `release`; the
+    /// default writes straight through `write_file`.
+    async fn write_handle(&self, _fh: u64, path: &str, offset: u64, data: &[u8]) -> FsResult<u32> {
+        self.write_file(path, offset, data).await
+    }
+
+    /// Release a handle returned by `open`.
+    async fn release(&self, _fh: u64) -> FsResult<()> {
+        Ok(())
+    }
+
+    /// Make everything written to `path` durable. Tarbox commits each write
+    /// before returning, so only backends that buffer writes need to override
+    /// this to flush and commit them.
+    async fn fsync(&self, _path: &str) -> FsResult<()> {
+        Ok(())
+    }
+
     // Directory operations
     async fn create_dir(&self, path: &str, mode: u32) -> FsResult<FileAttr>;
     async fn read_dir(&self, path: &str) -> FsResult<Vec<DirEntry>>;
     async fn remove_dir(&self, path: &str) -> FsResult<()>;
 
+    /// List a directory together with each entry's attributes. The default
+    /// asks `get_attr` per entry; backends that can fetch both at once should
+    /// override it.
+    async fn read_dir_with_attrs(&self, path: &str) -> FsResult<Vec<(DirEntry, FileAttr)>> {
+        let entries = self.read_dir(path).await?;
+        let mut listed = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let child = if path == "/" {
+                format!("/{}", entry.name)
+            } else {
+                format!("{}/{}", path, entry.name)
+            };
+            let attr = self.get_attr(&child).await?;
+            listed.push((entry, attr));
+        }
+        Ok(listed)
+    }
+
+    /// Rename `from` to `to`, atomically replacing `to` if it exists.
+    async fn rename(&self, from: &str, to: &str) -> FsResult<()> {
+        Err(FsError::NotSupported(format!("Rename not supported: {} -> {}", from, to)))
+    }
+
     // Metadata operations
     async fn get_attr(&self, path: &str) -> FsResult<FileAttr>;
     async fn set_attr(&self, path: &str, attr: SetAttr) -> FsResult<FileAttr>;
     async fn chmod(&self, path: &str, mode: u32) -> FsResult<()>;
     async fn chown(&self, path: &str, uid: u32, gid: u32) -> FsResult<()>;
 
+    /// Whether `uid`/`gid` may access `path` as `mask` asks (access(2)).
+    /// Fails with `PermissionDenied` when the mode bits deny it.
+    async fn check_access(&self, path: &str, uid: u32, gid: u32, mask: i32) -> FsResult<()> {
+        let attr = self.get_attr(path).await?;
+        check_mode(&attr, path, uid, gid, mask)
+    }
+
+    /// Enforce the mode bits of `path` on `uid`/`gid` before an operation
+    /// that needs `mask`. Unlike `check_access`, backends may be configured
+    /// not to enforce; the default never does. Reads and writes are checked
+    /// when the file is opened, as the kernel does.
+    async fn authorize(&self, _path: &str, _uid: u32, _gid: u32, _mask: i32) -> FsResult<()> {
+        Ok(())
+    }
+
+    /// Enforce who may make the changes in `attr` (chmod(2), chown(2),
+    /// truncate(2)): only the owner or root may change the mode, only root
+    /// the owner or group, and a new size needs write permission. Fails
+    /// with `NotPermitted` for the first two. Enforced like `authorize`.
+    async fn authorize_set_attr(
+        &self,
+        _path: &str,
+        _uid: u32,
+        _gid: u32,
+        _attr: &SetAttr,
+    ) -> FsResult<()> {
+        Ok(())
+    }
+
     // Link operations (optional, can return NotSupported)
     async fn create_symlink(&self, target: &str, link: &str) -> FsResult<FileAttr> {
         Err(FsError::NotSupported(format!("Symlink not supported: {} -> {}", link, target)))
@@ -153,6 +374,12 @@ pub trait FilesystemInterface: Send + Sync {
 
     // Filesystem information
     async fn statfs(&self) -> FsResult<StatFs>;
+
+    /// Counter bumped whenever everything an adapter has cached about paths
+    /// may be stale. Adapters that see it change drop their inode maps.
+    fn invalidation_generation(&self) -> u64 {
+        0
+    }
 }
 
 /// Filesystem statistics
@@ -171,17 +398,64 @@ pub struct StatFs {
 mod tests {
     use super::*;
 
+    /// Every variant with the errno it maps to. The match makes a new
+    /// variant fail to compile here until it is given an expected errno.
+    fn errno_table() -> Vec<(FsError, i32)> {
+        let table = vec![
+            (FsError::PathNotFound("test".to_string()), libc::ENOENT),
+            (FsError::AlreadyExists("test".to_string()), libc::EEXIST),
+            (FsError::NotDirectory("test".to_string()), libc::ENOTDIR),
+            (FsError::IsDirectory("test".to_string()), libc::EISDIR),
+            (FsError::DirectoryNotEmpty("test".to_string()), libc::ENOTEMPTY),
+            (FsError::InvalidPath("test".to_string()), libc::EINVAL),
+            (FsError::PermissionDenied("test".to_string()), libc::EACCES),
+            (FsError::NotPermitted("test".to_string()), libc::EPERM),
+            (FsError::NotSupported("test".to_string()), libc::EOPNOTSUPP),
+            (FsError::SymlinkLoop("test".to_string()), libc::ELOOP),
+            (FsError::NoSpace("test".to_string()), libc::ENOSPC),
+            (FsError::QuotaExceeded("test".to_string()), libc::EDQUOT),
+            (FsError::ReadOnly("test".to_string()), libc::EROFS),
+            (FsError::NoSuchOffset("test".to_string()), libc::ENXIO),
+            (FsError::IoError("test".to_string()), libc::EIO),
+        ];
+        for (error, _) in &table {
+            match error {
+                FsError::PathNotFound(_)
+                | FsError::AlreadyExists(_)
+                | FsError::NotDirectory(_)
+                | FsError::IsDirectory(_)
+                | FsError::DirectoryNotEmpty(_)
+                | FsError::InvalidPath(_)
+                | FsError::PermissionDenied(_)
+                | FsError::NotPermitted(_)
+                | FsError::NotSupported(_)
+                | FsError::SymlinkLoop(_)
+                | FsError::NoSpace(_)
+                | FsError::QuotaExceeded(_)
+                | FsError::ReadOnly(_)
+                | FsError::NoSuchOffset(_)
+                | FsError::IoError(_) => {}
+            }
+        }
+        table
+    }
+
     #[test]
     fn test_fserror_to_errno() {
-        assert_eq!(FsError::PathNotFound("test".to_string()).to_errno(), libc::ENOENT);
-        assert_eq!(FsError::AlreadyExists("test".to_string()).to_errno(), libc::EEXIST);
-        assert_eq!(FsError::NotDirectory("test".to_string()).to_errno(), libc::ENOTDIR);
-        assert_eq!(FsError::IsDirectory("test".to_string()).to_errno(), libc::EISDIR);
-        assert_eq!(FsError::DirectoryNotEmpty("test".to_string()).to_errno(), libc::ENOTEMPTY);
-        assert_eq!(FsError::InvalidPath("test".to_string()).to_errno(), libc::EINVAL);
-        assert_eq!(FsError::PermissionDenied("test".to_string()).to_errno(), libc::EACCES);
-        assert_eq!(FsError::NotSupported("test".to_string()).to_errno(), libc::ENOSYS);
-        assert_eq!(FsError::IoError("test".to_string()).to_errno(), libc::EIO);
+        for (error, errno) in errno_table() {
+            assert_eq!(error.to_errno(), errno, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn test_fserror_errnos_are_distinct() {
+        let table = errno_table();
+        let mut errnos: Vec<i32> = table.iter().map(|(error, _)| error.to_errno()).collect();
+        errnos.sort();
+        errnos.dedup();
+        assert_eq!(errnos.len(), table.len(), "two variants share an errno");
+        // Only a genuine I/O failure may surface as EIO
+        assert_eq!(errnos.iter().filter(|&&errno| errno == libc::EIO).count(), 1);
     }
 
     #[test]
@@ -276,7 +550,13 @@ mod tests {
             FsError::DirectoryNotEmpty("dir".to_string()),
             FsError::InvalidPath("invalid".to_string()),
             FsError::PermissionDenied("file".to_string()),
+            FsError::NotPermitted("file".to_string()),
             FsError::NotSupported("op".to_string()),
+            FsError::SymlinkLoop("link".to_string()),
+            FsError::NoSpace("file".to_string()),
+            FsError::QuotaExceeded("file".to_string()),
+            FsError::ReadOnly("file".to_string()),
+            FsError::NoSuchOffset("file".to_string()),
             FsError::IoError("error".to_string()),
         ];
 
@@ -286,6 +566,71 @@ mod tests {
         }
     }
 
+    fn attr_with(mode: u32) -> FileAttr {
+        let now = Utc::now();
+        FileAttr {
+            inode: 2,
+            kind: FileType::RegularFile,
+            size: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            mode,
+            uid: 1000,
+            gid: 100,
+            nlinks: 1,
+        }
+    }
+
+    fn allowed(mode: u32, uid: u32, gid: u32, mask: i32) -> bool {
+        check_mode(&attr_with(mode), "/f", uid, gid, mask).is_ok()
+    }
+
+    #[test]
+    fn test_check_mode_owner_bits() {
+        assert!(allowed(0o600, 1000, 100, libc::R_OK | libc::W_OK));
+        assert!(!allowed(0o600, 1000, 100, libc::X_OK));
+        assert!(!allowed(0o400, 1000, 100, libc::R_OK | libc::W_OK));
+        assert!(allowed(0o700, 1000, 100, libc::R_OK | libc::W_OK | libc::X_OK));
+        // The owner class applies even when group or other would allow more
+        assert!(!allowed(0o077, 1000, 100, libc::R_OK));
+    }
+
+    #[test]
+    fn test_check_mode_group_bits() {
+        assert!(allowed(0o640, 2000, 100, libc::R_OK));
+        assert!(!allowed(0o640, 2000, 100, libc::W_OK));
+        assert!(allowed(0o670, 2000, 100, libc::R_OK | libc::W_OK | libc::X_OK));
+        assert!(!allowed(0o607, 2000, 100, libc::R_OK));
+    }
+
+    #[test]
+    fn test_check_mode_other_bits() {
+        assert!(allowed(0o644, 2000, 200, libc::R_OK));
+        assert!(!allowed(0o644, 2000, 200, libc::W_OK));
+        assert!(allowed(0o755, 2000, 200, libc::R_OK | libc::X_OK));
+        assert!(!allowed(0o770, 2000, 200, libc::R_OK));
+        let err = check_mode(&attr_with(0o750), "/f", 2000, 200, libc::X_OK).unwrap_err();
+        assert_eq!(err.to_errno(), libc::EACCES);
+    }
+
+    #[test]
+    fn test_check_mode_root_and_existence() {
+        assert!(allowed(0o000, 0, 0, libc::R_OK | libc::W_OK));
+        assert!(allowed(0o000, 2000, 200, libc::F_OK));
+    }
+
+    #[test]
+    fn test_check_mode_root_execute_needs_an_x_bit() {
+        assert!(!allowed(0o644, 0, 0, libc::X_OK));
+        assert!(!allowed(0o000, 0, 0, libc::R_OK | libc::X_OK));
+        assert!(allowed(0o744, 0, 0, libc::X_OK));
+        assert!(allowed(0o001, 0, 0, libc::R_OK | libc::W_OK | libc::X_OK));
+        // Directories are always searchable by root
+        let dir = FileAttr { kind: FileType::Directory, ..attr_with(0o000) };
+        assert!(check_mode(&dir, "/d", 0, 0, libc::X_OK).is_ok());
+    }
+
     #[test]
     fn test_dir_entry_with_different_types() {
         let entries = vec![
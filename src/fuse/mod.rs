@@ -7,10 +7,14 @@ pub mod adapter;
 pub mod backend;
 pub mod interface;
 pub mod mount;
+pub mod negative_cache;
+pub mod write_buffer;
 
 pub use adapter::FuseAdapter;
 pub use backend::TarboxBackend;
 pub use interface::{
     DirEntry, FileAttr, FileType, FilesystemInterface, FsError, FsResult, SetAttr, StatFs,
 };
-pub use mount::{MountOptions, mount, unmount};
+pub use mount::{MountOptions, mount, shutdown, unmount};
+pub use negative_cache::NegativeCache;
+pub use write_buffer::WriteBuffer;
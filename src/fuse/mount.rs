@@ -2,19 +2,23 @@
 //
 // Provides functions to mount and unmount Tarbox filesystems via FUSE.
 
+use super::adapter::{DEFAULT_ATTR_TTL, DEFAULT_ENTRY_TTL};
 use super::{FuseAdapter, TarboxBackend};
+use crate::config::CacheConfig;
 use anyhow::{Context, Result};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Handle;
 
 /// Mount options for FUSE filesystem
 #[derive(Debug, Clone)]
 pub struct MountOptions {
-    /// Allow other users to access the filesystem
+    /// Allow other users, root included, to access the filesystem
     pub allow_other: bool,
 
-    /// Allow root to access the filesystem
+    /// Allow root, besides the mounting user, to access the filesystem.
+    /// Can't be combined with `allow_other`.
     pub allow_root: bool,
 
     /// Mount as read-only
@@ -23,8 +27,20 @@ pub struct MountOptions {
     /// Filesystem name (for mtab)
     pub fsname: Option<String>,
 
+    /// Filesystem subtype; the mount shows up as type `fuse.<subtype>`
+    pub subtype: Option<String>,
+
     /// Auto-unmount on process exit
     pub auto_unmount: bool,
+
+    /// How long the kernel caches file attributes; zero disables caching
+    pub attr_ttl: Duration,
+
+    /// How long the kernel caches name lookups; zero disables caching
+    pub entry_ttl: Duration,
+
+    /// Most paths the adapter remembers attributes or misses for
+    pub max_cached_entries: usize,
 }
 
 impl Default for MountOptions {
@@ -34,14 +50,31 @@ impl Default for MountOptions {
             allow_root: false,
             read_only: false,
             fsname: Some("tarbox".to_string()),
+            subtype: Some("tarbox".to_string()),
             // auto_unmount requires allow_other or allow_root, which needs
             // 'user_allow_other' in /etc/fuse.conf. Disabled by default.
             auto_unmount: false,
+            attr_ttl: DEFAULT_ATTR_TTL,
+            entry_ttl: DEFAULT_ENTRY_TTL,
+            max_cached_entries: CacheConfig::default().max_entries,
         }
     }
 }
 
 impl MountOptions {
+    /// Check the options make sense together, before anything is mounted.
+    pub fn validate(&self) -> Result<()> {
+        if self.allow_other && self.allow_root {
+            anyhow::bail!(
+                "allow_other and allow_root are mutually exclusive: allow_other already \
+                 admits root, allow_root admits only root besides the mounting user. \
+                 Either one needs 'user_allow_other' in /etc/fuse.conf unless mounting \
+                 as root"
+            );
+        }
+        Ok(())
+    }
+
     /// Convert to fuser mount options
     fn to_fuser_options(&self) -> Vec<fuser::MountOption> {
         let mut options = Vec::new();
@@ -62,6 +95,10 @@ impl MountOptions {
             options.push(fuser::MountOption::FSName(fsname.clone()));
         }
 
+        if let Some(ref subtype) = self.subtype {
+            options.push(fuser::MountOption::Subtype(subtype.clone()));
+        }
+
         if self.auto_unmount {
             options.push(fuser::MountOption::AutoUnmount);
         }
@@ -94,6 +131,7 @@ pub fn mount(
     options: MountOptions,
 ) -> Result<fuser::BackgroundSession> {
     let mountpoint = mountpoint.as_ref();
+    options.validate()?;
 
     // Validate mountpoint exists and is a directory
     if !mountpoint.exists() {
@@ -104,12 +142,20 @@ pub fn mount(
         anyhow::bail!("Mount point is not a directory: {}", mountpoint.display());
     }
 
+    // The kernel's RO flag alone doesn't cover hook writes or callers that
+    // reach the backend directly
+    if options.read_only && !backend.is_read_only() {
+        anyhow::bail!("A read-only mount needs a backend built with_read_only(true)");
+    }
+
     // Get current runtime handle - panics if not in a tokio runtime
     let runtime = Handle::current();
 
     // Create FUSE adapter with the current runtime handle
     // This ensures database connections and other runtime-bound resources work correctly
-    let adapter = FuseAdapter::with_runtime(backend, runtime);
+    let adapter = FuseAdapter::with_runtime(backend, runtime)
+        .with_ttls(options.attr_ttl, options.entry_ttl)
+        .with_max_cached_entries(options.max_cached_entries);
 
     // Convert mount options
     let fuser_options = options.to_fuser_options();
@@ -125,6 +171,21 @@ pub fn mount(
     Ok(session)
 }
 
+/// Unmount `session` in order: store the writes `backend` still buffers,
+/// unmount and wait for the session loop to end, then store anything written
+/// meanwhile.
+pub async fn shutdown(backend: &TarboxBackend, session: fuser::BackgroundSession) -> Result<()> {
+    backend.flush_writes().await.context("Failed to store buffered writes")?;
+    // Joining blocks until in-flight requests, which run on this runtime, finish
+    tokio::task::spawn_blocking(move || session.join())
+        .await
+        .context("FUSE session ended with an error")?;
+    backend.flush_writes().await.context("Failed to store buffered writes")?;
+
+    tracing::info!("Filesystem unmounted");
+    Ok(())
+}
+
 /// Unmount a FUSE filesystem
 ///
 /// Note: This is automatically handled when the BackgroundSession is dropped,
@@ -181,26 +242,75 @@ mod tests {
         assert!(!options.allow_root);
         assert!(!options.read_only);
         assert_eq!(options.fsname, Some("tarbox".to_string()));
+        assert_eq!(options.subtype, Some("tarbox".to_string()));
         assert!(!options.auto_unmount); // Disabled by default (requires fuse.conf config)
+        assert_eq!(options.attr_ttl, Duration::from_secs(1));
+        assert_eq!(options.entry_ttl, Duration::from_secs(1));
     }
 
     #[test]
     fn test_mount_options_to_fuser() {
         let options = MountOptions {
             allow_other: true,
-            allow_root: true,
             read_only: true,
             fsname: Some("test".to_string()),
             auto_unmount: false,
+            ..Default::default()
         };
 
         let fuser_options = options.to_fuser_options();
 
         // Should contain the options
         assert!(fuser_options.contains(&fuser::MountOption::AllowOther));
-        assert!(fuser_options.contains(&fuser::MountOption::AllowRoot));
+        assert!(!fuser_options.contains(&fuser::MountOption::AllowRoot));
         assert!(fuser_options.contains(&fuser::MountOption::RO));
         assert!(fuser_options.contains(&fuser::MountOption::FSName("test".to_string())));
+
+        let options = MountOptions { allow_root: true, ..Default::default() };
+        let fuser_options = options.to_fuser_options();
+        assert!(fuser_options.contains(&fuser::MountOption::AllowRoot));
+        assert!(!fuser_options.contains(&fuser::MountOption::AllowOther));
+    }
+
+    #[test]
+    fn test_mount_options_fsname_and_subtype() {
+        let options = MountOptions {
+            fsname: Some("tarbox:acme".to_string()),
+            subtype: Some("tarbox-dev".to_string()),
+            ..Default::default()
+        };
+        let fuser_options = options.to_fuser_options();
+        assert!(fuser_options.contains(&fuser::MountOption::FSName("tarbox:acme".to_string())));
+        assert!(fuser_options.contains(&fuser::MountOption::Subtype("tarbox-dev".to_string())));
+
+        let options = MountOptions { subtype: None, ..Default::default() };
+        assert!(
+            !options
+                .to_fuser_options()
+                .iter()
+                .any(|option| matches!(option, fuser::MountOption::Subtype(_)))
+        );
+    }
+
+    #[test]
+    fn test_mount_options_auto_unmount() {
+        let options = MountOptions { auto_unmount: true, ..Default::default() };
+        assert!(options.to_fuser_options().contains(&fuser::MountOption::AutoUnmount));
+
+        let options = MountOptions { auto_unmount: false, ..Default::default() };
+        assert!(!options.to_fuser_options().contains(&fuser::MountOption::AutoUnmount));
+    }
+
+    #[test]
+    fn test_allow_other_and_allow_root_rejected_together() {
+        assert!(MountOptions::default().validate().is_ok());
+        assert!(MountOptions { allow_other: true, ..Default::default() }.validate().is_ok());
+        assert!(MountOptions { allow_root: true, ..Default::default() }.validate().is_ok());
+
+        let both = MountOptions { allow_other: true, allow_root: true, ..Default::default() };
+        let message = both.validate().unwrap_err().to_string();
+        assert!(message.contains("mutually exclusive"));
+        assert!(message.contains("user_allow_other"));
     }
 
     #[test]
@@ -0,0 +1,118 @@
+// Negative lookup cache
+//
+// Build tools probe many paths that don't exist (include search paths, module
+// resolution). Each miss would otherwise cost a database round trip, so paths
+// recently found missing are remembered for a short TTL. The cache only sees
+// changes made through this mount; writers elsewhere become visible once the
+// entry expires.
+
+use std::time::Duration;
+
+use moka::future::Cache;
+
+use crate::config::CacheConfig;
+
+pub struct NegativeCache {
+    entries: Option<Cache<String, ()>>,
+}
+
+impl NegativeCache {
+    /// Build a cache bounded by `max_entries` whose entries live for
+    /// `negative_ttl_seconds`. A zero TTL or capacity disables it.
+    pub fn new(config: &CacheConfig) -> Self {
+        Self::with_ttl(Duration::from_secs(config.negative_ttl_seconds), config.max_entries)
+    }
+
+    /// Build a cache of at most `max_entries` paths, each remembered for
+    /// `ttl`. A zero TTL or capacity disables it.
+    pub fn with_ttl(ttl: Duration, max_entries: usize) -> Self {
+        if ttl.is_zero() || max_entries == 0 {
+            return Self::disabled();
+        }
+
+        let entries = Cache::builder().max_capacity(max_entries as u64).time_to_live(ttl).build();
+        Self { entries: Some(entries) }
+    }
+
+    pub fn disabled() -> Self {
+        Self { entries: None }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.entries.is_some()
+    }
+
+    /// Whether `path` is known not to exist.
+    pub fn is_missing(&self, path: &str) -> bool {
+        self.entries.as_ref().is_some_and(|entries| entries.contains_key(path))
+    }
+
+    pub async fn insert_missing(&self, path: &str) {
+        if let Some(entries) = &self.entries {
+            entries.insert(path.to_string(), ()).await;
+        }
+    }
+
+    /// Forget a path, e.g. because something was just created there.
+    pub async fn invalidate(&self, path: &str) {
+        if let Some(entries) = &self.entries {
+            entries.invalidate(path).await;
+        }
+    }
+
+    /// Forget everything, e.g. after a layer switch or a rename that may have
+    /// moved a whole subtree into place.
+    pub fn invalidate_all(&self) {
+        if let Some(entries) = &self.entries {
+            entries.invalidate_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(negative_ttl_seconds: u64) -> CacheConfig {
+        CacheConfig { negative_ttl_seconds, ..CacheConfig::default() }
+    }
+
+    #[tokio::test]
+    async fn test_insert_and_invalidate() {
+        let cache = NegativeCache::new(&config(60));
+        assert!(cache.is_enabled());
+        assert!(!cache.is_missing("/missing.h"));
+
+        cache.insert_missing("/missing.h").await;
+        assert!(cache.is_missing("/missing.h"));
+        assert!(!cache.is_missing("/other.h"));
+
+        cache.invalidate("/missing.h").await;
+        assert!(!cache.is_missing("/missing.h"));
+
+        cache.insert_missing("/a").await;
+        cache.insert_missing("/b").await;
+        cache.invalidate_all();
+        assert!(!cache.is_missing("/a"));
+        assert!(!cache.is_missing("/b"));
+    }
+
+    #[tokio::test]
+    async fn test_zero_ttl_disables() {
+        let cache = NegativeCache::new(&config(0));
+        assert!(!cache.is_enabled());
+
+        cache.insert_missing("/missing.h").await;
+        assert!(!cache.is_missing("/missing.h"));
+    }
+
+    #[tokio::test]
+    async fn test_entries_expire() {
+        let cache = NegativeCache::new(&config(1));
+        cache.insert_missing("/missing.h").await;
+        assert!(cache.is_missing("/missing.h"));
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(!cache.is_missing("/missing.h"));
+    }
+}
@@ -0,0 +1,146 @@
+// Per-handle write coalescing
+//
+// Editors and loggers write a few bytes at a time, and every write that
+// reaches the filesystem rewrites the block it lands in. Small writes through
+// an open handle are collected here while they continue one another and are
+// stored once they fill a block, or when the handle is synced or released.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::layer::BLOCK_SIZE;
+
+/// Bytes written through one handle that are not stored yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingWrite {
+    pub path: String,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl PendingWrite {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+#[derive(Default)]
+pub struct WriteBuffer {
+    pending: Mutex<HashMap<u64, PendingWrite>>,
+}
+
+impl WriteBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a write of `len` bytes is small enough to be buffered.
+    pub fn accepts(len: usize) -> bool {
+        len < BLOCK_SIZE
+    }
+
+    /// Buffer `data` written through `fh` at `offset` and return what has to
+    /// be stored now: the earlier writes of `fh` if `data` doesn't continue
+    /// them, and the whole blocks the buffer holds once it crosses a block
+    /// boundary.
+    pub fn append(&self, fh: u64, path: &str, offset: u64, data: &[u8]) -> Vec<PendingWrite> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut ready = Vec::new();
+        let continues = pending.get(&fh).is_some_and(|p| p.path == path && p.end() == offset);
+        if !continues {
+            ready.extend(pending.remove(&fh));
+        }
+
+        let buffer = pending.entry(fh).or_insert_with(|| PendingWrite {
+            path: path.to_string(),
+            offset,
+            data: Vec::new(),
+        });
+        buffer.data.extend_from_slice(data);
+
+        let block_size = BLOCK_SIZE as u64;
+        let boundary = buffer.end() / block_size * block_size;
+        if boundary > buffer.offset {
+            let tail = buffer.data.split_off((boundary - buffer.offset) as usize);
+            let full = std::mem::replace(&mut buffer.data, tail);
+            ready.push(PendingWrite { path: path.to_string(), offset: buffer.offset, data: full });
+            buffer.offset = boundary;
+            if buffer.data.is_empty() {
+                pending.remove(&fh);
+            }
+        }
+        ready
+    }
+
+    /// Take what `fh` has buffered.
+    pub fn take(&self, fh: u64) -> Option<PendingWrite> {
+        self.pending.lock().unwrap().remove(&fh)
+    }
+
+    /// Take what any handle has buffered for `path`.
+    pub fn take_path(&self, path: &str) -> Vec<PendingWrite> {
+        let mut pending = self.pending.lock().unwrap();
+        let handles: Vec<u64> =
+            pending.iter().filter(|(_, p)| p.path == path).map(|(&fh, _)| fh).collect();
+        handles.into_iter().filter_map(|fh| pending.remove(&fh)).collect()
+    }
+
+    /// Take everything buffered, e.g. before changes that may move paths.
+    pub fn take_all(&self) -> Vec<PendingWrite> {
+        self.pending.lock().unwrap().drain().map(|(_, p)| p).collect()
+    }
+
+    /// Where the buffered writes to `path` end, if any are buffered.
+    pub fn end_of(&self, path: &str) -> Option<u64> {
+        let pending = self.pending.lock().unwrap();
+        pending.values().filter(|p| p.path == path).map(PendingWrite::end).max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contiguous_writes_flush_by_block() {
+        let buffer = WriteBuffer::new();
+        for at in 0..BLOCK_SIZE as u64 - 1 {
+            assert!(buffer.append(1, "/log", at, b"x").is_empty());
+        }
+        assert_eq!(buffer.end_of("/log"), Some(BLOCK_SIZE as u64 - 1));
+
+        // Filling the block stores it, the next byte starts a new buffer
+        let ready = buffer.append(1, "/log", BLOCK_SIZE as u64 - 1, b"yz");
+        assert_eq!(ready.len(), 1);
+        assert_eq!((ready[0].offset, ready[0].data.len()), (0, BLOCK_SIZE));
+        assert_eq!(
+            buffer.take(1),
+            Some(PendingWrite {
+                path: "/log".into(),
+                offset: BLOCK_SIZE as u64,
+                data: b"z".into()
+            })
+        );
+        assert_eq!(buffer.take(1), None);
+    }
+
+    #[test]
+    fn test_seek_flushes_earlier_writes() {
+        let buffer = WriteBuffer::new();
+        assert!(buffer.append(1, "/a", 10, b"abc").is_empty());
+        assert!(buffer.append(2, "/a", 0, b"q").is_empty());
+
+        let ready = buffer.append(1, "/a", 100, b"d");
+        assert_eq!(
+            ready,
+            vec![PendingWrite { path: "/a".into(), offset: 10, data: b"abc".into() }]
+        );
+        assert_eq!(buffer.end_of("/a"), Some(101));
+
+        let mut taken = buffer.take_path("/a");
+        taken.sort_by_key(|p| p.offset);
+        assert_eq!(taken.iter().map(|p| p.offset).collect::<Vec<_>>(), vec![0, 100]);
+        assert!(buffer.take_all().is_empty());
+        assert_eq!(buffer.end_of("/a"), None);
+    }
+}
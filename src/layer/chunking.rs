@@ -0,0 +1,139 @@
+//! Content-defined chunking for binary files.
+//!
+//! Fixed-size blocks shift when bytes are inserted, so every block after the
+//! insert point changes. Content-defined chunking picks boundaries from the
+//! data itself with a rolling gear hash (FastCDC with normalized chunking):
+//! after an edit the boundaries resynchronize within a chunk or two, and the
+//! remaining chunks hash the same as before.
+
+/// How binary file contents are split for storage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChunkingMode {
+    /// Fixed 4 KiB blocks, overlaid per layer by block index.
+    #[default]
+    Fixed,
+    /// Variable-length chunks keyed by content hash.
+    ContentDefined,
+}
+
+/// Smallest chunk emitted, except for the tail of the data.
+pub const MIN_CHUNK_SIZE: usize = 2 * 1024;
+/// Target average chunk size.
+pub const AVG_CHUNK_SIZE: usize = 8 * 1024;
+/// Largest chunk emitted.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+// 15 and 11 one-bits spread over the hash: boundaries are harder to hit below
+// the average size and easier above it, which narrows the size distribution.
+const MASK_SMALL: u64 = 0x0003_5907_0353_0000;
+const MASK_LARGE: u64 = 0x0000_d900_0353_0000;
+
+const GEAR: [u64; 256] = gear_table();
+
+/// Pseudo-random gear values, fixed so chunk boundaries are stable across
+/// builds and hosts.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Length of the first chunk of `data`.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+
+    let mut hash: u64 = 0;
+    let mut i = MIN_CHUNK_SIZE;
+    while i < normal {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & MASK_SMALL == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    while i < end {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & MASK_LARGE == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    end
+}
+
+/// Split `data` into content-defined chunks, in order.
+pub fn content_defined_chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::with_capacity(data.len() / AVG_CHUNK_SIZE + 1);
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (chunk, tail) = rest.split_at(cut_point(rest));
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunks_cover_data_within_bounds() {
+        let data = pseudo_random(1024 * 1024, 7);
+        let chunks = content_defined_chunks(&data);
+
+        assert_eq!(chunks.concat(), data);
+        let (last, body) = chunks.split_last().unwrap();
+        assert!(body.iter().all(|c| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&c.len())));
+        assert!(!last.is_empty() && last.len() <= MAX_CHUNK_SIZE);
+
+        let average = data.len() / chunks.len();
+        assert!((AVG_CHUNK_SIZE / 2..AVG_CHUNK_SIZE * 2).contains(&average), "avg {}", average);
+    }
+
+    #[test]
+    fn test_small_and_empty_inputs() {
+        assert!(content_defined_chunks(&[]).is_empty());
+        let small = vec![1u8; MIN_CHUNK_SIZE];
+        assert_eq!(content_defined_chunks(&small), vec![small.as_slice()]);
+    }
+
+    #[test]
+    fn test_insert_at_start_keeps_most_chunks() {
+        let data = pseudo_random(512 * 1024, 42);
+        let mut edited = b"inserted".to_vec();
+        edited.extend_from_slice(&data);
+
+        let before: HashSet<&[u8]> = content_defined_chunks(&data).into_iter().collect();
+        let after = content_defined_chunks(&edited);
+        let shared = after.iter().filter(|c| before.contains(*c)).count();
+        assert!(shared + 2 >= after.len(), "{} of {} chunks shared", shared, after.len());
+    }
+}
@@ -1,17 +1,24 @@
 //! Copy-on-Write (COW) handler module.
 //!
 //! Implements write-time copy semantics for both binary and text files.
-//! Binary files use block-level COW, text files use line-level diff.
+//! Binary files use block-level COW (or content-defined chunks), text files
+//! use line-level diff.
+
+use std::collections::{HashMap, HashSet};
 
 use anyhow::Result;
-use similar::{ChangeTag, TextDiff};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, DiffOp, TextDiff};
 use sqlx::PgPool;
 use tracing::{debug, info};
 
+use crate::layer::chunking::{ChunkingMode, content_defined_chunks};
 use crate::layer::detection::{FileTypeDetector, FileTypeInfo, LineEnding, TextEncoding};
 use crate::storage::{
-    BlockOperations, ChangeType, CreateBlockInput, CreateTextBlockInput, CreateTextMetadataInput,
-    TextBlockOperations, TextBlockRepository,
+    BlockOperations, ChangeType, ChunkOperations, CreateBlockInput, CreateTextBlockInput,
+    CreateTextMetadataInput, DatabaseTransaction, LayerOperations, TextBlockOperations,
+    TextBlockRepository, begin_snapshot, block::compute_content_hash,
 };
 use crate::types::{InodeId, LayerId, TenantId};
 
@@ -24,17 +31,36 @@ pub struct CowResult {
     pub size_delta: i64,
     /// Text changes if applicable.
     pub text_changes: Option<TextChanges>,
+    /// Block changes if the file is stored as binary.
+    pub block_changes: Option<BlockChanges>,
     /// Whether the file is stored as text.
     pub is_text: bool,
 }
 
 /// Text file change statistics.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct TextChanges {
     pub lines_added: i32,
     pub lines_deleted: i32,
     pub lines_modified: i32,
     pub total_lines: i32,
+    /// Changed line ranges, in file order. Entries recorded before ranges were
+    /// tracked have none.
+    #[serde(default)]
+    pub hunks: Vec<TextHunk>,
+}
+
+/// A contiguous run of changed lines, in the style of a unified diff header
+/// (`@@ -old_start,old_lines +new_start,new_lines @@`).
+///
+/// Starts are 1-based. A zero-length side marks a pure insertion or deletion,
+/// and its start is the line the change sits before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextHunk {
+    pub old_start: i32,
+    pub old_lines: i32,
+    pub new_start: i32,
+    pub new_lines: i32,
 }
 
 impl TextChanges {
@@ -44,9 +70,116 @@ impl TextChanges {
             "lines_added": self.lines_added,
             "lines_deleted": self.lines_deleted,
             "lines_modified": self.lines_modified,
-            "total_lines": self.total_lines
+            "total_lines": self.total_lines,
+            "hunks": self.hunks,
         })
     }
+
+    /// Parse the value stored in `layer_entries.text_changes`.
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        serde_json::from_value(value.clone()).ok()
+    }
+
+    /// Line-level changes turning the text `old` into `new`, split into lines
+    /// the way text files are stored.
+    pub fn between(old: &str, new: &str) -> Self {
+        Self::from_lines(&split_lines(old), &split_lines(new))
+    }
+
+    /// Line-level changes turning `old_lines` into `new_lines`.
+    fn from_lines(old_lines: &[&str], new_lines: &[&str]) -> Self {
+        let diff = TextDiff::from_slices(old_lines, new_lines);
+
+        let mut lines_added: usize = 0;
+        let mut lines_deleted: usize = 0;
+
+        for change in diff.iter_all_changes() {
+            match change.tag() {
+                ChangeTag::Delete => lines_deleted += 1,
+                ChangeTag::Insert => lines_added += 1,
+                ChangeTag::Equal => {}
+            }
+        }
+
+        // Consider consecutive delete+insert pairs as modifications
+        // This is a simplification; a more sophisticated approach would
+        // track actual line positions
+        let modifications = lines_added.min(lines_deleted);
+        let lines_modified = modifications as i32;
+        let lines_added = (lines_added - modifications) as i32;
+        let lines_deleted = (lines_deleted - modifications) as i32;
+
+        Self {
+            lines_added,
+            lines_deleted,
+            lines_modified,
+            total_lines: new_lines.len() as i32,
+            hunks: collect_hunks(diff.ops()),
+        }
+    }
+
+    /// Render the hunks as a unified diff body without context lines: each
+    /// hunk's header followed by its `-` lines from `old` and `+` lines from
+    /// `new`, which must be the texts the hunks were computed between.
+    pub fn render_unified(&self, old: &str, new: &str) -> String {
+        let (old_lines, new_lines) = (split_lines(old), split_lines(new));
+        // Unified headers give the line before an empty side, ours the one after
+        let header_start = |start: i32, lines: i32| if lines == 0 { start - 1 } else { start };
+
+        let mut output = String::new();
+        for hunk in &self.hunks {
+            output.push_str(&format!(
+                "@@ -{},{} +{},{} @@\n",
+                header_start(hunk.old_start, hunk.old_lines),
+                hunk.old_lines,
+                header_start(hunk.new_start, hunk.new_lines),
+                hunk.new_lines
+            ));
+            let removed = hunk_lines(&old_lines, hunk.old_start, hunk.old_lines);
+            let added = hunk_lines(&new_lines, hunk.new_start, hunk.new_lines);
+            for (sign, lines) in [('-', removed), ('+', added)] {
+                for line in lines {
+                    output.push(sign);
+                    output.push_str(line.strip_suffix('\r').unwrap_or(line));
+                    output.push('\n');
+                }
+            }
+        }
+        output
+    }
+}
+
+/// Size of the blocks binary files are split into.
+pub const BLOCK_SIZE: usize = 4096;
+
+/// Binary file change record: which blocks a layer stores its own version of.
+///
+/// Blocks not listed are inherited unchanged from the parent layers. For
+/// content-defined chunked files `block_size` is 0, the counts refer to
+/// chunks, and listed chunks are the ones new relative to the prior version.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockChanges {
+    pub block_size: i32,
+    /// Number of blocks in the file after the write.
+    pub block_count: i32,
+    /// Indices of the blocks this layer overrides, ascending.
+    pub changed_blocks: Vec<i32>,
+    /// Length of the file in bytes after the write. Entries recorded before
+    /// it was tracked lack it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<i64>,
+}
+
+impl BlockChanges {
+    /// Convert to JSON value.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("block changes serialize to JSON")
+    }
+
+    /// Parse the value stored in `layer_entries.block_changes`.
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        serde_json::from_value(value.clone()).ok()
+    }
 }
 
 /// COW handler for managing copy-on-write operations.
@@ -55,12 +188,25 @@ pub struct CowHandler<'a> {
     tenant_id: TenantId,
     current_layer_id: LayerId,
     detector: FileTypeDetector,
+    chunking: ChunkingMode,
 }
 
 impl<'a> CowHandler<'a> {
     /// Create a new COW handler.
     pub fn new(pool: &'a PgPool, tenant_id: TenantId, current_layer_id: LayerId) -> Self {
-        Self { pool, tenant_id, current_layer_id, detector: FileTypeDetector::new() }
+        Self {
+            pool,
+            tenant_id,
+            current_layer_id,
+            detector: FileTypeDetector::new(),
+            chunking: ChunkingMode::default(),
+        }
+    }
+
+    /// Choose how binary files are split for storage.
+    pub fn with_chunking(mut self, chunking: ChunkingMode) -> Self {
+        self.chunking = chunking;
+        self
     }
 
     /// Write data to a file with COW semantics.
@@ -72,6 +218,20 @@ impl<'a> CowHandler<'a> {
         inode_id: InodeId,
         data: &[u8],
         old_data: Option<&[u8]>,
+    ) -> Result<CowResult> {
+        let mut tx = self.pool.begin().await?;
+        let result = self.write_file_in_tx(&mut tx, inode_id, data, old_data).await?;
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    /// Write data to a file with COW semantics as part of a caller-managed transaction.
+    pub async fn write_file_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        data: &[u8],
+        old_data: Option<&[u8]>,
     ) -> Result<CowResult> {
         let file_type = self.detector.detect(data);
         let is_new = old_data.is_none();
@@ -94,53 +254,231 @@ impl<'a> CowHandler<'a> {
                     line_count = line_count,
                     "Writing as text file"
                 );
-                self.write_text_file(inode_id, data, old_data, encoding, line_ending, line_count)
-                    .await
+                self.write_text_file(
+                    tx,
+                    inode_id,
+                    data,
+                    old_data,
+                    encoding,
+                    line_ending,
+                    line_count,
+                )
+                .await
             }
             FileTypeInfo::Binary => {
                 debug!("Writing as binary file");
-                self.write_binary_file(inode_id, data, is_new, old_size).await
+                self.write_binary_file(tx, inode_id, data, is_new, old_size).await
             }
         }
     }
 
     /// Write a binary file using block-level COW.
+    ///
+    /// The data is split into fixed-size blocks and compared by content hash
+    /// with the blocks the file inherits from the parent layers; only blocks
+    /// that differ are stored in the current layer.
     async fn write_binary_file(
         &self,
+        tx: &mut DatabaseTransaction<'_>,
         inode_id: InodeId,
         data: &[u8],
         is_new: bool,
         old_size: usize,
     ) -> Result<CowResult> {
-        let block_ops = BlockOperations::new(self.pool);
+        if self.chunking == ChunkingMode::ContentDefined {
+            return self.write_chunked_file(tx, inode_id, data, is_new, old_size).await;
+        }
 
-        // Delete old blocks (they belong to this layer)
-        block_ops.delete(self.tenant_id, inode_id).await?;
+        let blocks = futures::stream::iter(data.chunks(BLOCK_SIZE).map(|chunk| Ok(chunk.to_vec())));
+        self.write_binary_blocks_in_tx(tx, inode_id, blocks, is_new, old_size).await
+    }
 
-        // Create new blocks
-        const BLOCK_SIZE: usize = 4096;
-        let chunks: Vec<&[u8]> = data.chunks(BLOCK_SIZE).collect();
+    /// Write a binary file from a stream of `BLOCK_SIZE` blocks, the last of
+    /// which may be shorter, without holding more than one block at a time.
+    ///
+    /// Stores fixed-size blocks whatever the chunking mode, with the same
+    /// block-level COW as `write_binary_file`. All-zero blocks are left out
+    /// unless they replace inherited data; readers fill the holes with zeros
+    /// up to the inode size.
+    pub async fn write_binary_blocks_in_tx<S>(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        mut blocks: S,
+        is_new: bool,
+        old_size: usize,
+    ) -> Result<CowResult>
+    where
+        S: Stream<Item = Result<Vec<u8>>> + Unpin,
+    {
+        let block_ops = BlockOperations::new(self.pool);
+        let chunk_ops = ChunkOperations::new(self.pool);
+        let from_text = self.stored_as_text_in_tx(tx, inode_id).await?;
 
-        for (index, chunk) in chunks.iter().enumerate() {
+        // Drop this layer's earlier versions; what remains visible is inherited
+        block_ops
+            .delete_layer_blocks_in_tx(tx, self.tenant_id, inode_id, self.current_layer_id)
+            .await?;
+        chunk_ops.delete_map_in_tx(tx, self.tenant_id, inode_id, self.current_layer_id).await?;
+        self.clear_text_in_tx(tx, inode_id).await?;
+        // Blocks hidden under a chunked or text version are stale, so nothing
+        // is inherited
+        let inherited: HashMap<i32, String> = if from_text
+            || chunk_ops
+                .visible_map_layer_in_tx(tx, self.tenant_id, inode_id, self.current_layer_id)
+                .await?
+                .is_some()
+        {
+            HashMap::new()
+        } else {
             block_ops
-                .create(CreateBlockInput {
-                    tenant_id: self.tenant_id,
-                    inode_id,
-                    block_index: index as i32,
-                    data: chunk.to_vec(),
-                })
+                .list_visible_in_tx(tx, self.tenant_id, inode_id, self.current_layer_id)
+                .await?
+                .into_iter()
+                .map(|block| (block.block_index, block.content_hash))
+                .collect()
+        };
+
+        let mut changed_blocks = Vec::new();
+        let mut block_count = 0;
+        let mut size = 0;
+        while let Some(chunk) = blocks.next().await {
+            let chunk = chunk?;
+            let block_index = block_count;
+            block_count += 1;
+            size += chunk.len();
+            match inherited.get(&block_index) {
+                Some(hash) if *hash == compute_content_hash(&chunk) => continue,
+                // A missing block reads as zeros, so holes take no space. Over
+                // inherited data the zeros must be stored to mask it, and so
+                // must every block replacing text, as whatever the text hid
+                // would show through the holes.
+                None if !from_text && chunk.iter().all(|&b| b == 0) => continue,
+                _ => {}
+            }
+            block_ops
+                .create_in_tx(
+                    tx,
+                    CreateBlockInput {
+                        tenant_id: self.tenant_id,
+                        inode_id,
+                        block_index,
+                        data: chunk,
+                        layer_id: Some(self.current_layer_id),
+                    },
+                )
                 .await?;
+            changed_blocks.push(block_index);
+        }
+
+        let block_changes = BlockChanges {
+            block_size: BLOCK_SIZE as i32,
+            block_count,
+            changed_blocks,
+            file_size: Some(size as i64),
+        };
+        debug!(
+            inode_id = inode_id,
+            block_count = block_changes.block_count,
+            changed = block_changes.changed_blocks.len(),
+            "Stored changed binary blocks"
+        );
+
+        if from_text && !is_new {
+            info!(inode_id = inode_id, layer_id = %self.current_layer_id, "File changed from text to binary");
         }
 
-        let size_delta = data.len() as i64 - old_size as i64;
+        let size_delta = size as i64 - old_size as i64;
         let change_type = if is_new { ChangeType::Add } else { ChangeType::Modify };
 
-        Ok(CowResult { change_type, size_delta, text_changes: None, is_text: false })
+        Ok(CowResult {
+            change_type,
+            size_delta,
+            text_changes: None,
+            block_changes: Some(block_changes),
+            is_text: false,
+        })
+    }
+
+    /// Write a binary file as content-defined chunks.
+    ///
+    /// The layer gets the file's full chunk map; only chunks the tenant has
+    /// not stored before take new space. `changed_blocks` lists the chunks
+    /// that were not part of the previously visible version.
+    async fn write_chunked_file(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        data: &[u8],
+        is_new: bool,
+        old_size: usize,
+    ) -> Result<CowResult> {
+        let block_ops = BlockOperations::new(self.pool);
+        let chunk_ops = ChunkOperations::new(self.pool);
+        let from_text = self.stored_as_text_in_tx(tx, inode_id).await?;
+
+        let previous: HashSet<String> = match chunk_ops
+            .visible_map_layer_in_tx(tx, self.tenant_id, inode_id, self.current_layer_id)
+            .await?
+        {
+            // A text version in between replaced every chunk
+            Some(_) if from_text => HashSet::new(),
+            Some(layer_id) => chunk_ops
+                .list_map_in_tx(tx, self.tenant_id, inode_id, layer_id)
+                .await?
+                .into_iter()
+                .map(|chunk| chunk.content_hash)
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        block_ops
+            .delete_layer_blocks_in_tx(tx, self.tenant_id, inode_id, self.current_layer_id)
+            .await?;
+        self.clear_text_in_tx(tx, inode_id).await?;
+        let chunks = content_defined_chunks(data);
+        let map = chunk_ops
+            .replace_map_in_tx(tx, self.tenant_id, inode_id, self.current_layer_id, &chunks)
+            .await?;
+
+        let changed_blocks = map
+            .iter()
+            .filter(|chunk| !previous.contains(&chunk.content_hash))
+            .map(|chunk| chunk.chunk_index)
+            .collect();
+        // Chunks vary in length, so no fixed block size is recorded
+        let block_changes = BlockChanges {
+            block_size: 0,
+            block_count: map.len() as i32,
+            changed_blocks,
+            file_size: Some(data.len() as i64),
+        };
+        debug!(
+            inode_id = inode_id,
+            chunk_count = block_changes.block_count,
+            changed = block_changes.changed_blocks.len(),
+            "Stored content-defined chunks"
+        );
+
+        if from_text && !is_new {
+            info!(inode_id = inode_id, layer_id = %self.current_layer_id, "File changed from text to binary");
+        }
+
+        let change_type = if is_new { ChangeType::Add } else { ChangeType::Modify };
+        Ok(CowResult {
+            change_type,
+            size_delta: data.len() as i64 - old_size as i64,
+            text_changes: None,
+            block_changes: Some(block_changes),
+            is_text: false,
+        })
     }
 
     /// Write a text file using line-level diff.
+    #[allow(clippy::too_many_arguments)]
     async fn write_text_file(
         &self,
+        tx: &mut DatabaseTransaction<'_>,
         inode_id: InodeId,
         data: &[u8],
         old_data: Option<&[u8]>,
@@ -154,61 +492,62 @@ impl<'a> CowHandler<'a> {
         let new_text = String::from_utf8_lossy(data);
         let old_text = old_data.map(|d| String::from_utf8_lossy(d).into_owned());
 
-        // Split into lines (preserving line endings for accurate diff)
-        let new_lines: Vec<&str> = new_text.lines().collect();
-        let old_lines: Vec<&str> =
-            old_text.as_ref().map(|t| t.lines().collect()).unwrap_or_default();
+        let new_lines = split_lines(&new_text);
+        let old_lines = old_text.as_deref().map(split_lines).unwrap_or_default();
 
         let is_new = old_data.is_none();
         let total_lines = new_lines.len() as i32;
+        let from_binary = !is_new && !self.stored_as_text_in_tx(tx, inode_id).await?;
+        if from_binary {
+            info!(inode_id = inode_id, layer_id = %self.current_layer_id, "File changed from binary to text");
+        }
 
-        // Calculate diff if not a new file
-        let text_changes = if is_new {
+        // Calculate diff if not a new file. Binary content has no lines to
+        // diff against, so after binary every line counts as added.
+        let text_changes = if is_new || from_binary {
+            let hunks = if total_lines > 0 {
+                vec![TextHunk { old_start: 1, old_lines: 0, new_start: 1, new_lines: total_lines }]
+            } else {
+                Vec::new()
+            };
             TextChanges {
                 lines_added: total_lines,
                 lines_deleted: 0,
                 lines_modified: 0,
                 total_lines,
+                hunks,
             }
         } else {
-            self.calculate_line_diff(&old_lines, &new_lines)
+            TextChanges::from_lines(&old_lines, &new_lines)
         };
 
-        // Delete old text file data if exists (for overwrites)
-        if !is_new {
-            // Delete old line mappings
-            sqlx::query(
-                "DELETE FROM text_line_map WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3"
-            )
-            .bind(self.tenant_id)
-            .bind(inode_id)
-            .bind(self.current_layer_id)
-            .execute(self.pool)
+        // Delete old line mappings and metadata. Done even for "new" files: a
+        // file written empty already has metadata but is treated as new by the
+        // caller.
+        self.clear_text_in_tx(tx, inode_id).await?;
+        // This layer's binary version, if it wrote one, is replaced too
+        BlockOperations::new(self.pool)
+            .delete_layer_blocks_in_tx(tx, self.tenant_id, inode_id, self.current_layer_id)
             .await?;
-
-            // Delete old metadata
-            sqlx::query(
-                "DELETE FROM text_file_metadata WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3"
-            )
-            .bind(self.tenant_id)
-            .bind(inode_id)
-            .bind(self.current_layer_id)
-            .execute(self.pool)
+        ChunkOperations::new(self.pool)
+            .delete_map_in_tx(tx, self.tenant_id, inode_id, self.current_layer_id)
             .await?;
-        }
 
         // Create text file metadata
-        let has_trailing_newline = new_text.ends_with('\n') || new_text.ends_with("\r\n");
+        let has_trailing_newline = new_text.ends_with('\n');
         text_ops
-            .create_metadata(CreateTextMetadataInput {
-                tenant_id: self.tenant_id,
-                inode_id,
-                layer_id: self.current_layer_id,
-                total_lines,
-                encoding: encoding.to_string(),
-                line_ending: line_ending.to_string(),
-                has_trailing_newline,
-            })
+            .create_metadata_in_tx(
+                tx,
+                CreateTextMetadataInput {
+                    tenant_id: self.tenant_id,
+                    inode_id,
+                    layer_id: self.current_layer_id,
+                    total_lines,
+                    encoding: encoding.to_string(),
+                    line_ending: line_ending.to_string(),
+                    has_trailing_newline,
+                },
+            )
             .await?;
 
         // Create text blocks and line mappings
@@ -216,19 +555,22 @@ impl<'a> CowHandler<'a> {
         for (line_num, line) in new_lines.iter().enumerate() {
             // Try to find existing block with same content
             let content_hash = compute_text_hash(line);
-            let block_id = match text_ops.get_block_by_hash(&content_hash).await? {
+            let block_id = match text_ops.get_block_by_hash_in_tx(tx, &content_hash).await? {
                 Some(existing) => {
                     // Reuse existing block, increment ref count
-                    text_ops.increment_ref_count(existing.block_id).await?;
+                    text_ops.increment_ref_count_in_tx(tx, existing.block_id).await?;
                     existing.block_id
                 }
                 None => {
                     // Create new block
                     let block = text_ops
-                        .create_block(CreateTextBlockInput {
-                            content: line.to_string(),
-                            encoding: encoding.to_string(),
-                        })
+                        .create_block_in_tx(
+                            tx,
+                            CreateTextBlockInput {
+                                content: line.to_string(),
+                                encoding: encoding.to_string(),
+                            },
+                        )
                         .await?;
                     block.block_id
                 }
@@ -239,44 +581,167 @@ impl<'a> CowHandler<'a> {
 
         // Store line mappings
         text_ops
-            .create_line_mappings(self.tenant_id, inode_id, self.current_layer_id, mappings)
+            .create_line_mappings_in_tx(
+                tx,
+                self.tenant_id,
+                inode_id,
+                self.current_layer_id,
+                mappings,
+            )
             .await?;
 
         let size_delta = data.len() as i64 - old_data.map(|d| d.len()).unwrap_or(0) as i64;
         let change_type = if is_new { ChangeType::Add } else { ChangeType::Modify };
 
-        Ok(CowResult { change_type, size_delta, text_changes: Some(text_changes), is_text: true })
+        Ok(CowResult {
+            change_type,
+            size_delta,
+            text_changes: Some(text_changes),
+            block_changes: None,
+            is_text: true,
+        })
     }
 
-    /// Calculate line-level diff between old and new content.
-    fn calculate_line_diff(&self, old_lines: &[&str], new_lines: &[&str]) -> TextChanges {
-        let diff = TextDiff::from_slices(old_lines, new_lines);
+    /// Bring `inode_id`'s text content from `text_layer_id` into the current
+    /// layer, for changes recorded here that don't rewrite it, like a rename.
+    /// Binary content needs no copy, as blocks are overlaid along the chain.
+    pub async fn copy_up_text_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        text_layer_id: LayerId,
+    ) -> Result<()> {
+        if text_layer_id == self.current_layer_id {
+            return Ok(());
+        }
+        TextBlockOperations::new(self.pool)
+            .copy_file_in_tx(
+                tx,
+                self.tenant_id,
+                inode_id,
+                text_layer_id,
+                inode_id,
+                self.current_layer_id,
+            )
+            .await?;
+        Ok(())
+    }
 
-        let mut lines_added: usize = 0;
-        let mut lines_deleted: usize = 0;
+    /// Give `dst_inode_id` the content of `src_inode_id` in the current layer,
+    /// replacing whatever the layer already held for it.
+    ///
+    /// Text lines and content-defined chunks are shared by reference, so the
+    /// copy costs only the metadata until either side is rewritten. Fixed-size
+    /// blocks have no shared storage and are duplicated inside the database.
+    /// `src_text_layer_id` is the layer holding the source's text content.
+    /// Returns whether the source was a text file.
+    pub async fn copy_file_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        src_inode_id: InodeId,
+        src_text_layer_id: LayerId,
+        dst_inode_id: InodeId,
+    ) -> Result<bool> {
+        BlockOperations::new(self.pool)
+            .delete_layer_blocks_in_tx(tx, self.tenant_id, dst_inode_id, self.current_layer_id)
+            .await?;
+        ChunkOperations::new(self.pool)
+            .delete_map_in_tx(tx, self.tenant_id, dst_inode_id, self.current_layer_id)
+            .await?;
+        self.clear_text_in_tx(tx, dst_inode_id).await?;
 
-        for change in diff.iter_all_changes() {
-            match change.tag() {
-                ChangeTag::Delete => lines_deleted += 1,
-                ChangeTag::Insert => lines_added += 1,
-                ChangeTag::Equal => {}
+        let text_ops = TextBlockOperations::new(self.pool);
+        if text_ops
+            .copy_file_in_tx(
+                tx,
+                self.tenant_id,
+                src_inode_id,
+                src_text_layer_id,
+                dst_inode_id,
+                self.current_layer_id,
+            )
+            .await?
+        {
+            return Ok(true);
+        }
+
+        let chunk_ops = ChunkOperations::new(self.pool);
+        match chunk_ops
+            .visible_map_layer_in_tx(tx, self.tenant_id, src_inode_id, self.current_layer_id)
+            .await?
+        {
+            Some(map_layer) => {
+                chunk_ops
+                    .copy_map_in_tx(
+                        tx,
+                        self.tenant_id,
+                        src_inode_id,
+                        map_layer,
+                        dst_inode_id,
+                        self.current_layer_id,
+                    )
+                    .await?;
+            }
+            None => {
+                BlockOperations::new(self.pool)
+                    .copy_visible_in_tx(
+                        tx,
+                        self.tenant_id,
+                        src_inode_id,
+                        self.current_layer_id,
+                        dst_inode_id,
+                    )
+                    .await?;
             }
         }
 
-        // Consider consecutive delete+insert pairs as modifications
-        // This is a simplification; a more sophisticated approach would
-        // track actual line positions
-        let modifications = lines_added.min(lines_deleted);
-        let lines_modified = modifications as i32;
-        let lines_added = (lines_added - modifications) as i32;
-        let lines_deleted = (lines_deleted - modifications) as i32;
+        Ok(false)
+    }
 
-        TextChanges {
-            lines_added,
-            lines_deleted,
-            lines_modified,
-            total_lines: new_lines.len() as i32,
-        }
+    /// Whether the version of `inode_id` visible from the current layer is
+    /// stored as text. Text lives whole in the layer that last changed the
+    /// file.
+    async fn stored_as_text_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+    ) -> Result<bool> {
+        let layer_id = LayerOperations::new(self.pool)
+            .last_change_in_chain_in_tx(tx, self.tenant_id, self.current_layer_id, inode_id)
+            .await?
+            .unwrap_or(self.current_layer_id);
+        Ok(TextBlockOperations::new(self.pool)
+            .get_metadata_in_tx(tx, self.tenant_id, inode_id, layer_id)
+            .await?
+            .is_some())
+    }
+
+    /// Drop the text representation this layer holds for `inode_id`, so it
+    /// doesn't shadow content written in another form.
+    async fn clear_text_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+    ) -> Result<()> {
+        sqlx::query(
+            "DELETE FROM text_line_map WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3",
+        )
+        .bind(self.tenant_id)
+        .bind(inode_id)
+        .bind(self.current_layer_id)
+        .execute(&mut **tx)
+        .await?;
+
+        sqlx::query(
+            "DELETE FROM text_file_metadata WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3"
+        )
+        .bind(self.tenant_id)
+        .bind(inode_id)
+        .bind(self.current_layer_id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
     }
 
     /// Read a text file by reconstructing from text blocks.
@@ -284,17 +749,33 @@ impl<'a> CowHandler<'a> {
         &self,
         inode_id: InodeId,
         layer_id: LayerId,
+    ) -> Result<Option<String>> {
+        let mut tx = begin_snapshot(self.pool).await?;
+        let content = self.read_text_file_in_tx(&mut tx, inode_id, layer_id).await?;
+        tx.commit().await?;
+        Ok(content)
+    }
+
+    /// Read a text file within a caller-managed transaction, so metadata,
+    /// line mappings and blocks all come from the same snapshot.
+    pub async fn read_text_file_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        layer_id: LayerId,
     ) -> Result<Option<String>> {
         let text_ops = TextBlockOperations::new(self.pool);
 
         // Get metadata
-        let metadata = match text_ops.get_metadata(self.tenant_id, inode_id, layer_id).await? {
-            Some(m) => m,
-            None => return Ok(None),
-        };
+        let metadata =
+            match text_ops.get_metadata_in_tx(tx, self.tenant_id, inode_id, layer_id).await? {
+                Some(m) => m,
+                None => return Ok(None),
+            };
 
         // Get line mappings
-        let mappings = text_ops.get_line_mappings(self.tenant_id, inode_id, layer_id).await?;
+        let mappings =
+            text_ops.get_line_mappings_in_tx(tx, self.tenant_id, inode_id, layer_id).await?;
 
         // Sort by line number
         let mut mappings = mappings;
@@ -303,21 +784,15 @@ impl<'a> CowHandler<'a> {
         // Reconstruct file content
         let mut lines = Vec::with_capacity(mappings.len());
         for mapping in mappings {
-            if let Some(block) = text_ops.get_block(mapping.block_id).await? {
+            if let Some(block) = text_ops.get_block_in_tx(tx, mapping.block_id).await? {
                 lines.push(block.content);
             }
         }
 
-        // Join with appropriate line ending
-        let line_ending = match metadata.line_ending.as_str() {
-            "crlf" => "\r\n",
-            "cr" => "\r",
-            _ => "\n",
-        };
-
-        let mut result = lines.join(line_ending);
-        if metadata.has_trailing_newline && !result.is_empty() {
-            result.push_str(line_ending);
+        // Lines keep any `\r` of their own, so `\n` restores them exactly
+        let mut result = lines.join("\n");
+        if metadata.has_trailing_newline && !lines.is_empty() {
+            result.push('\n');
         }
 
         Ok(Some(result))
@@ -341,6 +816,52 @@ impl<'a> CowHandler<'a> {
     }
 }
 
+/// Split text into the lines stored for it: at each `\n`, with the `\r` of a
+/// CRLF (or any other) ending left on the line so that mixed endings survive.
+/// A final `\n` ends the last line rather than starting an empty one.
+fn split_lines(text: &str) -> Vec<&str> {
+    let body = text.strip_suffix('\n').unwrap_or(text);
+    if text.is_empty() { Vec::new() } else { body.split('\n').collect() }
+}
+
+/// The `count` lines of `lines` starting at the 1-based `start`, clamped to
+/// the lines that exist.
+fn hunk_lines<'l>(lines: &'l [&'l str], start: i32, count: i32) -> &'l [&'l str] {
+    let from = (start.max(1) as usize - 1).min(lines.len());
+    let to = (from + count.max(0) as usize).min(lines.len());
+    &lines[from..to]
+}
+
+/// Merge adjacent non-equal diff ops into hunks.
+fn collect_hunks(ops: &[DiffOp]) -> Vec<TextHunk> {
+    let mut hunks: Vec<TextHunk> = Vec::new();
+    let mut open = false;
+
+    for op in ops {
+        if let DiffOp::Equal { .. } = op {
+            open = false;
+            continue;
+        }
+
+        let (old_range, new_range) = (op.old_range(), op.new_range());
+        match hunks.last_mut() {
+            Some(hunk) if open => {
+                hunk.old_lines += old_range.len() as i32;
+                hunk.new_lines += new_range.len() as i32;
+            }
+            _ => hunks.push(TextHunk {
+                old_start: old_range.start as i32 + 1,
+                old_lines: old_range.len() as i32,
+                new_start: new_range.start as i32 + 1,
+                new_lines: new_range.len() as i32,
+            }),
+        }
+        open = true;
+    }
+
+    hunks
+}
+
 /// Compute a hash for text content.
 fn compute_text_hash(content: &str) -> String {
     use std::collections::hash_map::DefaultHasher;
@@ -379,8 +900,13 @@ mod tests {
 
     #[test]
     fn test_text_changes_to_json() {
-        let changes =
-            TextChanges { lines_added: 5, lines_deleted: 2, lines_modified: 3, total_lines: 100 };
+        let changes = TextChanges {
+            lines_added: 5,
+            lines_deleted: 2,
+            lines_modified: 3,
+            total_lines: 100,
+            hunks: Vec::new(),
+        };
         let json = changes.to_json();
         assert_eq!(json["lines_added"], 5);
         assert_eq!(json["lines_deleted"], 2);
@@ -388,10 +914,72 @@ mod tests {
         assert_eq!(json["total_lines"], 100);
     }
 
+    #[test]
+    fn test_collect_hunks_line_ranges() {
+        let old = ["a", "b", "c", "d", "e"];
+        let new = ["a", "B", "c", "e", "f", "g"];
+        let diff = TextDiff::from_slices(&old, &new);
+        let hunks = collect_hunks(diff.ops());
+
+        assert_eq!(
+            hunks,
+            vec![
+                TextHunk { old_start: 2, old_lines: 1, new_start: 2, new_lines: 1 },
+                TextHunk { old_start: 4, old_lines: 1, new_start: 4, new_lines: 0 },
+                TextHunk { old_start: 6, old_lines: 0, new_start: 5, new_lines: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_lines_keeps_carriage_returns() {
+        assert_eq!(split_lines("a\r\nb\nc\r\n"), vec!["a\r", "b", "c\r"]);
+        assert_eq!(split_lines("a\n\nb"), vec!["a", "", "b"]);
+        assert_eq!(split_lines("\n"), vec![""]);
+        assert!(split_lines("").is_empty());
+    }
+
+    #[test]
+    fn test_render_unified_hunks() {
+        let old = "a\nb\nc\r\nd\n";
+        let new = "a\nB\nc\r\nd\ne\n";
+        let changes = TextChanges::between(old, new);
+        assert_eq!(
+            changes.render_unified(old, new),
+            "@@ -2,1 +2,1 @@\n-b\n+B\n@@ -4,0 +5,1 @@\n+e\n"
+        );
+
+        let removed = TextChanges::between("a\nb\n", "b\n");
+        assert_eq!(removed.render_unified("a\nb\n", "b\n"), "@@ -1,1 +0,0 @@\n-a\n");
+    }
+
+    #[test]
+    fn test_text_changes_json_roundtrip() {
+        let changes = TextChanges {
+            lines_added: 1,
+            lines_deleted: 0,
+            lines_modified: 0,
+            total_lines: 3,
+            hunks: vec![TextHunk { old_start: 3, old_lines: 0, new_start: 3, new_lines: 1 }],
+        };
+        assert_eq!(TextChanges::from_json(&changes.to_json()), Some(changes));
+
+        // Entries written before hunks were tracked still parse
+        let legacy = serde_json::json!({
+            "lines_added": 1, "lines_deleted": 0, "lines_modified": 0, "total_lines": 1
+        });
+        assert!(TextChanges::from_json(&legacy).unwrap().hunks.is_empty());
+    }
+
     #[test]
     fn test_text_changes_zeros() {
-        let changes =
-            TextChanges { lines_added: 0, lines_deleted: 0, lines_modified: 0, total_lines: 0 };
+        let changes = TextChanges {
+            lines_added: 0,
+            lines_deleted: 0,
+            lines_modified: 0,
+            total_lines: 0,
+            hunks: Vec::new(),
+        };
         assert_eq!(changes.lines_added, 0);
         assert_eq!(changes.lines_deleted, 0);
         assert_eq!(changes.lines_modified, 0);
@@ -400,8 +988,13 @@ mod tests {
 
     #[test]
     fn test_text_changes_to_json_zeros() {
-        let changes =
-            TextChanges { lines_added: 0, lines_deleted: 0, lines_modified: 0, total_lines: 0 };
+        let changes = TextChanges {
+            lines_added: 0,
+            lines_deleted: 0,
+            lines_modified: 0,
+            total_lines: 0,
+            hunks: Vec::new(),
+        };
         let json = changes.to_json();
         assert_eq!(json["lines_added"], 0);
         assert_eq!(json["lines_deleted"], 0);
@@ -416,6 +1009,7 @@ mod tests {
             lines_deleted: 50000,
             lines_modified: 25000,
             total_lines: 1000000,
+            hunks: Vec::new(),
         };
         let json = changes.to_json();
         assert_eq!(json["lines_added"], 100000);
@@ -532,12 +1126,32 @@ mod tests {
         assert_ne!(hash1, hash3);
     }
 
+    #[test]
+    fn test_block_changes_json_roundtrip() {
+        let changes = BlockChanges {
+            block_size: 4096,
+            block_count: 256,
+            changed_blocks: vec![3, 7],
+            file_size: Some(1_048_000),
+        };
+        let json = changes.to_json();
+        assert_eq!(json["changed_blocks"], serde_json::json!([3, 7]));
+        assert_eq!(json["file_size"], 1_048_000);
+        assert_eq!(BlockChanges::from_json(&json), Some(changes));
+        // Entries recorded before sizes were tracked still parse
+        let legacy =
+            serde_json::json!({"block_size": 4096, "block_count": 1, "changed_blocks": []});
+        assert_eq!(BlockChanges::from_json(&legacy).and_then(|c| c.file_size), None);
+        assert_eq!(BlockChanges::from_json(&serde_json::json!({"block_size": "x"})), None);
+    }
+
     #[test]
     fn test_cow_result_binary() {
         let result = CowResult {
             change_type: crate::storage::ChangeType::Add,
             size_delta: 1024,
             text_changes: None,
+            block_changes: None,
             is_text: false,
         };
         assert!(result.text_changes.is_none());
@@ -547,12 +1161,18 @@ mod tests {
 
     #[test]
     fn test_cow_result_text() {
-        let changes =
-            TextChanges { lines_added: 10, lines_deleted: 5, lines_modified: 3, total_lines: 50 };
+        let changes = TextChanges {
+            lines_added: 10,
+            lines_deleted: 5,
+            lines_modified: 3,
+            total_lines: 50,
+            hunks: Vec::new(),
+        };
         let result = CowResult {
             change_type: crate::storage::ChangeType::Modify,
             size_delta: 100,
             text_changes: Some(changes),
+            block_changes: None,
             is_text: true,
         };
         assert!(result.text_changes.is_some());
@@ -103,6 +103,57 @@ impl std::fmt::Display for LineEnding {
     }
 }
 
+impl std::str::FromStr for LineEnding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::CrLf),
+            "cr" => Ok(LineEnding::Cr),
+            _ => Err(format!("Invalid line ending: {}", s)),
+        }
+    }
+}
+
+impl LineEnding {
+    /// Returns the byte sequence for this line ending, if it is a concrete style.
+    pub fn as_bytes(&self) -> Option<&'static [u8]> {
+        match self {
+            LineEnding::Lf => Some(b"\n"),
+            LineEnding::CrLf => Some(b"\r\n"),
+            LineEnding::Cr => Some(b"\r"),
+            LineEnding::Mixed | LineEnding::None => None,
+        }
+    }
+
+    /// Convert every line ending in `data` (LF, CRLF or CR) to this style.
+    ///
+    /// `Mixed` and `None` are not concrete styles, so data is returned unchanged.
+    pub fn convert(&self, data: &[u8]) -> Vec<u8> {
+        let Some(eol) = self.as_bytes() else {
+            return data.to_vec();
+        };
+
+        let mut result = Vec::with_capacity(data.len());
+        let mut i = 0;
+        while i < data.len() {
+            match data[i] {
+                b'\r' => {
+                    result.extend_from_slice(eol);
+                    if data.get(i + 1) == Some(&b'\n') {
+                        i += 1;
+                    }
+                }
+                b'\n' => result.extend_from_slice(eol),
+                b => result.push(b),
+            }
+            i += 1;
+        }
+        result
+    }
+}
+
 /// File type detector.
 pub struct FileTypeDetector {
     config: DetectionConfig,
@@ -301,6 +352,23 @@ impl Default for FileTypeDetector {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_line_ending_from_str() {
+        assert_eq!("lf".parse::<LineEnding>().unwrap(), LineEnding::Lf);
+        assert_eq!("CRLF".parse::<LineEnding>().unwrap(), LineEnding::CrLf);
+        assert_eq!("cr".parse::<LineEnding>().unwrap(), LineEnding::Cr);
+        assert!("mixed".parse::<LineEnding>().is_err());
+    }
+
+    #[test]
+    fn test_line_ending_convert() {
+        assert_eq!(LineEnding::CrLf.convert(b"a\nb\n"), b"a\r\nb\r\n");
+        assert_eq!(LineEnding::Lf.convert(b"a\r\nb\rc"), b"a\nb\nc");
+        assert_eq!(LineEnding::Cr.convert(b"a\r\nb\n"), b"a\rb\r");
+        assert_eq!(LineEnding::CrLf.convert(b"a\r\n"), b"a\r\n");
+        assert_eq!(LineEnding::Mixed.convert(b"a\nb\r\n"), b"a\nb\r\n");
+    }
+
     #[test]
     fn test_detect_empty_file() {
         let detector = FileTypeDetector::new();
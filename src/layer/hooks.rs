@@ -3,16 +3,28 @@
 //! Provides virtual filesystem interface at `/.tarbox/` for layer management.
 //! Users can control layers through standard file operations.
 
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 
-use crate::layer::manager::{LayerManager, LayerManagerError};
-use crate::storage::Layer;
-use crate::types::TenantId;
+use crate::config::LayerConfig;
+use crate::fs::FsError;
+use crate::fs::handles::HandleTable;
+use crate::fs::operations::FileSystem;
+use crate::fs::path::normalize_path;
+use crate::layer::cow::TextChanges;
+use crate::layer::detection::{FileTypeDetector, FileTypeInfo};
+use crate::layer::manager::{DropPlan, LayerManager, LayerManagerError};
+use crate::storage::{ChangeType, Layer, UsageOperations, WriteSession};
+use crate::types::{LayerId, TenantId};
 
 /// The base path for tarbox hooks.
 pub const TARBOX_HOOK_PATH: &str = "/.tarbox";
 
+/// Suffix of a diff hook path asking for the changed lines of text files.
+const UNIFIED_SUFFIX: &str = "?unified=true";
+
 /// Virtual file paths under /.tarbox/
 pub mod paths {
     pub const LAYERS: &str = "/.tarbox/layers";
@@ -21,18 +33,32 @@ pub mod paths {
     pub const LAYERS_NEW: &str = "/.tarbox/layers/new";
     pub const LAYERS_SWITCH: &str = "/.tarbox/layers/switch";
     pub const LAYERS_DROP: &str = "/.tarbox/layers/drop";
+    pub const LAYERS_ROLLBACK: &str = "/.tarbox/layers/rollback";
+    pub const LAYERS_SQUASH: &str = "/.tarbox/layers/squash";
+    pub const LAYERS_RENAME: &str = "/.tarbox/layers/rename";
+    pub const LAYERS_TAG: &str = "/.tarbox/layers/tag";
     pub const LAYERS_TREE: &str = "/.tarbox/layers/tree";
     pub const LAYERS_DIFF: &str = "/.tarbox/layers/diff";
+    pub const LAYERS_DIFF_UNIFIED: &str = "/.tarbox/layers/diff?unified=true";
+    pub const LAYERS_CHANGES: &str = "/.tarbox/layers/changes";
     pub const SNAPSHOTS: &str = "/.tarbox/snapshots";
     pub const STATS: &str = "/.tarbox/stats";
     pub const STATS_USAGE: &str = "/.tarbox/stats/usage";
+    pub const REFRESH: &str = "/.tarbox/refresh";
+    pub const GC: &str = "/.tarbox/gc";
+    pub const HANDLES: &str = "/.tarbox/handles";
+    pub const STORAGE: &str = "/.tarbox/storage";
 }
 
 /// Result of a hook operation.
 #[derive(Debug)]
 pub enum HookResult {
-    /// Read operation result with content.
+    /// Read operation result with plain text content.
     Content(String),
+    /// Read operation result with structured data.
+    Json(serde_json::Value),
+    /// Entries of a hook directory.
+    DirListing(Vec<HookDirEntry>),
     /// Write operation completed successfully.
     WriteSuccess { message: String },
     /// Error occurred.
@@ -41,6 +67,39 @@ pub enum HookResult {
     NotAHook,
 }
 
+impl HookResult {
+    /// Text a reader of the hook file sees: JSON is pretty-printed and
+    /// listings have one name per line. `None` for errors and non-hooks.
+    pub fn render(self) -> Option<String> {
+        match self {
+            HookResult::Content(text) => Some(text),
+            HookResult::Json(value) => Some(format!("{:#}", value)),
+            HookResult::DirListing(entries) => {
+                Some(entries.into_iter().map(|e| e.name).collect::<Vec<_>>().join("\n"))
+            }
+            HookResult::WriteSuccess { message } => Some(message),
+            HookResult::Error(_) | HookResult::NotAHook => None,
+        }
+    }
+}
+
+/// An entry of a hook directory listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookDirEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+impl HookDirEntry {
+    fn dir(name: impl Into<String>) -> Self {
+        Self { name: name.into(), is_dir: true }
+    }
+
+    fn file(name: impl Into<String>) -> Self {
+        Self { name: name.into(), is_dir: false }
+    }
+}
+
 /// Hook-specific errors.
 #[derive(Debug, thiserror::Error)]
 pub enum HookError {
@@ -82,6 +141,30 @@ pub struct DropLayerInput {
     pub layer: String,
     #[serde(default)]
     pub force: bool,
+    /// Report what would be deleted instead of deleting it
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Input for squashing a range of layers.
+#[derive(Debug, Deserialize)]
+pub struct SquashLayerInput {
+    pub from: String,
+    pub into: String,
+}
+
+/// Input for renaming a layer.
+#[derive(Debug, Deserialize)]
+pub struct RenameLayerInput {
+    pub layer: String,
+    pub name: String,
+}
+
+/// Input for replacing a layer's tags.
+#[derive(Debug, Deserialize)]
+pub struct TagLayerInput {
+    pub layer: String,
+    pub tags: Vec<String>,
 }
 
 /// Layer info for JSON output.
@@ -96,6 +179,10 @@ pub struct LayerInfo {
     pub file_count: i32,
     pub total_size: i64,
     pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// Layers from this one down to the base; only reported for the current layer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_depth: Option<usize>,
 }
 
 impl LayerInfo {
@@ -110,49 +197,127 @@ impl LayerInfo {
             file_count: layer.file_count,
             total_size: layer.total_size,
             description: layer.description.clone(),
+            tags: LayerManager::tags_of(layer),
+            chain_depth: None,
         }
     }
 }
 
+/// Open file handle info for JSON output.
+#[derive(Debug, Serialize)]
+pub struct OpenHandleInfo {
+    pub handle: u64,
+    pub path: String,
+    pub flags: i32,
+    pub offset: u64,
+    pub uid: u32,
+}
+
 /// Filesystem hooks handler.
 pub struct HooksHandler<'a> {
     pool: &'a PgPool,
+    /// Pool for read-only hooks such as diffs; the primary unless a replica is set.
+    read_pool: &'a PgPool,
+    session: WriteSession,
     tenant_id: TenantId,
+    /// Open handles of the mount, reported by `/.tarbox/handles`.
+    handles: Option<Arc<HandleTable>>,
+    /// Chain depth limit for checkpoints created through `/.tarbox/layers/new`.
+    layer_config: LayerConfig,
 }
 
 impl<'a> HooksHandler<'a> {
     /// Create a new hooks handler.
     pub fn new(pool: &'a PgPool, tenant_id: TenantId) -> Self {
-        Self { pool, tenant_id }
+        Self {
+            pool,
+            read_pool: pool,
+            session: WriteSession::new(),
+            tenant_id,
+            handles: None,
+            layer_config: LayerConfig::default(),
+        }
+    }
+
+    /// Check checkpoints against the chain depth limit of `config`.
+    pub fn with_layer_config(mut self, config: &LayerConfig) -> Self {
+        self.layer_config = config.clone();
+        self
+    }
+
+    /// Serve read-only hooks from `read_pool` until this session writes.
+    pub fn with_read_pool(mut self, read_pool: &'a PgPool) -> Self {
+        self.read_pool = read_pool;
+        self
+    }
+
+    /// Share read-your-writes state with the filesystem of the same session.
+    pub fn with_write_session(mut self, session: WriteSession) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Report the open handles of `handles` under `/.tarbox/handles`.
+    pub fn with_handles(mut self, handles: Arc<HandleTable>) -> Self {
+        self.handles = Some(handles);
+        self
+    }
+
+    fn reader(&self) -> &'a PgPool {
+        if self.session.has_written() { self.pool } else { self.read_pool }
     }
 
     /// Check if a path is a hook path.
     pub fn is_hook_path(path: &str) -> bool {
-        path.starts_with(TARBOX_HOOK_PATH)
+        Self::canonical_hook_path(path).is_some()
+    }
+
+    /// `path` in canonical form, if it names something under `/.tarbox`.
+    ///
+    /// Paths are classified only after normalization, so a detour such as
+    /// `/data/../.tarbox/layers/drop` still reaches the hook while
+    /// `/.tarbox/../data` is an ordinary file.
+    pub fn canonical_hook_path(path: &str) -> Option<String> {
+        let path = normalize_path(path).ok()?;
+        let below = path.strip_prefix(TARBOX_HOOK_PATH)?;
+        (below.is_empty() || below.starts_with('/')).then_some(path)
     }
 
     /// Handle a read operation on a hook path.
     pub async fn handle_read(&self, path: &str) -> HookResult {
-        if !Self::is_hook_path(path) {
+        let Some(path) = Self::canonical_hook_path(path) else {
             return HookResult::NotAHook;
-        }
+        };
+        let path = path.as_str();
 
         match path {
             paths::LAYERS_CURRENT => self.read_current_layer().await,
             paths::LAYERS_LIST => self.read_layer_list().await,
             paths::LAYERS_TREE => self.read_layer_tree().await,
-            paths::LAYERS_DIFF => self.read_current_diff().await,
+            paths::LAYERS_DIFF => self.read_current_diff(false).await,
+            paths::LAYERS_DIFF_UNIFIED => self.read_current_diff(true).await,
             paths::STATS_USAGE => self.read_stats_usage().await,
+            paths::HANDLES => self.read_open_handles(),
+            _ if path.starts_with(&format!("{}/", paths::LAYERS_DIFF)) => {
+                self.read_layers_diff(path).await
+            }
+            _ if path.starts_with(paths::LAYERS_CHANGES) => self.read_text_changes(path).await,
             _ if path.starts_with(paths::SNAPSHOTS) => self.handle_snapshot_read(path).await,
+            _ if path.starts_with(&format!("{}/", paths::STORAGE)) => {
+                self.read_storage_mode(path).await
+            }
             _ => HookResult::Error(HookError::InvalidPath(path.to_string())),
         }
     }
 
     /// Handle a write operation on a hook path.
     pub async fn handle_write(&self, path: &str, data: &[u8]) -> HookResult {
-        if !Self::is_hook_path(path) {
+        let Some(path) = Self::canonical_hook_path(path) else {
             return HookResult::NotAHook;
-        }
+        };
+        let path = path.as_str();
+
+        self.session.mark_written();
 
         let input = match std::str::from_utf8(data) {
             Ok(s) => s.trim(),
@@ -167,6 +332,15 @@ impl<'a> HooksHandler<'a> {
             paths::LAYERS_NEW => self.write_new_layer(input).await,
             paths::LAYERS_SWITCH => self.write_switch_layer(input).await,
             paths::LAYERS_DROP => self.write_drop_layer(input).await,
+            paths::LAYERS_ROLLBACK => self.write_rollback(input).await,
+            paths::LAYERS_SQUASH => self.write_squash_layers(input).await,
+            paths::LAYERS_RENAME => self.write_rename_layer(input).await,
+            paths::LAYERS_TAG => self.write_tag_layer(input).await,
+            paths::GC => self.write_gc().await,
+            // Nothing to do in the database; the mount drops its caches
+            paths::REFRESH => {
+                HookResult::WriteSuccess { message: "Cached paths invalidated".to_string() }
+            }
             _ => {
                 HookResult::Error(HookError::PermissionDenied(format!("Cannot write to {}", path)))
             }
@@ -175,9 +349,8 @@ impl<'a> HooksHandler<'a> {
 
     /// Handle getattr for hook paths.
     pub fn get_attr(&self, path: &str) -> Option<HookFileAttr> {
-        if !Self::is_hook_path(path) {
-            return None;
-        }
+        let path = Self::canonical_hook_path(path)?;
+        let path = path.as_str();
 
         match path {
             TARBOX_HOOK_PATH => Some(HookFileAttr::directory()),
@@ -187,11 +360,36 @@ impl<'a> HooksHandler<'a> {
             paths::LAYERS_NEW => Some(HookFileAttr::writeonly_file()),
             paths::LAYERS_SWITCH => Some(HookFileAttr::writeonly_file()),
             paths::LAYERS_DROP => Some(HookFileAttr::writeonly_file()),
+            paths::LAYERS_ROLLBACK => Some(HookFileAttr::writeonly_file()),
+            paths::LAYERS_SQUASH => Some(HookFileAttr::writeonly_file()),
+            paths::LAYERS_RENAME => Some(HookFileAttr::writeonly_file()),
+            paths::LAYERS_TAG => Some(HookFileAttr::writeonly_file()),
             paths::LAYERS_TREE => Some(HookFileAttr::readonly_file()),
             paths::LAYERS_DIFF => Some(HookFileAttr::readonly_file()),
+            paths::LAYERS_DIFF_UNIFIED => Some(HookFileAttr::readonly_file()),
+            _ if path.starts_with(&format!("{}/", paths::LAYERS_DIFF)) => {
+                Some(HookFileAttr::readonly_file())
+            }
+            paths::LAYERS_CHANGES => Some(HookFileAttr::directory()),
+            _ if path.starts_with(paths::LAYERS_CHANGES) => {
+                // /.tarbox/layers/changes/<layer> is a directory, anything below is a file
+                let rest = path[paths::LAYERS_CHANGES.len()..].trim_start_matches('/');
+                if rest.contains('/') {
+                    Some(HookFileAttr::readonly_file())
+                } else {
+                    Some(HookFileAttr::directory())
+                }
+            }
             paths::SNAPSHOTS => Some(HookFileAttr::directory()),
             paths::STATS => Some(HookFileAttr::directory()),
             paths::STATS_USAGE => Some(HookFileAttr::readonly_file()),
+            paths::REFRESH => Some(HookFileAttr::writeonly_file()),
+            paths::GC => Some(HookFileAttr::writeonly_file()),
+            paths::HANDLES => Some(HookFileAttr::readonly_file()),
+            paths::STORAGE => Some(HookFileAttr::directory()),
+            _ if path.starts_with(&format!("{}/", paths::STORAGE)) => {
+                Some(HookFileAttr::readonly_file())
+            }
             _ if path.starts_with(paths::SNAPSHOTS) => Some(HookFileAttr::directory()),
             _ => None,
         }
@@ -199,29 +397,49 @@ impl<'a> HooksHandler<'a> {
 
     /// List directory contents for hook paths.
     pub async fn read_dir(&self, path: &str) -> HookResult {
-        if !Self::is_hook_path(path) {
+        let Some(path) = Self::canonical_hook_path(path) else {
             return HookResult::NotAHook;
-        }
+        };
+        let path = path.as_str();
 
         let entries = match path {
-            TARBOX_HOOK_PATH => vec!["layers", "snapshots", "stats"],
-            paths::LAYERS => vec!["current", "list", "new", "switch", "drop", "tree", "diff"],
-            paths::SNAPSHOTS => {
-                // List all layers as snapshot directories
+            TARBOX_HOOK_PATH => vec![
+                HookDirEntry::dir("layers"),
+                HookDirEntry::dir("snapshots"),
+                HookDirEntry::dir("stats"),
+                HookDirEntry::dir("storage"),
+                HookDirEntry::file("refresh"),
+                HookDirEntry::file("handles"),
+                HookDirEntry::file("gc"),
+            ],
+            paths::LAYERS => {
+                let mut entries: Vec<HookDirEntry> = [
+                    "current", "list", "new", "switch", "drop", "rollback", "squash", "rename",
+                    "tag", "tree", "diff",
+                ]
+                .into_iter()
+                .map(HookDirEntry::file)
+                .collect();
+                entries.push(HookDirEntry::dir("changes"));
+                entries
+            }
+            paths::SNAPSHOTS | paths::LAYERS_CHANGES => {
+                // Every layer is a directory
                 let manager = LayerManager::new(self.pool, self.tenant_id);
-                let layer_names: Vec<String> = match manager.list_layers().await {
-                    Ok(layers) => layers.iter().map(|l| l.layer_name.clone()).collect(),
+                match manager.list_layers().await {
+                    Ok(layers) => {
+                        layers.into_iter().map(|l| HookDirEntry::dir(l.layer_name)).collect()
+                    }
                     Err(_) => vec![],
-                };
-                let output = layer_names.join("\n");
-                return HookResult::Content(output);
+                }
             }
-            paths::STATS => vec!["usage"],
+            paths::STATS => vec![HookDirEntry::file("usage")],
+            // Entries are looked up by path; there are too many to list
+            paths::STORAGE => vec![],
             _ => return HookResult::Error(HookError::InvalidPath(path.to_string())),
         };
 
-        let output = entries.join("\n");
-        HookResult::Content(output)
+        HookResult::DirListing(entries)
     }
 
     // --- Read handlers ---
@@ -231,9 +449,13 @@ impl<'a> HooksHandler<'a> {
 
         match manager.get_current_layer().await {
             Ok(layer) => {
-                let info = LayerInfo::from_layer(&layer, true);
-                match serde_json::to_string_pretty(&info) {
-                    Ok(json) => HookResult::Content(json),
+                let mut info = LayerInfo::from_layer(&layer, true);
+                match manager.chain_depth(layer.layer_id).await {
+                    Ok(depth) => info.chain_depth = Some(depth),
+                    Err(e) => return HookResult::Error(HookError::LayerError(e)),
+                }
+                match serde_json::to_value(&info) {
+                    Ok(value) => HookResult::Json(value),
                     Err(e) => HookResult::Error(HookError::Internal(e.to_string())),
                 }
             }
@@ -258,8 +480,8 @@ impl<'a> HooksHandler<'a> {
                     })
                     .collect();
 
-                match serde_json::to_string_pretty(&infos) {
-                    Ok(json) => HookResult::Content(json),
+                match serde_json::to_value(&infos) {
+                    Ok(value) => HookResult::Json(value),
                     Err(e) => HookResult::Error(HookError::Internal(e.to_string())),
                 }
             }
@@ -298,7 +520,10 @@ impl<'a> HooksHandler<'a> {
         }
     }
 
-    async fn read_current_diff(&self) -> HookResult {
+    /// Read `/.tarbox/layers/diff`: the files the current layer changed.
+    /// With `unified`, modified text files are followed by their changed
+    /// lines against the parent layer.
+    async fn read_current_diff(&self, unified: bool) -> HookResult {
         let manager = LayerManager::new(self.pool, self.tenant_id);
 
         match manager.get_current_layer().await {
@@ -313,12 +538,25 @@ impl<'a> HooksHandler<'a> {
                 output.push_str(&format!("Changes: {} files\n\n", entries.len()));
 
                 for entry in entries {
-                    let change_char = match entry.change_type {
-                        crate::storage::ChangeType::Add => 'A',
-                        crate::storage::ChangeType::Modify => 'M',
-                        crate::storage::ChangeType::Delete => 'D',
-                    };
-                    output.push_str(&format!("{}  {}\n", change_char, entry.path));
+                    output.push_str(&format!(
+                        "{}  {}\n",
+                        change_char(entry.change_type),
+                        entry.path
+                    ));
+                    if unified
+                        && entry.change_type == ChangeType::Modify
+                        && entry.text_changes.is_some()
+                        && let Err(e) = self
+                            .push_unified(
+                                &mut output,
+                                &entry.path,
+                                layer.parent_layer_id,
+                                layer.layer_id,
+                            )
+                            .await
+                    {
+                        return HookResult::Error(e);
+                    }
                 }
 
                 HookResult::Content(output)
@@ -330,27 +568,205 @@ impl<'a> HooksHandler<'a> {
         }
     }
 
+    /// Read `/.tarbox/layers/diff/<from>..<to>`: files that differ between
+    /// two layers, in the same format as the current layer's diff, including
+    /// changed lines when the path ends in `?unified=true`.
+    async fn read_layers_diff(&self, path: &str) -> HookResult {
+        let spec = &path[paths::LAYERS_DIFF.len() + 1..];
+        let (spec, unified) = match spec.strip_suffix(UNIFIED_SUFFIX) {
+            Some(spec) => (spec, true),
+            None => (spec, false),
+        };
+        let (from_ref, to_ref) = match spec.split_once("..") {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() && !to.contains('/') => {
+                (from, to)
+            }
+            _ => {
+                return HookResult::Error(HookError::InvalidPath(format!(
+                    "Expected {}/<from>..<to>",
+                    paths::LAYERS_DIFF
+                )));
+            }
+        };
+
+        let manager = LayerManager::new(self.reader(), self.tenant_id);
+        let (from_id, to_id) = match (
+            self.resolve_layer_ref(&manager, from_ref).await,
+            self.resolve_layer_ref(&manager, to_ref).await,
+        ) {
+            (Ok(from), Ok(to)) => (from, to),
+            (Err(e), _) | (_, Err(e)) => return HookResult::Error(e),
+        };
+
+        let changes = match manager.diff_layers(from_id, to_id).await {
+            Ok(c) => c,
+            Err(e) => return HookResult::Error(HookError::LayerError(e)),
+        };
+
+        let mut output = String::new();
+        output.push_str(&format!("Diff: {} ({}) .. {} ({})\n", from_ref, from_id, to_ref, to_id));
+        output.push_str(&format!("Changes: {} files\n\n", changes.len()));
+        for (file_path, change_type) in changes {
+            output.push_str(&format!("{}  {}\n", change_char(change_type), file_path));
+            if unified
+                && change_type == ChangeType::Modify
+                && let Err(e) =
+                    self.push_unified(&mut output, &file_path, Some(from_id), to_id).await
+            {
+                return HookResult::Error(e);
+            }
+        }
+
+        HookResult::Content(output)
+    }
+
+    /// Append a unified diff of `file_path` as layer `from` and layer `to`
+    /// see it. Files stored as binary on either side are left out, as are
+    /// files `from` doesn't have, which a missing parent implies.
+    async fn push_unified(
+        &self,
+        output: &mut String,
+        file_path: &str,
+        from: Option<LayerId>,
+        to: LayerId,
+    ) -> Result<(), HookError> {
+        let Some(from) = from else { return Ok(()) };
+        let fs = FileSystem::new(self.pool, self.tenant_id)
+            .await
+            .map_err(|e| HookError::Internal(e.to_string()))?
+            .with_read_pool(self.reader());
+        let (Some(old), Some(new)) = (
+            read_text_at_layer(&fs, file_path, from).await?,
+            read_text_at_layer(&fs, file_path, to).await?,
+        ) else {
+            return Ok(());
+        };
+        output.push_str(&format!("--- a{}\n+++ b{}\n", file_path, file_path));
+        output.push_str(&TextChanges::between(&old, &new).render_unified(&old, &new));
+        Ok(())
+    }
+
+    /// Read `/.tarbox/layers/changes/<layer>/<path>`: the line-level changes
+    /// recorded for a file in a layer, as JSON.
+    async fn read_text_changes(&self, path: &str) -> HookResult {
+        let rest = path[paths::LAYERS_CHANGES.len()..].trim_start_matches('/');
+        let (layer_ref, file_path) = match rest.split_once('/') {
+            Some((layer_ref, file_path)) if !layer_ref.is_empty() && !file_path.is_empty() => {
+                (layer_ref, format!("/{}", file_path))
+            }
+            _ => {
+                return HookResult::Error(HookError::InvalidPath(format!(
+                    "Expected {}/<layer>/<path>",
+                    paths::LAYERS_CHANGES
+                )));
+            }
+        };
+
+        let manager = LayerManager::new(self.pool, self.tenant_id);
+        let layer_id = match self.resolve_layer_ref(&manager, layer_ref).await {
+            Ok(id) => id,
+            Err(e) => return HookResult::Error(e),
+        };
+
+        match manager.get_text_changes(layer_id, &file_path).await {
+            Ok(Some(changes)) => match serde_json::to_value(&changes) {
+                Ok(value) => HookResult::Json(value),
+                Err(e) => HookResult::Error(HookError::Internal(e.to_string())),
+            },
+            Ok(None) => HookResult::Error(HookError::InvalidPath(format!(
+                "No text changes for {} in layer {}",
+                file_path, layer_ref
+            ))),
+            Err(e) => HookResult::Error(HookError::LayerError(e)),
+        }
+    }
+
     async fn read_stats_usage(&self) -> HookResult {
         let manager = LayerManager::new(self.pool, self.tenant_id);
 
-        match manager.list_layers().await {
-            Ok(layers) => {
-                let total_size: i64 = layers.iter().map(|l| l.total_size).sum();
-                let total_files: i32 = layers.iter().map(|l| l.file_count).sum();
-
-                let stats = serde_json::json!({
-                    "layer_count": layers.len(),
-                    "total_size": total_size,
-                    "total_files": total_files,
-                    "tenant_id": self.tenant_id.to_string(),
-                });
+        let layers = match manager.list_layers().await {
+            Ok(layers) => layers,
+            Err(e) => return HookResult::Error(HookError::LayerError(e)),
+        };
+        let usage = UsageOperations::new(self.read_pool);
+        let tenant_ids = [self.tenant_id];
+        let (own, tenant) = match tokio::try_join!(
+            usage.list_layer_usage(self.tenant_id),
+            usage.list_tenant_usage(Some(&tenant_ids)),
+        ) {
+            Ok(usage) => usage,
+            Err(e) => return HookResult::Error(HookError::Internal(e.to_string())),
+        };
 
-                match serde_json::to_string_pretty(&stats) {
-                    Ok(json) => HookResult::Content(json),
-                    Err(e) => HookResult::Error(HookError::Internal(e.to_string())),
-                }
+        let total_size: i64 = layers.iter().map(|l| l.total_size).sum();
+        let total_files: i32 = layers.iter().map(|l| l.file_count).sum();
+        let (logical_bytes, physical_bytes) =
+            tenant.first().map_or((0, 0), |t| (t.logical_bytes, t.physical_bytes));
+        let own: Vec<_> = own
+            .iter()
+            .map(|l| {
+                serde_json::json!({
+                    "name": l.layer_name,
+                    "own_files": l.own_files,
+                    "own_bytes": l.own_bytes,
+                })
+            })
+            .collect();
+
+        HookResult::Json(serde_json::json!({
+            "layer_count": layers.len(),
+            "total_size": total_size,
+            "total_files": total_files,
+            "layers": own,
+            "logical_bytes": logical_bytes,
+            "physical_bytes": physical_bytes,
+            "tenant_id": self.tenant_id.to_string(),
+        }))
+    }
+
+    /// Read `/.tarbox/storage/<path>`: how the file at `<path>` is stored in
+    /// the current layer, as JSON.
+    async fn read_storage_mode(&self, path: &str) -> HookResult {
+        let file_path = &path[paths::STORAGE.len()..];
+        let fs = match FileSystem::new(self.pool, self.tenant_id).await {
+            Ok(fs) => fs.with_read_pool(self.read_pool),
+            Err(e) => return HookResult::Error(HookError::Internal(e.to_string())),
+        };
+
+        match fs.storage_mode(file_path).await {
+            Ok(mode) => {
+                let mut value = match serde_json::to_value(&mode) {
+                    Ok(value) => value,
+                    Err(e) => return HookResult::Error(HookError::Internal(e.to_string())),
+                };
+                value["path"] = file_path.into();
+                value["storage"] = mode.to_string().into();
+                HookResult::Json(value)
             }
-            Err(e) => HookResult::Error(HookError::LayerError(e)),
+            Err(e @ (FsError::PathNotFound(_) | FsError::IsDirectory(_))) => {
+                HookResult::Error(HookError::InvalidPath(e.to_string()))
+            }
+            Err(e) => HookResult::Error(HookError::Internal(e.to_string())),
+        }
+    }
+
+    fn read_open_handles(&self) -> HookResult {
+        let handles: Vec<OpenHandleInfo> = self
+            .handles
+            .iter()
+            .flat_map(|table| table.list())
+            .map(|(fh, file)| OpenHandleInfo {
+                handle: fh,
+                path: file.path,
+                flags: file.flags,
+                offset: file.offset,
+                uid: file.uid,
+            })
+            .collect();
+
+        match serde_json::to_value(&handles) {
+            Ok(value) => HookResult::Json(value),
+            Err(e) => HookResult::Error(HookError::Internal(e.to_string())),
         }
     }
 
@@ -385,16 +801,41 @@ impl<'a> HooksHandler<'a> {
         // For now, just return layer info
         // Full snapshot file browsing would require more implementation
         let info = LayerInfo::from_layer(layer, false);
-        match serde_json::to_string_pretty(&info) {
-            Ok(json) => HookResult::Content(json),
+        match serde_json::to_value(&info) {
+            Ok(value) => HookResult::Json(value),
             Err(e) => HookResult::Error(HookError::Internal(e.to_string())),
         }
     }
 
+    /// Resolve a layer reference: `current`, a layer UUID, or a layer name.
+    async fn resolve_layer_ref(
+        &self,
+        manager: &LayerManager<'_>,
+        layer_ref: &str,
+    ) -> Result<LayerId, HookError> {
+        if layer_ref == "current" {
+            return manager
+                .get_current_layer_id()
+                .await?
+                .ok_or_else(|| HookError::InvalidInput("No current layer set".to_string()));
+        }
+        if let Ok(uuid) = layer_ref.parse::<uuid::Uuid>() {
+            return Ok(uuid);
+        }
+
+        let layers = manager.list_layers().await?;
+        layers
+            .iter()
+            .find(|l| l.layer_name == layer_ref)
+            .map(|l| l.layer_id)
+            .ok_or_else(|| HookError::InvalidInput(format!("Layer not found: {}", layer_ref)))
+    }
+
     // --- Write handlers ---
 
     async fn write_new_layer(&self, input: &str) -> HookResult {
-        let manager = LayerManager::new(self.pool, self.tenant_id);
+        let manager =
+            LayerManager::new(self.pool, self.tenant_id).with_layer_config(&self.layer_config);
 
         // Try to parse as JSON first
         let (name, description, confirm) = if input.starts_with('{') {
@@ -485,9 +926,9 @@ impl<'a> HooksHandler<'a> {
         let manager = LayerManager::new(self.pool, self.tenant_id);
 
         // Try to parse as JSON first
-        let (layer_ref, _force) = if input.starts_with('{') {
+        let (layer_ref, force, dry_run) = if input.starts_with('{') {
             match serde_json::from_str::<DropLayerInput>(input) {
-                Ok(parsed) => (parsed.layer, parsed.force),
+                Ok(parsed) => (parsed.layer, parsed.force, parsed.dry_run),
                 Err(e) => {
                     return HookResult::Error(HookError::InvalidInput(format!(
                         "Invalid JSON: {}",
@@ -496,7 +937,7 @@ impl<'a> HooksHandler<'a> {
                 }
             }
         } else {
-            (input.to_string(), false)
+            (input.to_string(), false, false)
         };
 
         // Special case: "current" means current layer
@@ -530,19 +971,224 @@ impl<'a> HooksHandler<'a> {
             }
         };
 
-        match manager.delete_layer(layer_id).await {
-            Ok(()) => HookResult::WriteSuccess { message: format!("Deleted layer {}\n", layer_id) },
-            Err(LayerManagerError::HasChildLayers(id)) => {
+        if dry_run {
+            return match manager.plan_drop(layer_id, force).await {
+                Ok(plan) => HookResult::Json(drop_summary(&plan)),
+                Err(e) => Self::drop_error(e, &layer_ref),
+            };
+        }
+
+        match manager.drop_layer(layer_id, force).await {
+            Ok(plan) if plan.child_layers.is_empty() => {
+                HookResult::WriteSuccess { message: format!("Deleted layer {}\n", layer_id) }
+            }
+            Ok(plan) => HookResult::WriteSuccess {
+                message: format!(
+                    "Deleted layer {} and {} child layers\n",
+                    layer_id,
+                    plan.child_layers.len()
+                ),
+            },
+            Err(e) => Self::drop_error(e, &layer_ref),
+        }
+    }
+
+    fn drop_error(error: LayerManagerError, layer_ref: &str) -> HookResult {
+        match error {
+            LayerManagerError::HasChildLayers(id) => {
                 HookResult::Error(HookError::InvalidInput(format!(
                     "Layer {} has child layers. Use {{\"layer\": \"{}\", \"force\": true}} to delete anyway.",
                     id, layer_ref
                 )))
             }
+            e => HookResult::Error(HookError::LayerError(e)),
+        }
+    }
+
+    async fn write_rollback(&self, input: &str) -> HookResult {
+        // The target as plain text, or as {"layer": ...} like switch takes
+        let layer_ref = if input.starts_with('{') {
+            match serde_json::from_str::<SwitchLayerInput>(input) {
+                Ok(parsed) => parsed.layer,
+                Err(e) => {
+                    return HookResult::Error(HookError::InvalidInput(format!(
+                        "Invalid JSON: {}",
+                        e
+                    )));
+                }
+            }
+        } else {
+            input.to_string()
+        };
+
+        let manager = LayerManager::new(self.pool, self.tenant_id);
+        let layer_id = match self.resolve_layer_ref(&manager, &layer_ref).await {
+            Ok(id) => id,
+            Err(e) => return HookResult::Error(e),
+        };
+
+        match manager.rollback(layer_id).await {
+            Ok(report) => HookResult::WriteSuccess {
+                message: format!(
+                    "Rolled back to {} in layer '{}': {} restored, {} removed, {} lost\n",
+                    layer_ref,
+                    report.layer.layer_name,
+                    report.restored.len(),
+                    report.removed.len(),
+                    report.lost.len()
+                ),
+            },
+            Err(e) => HookResult::Error(HookError::LayerError(e)),
+        }
+    }
+
+    async fn write_squash_layers(&self, input: &str) -> HookResult {
+        let parsed = match serde_json::from_str::<SquashLayerInput>(input) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                return HookResult::Error(HookError::InvalidInput(format!(
+                    "Expected {{\"from\": \"<layer>\", \"into\": \"<layer>\"}}: {}",
+                    e
+                )));
+            }
+        };
+
+        let manager = LayerManager::new(self.pool, self.tenant_id);
+        let (from_id, into_id) = match (
+            self.resolve_layer_ref(&manager, &parsed.from).await,
+            self.resolve_layer_ref(&manager, &parsed.into).await,
+        ) {
+            (Ok(from), Ok(into)) => (from, into),
+            (Err(e), _) | (_, Err(e)) => return HookResult::Error(e),
+        };
+
+        match manager.squash(from_id, into_id).await {
+            Ok(layer) => HookResult::WriteSuccess {
+                message: format!(
+                    "Squashed {}..{} into layer '{}' ({}), {} files\n",
+                    parsed.from, parsed.into, layer.layer_name, layer.layer_id, layer.file_count
+                ),
+            },
+            Err(e) => HookResult::Error(HookError::LayerError(e)),
+        }
+    }
+
+    async fn write_rename_layer(&self, input: &str) -> HookResult {
+        let parsed = match serde_json::from_str::<RenameLayerInput>(input) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                return HookResult::Error(HookError::InvalidInput(format!(
+                    "Expected {{\"layer\": \"<layer>\", \"name\": \"<new name>\"}}: {}",
+                    e
+                )));
+            }
+        };
+
+        let name = parsed.name.trim();
+        if name.is_empty() {
+            return HookResult::Error(HookError::InvalidInput(
+                "Layer name cannot be empty".to_string(),
+            ));
+        }
+
+        let manager = LayerManager::new(self.pool, self.tenant_id);
+        let layer_id = match self.resolve_layer_ref(&manager, &parsed.layer).await {
+            Ok(id) => id,
+            Err(e) => return HookResult::Error(e),
+        };
+
+        match manager.rename_layer(layer_id, name).await {
+            Ok(layer) => HookResult::WriteSuccess {
+                message: format!("Renamed layer {} to '{}'\n", layer.layer_id, layer.layer_name),
+            },
+            Err(LayerManagerError::DuplicateLayerName(name)) => HookResult::Error(
+                HookError::InvalidInput(format!("Layer name already in use: {}", name)),
+            ),
+            Err(e) => HookResult::Error(HookError::LayerError(e)),
+        }
+    }
+
+    async fn write_tag_layer(&self, input: &str) -> HookResult {
+        let parsed = match serde_json::from_str::<TagLayerInput>(input) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                return HookResult::Error(HookError::InvalidInput(format!(
+                    "Expected {{\"layer\": \"<layer>\", \"tags\": [\"<tag>\", ...]}}: {}",
+                    e
+                )));
+            }
+        };
+
+        let manager = LayerManager::new(self.pool, self.tenant_id);
+        let layer_id = match self.resolve_layer_ref(&manager, &parsed.layer).await {
+            Ok(id) => id,
+            Err(e) => return HookResult::Error(e),
+        };
+
+        match manager.set_tags(layer_id, &parsed.tags).await {
+            Ok(layer) => HookResult::WriteSuccess {
+                message: format!(
+                    "Tagged layer '{}': [{}]\n",
+                    layer.layer_name,
+                    LayerManager::tags_of(&layer).join(", ")
+                ),
+            },
+            Err(e) => HookResult::Error(HookError::LayerError(e)),
+        }
+    }
+
+    /// Whatever is written just triggers a pass.
+    async fn write_gc(&self) -> HookResult {
+        let manager = LayerManager::new(self.pool, self.tenant_id);
+
+        match manager.gc().await {
+            Ok(report) => HookResult::WriteSuccess {
+                message: format!(
+                    "Removed {} chunks ({} bytes) and {} text blocks ({} bytes)\n",
+                    report.chunks, report.chunk_bytes, report.text_blocks, report.text_bytes
+                ),
+            },
             Err(e) => HookResult::Error(HookError::LayerError(e)),
         }
     }
 }
 
+/// What a dry-run drop reports.
+fn drop_summary(plan: &DropPlan) -> serde_json::Value {
+    let layer = |l: &Layer| serde_json::json!({ "layer_id": l.layer_id, "name": l.layer_name });
+    serde_json::json!({
+        "dry_run": true,
+        "layer": layer(&plan.layer),
+        "child_layers": plan.child_layers.iter().map(layer).collect::<Vec<_>>(),
+        "reclaimed": plan.reclaimed,
+    })
+}
+
+/// Content of `path` in `layer_id` if it is there and stored as text.
+async fn read_text_at_layer(
+    fs: &FileSystem<'_>,
+    path: &str,
+    layer_id: LayerId,
+) -> Result<Option<String>, HookError> {
+    match fs.read_file_at_layer(path, layer_id).await {
+        Ok(data) => match FileTypeDetector::new().detect(&data) {
+            FileTypeInfo::Text { .. } => Ok(String::from_utf8(data).ok()),
+            FileTypeInfo::Binary => Ok(None),
+        },
+        Err(FsError::PathNotFound(_)) => Ok(None),
+        Err(e) => Err(HookError::Internal(e.to_string())),
+    }
+}
+
+/// Single-letter marker for a change in diff output.
+fn change_char(change_type: ChangeType) -> char {
+    match change_type {
+        ChangeType::Add => 'A',
+        ChangeType::Modify => 'M',
+        ChangeType::Delete => 'D',
+    }
+}
+
 /// File attributes for hook files.
 #[derive(Debug, Clone)]
 pub struct HookFileAttr {
@@ -599,6 +1245,35 @@ mod tests {
         assert!(!HooksHandler::is_hook_path("/home/.tarbox"));
     }
 
+    #[test]
+    fn test_is_hook_path_traversal() {
+        // Entering /.tarbox through a detour is still a hook path
+        assert!(HooksHandler::is_hook_path("/foo/../.tarbox/layers/drop"));
+        assert!(HooksHandler::is_hook_path("//.tarbox//layers/./drop"));
+        assert_eq!(
+            HooksHandler::canonical_hook_path("/foo/../.tarbox/layers/drop").as_deref(),
+            Some(paths::LAYERS_DROP)
+        );
+        // Leaving it is an ordinary path
+        assert!(!HooksHandler::is_hook_path("/.tarbox/../realfile"));
+        assert!(!HooksHandler::is_hook_path("/.tarbox/layers/../../etc"));
+        // A sibling that merely shares the prefix is not a hook
+        assert!(!HooksHandler::is_hook_path("/.tarboxfoo"));
+        // Escaping the root is neither; the filesystem rejects it
+        assert!(!HooksHandler::is_hook_path("/../.tarbox"));
+    }
+
+    #[tokio::test]
+    async fn test_get_attr_on_traversal_paths() {
+        let pool = sqlx::PgPool::connect_lazy("postgres://localhost/none").unwrap();
+        let handler = HooksHandler::new(&pool, uuid::Uuid::new_v4());
+
+        let attr = handler.get_attr("/data/../.tarbox/layers/./drop").unwrap();
+        assert!(!attr.is_dir);
+        assert!(handler.get_attr("/.tarbox/layers/..").unwrap().is_dir);
+        assert!(handler.get_attr("/.tarbox/../layers").is_none());
+    }
+
     #[test]
     fn test_hook_file_attr() {
         let dir = HookFileAttr::directory();
@@ -714,6 +1389,15 @@ mod tests {
         let content = HookResult::Content("test content".to_string());
         assert!(matches!(content, HookResult::Content(_)));
 
+        let json = HookResult::Json(serde_json::json!({ "layer_count": 2 }));
+        assert_eq!(json.render().unwrap(), "{\n  \"layer_count\": 2\n}");
+
+        let listing = HookResult::DirListing(vec![
+            HookDirEntry::dir("layers"),
+            HookDirEntry::file("refresh"),
+        ]);
+        assert_eq!(listing.render().unwrap(), "layers\nrefresh");
+
         let success = HookResult::WriteSuccess { message: "ok".to_string() };
         assert!(matches!(success, HookResult::WriteSuccess { .. }));
 
@@ -766,10 +1450,11 @@ mod tests {
 
     #[test]
     fn test_drop_layer_input_deserialization() {
-        let json = r#"{"layer": "old-layer", "force": true}"#;
+        let json = r#"{"layer": "old-layer", "force": true, "dry_run": true}"#;
         let input: DropLayerInput = serde_json::from_str(json).unwrap();
         assert_eq!(input.layer, "old-layer");
         assert!(input.force);
+        assert!(input.dry_run);
     }
 
     #[test]
@@ -778,6 +1463,7 @@ mod tests {
         let input: DropLayerInput = serde_json::from_str(json).unwrap();
         assert_eq!(input.layer, "layer-name");
         assert!(!input.force);
+        assert!(!input.dry_run);
     }
 
     #[test]
@@ -793,10 +1479,18 @@ mod tests {
         assert_eq!(paths::LAYERS_NEW, "/.tarbox/layers/new");
         assert_eq!(paths::LAYERS_SWITCH, "/.tarbox/layers/switch");
         assert_eq!(paths::LAYERS_DROP, "/.tarbox/layers/drop");
+        assert_eq!(paths::LAYERS_ROLLBACK, "/.tarbox/layers/rollback");
+        assert_eq!(paths::LAYERS_SQUASH, "/.tarbox/layers/squash");
+        assert_eq!(paths::LAYERS_RENAME, "/.tarbox/layers/rename");
+        assert_eq!(paths::LAYERS_TAG, "/.tarbox/layers/tag");
         assert_eq!(paths::LAYERS_TREE, "/.tarbox/layers/tree");
         assert_eq!(paths::LAYERS_DIFF, "/.tarbox/layers/diff");
+        assert_eq!(paths::LAYERS_CHANGES, "/.tarbox/layers/changes");
         assert_eq!(paths::SNAPSHOTS, "/.tarbox/snapshots");
         assert_eq!(paths::STATS, "/.tarbox/stats");
         assert_eq!(paths::STATS_USAGE, "/.tarbox/stats/usage");
+        assert_eq!(paths::HANDLES, "/.tarbox/handles");
+        assert_eq!(paths::STORAGE, "/.tarbox/storage");
+        assert_eq!(paths::GC, "/.tarbox/gc");
     }
 }
@@ -3,13 +3,25 @@
 //! Provides high-level operations for managing layers in a layered filesystem.
 //! This includes creating checkpoints, switching layers, and managing layer lifecycle.
 
+use std::collections::{BTreeMap, HashSet};
+
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::PgPool;
 use thiserror::Error;
-use tracing::{debug, info};
-
+use tracing::{debug, info, warn};
+
+use crate::config::LayerConfig;
+use crate::fs::FsError;
+use crate::fs::ingest::{ensure_directory_in_tx, write_entry_in_tx};
+use crate::fs::operations::FileSystem;
+use crate::layer::cow::{BlockChanges, TextChanges};
+use crate::layer::union_view::UnionView;
 use crate::storage::{
-    ChangeType, CreateLayerEntryInput, CreateLayerInput, Layer, LayerOperations, LayerRepository,
+    ChangeType, ChunkOperations, CreateLayerEntryInput, CreateLayerInput, DatabaseTransaction,
+    InodeType, Layer, LayerEntry, LayerOperations, LayerRepository, ReclaimableBlocks,
+    TextBlockOperations,
 };
 use crate::types::{InodeId, LayerId, TenantId};
 
@@ -34,6 +46,9 @@ pub enum LayerManagerError {
     #[error("Invalid layer chain: {0}")]
     InvalidLayerChain(String),
 
+    #[error("Layer name already in use: {0}")]
+    DuplicateLayerName(String),
+
     #[error("Storage error: {0}")]
     Storage(#[from] anyhow::Error),
 }
@@ -41,16 +56,55 @@ pub enum LayerManagerError {
 /// Result type for layer manager operations.
 pub type LayerManagerResult<T> = Result<T, LayerManagerError>;
 
+/// What a garbage collection pass removed.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GcReport {
+    pub chunks: i64,
+    pub chunk_bytes: i64,
+    pub text_blocks: i64,
+    pub text_bytes: i64,
+}
+
+/// What dropping a layer deletes, as reported before doing it.
+#[derive(Debug, Clone)]
+pub struct DropPlan {
+    pub layer: Layer,
+    /// Layers built on `layer`, deepest first; only dropped with `force`
+    pub child_layers: Vec<Layer>,
+    pub reclaimed: ReclaimableBlocks,
+}
+
+/// What `rollback` changed to bring the tree back to a layer's state.
+#[derive(Debug, Clone, Serialize)]
+pub struct RollbackReport {
+    /// The new current layer holding the restored state
+    pub layer: Layer,
+    /// Files rewritten or recreated with their content in the target
+    pub restored: Vec<String>,
+    /// Files added since the target, deleted again
+    pub removed: Vec<String>,
+    /// Files deleted since the target; their content is gone with their inode
+    pub lost: Vec<String>,
+}
+
 /// Layer manager for high-level layer operations.
 pub struct LayerManager<'a> {
     pool: &'a PgPool,
     tenant_id: TenantId,
+    /// Chain depth limit checked after each checkpoint.
+    layer_config: LayerConfig,
 }
 
 impl<'a> LayerManager<'a> {
     /// Create a new layer manager for a tenant.
     pub fn new(pool: &'a PgPool, tenant_id: TenantId) -> Self {
-        Self { pool, tenant_id }
+        Self { pool, tenant_id, layer_config: LayerConfig::default() }
+    }
+
+    /// Apply the chain depth limit from configuration.
+    pub fn with_layer_config(mut self, config: &LayerConfig) -> Self {
+        self.layer_config = config.clone();
+        self
     }
 
     /// Get the layer operations instance.
@@ -136,50 +190,126 @@ impl<'a> LayerManager<'a> {
             .await?
             .ok_or(LayerManagerError::NoCurrentLayer)?;
 
-        let _current_layer = ops
+        // Delete future layers if at a historical layer and confirmed
+        let future_layers =
+            self.future_layers_to_replace(current_layer_id, confirm_delete_future).await?;
+        for layer in future_layers.iter().rev() {
+            ops.delete(self.tenant_id, layer.layer_id).await?;
+        }
+
+        let mut tx = self.pool.begin().await.map_err(anyhow::Error::from)?;
+        let new_layer = self.checkpoint_in_tx(&mut tx, name, description).await?;
+        tx.commit().await.map_err(anyhow::Error::from)?;
+
+        self.check_chain_depth(&new_layer).await?;
+
+        Ok(new_layer)
+    }
+
+    /// Layers built on `current_layer_id`, which a new checkpoint there
+    /// would replace. Fails unless there are none or `confirm` is set.
+    async fn future_layers_to_replace(
+        &self,
+        current_layer_id: LayerId,
+        confirm: bool,
+    ) -> LayerManagerResult<Vec<Layer>> {
+        self.layer_ops()
             .get(self.tenant_id, current_layer_id)
             .await?
             .ok_or(LayerManagerError::LayerNotFound(current_layer_id))?;
 
-        // Check if we're at a historical layer (have future layers)
         let future_layers = self.get_future_layers(current_layer_id).await?;
-        if !future_layers.is_empty() && !confirm_delete_future {
+        if !future_layers.is_empty() && !confirm {
             return Err(LayerManagerError::HistoricalLayerNeedsConfirmation {
                 current_layer: current_layer_id,
                 future_layers,
             });
         }
+        Ok(future_layers)
+    }
 
-        // Delete future layers if confirmed
-        if !future_layers.is_empty() && confirm_delete_future {
-            for layer in future_layers.iter().rev() {
-                ops.delete(self.tenant_id, layer.layer_id).await?;
-            }
-        }
+    /// Mark the current layer readonly and make a new writable layer on top
+    /// of it current, as part of a caller-managed transaction.
+    async fn checkpoint_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        name: &str,
+        description: Option<&str>,
+    ) -> LayerManagerResult<Layer> {
+        let ops = self.layer_ops();
+        let current_layer_id = ops
+            .get_current_layer_in_tx(tx, self.tenant_id)
+            .await?
+            .ok_or(LayerManagerError::NoCurrentLayer)?;
 
-        // Mark current layer as readonly
-        self.set_layer_readonly(current_layer_id, true).await?;
+        self.set_layer_readonly_in_tx(tx, current_layer_id, true).await?;
 
-        // Create new layer
         let new_layer = ops
-            .create(CreateLayerInput {
-                tenant_id: self.tenant_id,
-                parent_layer_id: Some(current_layer_id),
-                layer_name: name.to_string(),
-                description: description.map(String::from),
-                tags: None,
-                created_by: "user".to_string(),
-                mount_entry_id: None,
-                is_working: false,
-            })
+            .create_in_tx(
+                tx,
+                CreateLayerInput {
+                    tenant_id: self.tenant_id,
+                    parent_layer_id: Some(current_layer_id),
+                    layer_name: name.to_string(),
+                    description: description.map(String::from),
+                    tags: None,
+                    created_by: "user".to_string(),
+                    mount_entry_id: None,
+                    is_working: false,
+                },
+            )
             .await?;
 
-        // Set new layer as current
-        ops.set_current_layer(self.tenant_id, new_layer.layer_id).await?;
+        ops.set_current_layer_in_tx(tx, self.tenant_id, new_layer.layer_id).await?;
 
         Ok(new_layer)
     }
 
+    /// Number of layers from `layer_id` down to its root, inclusive.
+    pub async fn chain_depth(&self, layer_id: LayerId) -> LayerManagerResult<usize> {
+        Ok(self.get_layer_chain(layer_id).await?.len())
+    }
+
+    /// Warn when `layer`'s chain is deeper than the configured limit, and
+    /// with `auto_squash` fold the oldest layers into the root until it
+    /// isn't. The two newest layers are never folded. A failed squash only
+    /// warns: the checkpoint itself has already been taken.
+    async fn check_chain_depth(&self, layer: &Layer) -> LayerManagerResult<()> {
+        let chain = self.get_layer_chain(layer.layer_id).await?;
+        let max_depth = self.layer_config.max_chain_depth;
+        if chain.len() <= max_depth {
+            return Ok(());
+        }
+        warn!(
+            tenant_id = %self.tenant_id,
+            layer_id = %layer.layer_id,
+            depth = chain.len(),
+            max_depth,
+            "Layer chain is deeper than the configured limit; reads will slow down"
+        );
+        if !self.layer_config.auto_squash {
+            return Ok(());
+        }
+
+        // `chain` runs newest to oldest; merging chain[keep - 1..] leaves `keep` layers
+        let keep = max_depth.max(2);
+        if chain.len() <= keep {
+            return Ok(());
+        }
+        let root = &chain[chain.len() - 1];
+        let from = &chain[keep - 1];
+        match self.squash(from.layer_id, root.layer_id).await {
+            Ok(_) => info!(
+                tenant_id = %self.tenant_id,
+                squashed = chain.len() - keep + 1,
+                into = %root.layer_id,
+                "Squashed oldest layers to shorten the chain"
+            ),
+            Err(e) => warn!(tenant_id = %self.tenant_id, error = %e, "Automatic squash failed"),
+        }
+        Ok(())
+    }
+
     /// Switch to a different layer.
     ///
     /// This changes the current layer to the specified layer.
@@ -204,6 +334,18 @@ impl<'a> LayerManager<'a> {
         Ok(self.layer_ops().list(self.tenant_id).await?)
     }
 
+    /// Get the layer that was current at `at`.
+    ///
+    /// Returns `None` if the tenant had no current layer yet, or if that layer
+    /// has since been deleted along with its history.
+    pub async fn layer_at_time(&self, at: DateTime<Utc>) -> LayerManagerResult<Option<Layer>> {
+        let ops = self.layer_ops();
+        match ops.layer_at_time(self.tenant_id, at).await? {
+            Some(layer_id) => Ok(ops.get(self.tenant_id, layer_id).await?),
+            None => Ok(None),
+        }
+    }
+
     /// Get the layer chain from a specific layer up to the root.
     pub async fn get_layer_chain(&self, layer_id: LayerId) -> LayerManagerResult<Vec<Layer>> {
         Ok(self.layer_ops().get_layer_chain(self.tenant_id, layer_id).await?)
@@ -263,12 +405,29 @@ impl<'a> LayerManager<'a> {
             return Err(LayerManagerError::HasChildLayers(layer_id));
         }
 
+        let mut tx = self.pool.begin().await.map_err(anyhow::Error::from)?;
+        self.delete_layer_in_tx(&mut tx, &layer).await?;
+        tx.commit().await.map_err(anyhow::Error::from)?;
+
+        Ok(())
+    }
+
+    /// Delete `layer`, whose child layers must already be gone, as part of
+    /// a caller-managed transaction. If it is current, its parent becomes
+    /// current.
+    async fn delete_layer_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        layer: &Layer,
+    ) -> LayerManagerResult<()> {
+        let ops = self.layer_ops();
+
         // If this is the current layer, switch to parent
-        if let Some(current_id) = ops.get_current_layer(self.tenant_id).await?
-            && current_id == layer_id
+        if let Some(current_id) = ops.get_current_layer_in_tx(tx, self.tenant_id).await?
+            && current_id == layer.layer_id
         {
             if let Some(parent_id) = layer.parent_layer_id {
-                ops.set_current_layer(self.tenant_id, parent_id).await?;
+                ops.set_current_layer_in_tx(tx, self.tenant_id, parent_id).await?;
             } else {
                 // Can't delete base layer if it's current and has no parent
                 return Err(LayerManagerError::InvalidLayerChain(
@@ -278,19 +437,613 @@ impl<'a> LayerManager<'a> {
         }
 
         // Delete the layer
-        ops.delete(self.tenant_id, layer_id).await?;
+        ops.delete_in_tx(tx, self.tenant_id, layer.layer_id).await?;
 
         Ok(())
     }
 
+    /// Work out what `drop_layer` would delete, without deleting anything.
+    ///
+    /// Fails as `drop_layer` would: on child layers unless `force`, and on
+    /// a base layer that the current layer would be dropped with.
+    pub async fn plan_drop(&self, layer_id: LayerId, force: bool) -> LayerManagerResult<DropPlan> {
+        let ops = self.layer_ops();
+        let layer = ops
+            .get(self.tenant_id, layer_id)
+            .await?
+            .ok_or(LayerManagerError::LayerNotFound(layer_id))?;
+
+        let mut child_layers = Vec::new();
+        for child in self.get_future_layers(layer_id).await? {
+            let depth = self.get_layer_chain(child.layer_id).await?.len();
+            child_layers.push((depth, child));
+        }
+        if !child_layers.is_empty() && !force {
+            return Err(LayerManagerError::HasChildLayers(layer_id));
+        }
+        child_layers.sort_by(|a, b| b.0.cmp(&a.0));
+        let child_layers: Vec<Layer> = child_layers.into_iter().map(|(_, child)| child).collect();
+
+        let mut layer_ids: Vec<LayerId> = child_layers.iter().map(|l| l.layer_id).collect();
+        layer_ids.push(layer_id);
+        if layer.parent_layer_id.is_none()
+            && let Some(current_id) = ops.get_current_layer(self.tenant_id).await?
+            && layer_ids.contains(&current_id)
+        {
+            return Err(LayerManagerError::InvalidLayerChain(
+                "Cannot delete base layer".to_string(),
+            ));
+        }
+
+        let reclaimed = ops.reclaimable_blocks(self.tenant_id, &layer_ids).await?;
+        Ok(DropPlan { layer, child_layers, reclaimed })
+    }
+
+    /// Delete a layer, and with `force` every layer built on it, deepest
+    /// first. Returns what was deleted.
+    pub async fn drop_layer(&self, layer_id: LayerId, force: bool) -> LayerManagerResult<DropPlan> {
+        let plan = self.plan_drop(layer_id, force).await?;
+        let mut tx = self.pool.begin().await.map_err(anyhow::Error::from)?;
+        for child in &plan.child_layers {
+            self.delete_layer_in_tx(&mut tx, child).await?;
+        }
+        self.delete_layer_in_tx(&mut tx, &plan.layer).await?;
+        tx.commit().await.map_err(anyhow::Error::from)?;
+        Ok(plan)
+    }
+
+    /// Restore the files of `layer_id` in a new layer on top of the current
+    /// one, leaving every existing layer as it is.
+    ///
+    /// Unlike `switch_to_layer` nothing is discarded: the rollback is a
+    /// change like any other and can itself be rolled back. Files are
+    /// compared as the target and current layers see them; only those that
+    /// differ are written. Files added since are deleted like any other, so
+    /// older layers can no longer read them either. Directories are created
+    /// as needed but never removed, and symlinks are left alone.
+    pub async fn rollback(&self, layer_id: LayerId) -> LayerManagerResult<RollbackReport> {
+        let target =
+            self.get_layer(layer_id).await?.ok_or(LayerManagerError::LayerNotFound(layer_id))?;
+        let current = self.get_current_layer().await?;
+        self.future_layers_to_replace(current.layer_id, false).await?;
+        let wanted =
+            UnionView::from_layer(self.pool, self.tenant_id, layer_id).await?.list_all().await?;
+        let present = UnionView::from_layer(self.pool, self.tenant_id, current.layer_id)
+            .await?
+            .list_all()
+            .await?;
+        let fs = FileSystem::new(self.pool, self.tenant_id).await.map_err(fs_error)?;
+
+        // Work out what differs first; everything after is one transaction
+        let mut restore = Vec::new();
+        let mut lost = Vec::new();
+        for path in wanted.keys() {
+            let data = match fs.stat_at_layer(path, layer_id).await {
+                Ok(inode) if inode.inode_type != InodeType::File => continue,
+                Ok(_) => fs.read_file_at_layer(path, layer_id).await.map_err(fs_error)?,
+                Err(FsError::PathNotFound(_)) => {
+                    lost.push(path.clone());
+                    continue;
+                }
+                Err(e) => return Err(fs_error(e)),
+            };
+            match fs.read_file(path).await {
+                Ok(existing) if existing == data => continue,
+                Ok(_) | Err(FsError::PathNotFound(_) | FsError::NotDirectory(_)) => {}
+                Err(e) => return Err(fs_error(e)),
+            }
+            restore.push((path.clone(), data));
+        }
+
+        let name = self.unused_layer_name(&format!("rollback-{}", target.layer_name)).await?;
+        let description = format!("Rollback to {} ({})", target.layer_name, layer_id);
+        let mut tx = self.pool.begin().await.map_err(anyhow::Error::from)?;
+        let layer = self.checkpoint_in_tx(&mut tx, &name, Some(&description)).await?;
+        let fs = fs.with_current_layer(layer.layer_id);
+
+        let mut report = RollbackReport { layer, restored: Vec::new(), removed: Vec::new(), lost };
+        for (path, data) in restore {
+            // Every ancestor below the root, outermost first
+            for (at, _) in path.match_indices('/').skip(1) {
+                ensure_directory_in_tx(&fs, &mut tx, &path[..at]).await.map_err(fs_error)?;
+            }
+            write_entry_in_tx(&fs, &mut tx, &path, &data).await.map_err(fs_error)?;
+            report.restored.push(path);
+        }
+
+        for path in present.keys().filter(|path| !wanted.contains_key(*path)) {
+            match fs.delete_file_in_tx(&mut tx, path).await {
+                Ok(()) => report.removed.push(path.clone()),
+                Err(FsError::PathNotFound(_) | FsError::IsDirectory(_)) => {}
+                Err(e) => return Err(fs_error(e)),
+            }
+        }
+        tx.commit().await.map_err(anyhow::Error::from)?;
+
+        self.check_chain_depth(&report.layer).await?;
+
+        info!(
+            tenant_id = %self.tenant_id,
+            target = %layer_id,
+            layer = %report.layer.layer_id,
+            restored = report.restored.len(),
+            removed = report.removed.len(),
+            lost = report.lost.len(),
+            "Rolled back"
+        );
+        Ok(report)
+    }
+
+    /// `base`, or `base` with the first free numeric suffix if a layer of
+    /// this tenant already has that name.
+    async fn unused_layer_name(&self, base: &str) -> LayerManagerResult<String> {
+        let names: Vec<String> =
+            self.list_layers().await?.into_iter().map(|l| l.layer_name).collect();
+        let mut name = base.to_string();
+        let mut suffix = 2;
+        while names.contains(&name) {
+            name = format!("{}-{}", base, suffix);
+            suffix += 1;
+        }
+        Ok(name)
+    }
+
+    /// Rename a layer. Names are unique per tenant.
+    pub async fn rename_layer(
+        &self,
+        layer_id: LayerId,
+        new_name: &str,
+    ) -> LayerManagerResult<Layer> {
+        let ops = self.layer_ops();
+        let layer = ops
+            .get(self.tenant_id, layer_id)
+            .await?
+            .ok_or(LayerManagerError::LayerNotFound(layer_id))?;
+        if layer.layer_name == new_name {
+            return Ok(layer);
+        }
+
+        let layers = ops.list(self.tenant_id).await?;
+        if layers.iter().any(|l| l.layer_name == new_name) {
+            return Err(LayerManagerError::DuplicateLayerName(new_name.to_string()));
+        }
+
+        // The unique constraint still catches a concurrent rename to the same name
+        match ops.rename(self.tenant_id, layer_id, new_name).await {
+            Ok(Some(layer)) => Ok(layer),
+            Ok(None) => Err(LayerManagerError::LayerNotFound(layer_id)),
+            Err(e) if is_unique_violation(&e) => {
+                Err(LayerManagerError::DuplicateLayerName(new_name.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Tags of a layer, in the order they were set.
+    ///
+    /// Tags are stored as a JSON array of strings; anything else reads as no
+    /// tags.
+    pub fn tags_of(layer: &Layer) -> Vec<String> {
+        match &layer.tags {
+            Some(serde_json::Value::Array(values)) => {
+                values.iter().filter_map(|v| v.as_str().map(str::to_string)).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Replace the tags of a layer. Blank and repeated tags are dropped.
+    pub async fn set_tags(&self, layer_id: LayerId, tags: &[String]) -> LayerManagerResult<Layer> {
+        let mut unique: Vec<&str> = Vec::with_capacity(tags.len());
+        for tag in tags.iter().map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            if !unique.contains(&tag) {
+                unique.push(tag);
+            }
+        }
+
+        let value = (!unique.is_empty()).then(|| serde_json::json!(unique));
+        self.layer_ops()
+            .set_tags(self.tenant_id, layer_id, value.as_ref())
+            .await?
+            .ok_or(LayerManagerError::LayerNotFound(layer_id))
+    }
+
+    /// Add a tag to a layer if it doesn't already have it.
+    pub async fn add_tag(&self, layer_id: LayerId, tag: &str) -> LayerManagerResult<Layer> {
+        let layer =
+            self.get_layer(layer_id).await?.ok_or(LayerManagerError::LayerNotFound(layer_id))?;
+        let mut tags = Self::tags_of(&layer);
+        tags.push(tag.to_string());
+        self.set_tags(layer_id, &tags).await
+    }
+
+    /// Remove a tag from a layer. Removing a missing tag is a no-op.
+    pub async fn remove_tag(&self, layer_id: LayerId, tag: &str) -> LayerManagerResult<Layer> {
+        let layer =
+            self.get_layer(layer_id).await?.ok_or(LayerManagerError::LayerNotFound(layer_id))?;
+        let tags: Vec<String> =
+            Self::tags_of(&layer).into_iter().filter(|t| t != tag.trim()).collect();
+        self.set_tags(layer_id, &tags).await
+    }
+
+    /// Collapse the part of a chain between `from_id` and `into_id` (inclusive)
+    /// into the single layer `into_id`.
+    ///
+    /// Either end may be the newer one. Entries are merged oldest-first with
+    /// last-writer-wins per path; a file added and then deleted inside the
+    /// range leaves no entry. The squashed layer takes over the parent of the
+    /// oldest layer in the range, children of the newest are reparented onto
+    /// it, and the other layers in the range are removed. Everything happens
+    /// in one transaction.
+    pub async fn squash(&self, from_id: LayerId, into_id: LayerId) -> LayerManagerResult<Layer> {
+        let into =
+            self.get_layer(into_id).await?.ok_or(LayerManagerError::LayerNotFound(into_id))?;
+        if from_id == into_id {
+            return Ok(into);
+        }
+
+        // Find which end is the descendant; its chain contains the whole range
+        let from_chain = self.get_layer_chain(from_id).await?;
+        if from_chain.is_empty() {
+            return Err(LayerManagerError::LayerNotFound(from_id));
+        }
+        let into_chain = self.get_layer_chain(into_id).await?;
+        let range: Vec<Layer> =
+            if let Some(pos) = from_chain.iter().position(|l| l.layer_id == into_id) {
+                from_chain[..=pos].to_vec()
+            } else if let Some(pos) = into_chain.iter().position(|l| l.layer_id == from_id) {
+                into_chain[..=pos].to_vec()
+            } else {
+                return Err(LayerManagerError::InvalidLayerChain(format!(
+                    "{} and {} are not on the same chain",
+                    from_id, into_id
+                )));
+            };
+        // `range` runs newest to oldest
+        let newest = &range[0];
+        let oldest = &range[range.len() - 1];
+
+        // The squashed layer takes over the oldest layer's parent
+        self.check_parent(into_id, oldest.parent_layer_id).await?;
+
+        let mut entries = Vec::new();
+        for layer in range.iter().rev() {
+            entries.extend(self.get_layer_entries(layer.layer_id).await?);
+        }
+        let merged = merge_entries(entries);
+
+        let mut tx = self.pool.begin().await.map_err(anyhow::Error::from)?;
+        let range_ids: Vec<LayerId> = range.iter().map(|l| l.layer_id).collect();
+
+        // Carry each surviving text file's content over to the squashed layer
+        for (entry, source_layer) in &merged {
+            if *source_layer == into_id || entry.change_type == ChangeType::Delete {
+                continue;
+            }
+            sqlx::query(
+                "DELETE FROM text_file_metadata \
+                 WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3",
+            )
+            .bind(self.tenant_id)
+            .bind(entry.inode_id)
+            .bind(into_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(anyhow::Error::from)?;
+            sqlx::query(
+                r#"
+                INSERT INTO text_file_metadata (
+                    tenant_id, inode_id, layer_id, total_lines, encoding, line_ending,
+                    has_trailing_newline, created_at
+                )
+                SELECT tenant_id, inode_id, $4, total_lines, encoding, line_ending,
+                       has_trailing_newline, created_at
+                FROM text_file_metadata
+                WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3
+                "#,
+            )
+            .bind(self.tenant_id)
+            .bind(entry.inode_id)
+            .bind(source_layer)
+            .bind(into_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(anyhow::Error::from)?;
+            sqlx::query(
+                r#"
+                INSERT INTO text_line_map (
+                    tenant_id, inode_id, layer_id, line_number, block_id, block_line_offset
+                )
+                SELECT tenant_id, inode_id, $4, line_number, block_id, block_line_offset
+                FROM text_line_map
+                WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3
+                "#,
+            )
+            .bind(self.tenant_id)
+            .bind(entry.inode_id)
+            .bind(source_layer)
+            .bind(into_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(anyhow::Error::from)?;
+        }
+
+        // A file's newest binary version in the range wins whether it was
+        // stored as chunks or blocks; drop older versions of the other kind
+        sqlx::query(
+            r#"
+            DELETE FROM file_chunks fc
+            WHERE fc.tenant_id = $1 AND fc.layer_id = ANY($2)
+              AND (
+                EXISTS (
+                    SELECT 1 FROM file_chunks newer
+                    WHERE newer.tenant_id = $1 AND newer.inode_id = fc.inode_id
+                      AND newer.layer_id = ANY($2)
+                      AND array_position($2, newer.layer_id) < array_position($2, fc.layer_id)
+                )
+                OR EXISTS (
+                    SELECT 1 FROM data_blocks newer
+                    WHERE newer.tenant_id = $1 AND newer.inode_id = fc.inode_id
+                      AND newer.layer_id = ANY($2)
+                      AND array_position($2, newer.layer_id) < array_position($2, fc.layer_id)
+                )
+              )
+            "#,
+        )
+        .bind(self.tenant_id)
+        .bind(&range_ids)
+        .execute(&mut *tx)
+        .await
+        .map_err(anyhow::Error::from)?;
+        sqlx::query(
+            r#"
+            DELETE FROM data_blocks b
+            USING file_chunks newer
+            WHERE b.tenant_id = $1 AND newer.tenant_id = $1 AND b.inode_id = newer.inode_id
+              AND b.layer_id = ANY($2) AND newer.layer_id = ANY($2)
+              AND array_position($2, newer.layer_id) < array_position($2, b.layer_id)
+            "#,
+        )
+        .bind(self.tenant_id)
+        .bind(&range_ids)
+        .execute(&mut *tx)
+        .await
+        .map_err(anyhow::Error::from)?;
+        sqlx::query(
+            "UPDATE file_chunks SET layer_id = $2 \
+             WHERE tenant_id = $1 AND layer_id = ANY($3) AND layer_id <> $2",
+        )
+        .bind(self.tenant_id)
+        .bind(into_id)
+        .bind(&range_ids)
+        .execute(&mut *tx)
+        .await
+        .map_err(anyhow::Error::from)?;
+
+        // Fold the range's binary blocks into the squashed layer, keeping the
+        // newest version of each block
+        sqlx::query(
+            r#"
+            DELETE FROM data_blocks b
+            USING data_blocks newer
+            WHERE b.tenant_id = $1 AND newer.tenant_id = $1
+              AND b.inode_id = newer.inode_id AND b.block_index = newer.block_index
+              AND b.layer_id = ANY($2) AND newer.layer_id = ANY($2)
+              AND array_position($2, newer.layer_id) < array_position($2, b.layer_id)
+            "#,
+        )
+        .bind(self.tenant_id)
+        .bind(&range_ids)
+        .execute(&mut *tx)
+        .await
+        .map_err(anyhow::Error::from)?;
+        sqlx::query(
+            "UPDATE data_blocks SET layer_id = $2 \
+             WHERE tenant_id = $1 AND layer_id = ANY($3) AND layer_id <> $2",
+        )
+        .bind(self.tenant_id)
+        .bind(into_id)
+        .bind(&range_ids)
+        .execute(&mut *tx)
+        .await
+        .map_err(anyhow::Error::from)?;
+
+        // Replace the range's entries with the merged set
+        sqlx::query("DELETE FROM layer_entries WHERE tenant_id = $1 AND layer_id = ANY($2)")
+            .bind(self.tenant_id)
+            .bind(&range_ids)
+            .execute(&mut *tx)
+            .await
+            .map_err(anyhow::Error::from)?;
+        for (entry, _) in &merged {
+            sqlx::query(
+                r#"
+                INSERT INTO layer_entries (
+                    layer_id, tenant_id, inode_id, path, change_type, size_delta, text_changes,
+                    block_changes
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                "#,
+            )
+            .bind(into_id)
+            .bind(self.tenant_id)
+            .bind(entry.inode_id)
+            .bind(&entry.path)
+            .bind(entry.change_type)
+            .bind(entry.size_delta)
+            .bind(&entry.text_changes)
+            .bind(&entry.block_changes)
+            .execute(&mut *tx)
+            .await
+            .map_err(anyhow::Error::from)?;
+        }
+
+        // Relink the chain around the squashed layer
+        sqlx::query(
+            r#"
+            UPDATE layers SET parent_layer_id = $3
+            WHERE tenant_id = $1 AND parent_layer_id = $2 AND layer_id <> ALL($4)
+            "#,
+        )
+        .bind(self.tenant_id)
+        .bind(newest.layer_id)
+        .bind(into_id)
+        .bind(&range_ids)
+        .execute(&mut *tx)
+        .await
+        .map_err(anyhow::Error::from)?;
+        sqlx::query(
+            "UPDATE layers SET parent_layer_id = $3, is_readonly = $4 \
+             WHERE tenant_id = $1 AND layer_id = $2",
+        )
+        .bind(self.tenant_id)
+        .bind(into_id)
+        .bind(oldest.parent_layer_id)
+        .bind(newest.is_readonly)
+        .execute(&mut *tx)
+        .await
+        .map_err(anyhow::Error::from)?;
+        sqlx::query(
+            "UPDATE tenant_current_layer SET current_layer_id = $2 \
+             WHERE tenant_id = $1 AND current_layer_id = ANY($3)",
+        )
+        .bind(self.tenant_id)
+        .bind(into_id)
+        .bind(&range_ids)
+        .execute(&mut *tx)
+        .await
+        .map_err(anyhow::Error::from)?;
+
+        // Newest first, so no removed layer is still some other layer's parent
+        for layer in range.iter().filter(|l| l.layer_id != into_id) {
+            sqlx::query("DELETE FROM layers WHERE tenant_id = $1 AND layer_id = $2")
+                .bind(self.tenant_id)
+                .bind(layer.layer_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(anyhow::Error::from)?;
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE layers
+            SET file_count = stats.file_count, total_size = GREATEST(stats.total_size, 0)
+            FROM (
+                SELECT COUNT(*)::int AS file_count, COALESCE(SUM(size_delta), 0)::bigint AS total_size
+                FROM layer_entries
+                WHERE tenant_id = $1 AND layer_id = $2
+            ) AS stats
+            WHERE tenant_id = $1 AND layer_id = $2
+            "#,
+        )
+        .bind(self.tenant_id)
+        .bind(into_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(anyhow::Error::from)?;
+
+        tx.commit().await.map_err(anyhow::Error::from)?;
+
+        info!(
+            tenant_id = %self.tenant_id,
+            into = %into_id,
+            squashed = range.len(),
+            entries = merged.len(),
+            "Squashed layers"
+        );
+
+        self.get_layer(into_id).await?.ok_or(LayerManagerError::LayerNotFound(into_id))
+    }
+
+    /// Delete stored content that no file version refers to any more.
+    ///
+    /// Fixed-size blocks go away with their inode or layer through foreign
+    /// keys; deduplicated chunks and text blocks are shared and outlive the
+    /// files that used them until swept here.
+    pub async fn gc(&self) -> LayerManagerResult<GcReport> {
+        let (chunks, chunk_bytes) =
+            ChunkOperations::new(self.pool).delete_unreferenced(self.tenant_id).await?;
+        let (text_blocks, text_bytes) =
+            TextBlockOperations::new(self.pool).delete_unreferenced().await?;
+
+        let report = GcReport { chunks, chunk_bytes, text_blocks, text_bytes };
+        info!(tenant_id = %self.tenant_id, ?report, "Collected garbage");
+        Ok(report)
+    }
+
     /// Get a specific layer by ID.
     pub async fn get_layer(&self, layer_id: LayerId) -> LayerManagerResult<Option<Layer>> {
         Ok(self.layer_ops().get(self.tenant_id, layer_id).await?)
     }
 
+    /// Move a layer under a different parent, or make it a root with `None`.
+    ///
+    /// The layer's own entries are untouched; only its position in the chain
+    /// changes. Parents that would put the layer in its own ancestry are
+    /// rejected.
+    pub async fn reparent(
+        &self,
+        layer_id: LayerId,
+        parent_id: Option<LayerId>,
+    ) -> LayerManagerResult<Layer> {
+        self.get_layer(layer_id).await?.ok_or(LayerManagerError::LayerNotFound(layer_id))?;
+        self.check_parent(layer_id, parent_id).await?;
+
+        sqlx::query(
+            "UPDATE layers SET parent_layer_id = $3 WHERE tenant_id = $1 AND layer_id = $2",
+        )
+        .bind(self.tenant_id)
+        .bind(layer_id)
+        .bind(parent_id)
+        .execute(self.pool)
+        .await
+        .map_err(anyhow::Error::from)?;
+
+        info!(tenant_id = %self.tenant_id, layer_id = %layer_id, parent_id = ?parent_id, "Reparented layer");
+        self.get_layer(layer_id).await?.ok_or(LayerManagerError::LayerNotFound(layer_id))
+    }
+
+    /// Check that `parent_id` can become the parent of `layer_id`.
+    ///
+    /// Walks up from the prospective parent; reaching `layer_id` means the
+    /// assignment would close a cycle, which the recursive chain queries
+    /// cannot cope with. An ancestry that already loops is rejected too.
+    async fn check_parent(
+        &self,
+        layer_id: LayerId,
+        parent_id: Option<LayerId>,
+    ) -> LayerManagerResult<()> {
+        let Some(parent_id) = parent_id else {
+            return Ok(());
+        };
+        let mut seen = HashSet::new();
+        let mut next = Some(parent_id);
+        while let Some(id) = next {
+            if id == layer_id {
+                return Err(LayerManagerError::InvalidLayerChain(format!(
+                    "making {} the parent of {} would create a cycle",
+                    parent_id, layer_id
+                )));
+            }
+            if !seen.insert(id) {
+                return Err(LayerManagerError::InvalidLayerChain(format!(
+                    "ancestry of {} already loops at {}",
+                    parent_id, id
+                )));
+            }
+            next = self
+                .get_layer(id)
+                .await?
+                .ok_or(LayerManagerError::LayerNotFound(id))?
+                .parent_layer_id;
+        }
+        Ok(())
+    }
+
     /// Set a layer as readonly or writable.
-    async fn set_layer_readonly(
+    async fn set_layer_readonly_in_tx(
         &self,
+        tx: &mut DatabaseTransaction<'_>,
         layer_id: LayerId,
         readonly: bool,
     ) -> LayerManagerResult<()> {
@@ -306,7 +1059,7 @@ impl<'a> LayerManager<'a> {
         .bind(self.tenant_id)
         .bind(layer_id)
         .bind(readonly)
-        .execute(self.pool)
+        .execute(&mut **tx)
         .await
         .map_err(|e| LayerManagerError::Storage(e.into()))?;
 
@@ -321,17 +1074,66 @@ impl<'a> LayerManager<'a> {
         change_type: ChangeType,
         size_delta: Option<i64>,
         text_changes: Option<serde_json::Value>,
+    ) -> LayerManagerResult<()> {
+        let mut tx = self.pool.begin().await.map_err(anyhow::Error::from)?;
+        self.record_change_in_tx(&mut tx, inode_id, path, change_type, size_delta, text_changes)
+            .await?;
+        tx.commit().await.map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    /// Record a change to the current layer as part of a caller-managed transaction.
+    pub async fn record_change_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        path: &str,
+        change_type: ChangeType,
+        size_delta: Option<i64>,
+        text_changes: Option<serde_json::Value>,
+    ) -> LayerManagerResult<()> {
+        self.record_entry_in_tx(tx, inode_id, path, change_type, size_delta, text_changes, None)
+            .await
+    }
+
+    /// Record a binary file write to the current layer, along with the blocks
+    /// the layer now overrides.
+    pub async fn record_binary_change_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        path: &str,
+        change_type: ChangeType,
+        size_delta: Option<i64>,
+        block_changes: &BlockChanges,
+    ) -> LayerManagerResult<()> {
+        let block_changes = Some(block_changes.to_json());
+        self.record_entry_in_tx(tx, inode_id, path, change_type, size_delta, None, block_changes)
+            .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn record_entry_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        inode_id: InodeId,
+        path: &str,
+        change_type: ChangeType,
+        size_delta: Option<i64>,
+        text_changes: Option<serde_json::Value>,
+        block_changes: Option<serde_json::Value>,
     ) -> LayerManagerResult<()> {
         let ops = self.layer_ops();
 
+        // Read in the transaction, which may have made a new layer current
         let current_layer_id = ops
-            .get_current_layer(self.tenant_id)
+            .get_current_layer_in_tx(tx, self.tenant_id)
             .await?
             .ok_or(LayerManagerError::NoCurrentLayer)?;
 
         // Check if layer is readonly
         let layer = ops
-            .get(self.tenant_id, current_layer_id)
+            .get_in_tx(tx, self.tenant_id, current_layer_id)
             .await?
             .ok_or(LayerManagerError::LayerNotFound(current_layer_id))?;
 
@@ -349,15 +1151,19 @@ impl<'a> LayerManager<'a> {
         );
 
         // Add entry
-        ops.add_entry(CreateLayerEntryInput {
-            layer_id: current_layer_id,
-            tenant_id: self.tenant_id,
-            inode_id,
-            path: path.to_string(),
-            change_type,
-            size_delta,
-            text_changes,
-        })
+        ops.add_entry_in_tx(
+            tx,
+            CreateLayerEntryInput {
+                layer_id: current_layer_id,
+                tenant_id: self.tenant_id,
+                inode_id,
+                path: path.to_string(),
+                change_type,
+                size_delta,
+                text_changes,
+                block_changes,
+            },
+        )
         .await?;
 
         Ok(())
@@ -371,6 +1177,86 @@ impl<'a> LayerManager<'a> {
         Ok(self.layer_ops().list_entries(self.tenant_id, layer_id).await?)
     }
 
+    /// Get the line-level changes recorded for a file in a layer.
+    pub async fn get_text_changes(
+        &self,
+        layer_id: LayerId,
+        path: &str,
+    ) -> LayerManagerResult<Option<TextChanges>> {
+        Ok(self.layer_ops().get_text_changes(self.tenant_id, layer_id, path).await?)
+    }
+
+    /// Get the block-level changes recorded for a binary file in a layer.
+    pub async fn get_block_changes(
+        &self,
+        layer_id: LayerId,
+        path: &str,
+    ) -> LayerManagerResult<Option<BlockChanges>> {
+        Ok(self.layer_ops().get_block_changes(self.tenant_id, layer_id, path).await?)
+    }
+
+    /// Compute which files differ between two layers.
+    ///
+    /// Each side's effective file set is built by replaying its layer chain
+    /// from the base, so the layers need not be ancestors of each other. A
+    /// path present on both sides is `Modify` when a different layer last
+    /// wrote it. Results are sorted by path.
+    pub async fn diff_layers(
+        &self,
+        from_id: LayerId,
+        to_id: LayerId,
+    ) -> LayerManagerResult<Vec<(String, ChangeType)>> {
+        let from = self.effective_files(from_id).await?;
+        let to = self.effective_files(to_id).await?;
+
+        let mut changes = Vec::new();
+        for (path, from_layer) in &from {
+            match to.get(path) {
+                None => changes.push((path.clone(), ChangeType::Delete)),
+                Some(to_layer) if to_layer != from_layer => {
+                    changes.push((path.clone(), ChangeType::Modify))
+                }
+                Some(_) => {}
+            }
+        }
+        for path in to.keys() {
+            if !from.contains_key(path) {
+                changes.push((path.clone(), ChangeType::Add));
+            }
+        }
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(changes)
+    }
+
+    /// Map each path visible at `layer_id` to the layer that last wrote it.
+    async fn effective_files(
+        &self,
+        layer_id: LayerId,
+    ) -> LayerManagerResult<BTreeMap<String, LayerId>> {
+        let chain = self.get_layer_chain(layer_id).await?;
+        if chain.is_empty() {
+            return Err(LayerManagerError::LayerNotFound(layer_id));
+        }
+
+        let mut files = BTreeMap::new();
+        // The chain starts at `layer_id`; replay from the base upwards
+        for layer in chain.iter().rev() {
+            for entry in self.get_layer_entries(layer.layer_id).await? {
+                match entry.change_type {
+                    ChangeType::Delete => {
+                        files.remove(&entry.path);
+                    }
+                    ChangeType::Add | ChangeType::Modify => {
+                        files.insert(entry.path, layer.layer_id);
+                    }
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
     /// Check if the current layer is at a historical position.
     pub async fn is_at_historical_position(&self) -> LayerManagerResult<bool> {
         if let Some(current_id) = self.get_current_layer_id().await? {
@@ -382,7 +1268,116 @@ impl<'a> LayerManager<'a> {
     }
 }
 
+/// Merge layer entries given oldest-first into one entry per path, paired
+/// with the layer that last wrote the path.
+///
+/// The first change to a path tells whether it existed below the merged
+/// range: a path that starts with `Add` and ends with `Delete` never did and is
+/// dropped, one that starts with anything else keeps a `Delete`, and one
+/// re-added after a delete becomes a `Modify`.
+fn merge_entries(entries: Vec<LayerEntry>) -> Vec<(LayerEntry, LayerId)> {
+    let mut merged: BTreeMap<String, (ChangeType, i64, LayerEntry)> = BTreeMap::new();
+
+    for entry in entries {
+        let delta = entry.size_delta.unwrap_or(0);
+        match merged.get_mut(&entry.path) {
+            Some((_, total_delta, last)) => {
+                *total_delta += delta;
+                *last = entry;
+            }
+            None => {
+                merged.insert(entry.path.clone(), (entry.change_type, delta, entry));
+            }
+        }
+    }
+
+    merged
+        .into_values()
+        .filter_map(|(first, total_delta, mut last)| {
+            let change_type = match (first, last.change_type) {
+                (ChangeType::Add, ChangeType::Delete) => return None,
+                (ChangeType::Add, _) => ChangeType::Add,
+                (_, ChangeType::Delete) => ChangeType::Delete,
+                _ => ChangeType::Modify,
+            };
+            let source_layer = last.layer_id;
+            last.change_type = change_type;
+            last.size_delta = Some(total_delta);
+            if change_type == ChangeType::Delete {
+                last.text_changes = None;
+            }
+            Some((last, source_layer))
+        })
+        .collect()
+}
+
+/// A filesystem error met while rewriting files, as a layer error.
+fn fs_error(error: FsError) -> LayerManagerError {
+    LayerManagerError::Storage(error.into())
+}
+
+/// Whether a storage error is a Postgres unique constraint violation.
+fn is_unique_violation(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<sqlx::Error>(),
+        Some(sqlx::Error::Database(db)) if db.code().as_deref() == Some("23505")
+    )
+}
+
 #[cfg(test)]
 mod tests {
-    // Tests would require database setup; see integration tests
+    // Database-backed behavior is covered by integration tests
+    use super::*;
+
+    fn entry(layer_id: LayerId, path: &str, change_type: ChangeType, delta: i64) -> LayerEntry {
+        LayerEntry {
+            entry_id: uuid::Uuid::new_v4(),
+            layer_id,
+            tenant_id: uuid::Uuid::nil(),
+            inode_id: 1,
+            path: path.to_string(),
+            change_type,
+            size_delta: Some(delta),
+            text_changes: None,
+            block_changes: None,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_merge_entries_last_writer_wins() {
+        let (l1, l2) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        let merged = merge_entries(vec![
+            entry(l1, "/added", ChangeType::Add, 10),
+            entry(l1, "/gone", ChangeType::Add, 5),
+            entry(l1, "/old", ChangeType::Modify, 3),
+            entry(l2, "/added", ChangeType::Modify, 2),
+            entry(l2, "/gone", ChangeType::Delete, -5),
+            entry(l2, "/old", ChangeType::Delete, -8),
+        ]);
+
+        let summary: Vec<(&str, ChangeType, Option<i64>, LayerId)> = merged
+            .iter()
+            .map(|(e, layer)| (e.path.as_str(), e.change_type, e.size_delta, *layer))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("/added", ChangeType::Add, Some(12), l2),
+                ("/old", ChangeType::Delete, Some(-5), l2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_entries_readd_after_delete_is_modify() {
+        let (l1, l2) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        let merged = merge_entries(vec![
+            entry(l1, "/f", ChangeType::Delete, -4),
+            entry(l2, "/f", ChangeType::Add, 6),
+        ]);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].0.change_type, ChangeType::Modify);
+        assert_eq!(merged[0].1, l2);
+    }
 }
@@ -9,14 +9,18 @@
 //! - Text file diff and line-level COW
 //! - Filesystem hooks (/.tarbox/)
 
+pub mod chunking;
 mod cow;
 mod detection;
 mod hooks;
 mod manager;
 mod union_view;
 
-pub use cow::{CowHandler, CowResult, TextChanges};
+pub use chunking::ChunkingMode;
+pub use cow::{BLOCK_SIZE, BlockChanges, CowHandler, CowResult, TextChanges, TextHunk};
 pub use detection::{DetectionConfig, FileTypeDetector, FileTypeInfo, LineEnding, TextEncoding};
-pub use hooks::{HookError, HookFileAttr, HookResult, HooksHandler, TARBOX_HOOK_PATH};
-pub use manager::{LayerManager, LayerManagerError};
+pub use hooks::{
+    HookDirEntry, HookError, HookFileAttr, HookResult, HooksHandler, TARBOX_HOOK_PATH, paths,
+};
+pub use manager::{DropPlan, GcReport, LayerManager, LayerManagerError, RollbackReport};
 pub use union_view::{DirectoryEntry, FileState, FileVersion, UnionView};
@@ -5,7 +5,7 @@
 
 use anyhow::Result;
 use sqlx::PgPool;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::storage::{ChangeType, Layer, LayerOperations, LayerRepository};
 use crate::types::{InodeId, LayerId, TenantId};
@@ -91,28 +91,30 @@ impl<'a> UnionView<'a> {
     /// Lookup a file by path in the union view.
     ///
     /// Traverses layers from current to base, returning the first match.
-    /// If a Delete entry is found, returns Deleted state.
+    /// A `Delete` entry is a whiteout: for the path itself or for any of its
+    /// ancestor directories it hides everything older layers hold there. An
+    /// entry for the path in the same layer as an ancestor's whiteout wins,
+    /// since it was written after the directory was recreated.
     pub async fn lookup_file(&self, path: &str) -> Result<FileState> {
         let layer_ops = LayerOperations::new(self.pool);
 
         for layer in &self.layer_chain {
             let entries = layer_ops.list_entries(self.tenant_id, layer.layer_id).await?;
 
-            // Find entry for this path
-            for entry in entries {
-                if entry.path == path {
-                    match entry.change_type {
-                        ChangeType::Delete => {
-                            return Ok(FileState::Deleted { deleted_in_layer: layer.layer_id });
-                        }
-                        ChangeType::Add | ChangeType::Modify => {
-                            return Ok(FileState::Exists {
-                                layer_id: layer.layer_id,
-                                inode_id: entry.inode_id,
-                            });
-                        }
+            if let Some(entry) = entries.iter().find(|e| e.path == path) {
+                return Ok(match entry.change_type {
+                    ChangeType::Delete => FileState::Deleted { deleted_in_layer: layer.layer_id },
+                    ChangeType::Add | ChangeType::Modify => {
+                        FileState::Exists { layer_id: layer.layer_id, inode_id: entry.inode_id }
                     }
-                }
+                });
+            }
+
+            if entries
+                .iter()
+                .any(|e| e.change_type == ChangeType::Delete && is_ancestor(&e.path, path))
+            {
+                return Ok(FileState::Deleted { deleted_in_layer: layer.layer_id });
             }
         }
 
@@ -124,6 +126,8 @@ impl<'a> UnionView<'a> {
     /// List all files in a directory across all layers.
     ///
     /// Merges directory contents from all layers, respecting delete markers.
+    /// A whiteout of the directory itself or one of its ancestors drops
+    /// everything contributed by older layers. Entries are sorted by name.
     pub async fn list_directory(&self, dir_path: &str) -> Result<Vec<DirectoryEntry>> {
         let layer_ops = LayerOperations::new(self.pool);
         let mut result_map: HashMap<String, DirectoryEntry> = HashMap::new();
@@ -132,6 +136,14 @@ impl<'a> UnionView<'a> {
         for layer in self.layer_chain.iter().rev() {
             let entries = layer_ops.list_entries(self.tenant_id, layer.layer_id).await?;
 
+            let dir_whited_out = entries.iter().any(|e| {
+                e.change_type == ChangeType::Delete
+                    && (e.path == dir_path || is_ancestor(&e.path, dir_path))
+            });
+            if dir_whited_out {
+                result_map.clear();
+            }
+
             for entry in entries {
                 // Check if this entry is in the target directory
                 if let Some(parent) = get_parent_path(&entry.path)
@@ -157,7 +169,37 @@ impl<'a> UnionView<'a> {
             }
         }
 
-        Ok(result_map.into_values().collect())
+        let mut entries: Vec<DirectoryEntry> = result_map.into_values().collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// List every file visible in the view, keyed by full path.
+    ///
+    /// Replays the chain from base to current. Within a layer whiteouts are
+    /// applied before additions, matching `lookup_file`. Directories have no
+    /// entries of their own and only show up as parents of the paths.
+    pub async fn list_all(&self) -> Result<BTreeMap<String, DirectoryEntry>> {
+        let layer_ops = LayerOperations::new(self.pool);
+        let mut result_map: BTreeMap<String, DirectoryEntry> = BTreeMap::new();
+
+        for layer in self.layer_chain.iter().rev() {
+            let entries = layer_ops.list_entries(self.tenant_id, layer.layer_id).await?;
+
+            for entry in entries.iter().filter(|e| e.change_type == ChangeType::Delete) {
+                result_map.retain(|path, _| path != &entry.path && !is_ancestor(&entry.path, path));
+            }
+
+            for entry in entries.into_iter().filter(|e| e.change_type != ChangeType::Delete) {
+                let name = get_filename(&entry.path);
+                result_map.insert(
+                    entry.path,
+                    DirectoryEntry { name, inode_id: entry.inode_id, layer_id: layer.layer_id },
+                );
+            }
+        }
+
+        Ok(result_map)
     }
 
     /// Get the history of a file across layers.
@@ -231,6 +273,12 @@ fn get_parent_path(path: &str) -> Option<String> {
     }
 }
 
+/// Whether `dir` is a proper ancestor directory of `path`.
+fn is_ancestor(dir: &str, path: &str) -> bool {
+    let dir = dir.trim_end_matches('/');
+    dir.is_empty() || path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
 /// Get the filename from a path.
 fn get_filename(path: &str) -> String {
     let path = path.trim_end_matches('/');
@@ -252,6 +300,16 @@ mod tests {
         assert_eq!(get_parent_path("/foo/bar/baz"), Some("/foo/bar".to_string()));
     }
 
+    #[test]
+    fn test_is_ancestor() {
+        assert!(is_ancestor("/dir", "/dir/a.txt"));
+        assert!(is_ancestor("/dir", "/dir/sub/a.txt"));
+        assert!(is_ancestor("/", "/a.txt"));
+        assert!(!is_ancestor("/dir", "/dir"));
+        assert!(!is_ancestor("/dir", "/directory/a.txt"));
+        assert!(!is_ancestor("/dir/a.txt", "/dir"));
+    }
+
     #[test]
     fn test_get_filename() {
         assert_eq!(get_filename("/foo/bar"), "bar");
@@ -5,5 +5,6 @@ pub mod fs;
 pub mod fuse;
 pub mod layer;
 pub mod storage;
+pub mod testkit;
 pub mod types;
 pub mod wasi;
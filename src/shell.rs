@@ -0,0 +1,185 @@
+//! `tarbox shell`: many commands against one tenant over a single pool.
+//!
+//! Commands are read a line at a time, from a terminal or a pipe, and run
+//! against a `FileSystem` opened once. Relative paths are taken from the
+//! working directory `cd` sets.
+
+use anyhow::{Result, bail};
+use std::io::{IsTerminal, Write};
+use tarbox::config::LayerConfig;
+use tarbox::fs::FileSystem;
+use tarbox::layer::LayerManager;
+use tarbox::storage::{DatabasePool, InodeType};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use uuid::Uuid;
+
+const HELP: &str = "\
+Commands:
+  ls [path]                 List a directory
+  cat <path>                Print a file
+  write <path> <content>    Replace a file's content with the rest of the line
+  cd [path]                 Change the working directory (default /)
+  pwd                       Print the working directory
+  mkdir <path>              Create a directory
+  rm [-r] <path>            Remove a file, or a directory with -r
+  stat <path>               Show file or directory information
+  layer new <name>          Checkpoint the current layer and start <name>
+  layer switch <layer>      Make a layer (name or ID) current
+  help                      Show this help
+  exit                      Leave the shell";
+
+struct Shell<'a> {
+    pool: &'a DatabasePool,
+    tenant_id: Uuid,
+    layer_config: &'a LayerConfig,
+    fs: FileSystem<'a>,
+    cwd: String,
+}
+
+/// Run commands from standard input until it ends or `exit` is read. A
+/// failed command is reported and the next one runs; the shell fails at the
+/// end if any did.
+pub async fn run(pool: &DatabasePool, tenant_id: Uuid, layer_config: &LayerConfig) -> Result<()> {
+    let fs = FileSystem::new(pool.pool(), tenant_id).await?;
+    let mut shell = Shell { pool, tenant_id, layer_config, fs, cwd: "/".to_string() };
+    let interactive = std::io::stdin().is_terminal();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut failed = 0;
+
+    loop {
+        if interactive {
+            print!("tarbox:{}> ", shell.cwd);
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next_line().await? else {
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "exit" || line == "quit" {
+            break;
+        }
+
+        let mut out = std::io::stdout().lock();
+        if let Err(e) = shell.execute(line, &mut out).await {
+            failed += 1;
+            eprintln!("{}: {}", line.split_whitespace().next().unwrap_or(line), e);
+        }
+        out.flush()?;
+    }
+
+    if failed > 0 {
+        bail!("{} commands failed", failed);
+    }
+    Ok(())
+}
+
+impl<'a> Shell<'a> {
+    async fn execute(&mut self, line: &str, out: &mut impl Write) -> Result<()> {
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let args: Vec<&str> = rest.split_whitespace().collect();
+
+        match (command, args.as_slice()) {
+            ("help", []) => writeln!(out, "{}", HELP)?,
+            ("pwd", []) => writeln!(out, "{}", self.cwd)?,
+            ("ls", [] | [_]) => {
+                let path = self.resolve(args.first().copied().unwrap_or("."));
+                for entry in self.fs.list_directory_with_attrs(&path).await? {
+                    let suffix = if entry.inode_type == InodeType::Dir { "/" } else { "" };
+                    writeln!(out, "{}{}", entry.name, suffix)?;
+                }
+            }
+            ("cat", [path]) => out.write_all(&self.fs.read_file(&self.resolve(path)).await?)?,
+            ("write", [path, ..]) => {
+                // Everything after the path is content, spaces and all
+                let content = rest.trim_start()[path.len()..].trim_start();
+                let path = self.resolve(path);
+                if self.fs.stat(&path).await.is_err() {
+                    self.fs.create_file(&path).await?;
+                }
+                self.fs.write_file(&path, content.as_bytes()).await?;
+                writeln!(out, "Wrote {} bytes to {}", content.len(), path)?;
+            }
+            ("cd", [] | [_]) => {
+                let path = self.resolve(args.first().copied().unwrap_or("/"));
+                if self.fs.stat(&path).await?.inode_type != InodeType::Dir {
+                    bail!("not a directory: {}", path);
+                }
+                self.cwd = path;
+            }
+            ("mkdir", [path]) => {
+                let path = self.resolve(path);
+                self.fs.create_directory(&path).await?;
+                writeln!(out, "Created directory: {}", path)?;
+            }
+            ("rm", [path]) => {
+                let path = self.resolve(path);
+                self.fs.delete_file(&path).await?;
+                writeln!(out, "Removed file: {}", path)?;
+            }
+            ("rm", ["-r", path]) => {
+                let path = self.resolve(path);
+                if self.fs.stat(&path).await?.inode_type == InodeType::Dir {
+                    let removed = self.fs.remove_directory_recursive(&path).await?;
+                    writeln!(out, "Removed directory: {} ({} entries)", path, removed)?;
+                } else {
+                    self.fs.delete_file(&path).await?;
+                    writeln!(out, "Removed file: {}", path)?;
+                }
+            }
+            ("stat", [path]) => {
+                let path = self.resolve(path);
+                let inode = self.fs.stat(&path).await?;
+                let storage = match inode.inode_type {
+                    InodeType::File => Some(self.fs.storage_mode(&path).await?),
+                    _ => None,
+                };
+                super::write_stat(out, &path, &inode, storage.as_ref())?;
+            }
+            ("layer", ["new", name]) => {
+                let layer = self.layers().create_checkpoint(name, None).await?;
+                self.reopen().await?;
+                writeln!(out, "Created layer {} ({})", layer.layer_name, layer.layer_id)?;
+            }
+            ("layer", ["switch", layer]) => {
+                let layer_id = super::resolve_layer(self.pool, self.tenant_id, layer).await?;
+                let layer = self.layers().switch_to_layer(layer_id).await?;
+                self.reopen().await?;
+                writeln!(out, "Switched to layer {} ({})", layer.layer_name, layer.layer_id)?;
+            }
+            _ => bail!("unknown command or wrong arguments; try help"),
+        }
+        Ok(())
+    }
+
+    fn layers(&self) -> LayerManager<'a> {
+        LayerManager::new(self.pool.pool(), self.tenant_id).with_layer_config(self.layer_config)
+    }
+
+    /// Open the filesystem again, as it keeps writing to the layer that was
+    /// current when it was opened.
+    async fn reopen(&mut self) -> Result<()> {
+        self.fs = FileSystem::new(self.pool.pool(), self.tenant_id).await?;
+        Ok(())
+    }
+
+    /// `path` made absolute against the working directory, with `.` and `..`
+    /// taken out.
+    fn resolve(&self, path: &str) -> String {
+        let joined =
+            if path.starts_with('/') { path.to_string() } else { format!("{}/{}", self.cwd, path) };
+        let mut parts: Vec<&str> = Vec::new();
+        for part in joined.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                name => parts.push(name),
+            }
+        }
+        format!("/{}", parts.join("/"))
+    }
+}
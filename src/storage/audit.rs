@@ -1,13 +1,133 @@
+use std::str::FromStr;
+
 use anyhow::Result;
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::stream::{BoxStream, StreamExt};
+use serde::Serialize;
 use sqlx::PgPool;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::types::TenantId;
 
 use super::models::{AuditLog, AuditStats, CreateAuditLogInput, QueryAuditLogsInput};
 use super::traits::AuditLogRepository;
 
+/// CEF header fields identifying the producer of exported events.
+const CEF_VENDOR: &str = "Tarbox";
+const CEF_PRODUCT: &str = "tarbox";
+
+/// Time range of an export: `start` inclusive, `end` exclusive.
+#[derive(Debug, Clone, Copy)]
+pub struct AuditWindow {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Narrows an export to matching entries; unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub operation: Option<String>,
+    pub uid: Option<i32>,
+    /// SQL `LIKE` pattern the path must match.
+    pub path_pattern: Option<String>,
+    pub success: Option<bool>,
+}
+
+/// An audit entry as exported in JSON lines.
+///
+/// This is the wire schema consumers parse, kept separate from the database
+/// model: fields are only ever added, every field is always present (`null`
+/// when unset) and the timestamp is RFC 3339 UTC with microseconds.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord<'a> {
+    pub schema_version: u32,
+    pub log_id: i64,
+    pub timestamp: String,
+    pub tenant_id: TenantId,
+    pub operation: &'a str,
+    pub path: &'a str,
+    pub success: bool,
+    pub uid: i32,
+    pub gid: i32,
+    pub pid: Option<i32>,
+    pub inode_id: Option<i64>,
+    pub error_code: Option<i32>,
+    pub error_message: Option<&'a str>,
+    pub bytes_read: Option<i64>,
+    pub bytes_written: Option<i64>,
+    pub duration_ms: Option<i32>,
+    pub is_native_mount: bool,
+    pub native_source_path: Option<&'a str>,
+    pub text_changes: Option<&'a serde_json::Value>,
+    pub metadata: Option<&'a serde_json::Value>,
+    pub agent_id: Option<&'a str>,
+}
+
+/// Version of `AuditRecord`, bumped only if a field changes meaning.
+pub const AUDIT_RECORD_SCHEMA_VERSION: u32 = 1;
+
+impl<'a> From<&'a AuditLog> for AuditRecord<'a> {
+    fn from(log: &'a AuditLog) -> Self {
+        Self {
+            schema_version: AUDIT_RECORD_SCHEMA_VERSION,
+            log_id: log.log_id,
+            timestamp: log.created_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            tenant_id: log.tenant_id,
+            operation: &log.operation,
+            path: &log.path,
+            success: log.success,
+            uid: log.uid,
+            gid: log.gid,
+            pid: log.pid,
+            inode_id: log.inode_id,
+            error_code: log.error_code,
+            error_message: log.error_message.as_deref(),
+            bytes_read: log.bytes_read,
+            bytes_written: log.bytes_written,
+            duration_ms: log.duration_ms,
+            is_native_mount: log.is_native_mount,
+            native_source_path: log.native_source_path.as_deref(),
+            text_changes: log.text_changes.as_ref(),
+            metadata: log.metadata.as_ref(),
+            agent_id: log.agent_id.as_deref(),
+        }
+    }
+}
+
+/// Output format of `AuditLogOperations::export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditExportFormat {
+    /// One JSON object per line.
+    JsonLines,
+    /// ArcSight Common Event Format, one event per line.
+    Cef,
+}
+
+impl FromStr for AuditExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" | "jsonl" | "ndjson" => Ok(Self::JsonLines),
+            "cef" => Ok(Self::Cef),
+            other => {
+                anyhow::bail!("Unknown audit export format '{}' (expected json or cef)", other)
+            }
+        }
+    }
+}
+
+impl AuditExportFormat {
+    /// Render one audit log entry as a line (without trailing newline).
+    pub fn format(&self, log: &AuditLog) -> Result<String> {
+        match self {
+            Self::JsonLines => Ok(serde_json::to_string(&AuditRecord::from(log))?),
+            Self::Cef => Ok(to_cef(log)),
+        }
+    }
+}
+
 pub struct AuditLogOperations<'a> {
     pool: &'a PgPool,
 }
@@ -16,6 +136,172 @@ impl<'a> AuditLogOperations<'a> {
     pub fn new(pool: &'a PgPool) -> Self {
         Self { pool }
     }
+
+    /// Create this month's partition of `audit_logs` if it is missing; the
+    /// migrations only create the first few. Safe to run concurrently.
+    pub async fn ensure_current_partition(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        // create_audit_log_partition checks, then creates
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('audit_logs_partition'))")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("SELECT create_audit_log_partition(date_trunc('month', CURRENT_DATE)::date)")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Stream a tenant's audit logs in `window`, oldest first, rendered in
+    /// `format`. Rows are fetched incrementally, so large windows are not
+    /// buffered in memory.
+    pub fn export(
+        &self,
+        tenant_id: TenantId,
+        window: AuditWindow,
+        format: AuditExportFormat,
+    ) -> BoxStream<'a, Result<String>> {
+        self.export_matching(tenant_id, window, &AuditFilter::default(), format)
+    }
+
+    /// Like `export`, limited to entries matching `filter`.
+    pub fn export_matching(
+        &self,
+        tenant_id: TenantId,
+        window: AuditWindow,
+        filter: &AuditFilter,
+        format: AuditExportFormat,
+    ) -> BoxStream<'a, Result<String>> {
+        // log_date bounds let Postgres skip partitions outside the window
+        sqlx::query_as::<_, AuditLog>(
+            r#"
+            SELECT log_id, tenant_id, inode_id, operation, uid, gid, pid,
+                   path, success, error_code, error_message,
+                   bytes_read, bytes_written, duration_ms, text_changes,
+                   is_native_mount, native_source_path, metadata, agent_id,
+                   created_at, log_date
+            FROM audit_logs
+            WHERE tenant_id = $1
+              AND created_at >= $2
+              AND created_at < $3
+              AND log_date >= $2::date
+              AND log_date <= $3::date
+              AND ($4::text IS NULL OR operation = $4)
+              AND ($5::int IS NULL OR uid = $5)
+              AND ($6::text IS NULL OR path LIKE $6)
+              AND ($7::bool IS NULL OR success = $7)
+            ORDER BY created_at, log_id
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(window.start)
+        .bind(window.end)
+        .bind(filter.operation.clone())
+        .bind(filter.uid)
+        .bind(filter.path_pattern.clone())
+        .bind(filter.success)
+        .fetch(self.pool)
+        .map(move |row| format.format(&row?))
+        .boxed()
+    }
+
+    /// Write a tenant's matching audit logs in `window` to `writer` as
+    /// newline-delimited `AuditRecord`s, oldest first. Returns the number of
+    /// entries written.
+    pub async fn export_ndjson<W: AsyncWrite + Unpin>(
+        &self,
+        tenant_id: TenantId,
+        window: AuditWindow,
+        filter: &AuditFilter,
+        writer: &mut W,
+    ) -> Result<u64> {
+        let mut lines =
+            self.export_matching(tenant_id, window, filter, AuditExportFormat::JsonLines);
+        let mut written = 0;
+        while let Some(line) = lines.next().await {
+            writer.write_all(line?.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+            written += 1;
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+}
+
+/// Render an audit log entry as a CEF:0 event.
+///
+/// tarbox operations map to `act`, the caller to `suser`/`suid`/`spid`, and
+/// the file to `fname`/`filePath`; the tenant is carried in `cs1`.
+pub fn to_cef(log: &AuditLog) -> String {
+    let severity = if log.success { 3 } else { 7 };
+    let name = if log.success {
+        format!("{} succeeded", log.operation)
+    } else {
+        format!("{} failed", log.operation)
+    };
+    let fname = log.path.rsplit('/').next().filter(|n| !n.is_empty()).unwrap_or("/");
+
+    let mut ext: Vec<(&str, String)> = vec![
+        ("rt", log.created_at.timestamp_millis().to_string()),
+        ("act", log.operation.clone()),
+        ("outcome", if log.success { "success" } else { "failure" }.to_string()),
+        ("suser", log.uid.to_string()),
+        ("suid", log.uid.to_string()),
+        ("fname", fname.to_string()),
+        ("filePath", log.path.clone()),
+        ("externalId", log.log_id.to_string()),
+        ("cs1Label", "tenantId".to_string()),
+        ("cs1", log.tenant_id.to_string()),
+    ];
+    if let Some(pid) = log.pid {
+        ext.push(("spid", pid.to_string()));
+    }
+    if let Some(bytes) = log.bytes_read {
+        ext.push(("in", bytes.to_string()));
+    }
+    if let Some(bytes) = log.bytes_written {
+        ext.push(("out", bytes.to_string()));
+    }
+    if let Some(ms) = log.duration_ms {
+        ext.push(("cn1Label", "durationMs".to_string()));
+        ext.push(("cn1", ms.to_string()));
+    }
+    if let Some(code) = log.error_code {
+        ext.push(("cn2Label", "errorCode".to_string()));
+        ext.push(("cn2", code.to_string()));
+    }
+    if let Some(message) = &log.error_message {
+        ext.push(("reason", message.clone()));
+    }
+
+    let extension: Vec<String> =
+        ext.iter().map(|(key, value)| format!("{}={}", key, cef_escape_value(value))).collect();
+
+    format!(
+        "CEF:0|{}|{}|{}|{}|{}|{}|{}",
+        CEF_VENDOR,
+        CEF_PRODUCT,
+        env!("CARGO_PKG_VERSION"),
+        cef_escape_header(&log.operation),
+        cef_escape_header(&name),
+        severity,
+        extension.join(" ")
+    )
+}
+
+/// Escape a CEF header field: backslash and pipe.
+fn cef_escape_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+/// Escape a CEF extension value: backslash, equals sign and line breaks.
+fn cef_escape_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
 }
 
 #[async_trait]
@@ -29,13 +315,15 @@ impl<'a> AuditLogRepository for AuditLogOperations<'a> {
                 tenant_id, inode_id, operation, uid, gid, pid,
                 path, success, error_code, error_message,
                 bytes_read, bytes_written, duration_ms, text_changes,
-                is_native_mount, native_source_path, metadata, log_date
+                is_native_mount, native_source_path, metadata, log_date, agent_id
+            )
+            VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19
             )
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)
             RETURNING log_id, tenant_id, inode_id, operation, uid, gid, pid,
                       path, success, error_code, error_message,
                       bytes_read, bytes_written, duration_ms, text_changes,
-                      is_native_mount, native_source_path, metadata,
+                      is_native_mount, native_source_path, metadata, agent_id,
                       created_at, log_date
             "#,
         )
@@ -57,6 +345,7 @@ impl<'a> AuditLogRepository for AuditLogOperations<'a> {
         .bind(&input.native_source_path)
         .bind(&input.metadata)
         .bind(log_date)
+        .bind(&input.agent_id)
         .fetch_one(self.pool)
         .await?;
 
@@ -87,9 +376,11 @@ impl<'a> AuditLogRepository for AuditLogOperations<'a> {
                     tenant_id, inode_id, operation, uid, gid, pid,
                     path, success, error_code, error_message,
                     bytes_read, bytes_written, duration_ms, text_changes,
-                    is_native_mount, native_source_path, metadata, log_date
+                    is_native_mount, native_source_path, metadata, log_date, agent_id
                 )
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)
+                VALUES (
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19
+            )
                 "#,
             )
             .bind(input.tenant_id)
@@ -110,6 +401,7 @@ impl<'a> AuditLogRepository for AuditLogOperations<'a> {
             .bind(&input.native_source_path)
             .bind(&input.metadata)
             .bind(log_date)
+            .bind(&input.agent_id)
             .execute(&mut *tx)
             .await?;
 
@@ -129,7 +421,7 @@ impl<'a> AuditLogRepository for AuditLogOperations<'a> {
             SELECT log_id, tenant_id, inode_id, operation, uid, gid, pid,
                    path, success, error_code, error_message,
                    bytes_read, bytes_written, duration_ms, text_changes,
-                   is_native_mount, native_source_path, metadata,
+                   is_native_mount, native_source_path, metadata, agent_id,
                    created_at, log_date
             FROM audit_logs
             WHERE tenant_id = $1
@@ -258,6 +550,55 @@ mod tests {
     use super::*;
     use chrono::Utc;
 
+    fn sample_log(success: bool) -> AuditLog {
+        AuditLog {
+            log_id: 7,
+            tenant_id: uuid::Uuid::nil(),
+            inode_id: Some(3),
+            operation: "write".to_string(),
+            uid: 1000,
+            gid: 1000,
+            pid: Some(42),
+            path: "/data/a=b|c.txt".to_string(),
+            success,
+            error_code: (!success).then_some(28),
+            error_message: (!success).then(|| "no space\nleft".to_string()),
+            bytes_read: None,
+            bytes_written: Some(512),
+            duration_ms: Some(5),
+            text_changes: None,
+            is_native_mount: false,
+            native_source_path: None,
+            metadata: None,
+            agent_id: None,
+            created_at: Utc::now(),
+            log_date: Utc::now().date_naive(),
+        }
+    }
+
+    #[test]
+    fn test_export_format_from_str() {
+        assert_eq!("cef".parse::<AuditExportFormat>().unwrap(), AuditExportFormat::Cef);
+        assert_eq!("JSON".parse::<AuditExportFormat>().unwrap(), AuditExportFormat::JsonLines);
+        assert!("xml".parse::<AuditExportFormat>().is_err());
+    }
+
+    #[test]
+    fn test_to_cef_escapes_fields() {
+        let cef = to_cef(&sample_log(false));
+        assert!(cef.starts_with("CEF:0|Tarbox|tarbox|"));
+        assert!(cef.contains("|write|write failed|7|"));
+        assert!(cef.contains("filePath=/data/a\\=b|c.txt"));
+        assert!(cef.contains("reason=no space\\nleft"));
+        assert!(cef.contains("cn2=28"));
+        assert!(!cef.contains('\n'));
+    }
+
+    #[test]
+    fn test_cef_escape_header() {
+        assert_eq!(cef_escape_header("a|b\\c"), "a\\|b\\\\c");
+    }
+
     #[test]
     fn test_build_query_conditions_no_filters() {
         let input = QueryAuditLogsInput {
@@ -1,11 +1,14 @@
 use anyhow::Result;
 use async_trait::async_trait;
-use sqlx::PgPool;
+use sqlx::{PgExecutor, PgPool};
 use uuid::Uuid;
 
-use crate::types::{BlockId, InodeId, TenantId};
+use crate::layer::BLOCK_SIZE;
+use crate::types::{BlockId, InodeId, LayerId, TenantId};
 
+use super::chunk::ChunkOperations;
 use super::models::{CreateBlockInput, DataBlock};
+use super::pool::{DatabaseTransaction, begin_snapshot};
 use super::traits::BlockRepository;
 
 pub struct BlockOperations<'a> {
@@ -18,15 +21,30 @@ impl<'a> BlockOperations<'a> {
     }
 
     pub async fn create(&self, input: CreateBlockInput) -> Result<DataBlock> {
+        let mut tx = self.pool.begin().await?;
+        let block = self.create_in_tx(&mut tx, input).await?;
+        tx.commit().await?;
+        Ok(block)
+    }
+
+    /// Create a data block as part of a caller-managed transaction.
+    pub async fn create_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        input: CreateBlockInput,
+    ) -> Result<DataBlock> {
         let block_id = Uuid::new_v4();
         let size = input.data.len() as i32;
         let content_hash = compute_content_hash(&input.data);
 
         let block = sqlx::query_as::<_, DataBlock>(
             r#"
-            INSERT INTO data_blocks (block_id, tenant_id, inode_id, block_index, data, size, content_hash)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-            RETURNING block_id, tenant_id, inode_id, block_index, data, size, content_hash, created_at
+            INSERT INTO data_blocks (
+                block_id, tenant_id, inode_id, block_index, data, size, content_hash, layer_id
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING block_id, tenant_id, inode_id, block_index, data, size, content_hash,
+                      created_at, layer_id
             "#,
         )
         .bind(block_id)
@@ -36,9 +54,9 @@ impl<'a> BlockOperations<'a> {
         .bind(&input.data)
         .bind(size)
         .bind(&content_hash)
-        .fetch_one(self.pool)
-        .await
-        ?;
+        .bind(input.layer_id)
+        .fetch_one(&mut **tx)
+        .await?;
 
         tracing::debug!(
             tenant_id = %block.tenant_id,
@@ -60,7 +78,8 @@ impl<'a> BlockOperations<'a> {
     ) -> Result<Option<DataBlock>> {
         let block = sqlx::query_as::<_, DataBlock>(
             r#"
-            SELECT block_id, tenant_id, inode_id, block_index, data, size, content_hash, created_at
+            SELECT block_id, tenant_id, inode_id, block_index, data, size, content_hash,
+                   created_at, layer_id
             FROM data_blocks
             WHERE tenant_id = $1 AND inode_id = $2 AND block_index = $3
             "#,
@@ -77,7 +96,8 @@ impl<'a> BlockOperations<'a> {
     pub async fn get_by_id(&self, block_id: BlockId) -> Result<Option<DataBlock>> {
         let block = sqlx::query_as::<_, DataBlock>(
             r#"
-            SELECT block_id, tenant_id, inode_id, block_index, data, size, content_hash, created_at
+            SELECT block_id, tenant_id, inode_id, block_index, data, size, content_hash,
+                   created_at, layer_id
             FROM data_blocks
             WHERE block_id = $1
             "#,
@@ -90,27 +110,27 @@ impl<'a> BlockOperations<'a> {
     }
 
     pub async fn list(&self, tenant_id: TenantId, inode_id: InodeId) -> Result<Vec<DataBlock>> {
-        let blocks = sqlx::query_as::<_, DataBlock>(
-            r#"
-            SELECT block_id, tenant_id, inode_id, block_index, data, size, content_hash, created_at
-            FROM data_blocks
-            WHERE tenant_id = $1 AND inode_id = $2
-            ORDER BY block_index
-            "#,
-        )
-        .bind(tenant_id)
-        .bind(inode_id)
-        .fetch_all(self.pool)
-        .await?;
-
-        Ok(blocks)
+        Self::list_with(self.pool, tenant_id, inode_id).await
     }
 
     pub async fn delete(&self, tenant_id: TenantId, inode_id: InodeId) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let count = self.delete_in_tx(&mut tx, tenant_id, inode_id).await?;
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    /// Delete all data blocks of an inode as part of a caller-managed transaction.
+    pub async fn delete_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+    ) -> Result<u64> {
         let result = sqlx::query("DELETE FROM data_blocks WHERE tenant_id = $1 AND inode_id = $2")
             .bind(tenant_id)
             .bind(inode_id)
-            .execute(self.pool)
+            .execute(&mut **tx)
             .await?;
 
         let count = result.rows_affected();
@@ -127,6 +147,104 @@ impl<'a> BlockOperations<'a> {
         Ok(count)
     }
 
+    /// Delete the blocks one layer stored for an inode, leaving the versions
+    /// other layers hold untouched.
+    pub async fn delete_layer_blocks_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM data_blocks WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3",
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(layer_id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Delete the blocks one layer stored for an inode with indices from
+    /// `from`, below `until` if given.
+    pub async fn delete_layer_range_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        from: i32,
+        until: Option<i32>,
+    ) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM data_blocks
+            WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3
+              AND block_index >= $4 AND ($5::int IS NULL OR block_index < $5)
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(layer_id)
+        .bind(from)
+        .bind(until)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Store one block of an inode in `layer_id`, replacing the version that
+    /// layer already holds at that index.
+    ///
+    /// An empty block masks the versions lower layers hold, so the index
+    /// reads as a hole from this layer up.
+    pub async fn put_layer_block_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        block_index: i32,
+        data: &[u8],
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO data_blocks (
+                block_id, tenant_id, inode_id, block_index, data, size, content_hash, layer_id
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (tenant_id, inode_id, layer_id, block_index) WHERE layer_id IS NOT NULL
+            DO UPDATE SET data = EXCLUDED.data, size = EXCLUDED.size,
+                          content_hash = EXCLUDED.content_hash
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(block_index)
+        .bind(data)
+        .bind(data.len() as i32)
+        .bind(compute_content_hash(data))
+        .bind(layer_id)
+        .execute(&mut **tx)
+        .await?;
+
+        tracing::debug!(
+            tenant_id = %tenant_id,
+            inode_id = inode_id,
+            layer_id = %layer_id,
+            block_index = block_index,
+            size = data.len(),
+            "Stored data block"
+        );
+
+        Ok(())
+    }
+
     pub async fn delete_block(
         &self,
         tenant_id: TenantId,
@@ -144,6 +262,308 @@ impl<'a> BlockOperations<'a> {
 
         Ok(result.rows_affected() > 0)
     }
+
+    /// List an inode's data blocks within a caller-managed transaction.
+    pub async fn list_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+    ) -> Result<Vec<DataBlock>> {
+        Self::list_with(&mut **tx, tenant_id, inode_id).await
+    }
+
+    /// List the blocks of an inode as seen from `layer_id`.
+    ///
+    /// For each block index the version from the nearest layer in the chain
+    /// from `layer_id` down to the base wins; blocks without a layer sit
+    /// below the base. Versions stored by layers off the chain are ignored.
+    pub async fn list_visible(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Vec<DataBlock>> {
+        Self::list_visible_with(self.pool, tenant_id, inode_id, layer_id, 0, None, None).await
+    }
+
+    /// List the blocks visible from a layer within a caller-managed transaction.
+    pub async fn list_visible_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Vec<DataBlock>> {
+        Self::list_visible_with(&mut **tx, tenant_id, inode_id, layer_id, 0, None, None).await
+    }
+
+    /// List up to `limit` visible blocks starting at block index `from`,
+    /// for reading a file a page at a time.
+    pub async fn list_visible_page_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        from: i32,
+        limit: i64,
+    ) -> Result<Vec<DataBlock>> {
+        Self::list_visible_with(&mut **tx, tenant_id, inode_id, layer_id, from, None, Some(limit))
+            .await
+    }
+
+    /// List the visible blocks with indices in `first..first + count`.
+    /// Indices without a visible block are holes and are left out.
+    pub async fn list_visible_range(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        first: i32,
+        count: i32,
+    ) -> Result<Vec<DataBlock>> {
+        let until = first.saturating_add(count);
+        Self::list_visible_with(self.pool, tenant_id, inode_id, layer_id, first, Some(until), None)
+            .await
+    }
+
+    /// The visible blocks with indices in `first..until`, within a
+    /// caller-managed transaction.
+    pub async fn list_visible_range_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        first: i32,
+        until: i32,
+    ) -> Result<Vec<DataBlock>> {
+        Self::list_visible_with(&mut **tx, tenant_id, inode_id, layer_id, first, Some(until), None)
+            .await
+    }
+
+    /// Indices of the blocks visible from `layer_id`, in order, without
+    /// fetching their data. Indices left out are holes, including those
+    /// where the nearest version is an empty block masking lower layers.
+    pub async fn list_visible_indices_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Vec<i32>> {
+        let indices = sqlx::query_scalar::<_, i32>(
+            r#"
+            WITH RECURSIVE layer_chain AS (
+                SELECT layer_id, parent_layer_id, 0 AS depth
+                FROM layers
+                WHERE tenant_id = $1 AND layer_id = $3
+
+                UNION ALL
+
+                SELECT l.layer_id, l.parent_layer_id, lc.depth + 1
+                FROM layers l
+                INNER JOIN layer_chain lc ON l.layer_id = lc.parent_layer_id
+                WHERE l.tenant_id = $1
+            )
+            SELECT block_index
+            FROM (
+                SELECT DISTINCT ON (b.block_index) b.block_index, b.size
+                FROM data_blocks b
+                LEFT JOIN layer_chain lc ON lc.layer_id = b.layer_id
+                WHERE b.tenant_id = $1 AND b.inode_id = $2
+                  AND (b.layer_id IS NULL OR lc.layer_id IS NOT NULL)
+                ORDER BY b.block_index, lc.depth NULLS LAST
+            ) visible
+            WHERE size > 0
+            ORDER BY block_index
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(layer_id)
+        .fetch_all(&mut **tx)
+        .await?;
+
+        Ok(indices)
+    }
+
+    /// Store the blocks of `src_inode_id` visible from `layer_id` as
+    /// `dst_inode_id`'s blocks in `dst_layer_id`, without leaving the database.
+    pub async fn copy_visible_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        src_inode_id: InodeId,
+        layer_id: LayerId,
+        dst_inode_id: InodeId,
+    ) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            WITH RECURSIVE layer_chain AS (
+                SELECT layer_id, parent_layer_id, 0 AS depth
+                FROM layers
+                WHERE tenant_id = $1 AND layer_id = $3
+
+                UNION ALL
+
+                SELECT l.layer_id, l.parent_layer_id, lc.depth + 1
+                FROM layers l
+                INNER JOIN layer_chain lc ON l.layer_id = lc.parent_layer_id
+                WHERE l.tenant_id = $1
+            ),
+            visible AS (
+                SELECT DISTINCT ON (b.block_index) b.block_index, b.data, b.size, b.content_hash
+                FROM data_blocks b
+                LEFT JOIN layer_chain lc ON lc.layer_id = b.layer_id
+                WHERE b.tenant_id = $1 AND b.inode_id = $2
+                  AND (b.layer_id IS NULL OR lc.layer_id IS NOT NULL)
+                ORDER BY b.block_index, lc.depth NULLS LAST
+            )
+            INSERT INTO data_blocks (
+                tenant_id, inode_id, block_index, data, size, content_hash, layer_id
+            )
+            SELECT $1, $4, block_index, data, size, content_hash, $3
+            FROM visible
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(src_inode_id)
+        .bind(layer_id)
+        .bind(dst_inode_id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Read an inode's binary contents as seen from `layer_id`, truncated to
+    /// `size`.
+    ///
+    /// Uses the visible content-defined chunk map if there is one, otherwise
+    /// overlays fixed-size blocks along the layer chain.
+    pub async fn read_visible(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        size: i64,
+    ) -> Result<Vec<u8>> {
+        let mut tx = begin_snapshot(self.pool).await?;
+        let data = self.read_visible_in_tx(&mut tx, tenant_id, inode_id, layer_id, size).await?;
+        tx.commit().await?;
+        Ok(data)
+    }
+
+    pub async fn read_visible_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        size: i64,
+    ) -> Result<Vec<u8>> {
+        let chunk_ops = ChunkOperations::new(self.pool);
+        let mut data = match chunk_ops
+            .visible_map_layer_in_tx(tx, tenant_id, inode_id, layer_id)
+            .await?
+        {
+            Some(map_layer) => chunk_ops.read_map_in_tx(tx, tenant_id, inode_id, map_layer).await?,
+            None => {
+                let blocks = self.list_visible_in_tx(tx, tenant_id, inode_id, layer_id).await?;
+                return Ok(assemble_blocks(blocks, size.max(0) as usize));
+            }
+        };
+        data.truncate(size.max(0) as usize);
+        Ok(data)
+    }
+
+    /// Visible blocks from index `from`, below `until` if given, at most
+    /// `limit` of them.
+    async fn list_visible_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        from: i32,
+        until: Option<i32>,
+        limit: Option<i64>,
+    ) -> Result<Vec<DataBlock>> {
+        let blocks = sqlx::query_as::<_, DataBlock>(
+            r#"
+            WITH RECURSIVE layer_chain AS (
+                SELECT layer_id, parent_layer_id, 0 AS depth
+                FROM layers
+                WHERE tenant_id = $1 AND layer_id = $3
+
+                UNION ALL
+
+                SELECT l.layer_id, l.parent_layer_id, lc.depth + 1
+                FROM layers l
+                INNER JOIN layer_chain lc ON l.layer_id = lc.parent_layer_id
+                WHERE l.tenant_id = $1
+            )
+            SELECT DISTINCT ON (b.block_index)
+                   b.block_id, b.tenant_id, b.inode_id, b.block_index, b.data, b.size,
+                   b.content_hash, b.created_at, b.layer_id
+            FROM data_blocks b
+            LEFT JOIN layer_chain lc ON lc.layer_id = b.layer_id
+            WHERE b.tenant_id = $1 AND b.inode_id = $2
+              AND (b.layer_id IS NULL OR lc.layer_id IS NOT NULL)
+              AND b.block_index >= $4 AND ($6::int IS NULL OR b.block_index < $6)
+            ORDER BY b.block_index, lc.depth NULLS LAST
+            LIMIT $5
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(layer_id)
+        .bind(from)
+        .bind(limit)
+        .bind(until)
+        .fetch_all(executor)
+        .await?;
+
+        Ok(blocks)
+    }
+
+    async fn list_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+    ) -> Result<Vec<DataBlock>> {
+        let blocks = sqlx::query_as::<_, DataBlock>(
+            r#"
+            SELECT block_id, tenant_id, inode_id, block_index, data, size, content_hash,
+                   created_at, layer_id
+            FROM data_blocks
+            WHERE tenant_id = $1 AND inode_id = $2
+            ORDER BY block_index
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .fetch_all(executor)
+        .await?;
+
+        Ok(blocks)
+    }
+}
+
+/// Lay `blocks` out at their `BLOCK_SIZE` offsets in a buffer of `size`
+/// bytes. Indices without a block are holes and read as zeros.
+fn assemble_blocks(blocks: Vec<DataBlock>, size: usize) -> Vec<u8> {
+    let mut data = vec![0u8; size];
+    for block in blocks {
+        let start = block.block_index as usize * BLOCK_SIZE;
+        if start >= size {
+            break;
+        }
+        let len = block.data.len().min(size - start);
+        data[start..start + len].copy_from_slice(&block.data[..len]);
+    }
+    data
 }
 
 pub fn compute_content_hash(data: &[u8]) -> String {
@@ -174,6 +594,17 @@ impl<'a> BlockRepository for BlockOperations<'a> {
     async fn delete(&self, tenant_id: TenantId, inode_id: InodeId) -> Result<u64> {
         BlockOperations::delete(self, tenant_id, inode_id).await
     }
+
+    async fn list_visible_range(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        first: i32,
+        count: i32,
+    ) -> Result<Vec<DataBlock>> {
+        BlockOperations::list_visible_range(self, tenant_id, inode_id, layer_id, first, count).await
+    }
 }
 
 #[cfg(test)]
@@ -194,6 +625,34 @@ mod tests {
         assert_ne!(hash, hash3);
     }
 
+    fn block(block_index: i32, data: Vec<u8>) -> DataBlock {
+        DataBlock {
+            block_id: Uuid::new_v4(),
+            tenant_id: Uuid::new_v4(),
+            inode_id: 1,
+            block_index,
+            size: data.len() as i32,
+            content_hash: compute_content_hash(&data),
+            data,
+            created_at: chrono::Utc::now(),
+            layer_id: None,
+        }
+    }
+
+    #[test]
+    fn test_assemble_blocks_fills_holes_with_zeros() {
+        let blocks = vec![block(0, vec![1; BLOCK_SIZE]), block(2, vec![3; 10])];
+        let data = assemble_blocks(blocks, 2 * BLOCK_SIZE + 10);
+        assert_eq!(&data[..BLOCK_SIZE], &[1; BLOCK_SIZE][..]);
+        assert!(data[BLOCK_SIZE..2 * BLOCK_SIZE].iter().all(|&b| b == 0));
+        assert_eq!(&data[2 * BLOCK_SIZE..], &[3; 10][..]);
+
+        // A trailing hole and blocks past the end of the file
+        let blocks = vec![block(0, vec![1; 4]), block(1, vec![2; BLOCK_SIZE])];
+        assert_eq!(assemble_blocks(blocks.clone(), 6), vec![1, 1, 1, 1, 0, 0]);
+        assert_eq!(assemble_blocks(blocks, 0), Vec::<u8>::new());
+    }
+
     #[test]
     fn test_compute_content_hash_empty() {
         let hash = compute_content_hash(b"");
@@ -0,0 +1,288 @@
+use anyhow::Result;
+use sqlx::{PgExecutor, PgPool};
+
+use crate::types::{InodeId, LayerId, TenantId};
+
+use super::block::compute_content_hash;
+use super::models::FileChunk;
+use super::pool::DatabaseTransaction;
+
+/// Storage for content-defined chunks and the per-layer chunk maps of files
+pub struct ChunkOperations<'a> {
+    pool: &'a PgPool,
+}
+
+impl<'a> ChunkOperations<'a> {
+    pub fn new(pool: &'a PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Replace the file's chunk map in `layer_id` with `chunks`, storing any
+    /// chunk the tenant doesn't have yet.
+    pub async fn replace_map_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        chunks: &[&[u8]],
+    ) -> Result<Vec<FileChunk>> {
+        self.delete_map_in_tx(tx, tenant_id, inode_id, layer_id).await?;
+
+        let mut map = Vec::with_capacity(chunks.len());
+        let mut offset = 0i64;
+        for (index, chunk) in chunks.iter().enumerate() {
+            let content_hash = compute_content_hash(chunk);
+            sqlx::query(
+                r#"
+                INSERT INTO content_chunks (tenant_id, content_hash, data, size)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (tenant_id, content_hash) DO NOTHING
+                "#,
+            )
+            .bind(tenant_id)
+            .bind(&content_hash)
+            .bind(chunk)
+            .bind(chunk.len() as i32)
+            .execute(&mut **tx)
+            .await?;
+
+            let entry = sqlx::query_as::<_, FileChunk>(
+                r#"
+                INSERT INTO file_chunks (
+                    tenant_id, inode_id, layer_id, chunk_index, chunk_offset, size, content_hash
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                RETURNING tenant_id, inode_id, layer_id, chunk_index, chunk_offset, size,
+                          content_hash
+                "#,
+            )
+            .bind(tenant_id)
+            .bind(inode_id)
+            .bind(layer_id)
+            .bind(index as i32)
+            .bind(offset)
+            .bind(chunk.len() as i32)
+            .bind(&content_hash)
+            .fetch_one(&mut **tx)
+            .await?;
+
+            offset += chunk.len() as i64;
+            map.push(entry);
+        }
+
+        Ok(map)
+    }
+
+    /// Give `dst_inode_id` in `dst_layer_id` the chunk map the source has in
+    /// `src_layer_id`. The chunks themselves are shared.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn copy_map_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        src_inode_id: InodeId,
+        src_layer_id: LayerId,
+        dst_inode_id: InodeId,
+        dst_layer_id: LayerId,
+    ) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO file_chunks (
+                tenant_id, inode_id, layer_id, chunk_index, chunk_offset, size, content_hash
+            )
+            SELECT tenant_id, $4, $5, chunk_index, chunk_offset, size, content_hash
+            FROM file_chunks
+            WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(src_inode_id)
+        .bind(src_layer_id)
+        .bind(dst_inode_id)
+        .bind(dst_layer_id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Drop the file's chunk map in `layer_id`. Chunks stay for other maps.
+    pub async fn delete_map_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM file_chunks WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3",
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(layer_id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// The file's chunk map as stored in `layer_id` itself, in order.
+    pub async fn list_map(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Vec<FileChunk>> {
+        Self::list_map_with(self.pool, tenant_id, inode_id, layer_id).await
+    }
+
+    pub async fn list_map_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Vec<FileChunk>> {
+        Self::list_map_with(&mut **tx, tenant_id, inode_id, layer_id).await
+    }
+
+    /// Layer whose chunk map holds the file's contents as seen from
+    /// `layer_id`, if any.
+    ///
+    /// That is the nearest layer in the chain with a map, unless a nearer
+    /// layer has since rewritten the file as fixed-size blocks.
+    pub async fn visible_map_layer_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Option<LayerId>> {
+        let layer = sqlx::query_as::<_, (LayerId,)>(
+            r#"
+            WITH RECURSIVE layer_chain AS (
+                SELECT layer_id, parent_layer_id, 0 AS depth
+                FROM layers
+                WHERE tenant_id = $1 AND layer_id = $3
+
+                UNION ALL
+
+                SELECT l.layer_id, l.parent_layer_id, lc.depth + 1
+                FROM layers l
+                INNER JOIN layer_chain lc ON l.layer_id = lc.parent_layer_id
+                WHERE l.tenant_id = $1
+            ),
+            nearest_map AS (
+                SELECT fc.layer_id, lc.depth
+                FROM file_chunks fc
+                INNER JOIN layer_chain lc ON lc.layer_id = fc.layer_id
+                WHERE fc.tenant_id = $1 AND fc.inode_id = $2
+                ORDER BY lc.depth
+                LIMIT 1
+            )
+            SELECT nm.layer_id
+            FROM nearest_map nm
+            WHERE NOT EXISTS (
+                SELECT 1
+                FROM data_blocks b
+                INNER JOIN layer_chain lc ON lc.layer_id = b.layer_id
+                WHERE b.tenant_id = $1 AND b.inode_id = $2 AND lc.depth < nm.depth
+            )
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(layer_id)
+        .fetch_optional(&mut **tx)
+        .await?
+        .map(|row| row.0);
+
+        Ok(layer)
+    }
+
+    /// Reassemble the file from the chunk map stored in `layer_id`.
+    pub async fn read_map_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Vec<u8>> {
+        let chunks = sqlx::query_as::<_, (Vec<u8>,)>(
+            r#"
+            SELECT c.data
+            FROM file_chunks fc
+            INNER JOIN content_chunks c
+                ON c.tenant_id = fc.tenant_id AND c.content_hash = fc.content_hash
+            WHERE fc.tenant_id = $1 AND fc.inode_id = $2 AND fc.layer_id = $3
+            ORDER BY fc.chunk_index
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(layer_id)
+        .fetch_all(&mut **tx)
+        .await?;
+
+        Ok(chunks.into_iter().flat_map(|(data,)| data).collect())
+    }
+
+    /// Number of distinct chunks stored for the tenant.
+    pub async fn count_chunks(&self, tenant_id: TenantId) -> Result<i64> {
+        let (count,) =
+            sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM content_chunks WHERE tenant_id = $1")
+                .bind(tenant_id)
+                .fetch_one(self.pool)
+                .await?;
+
+        Ok(count)
+    }
+
+    /// Delete the tenant's chunks no chunk map refers to any more, returning
+    /// how many were removed and their total size.
+    pub async fn delete_unreferenced(&self, tenant_id: TenantId) -> Result<(i64, i64)> {
+        let removed = sqlx::query_as::<_, (i64, i64)>(
+            r#"
+            WITH removed AS (
+                DELETE FROM content_chunks c
+                WHERE c.tenant_id = $1
+                  AND NOT EXISTS (
+                      SELECT 1 FROM file_chunks f
+                      WHERE f.tenant_id = c.tenant_id AND f.content_hash = c.content_hash
+                  )
+                RETURNING size
+            )
+            SELECT COUNT(*), COALESCE(SUM(size), 0)::BIGINT FROM removed
+            "#,
+        )
+        .bind(tenant_id)
+        .fetch_one(self.pool)
+        .await?;
+
+        Ok(removed)
+    }
+
+    async fn list_map_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Vec<FileChunk>> {
+        let map = sqlx::query_as::<_, FileChunk>(
+            r#"
+            SELECT tenant_id, inode_id, layer_id, chunk_index, chunk_offset, size, content_hash
+            FROM file_chunks
+            WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3
+            ORDER BY chunk_index
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(layer_id)
+        .fetch_all(executor)
+        .await?;
+
+        Ok(map)
+    }
+}
@@ -1,23 +1,44 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use chrono::Utc;
-use sqlx::PgPool;
+use sqlx::{PgExecutor, PgPool};
 
-use crate::types::{InodeId, TenantId};
+use crate::types::{InodeId, LayerId, TenantId};
 
-use super::models::{CreateInodeInput, Inode, UpdateInodeInput};
+use super::models::{ChangeType, CreateInodeInput, Inode, UpdateInodeInput};
+use super::pool::DatabaseTransaction;
 use super::traits::InodeRepository;
 
 pub struct InodeOperations<'a> {
     pool: &'a PgPool,
 }
 
+/// A row of `list_tree_in_tx`
+#[derive(sqlx::FromRow)]
+struct TreeEntry {
+    path: String,
+    #[sqlx(flatten)]
+    inode: Inode,
+}
+
 impl<'a> InodeOperations<'a> {
     pub fn new(pool: &'a PgPool) -> Self {
         Self { pool }
     }
 
     pub async fn create(&self, input: CreateInodeInput) -> Result<Inode> {
+        let mut tx = self.pool.begin().await?;
+        let inode = self.create_in_tx(&mut tx, input).await?;
+        tx.commit().await?;
+        Ok(inode)
+    }
+
+    /// Create an inode as part of a caller-managed transaction.
+    pub async fn create_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        input: CreateInodeInput,
+    ) -> Result<Inode> {
         let inode = sqlx::query_as::<_, Inode>(
             r#"
             INSERT INTO inodes (tenant_id, parent_id, name, inode_type, mode, uid, gid, size)
@@ -33,7 +54,7 @@ impl<'a> InodeOperations<'a> {
         .bind(input.mode)
         .bind(input.uid)
         .bind(input.gid)
-        .fetch_one(self.pool)
+        .fetch_one(&mut **tx)
         .await?;
 
         tracing::debug!(
@@ -48,20 +69,7 @@ impl<'a> InodeOperations<'a> {
     }
 
     pub async fn get(&self, tenant_id: TenantId, inode_id: InodeId) -> Result<Option<Inode>> {
-        let inode = sqlx::query_as::<_, Inode>(
-            r#"
-            SELECT inode_id, tenant_id, parent_id, name, inode_type, mode, uid, gid, size,
-                   atime, mtime, ctime
-            FROM inodes
-            WHERE tenant_id = $1 AND inode_id = $2
-            "#,
-        )
-        .bind(tenant_id)
-        .bind(inode_id)
-        .fetch_optional(self.pool)
-        .await?;
-
-        Ok(inode)
+        Self::get_with(self.pool, tenant_id, inode_id).await
     }
 
     pub async fn get_by_parent_and_name(
@@ -70,25 +78,25 @@ impl<'a> InodeOperations<'a> {
         parent_id: InodeId,
         name: &str,
     ) -> Result<Option<Inode>> {
-        let inode = sqlx::query_as::<_, Inode>(
-            r#"
-            SELECT inode_id, tenant_id, parent_id, name, inode_type, mode, uid, gid, size,
-                   atime, mtime, ctime
-            FROM inodes
-            WHERE tenant_id = $1 AND parent_id = $2 AND name = $3
-            "#,
-        )
-        .bind(tenant_id)
-        .bind(parent_id)
-        .bind(name)
-        .fetch_optional(self.pool)
-        .await?;
+        Self::get_by_parent_and_name_with(self.pool, tenant_id, parent_id, name).await
+    }
 
+    pub async fn update(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        input: UpdateInodeInput,
+    ) -> Result<Inode> {
+        let mut tx = self.pool.begin().await?;
+        let inode = self.update_in_tx(&mut tx, tenant_id, inode_id, input).await?;
+        tx.commit().await?;
         Ok(inode)
     }
 
-    pub async fn update(
+    /// Update inode attributes as part of a caller-managed transaction.
+    pub async fn update_in_tx(
         &self,
+        tx: &mut DatabaseTransaction<'_>,
         tenant_id: TenantId,
         inode_id: InodeId,
         input: UpdateInodeInput,
@@ -161,7 +169,7 @@ impl<'a> InodeOperations<'a> {
             q = q.bind(now);
         }
 
-        let inode = q.fetch_one(self.pool).await?;
+        let inode = q.fetch_one(&mut **tx).await?;
 
         tracing::debug!(
             tenant_id = %tenant_id,
@@ -173,10 +181,23 @@ impl<'a> InodeOperations<'a> {
     }
 
     pub async fn delete(&self, tenant_id: TenantId, inode_id: InodeId) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = self.delete_in_tx(&mut tx, tenant_id, inode_id).await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    /// Delete an inode as part of a caller-managed transaction.
+    pub async fn delete_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+    ) -> Result<bool> {
         let result = sqlx::query("DELETE FROM inodes WHERE tenant_id = $1 AND inode_id = $2")
             .bind(tenant_id)
             .bind(inode_id)
-            .execute(self.pool)
+            .execute(&mut **tx)
             .await?;
 
         let deleted = result.rows_affected() > 0;
@@ -188,10 +209,66 @@ impl<'a> InodeOperations<'a> {
         Ok(deleted)
     }
 
+    /// Re-link an inode under a new parent and name as part of a
+    /// caller-managed transaction. The inode keeps its id and content.
+    pub async fn move_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        new_parent_id: InodeId,
+        new_name: &str,
+    ) -> Result<Inode> {
+        let inode = sqlx::query_as::<_, Inode>(
+            r#"
+            UPDATE inodes
+            SET parent_id = $3, name = $4, ctime = $5
+            WHERE tenant_id = $1 AND inode_id = $2
+            RETURNING inode_id, tenant_id, parent_id, name, inode_type, mode, uid, gid, size,
+                      atime, mtime, ctime
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(new_parent_id)
+        .bind(new_name)
+        .bind(Utc::now())
+        .fetch_one(&mut **tx)
+        .await?;
+
+        tracing::debug!(
+            tenant_id = %tenant_id,
+            inode_id = inode_id,
+            new_parent_id = new_parent_id,
+            new_name = %new_name,
+            "Moved inode"
+        );
+
+        Ok(inode)
+    }
+
     pub async fn list_children(
         &self,
         tenant_id: TenantId,
         parent_id: InodeId,
+    ) -> Result<Vec<Inode>> {
+        Self::list_children_with(self.pool, tenant_id, parent_id).await
+    }
+
+    /// List a directory's children within a caller-managed transaction.
+    pub async fn list_children_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        parent_id: InodeId,
+    ) -> Result<Vec<Inode>> {
+        Self::list_children_with(&mut **tx, tenant_id, parent_id).await
+    }
+
+    async fn list_children_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        parent_id: InodeId,
     ) -> Result<Vec<Inode>> {
         let children = sqlx::query_as::<_, Inode>(
             r#"
@@ -204,11 +281,307 @@ impl<'a> InodeOperations<'a> {
         )
         .bind(tenant_id)
         .bind(parent_id)
-        .fetch_all(self.pool)
+        .fetch_all(executor)
         .await?;
 
         Ok(children)
     }
+
+    /// Sum of the sizes of all regular files of a tenant
+    pub async fn total_file_size(&self, tenant_id: TenantId) -> Result<i64> {
+        let (total,): (i64,) = sqlx::query_as(
+            r#"
+            SELECT COALESCE(SUM(size), 0)::BIGINT
+            FROM inodes
+            WHERE tenant_id = $1 AND inode_type = 'file'
+            "#,
+        )
+        .bind(tenant_id)
+        .fetch_one(self.pool)
+        .await?;
+
+        Ok(total)
+    }
+
+    /// Delete an inode and everything below it in a single transaction.
+    ///
+    /// Entries are removed depth-first, freeing data blocks as they go. When
+    /// `layer_id` is given, a `Delete` layer entry is recorded for each removed
+    /// path, with paths built from `path` (the path of `inode_id`). Returns the
+    /// removed inodes and their paths, deepest first. Any failure rolls back the
+    /// whole tree.
+    pub async fn delete_tree(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        path: &str,
+        layer_id: Option<LayerId>,
+    ) -> Result<Vec<(InodeId, String)>> {
+        let mut tx = self.pool.begin().await?;
+
+        let tree: Vec<(InodeId, String, i64)> = sqlx::query_as(
+            r#"
+            WITH RECURSIVE tree AS (
+                SELECT inode_id, $3::text AS path, size, 0 AS depth
+                FROM inodes
+                WHERE tenant_id = $1 AND inode_id = $2
+                UNION ALL
+                SELECT c.inode_id,
+                       CASE WHEN t.path = '/' THEN '/' || c.name ELSE t.path || '/' || c.name END,
+                       c.size, t.depth + 1
+                FROM inodes c
+                JOIN tree t ON c.parent_id = t.inode_id
+                WHERE c.tenant_id = $1
+            )
+            SELECT inode_id, path, size FROM tree ORDER BY depth DESC, path
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(path)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        for (id, entry_path, size) in &tree {
+            if let Some(layer_id) = layer_id {
+                sqlx::query(
+                    r#"
+                    INSERT INTO layer_entries (
+                        layer_id, tenant_id, inode_id, path, change_type, size_delta
+                    )
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT (layer_id, path)
+                    DO UPDATE SET
+                        inode_id = EXCLUDED.inode_id,
+                        change_type = EXCLUDED.change_type,
+                        size_delta = EXCLUDED.size_delta,
+                        text_changes = NULL,
+                        created_at = CURRENT_TIMESTAMP
+                    "#,
+                )
+                .bind(layer_id)
+                .bind(tenant_id)
+                .bind(id)
+                .bind(entry_path)
+                .bind(ChangeType::Delete)
+                .bind(-size)
+                .execute(&mut *tx)
+                .await?;
+            }
+
+            sqlx::query("DELETE FROM data_blocks WHERE tenant_id = $1 AND inode_id = $2")
+                .bind(tenant_id)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+
+            sqlx::query("DELETE FROM inodes WHERE tenant_id = $1 AND inode_id = $2")
+                .bind(tenant_id)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        tracing::debug!(
+            tenant_id = %tenant_id,
+            inode_id = inode_id,
+            removed = tree.len(),
+            "Deleted inode tree"
+        );
+
+        Ok(tree.into_iter().map(|(id, entry_path, _)| (id, entry_path)).collect())
+    }
+
+    /// Get an inode within a caller-managed transaction.
+    pub async fn get_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+    ) -> Result<Option<Inode>> {
+        Self::get_with(&mut **tx, tenant_id, inode_id).await
+    }
+
+    /// Lock the inode row until the transaction ends, serializing writers of
+    /// the same file. Missing inodes are ignored.
+    pub async fn lock_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+    ) -> Result<()> {
+        sqlx::query("SELECT 1 FROM inodes WHERE tenant_id = $1 AND inode_id = $2 FOR UPDATE")
+            .bind(tenant_id)
+            .bind(inode_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Everything below `dir_id`, at most `max_depth` levels down if given,
+    /// with paths relative to it (`a`, `a/b.txt`), in no particular order.
+    pub async fn list_tree_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        dir_id: InodeId,
+        max_depth: Option<i32>,
+    ) -> Result<Vec<(String, Inode)>> {
+        let entries = sqlx::query_as::<_, TreeEntry>(
+            r#"
+            WITH RECURSIVE tree AS (
+                SELECT i.*, i.name::text AS path, 1 AS depth
+                FROM inodes i
+                WHERE i.tenant_id = $1 AND i.parent_id = $2
+                UNION ALL
+                SELECT i.*, t.path || '/' || i.name, t.depth + 1
+                FROM tree t
+                JOIN inodes i ON i.tenant_id = $1 AND i.parent_id = t.inode_id
+                WHERE t.inode_type = 'dir' AND ($3::int IS NULL OR t.depth < $3)
+            )
+            SELECT path, inode_id, tenant_id, parent_id, name, inode_type, mode, uid, gid, size,
+                   atime, mtime, ctime
+            FROM tree
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(dir_id)
+        .bind(max_depth)
+        .fetch_all(&mut **tx)
+        .await?;
+
+        Ok(entries.into_iter().map(|e| (e.path, e.inode)).collect())
+    }
+
+    async fn get_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+    ) -> Result<Option<Inode>> {
+        let inode = sqlx::query_as::<_, Inode>(
+            r#"
+            SELECT inode_id, tenant_id, parent_id, name, inode_type, mode, uid, gid, size,
+                   atime, mtime, ctime
+            FROM inodes
+            WHERE tenant_id = $1 AND inode_id = $2
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(inode)
+    }
+
+    /// Resolve `components` below `root_inode_id` in a single query.
+    ///
+    /// Returns the terminal inode, or `None` if any component is missing.
+    pub async fn resolve_path(
+        &self,
+        tenant_id: TenantId,
+        root_inode_id: InodeId,
+        components: &[String],
+    ) -> Result<Option<Inode>> {
+        if components.is_empty() {
+            return Self::get_with(self.pool, tenant_id, root_inode_id).await;
+        }
+        let chain = Self::walk_with(self.pool, tenant_id, root_inode_id, components).await?;
+        Ok(if chain.len() == components.len() { chain.into_iter().last() } else { None })
+    }
+
+    /// The inodes along `components` below `dir_id`, in path order, found
+    /// with one recursive query. The chain stops before the first missing
+    /// component.
+    pub async fn walk(
+        &self,
+        tenant_id: TenantId,
+        dir_id: InodeId,
+        components: &[String],
+    ) -> Result<Vec<Inode>> {
+        Self::walk_with(self.pool, tenant_id, dir_id, components).await
+    }
+
+    /// `walk` within a caller-managed transaction.
+    pub async fn walk_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        dir_id: InodeId,
+        components: &[String],
+    ) -> Result<Vec<Inode>> {
+        Self::walk_with(&mut **tx, tenant_id, dir_id, components).await
+    }
+
+    async fn walk_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        dir_id: InodeId,
+        components: &[String],
+    ) -> Result<Vec<Inode>> {
+        let chain = sqlx::query_as::<_, Inode>(
+            r#"
+            WITH RECURSIVE walk AS (
+                SELECT i.*, 1 AS depth
+                FROM inodes i
+                WHERE i.tenant_id = $1 AND i.parent_id = $2 AND i.name = $3[1]
+                UNION ALL
+                SELECT i.*, w.depth + 1
+                FROM walk w
+                JOIN inodes i
+                  ON i.tenant_id = $1 AND i.parent_id = w.inode_id AND i.name = $3[w.depth + 1]
+                WHERE w.depth < cardinality($3)
+            )
+            SELECT inode_id, tenant_id, parent_id, name, inode_type, mode, uid, gid, size,
+                   atime, mtime, ctime
+            FROM walk
+            ORDER BY depth
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(dir_id)
+        .bind(components)
+        .fetch_all(executor)
+        .await?;
+
+        Ok(chain)
+    }
+
+    /// Look up a child by name within a caller-managed transaction.
+    pub async fn get_by_parent_and_name_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        parent_id: InodeId,
+        name: &str,
+    ) -> Result<Option<Inode>> {
+        Self::get_by_parent_and_name_with(&mut **tx, tenant_id, parent_id, name).await
+    }
+
+    async fn get_by_parent_and_name_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        parent_id: InodeId,
+        name: &str,
+    ) -> Result<Option<Inode>> {
+        let inode = sqlx::query_as::<_, Inode>(
+            r#"
+            SELECT inode_id, tenant_id, parent_id, name, inode_type, mode, uid, gid, size,
+                   atime, mtime, ctime
+            FROM inodes
+            WHERE tenant_id = $1 AND parent_id = $2 AND name = $3
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(parent_id)
+        .bind(name)
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(inode)
+    }
 }
 
 // Implement InodeRepository trait for InodeOperations
@@ -1,11 +1,16 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_trait::async_trait;
-use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+use sqlx::{PgExecutor, PgPool};
 use uuid::Uuid;
 
-use crate::types::{LayerId, TenantId};
+use crate::layer::{BlockChanges, TextChanges};
+use crate::types::{InodeId, LayerId, TenantId};
 
-use super::models::{CreateLayerEntryInput, CreateLayerInput, Layer, LayerEntry, LayerStatus};
+use super::models::{
+    CreateLayerEntryInput, CreateLayerInput, Layer, LayerEntry, LayerStatus, ReclaimableBlocks,
+};
+use super::pool::DatabaseTransaction;
 use super::traits::LayerRepository;
 
 pub struct LayerOperations<'a> {
@@ -16,11 +21,11 @@ impl<'a> LayerOperations<'a> {
     pub fn new(pool: &'a PgPool) -> Self {
         Self { pool }
     }
-}
 
-#[async_trait]
-impl<'a> LayerRepository for LayerOperations<'a> {
-    async fn create(&self, input: CreateLayerInput) -> Result<Layer> {
+    async fn create_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        input: CreateLayerInput,
+    ) -> Result<Layer> {
         let layer_id = Uuid::new_v4();
 
         let layer = sqlx::query_as::<_, Layer>(
@@ -46,7 +51,7 @@ impl<'a> LayerRepository for LayerOperations<'a> {
         .bind(&input.created_by)
         .bind(input.mount_entry_id)
         .bind(input.is_working)
-        .fetch_one(self.pool)
+        .fetch_one(executor)
         .await?;
 
         tracing::info!(
@@ -59,7 +64,11 @@ impl<'a> LayerRepository for LayerOperations<'a> {
         Ok(layer)
     }
 
-    async fn get(&self, tenant_id: TenantId, layer_id: LayerId) -> Result<Option<Layer>> {
+    pub(crate) async fn get_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+    ) -> Result<Option<Layer>> {
         let layer = sqlx::query_as::<_, Layer>(
             r#"
             SELECT layer_id, tenant_id, parent_layer_id, layer_name, description,
@@ -71,107 +80,237 @@ impl<'a> LayerRepository for LayerOperations<'a> {
         )
         .bind(tenant_id)
         .bind(layer_id)
-        .fetch_optional(self.pool)
+        .fetch_optional(executor)
         .await?;
 
         Ok(layer)
     }
 
-    async fn list(&self, tenant_id: TenantId) -> Result<Vec<Layer>> {
-        let layers = sqlx::query_as::<_, Layer>(
+    async fn delete_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+    ) -> Result<bool> {
+        let result = sqlx::query(
             r#"
-            SELECT layer_id, tenant_id, parent_layer_id, layer_name, description,
-                   file_count, total_size, status, is_readonly, tags,
-                   created_at, created_by, mount_entry_id, is_working
-            FROM layers
-            WHERE tenant_id = $1
-            ORDER BY created_at DESC
+            DELETE FROM layers
+            WHERE tenant_id = $1 AND layer_id = $2
             "#,
         )
         .bind(tenant_id)
-        .fetch_all(self.pool)
+        .bind(layer_id)
+        .execute(executor)
         .await?;
 
-        Ok(layers)
+        let deleted = result.rows_affected() > 0;
+
+        if deleted {
+            tracing::info!(
+                layer_id = %layer_id,
+                tenant_id = %tenant_id,
+                "Deleted layer"
+            );
+        }
+
+        Ok(deleted)
     }
 
-    async fn get_layer_chain(&self, tenant_id: TenantId, layer_id: LayerId) -> Result<Vec<Layer>> {
-        let layers = sqlx::query_as::<_, Layer>(
+    async fn get_current_layer_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+    ) -> Result<Option<LayerId>> {
+        let layer_id = sqlx::query_as::<_, (LayerId,)>(
             r#"
-            WITH RECURSIVE layer_chain AS (
-                SELECT layer_id, tenant_id, parent_layer_id, layer_name, description,
-                       file_count, total_size, status, is_readonly, tags,
-                       created_at, created_by, mount_entry_id, is_working, 0 as depth
-                FROM layers
-                WHERE layer_id = $2 AND tenant_id = $1
+            SELECT current_layer_id
+            FROM tenant_current_layer
+            WHERE tenant_id = $1
+            "#,
+        )
+        .bind(tenant_id)
+        .fetch_optional(executor)
+        .await?
+        .map(|row| row.0);
 
-                UNION ALL
+        Ok(layer_id)
+    }
 
-                SELECT l.layer_id, l.tenant_id, l.parent_layer_id, l.layer_name, l.description,
-                       l.file_count, l.total_size, l.status, l.is_readonly, l.tags,
-                       l.created_at, l.created_by, l.mount_entry_id, l.is_working, lc.depth + 1
-                FROM layers l
-                INNER JOIN layer_chain lc ON l.layer_id = lc.parent_layer_id
-                WHERE l.tenant_id = $1
-            )
+    async fn set_current_layer_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO tenant_current_layer (tenant_id, current_layer_id)
+            VALUES ($1, $2)
+            ON CONFLICT (tenant_id)
+            DO UPDATE SET current_layer_id = $2, updated_at = CURRENT_TIMESTAMP
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_id)
+        .execute(executor)
+        .await?;
+
+        tracing::info!(
+            tenant_id = %tenant_id,
+            layer_id = %layer_id,
+            "Set current layer for tenant"
+        );
+
+        Ok(())
+    }
+
+    /// Create a layer as part of a caller-managed transaction.
+    pub async fn create_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        input: CreateLayerInput,
+    ) -> Result<Layer> {
+        Self::create_with(&mut **tx, input).await
+    }
+
+    /// Get a layer within a caller-managed transaction.
+    pub async fn get_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+    ) -> Result<Option<Layer>> {
+        Self::get_with(&mut **tx, tenant_id, layer_id).await
+    }
+
+    /// Delete a layer as part of a caller-managed transaction.
+    pub async fn delete_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+    ) -> Result<bool> {
+        Self::delete_with(&mut **tx, tenant_id, layer_id).await
+    }
+
+    /// The tenant's current layer within a caller-managed transaction.
+    pub async fn get_current_layer_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+    ) -> Result<Option<LayerId>> {
+        Self::get_current_layer_with(&mut **tx, tenant_id).await
+    }
+
+    /// Make `layer_id` the tenant's current layer as part of a
+    /// caller-managed transaction.
+    pub async fn set_current_layer_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+    ) -> Result<()> {
+        Self::set_current_layer_with(&mut **tx, tenant_id, layer_id).await
+    }
+
+    async fn get_working_layer_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        mount_entry_id: Uuid,
+    ) -> Result<Option<Layer>> {
+        let layer = sqlx::query_as::<_, Layer>(
+            r#"
             SELECT layer_id, tenant_id, parent_layer_id, layer_name, description,
                    file_count, total_size, status, is_readonly, tags,
                    created_at, created_by, mount_entry_id, is_working
-            FROM layer_chain
-            ORDER BY depth
+            FROM layers
+            WHERE mount_entry_id = $1 AND is_working = true
             "#,
         )
-        .bind(tenant_id)
-        .bind(layer_id)
-        .fetch_all(self.pool)
+        .bind(mount_entry_id)
+        .fetch_optional(executor)
         .await?;
 
-        Ok(layers)
+        Ok(layer)
     }
 
-    async fn delete(&self, tenant_id: TenantId, layer_id: LayerId) -> Result<bool> {
-        let result = sqlx::query(
+    /// Turn a mount's working layer into a snapshot named `name` and start a
+    /// new working layer on top of it, as part of a caller-managed
+    /// transaction. Returns the new working layer.
+    async fn snapshot_in_tx(
+        tx: &mut DatabaseTransaction<'_>,
+        mount_entry_id: Uuid,
+        name: &str,
+        description: Option<String>,
+    ) -> Result<Layer> {
+        let working_layer = Self::get_working_layer_with(&mut **tx, mount_entry_id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Working layer not found for mount"))?;
+
+        // Set working layer to snapshot (is_working = false)
+        sqlx::query(
             r#"
-            DELETE FROM layers
-            WHERE tenant_id = $1 AND layer_id = $2
+            UPDATE layers
+            SET is_working = false,
+                layer_name = $2,
+                description = $3
+            WHERE layer_id = $1
             "#,
         )
-        .bind(tenant_id)
-        .bind(layer_id)
-        .execute(self.pool)
+        .bind(working_layer.layer_id)
+        .bind(name)
+        .bind(description.as_deref())
+        .execute(&mut **tx)
         .await?;
 
-        let deleted = result.rows_affected() > 0;
+        // Create new working layer
+        let new_working_layer = Self::create_with(
+            &mut **tx,
+            CreateLayerInput {
+                tenant_id: working_layer.tenant_id,
+                parent_layer_id: Some(working_layer.layer_id),
+                layer_name: format!("working-{}", mount_entry_id),
+                description: Some("Working layer".to_string()),
+                tags: None,
+                created_by: "system".to_string(),
+                mount_entry_id: Some(mount_entry_id),
+                is_working: true,
+            },
+        )
+        .await?;
 
-        if deleted {
-            tracing::info!(
-                layer_id = %layer_id,
-                tenant_id = %tenant_id,
-                "Deleted layer"
-            );
-        }
+        tracing::info!(
+            mount_entry_id = %mount_entry_id,
+            snapshot_name = %name,
+            old_working_layer_id = %working_layer.layer_id,
+            new_working_layer_id = %new_working_layer.layer_id,
+            "Created snapshot for mount"
+        );
 
-        Ok(deleted)
+        Ok(new_working_layer)
     }
 
-    async fn add_entry(&self, input: CreateLayerEntryInput) -> Result<LayerEntry> {
+    /// Add or update a layer entry as part of a caller-managed transaction.
+    pub async fn add_entry_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        input: CreateLayerEntryInput,
+    ) -> Result<LayerEntry> {
         let entry_id = Uuid::new_v4();
 
         let entry = sqlx::query_as::<_, LayerEntry>(
             r#"
             INSERT INTO layer_entries (
                 entry_id, layer_id, tenant_id, inode_id, path,
-                change_type, size_delta, text_changes
+                change_type, size_delta, text_changes, block_changes
             )
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
             ON CONFLICT (layer_id, path)
             DO UPDATE SET
+                inode_id = EXCLUDED.inode_id,
                 change_type = EXCLUDED.change_type,
                 size_delta = EXCLUDED.size_delta,
                 text_changes = EXCLUDED.text_changes,
+                block_changes = EXCLUDED.block_changes,
                 created_at = CURRENT_TIMESTAMP
             RETURNING entry_id, layer_id, tenant_id, inode_id, path,
-                      change_type, size_delta, text_changes, created_at
+                      change_type, size_delta, text_changes, block_changes, created_at
             "#,
         )
         .bind(entry_id)
@@ -182,7 +321,8 @@ impl<'a> LayerRepository for LayerOperations<'a> {
         .bind(input.change_type)
         .bind(input.size_delta)
         .bind(&input.text_changes)
-        .fetch_one(self.pool)
+        .bind(&input.block_changes)
+        .fetch_one(&mut **tx)
         .await?;
 
         tracing::debug!(
@@ -195,37 +335,133 @@ impl<'a> LayerRepository for LayerOperations<'a> {
         Ok(entry)
     }
 
-    async fn list_entries(
+    /// The entry a layer holds for `path`, within a caller-managed transaction.
+    pub async fn get_entry_in_tx(
         &self,
+        tx: &mut DatabaseTransaction<'_>,
         tenant_id: TenantId,
         layer_id: LayerId,
-    ) -> Result<Vec<LayerEntry>> {
-        let entries = sqlx::query_as::<_, LayerEntry>(
+        path: &str,
+    ) -> Result<Option<LayerEntry>> {
+        let entry = sqlx::query_as::<_, LayerEntry>(
             r#"
             SELECT entry_id, layer_id, tenant_id, inode_id, path,
-                   change_type, size_delta, text_changes, created_at
+                   change_type, size_delta, text_changes, block_changes, created_at
             FROM layer_entries
+            WHERE tenant_id = $1 AND layer_id = $2 AND path = $3
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_id)
+        .bind(path)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        Ok(entry)
+    }
+
+    /// Change a layer's name. Returns the updated layer, or `None` if it
+    /// does not exist. Fails if another layer of the tenant has that name.
+    pub async fn rename(
+        &self,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+        new_name: &str,
+    ) -> Result<Option<Layer>> {
+        let layer = sqlx::query_as::<_, Layer>(
+            r#"
+            UPDATE layers
+            SET layer_name = $3
             WHERE tenant_id = $1 AND layer_id = $2
-            ORDER BY created_at
+            RETURNING layer_id, tenant_id, parent_layer_id, layer_name, description,
+                      file_count, total_size, status, is_readonly, tags,
+                      created_at, created_by, mount_entry_id, is_working
             "#,
         )
         .bind(tenant_id)
         .bind(layer_id)
-        .fetch_all(self.pool)
+        .bind(new_name)
+        .fetch_optional(self.pool)
         .await?;
 
-        Ok(entries)
+        Ok(layer)
     }
 
-    async fn get_current_layer(&self, tenant_id: TenantId) -> Result<Option<LayerId>> {
+    /// Replace a layer's tags. Returns the updated layer, or `None` if it
+    /// does not exist.
+    pub async fn set_tags(
+        &self,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+        tags: Option<&serde_json::Value>,
+    ) -> Result<Option<Layer>> {
+        let layer = sqlx::query_as::<_, Layer>(
+            r#"
+            UPDATE layers
+            SET tags = $3
+            WHERE tenant_id = $1 AND layer_id = $2
+            RETURNING layer_id, tenant_id, parent_layer_id, layer_name, description,
+                      file_count, total_size, status, is_readonly, tags,
+                      created_at, created_by, mount_entry_id, is_working
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_id)
+        .bind(tags)
+        .fetch_optional(self.pool)
+        .await?;
+
+        Ok(layer)
+    }
+
+    /// Get the line-level changes recorded for `path` in a layer.
+    ///
+    /// Returns `None` when the layer has no entry for the path or the entry
+    /// carries no text changes (binary files, deletes).
+    pub async fn get_text_changes(
+        &self,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+        path: &str,
+    ) -> Result<Option<TextChanges>> {
+        let row = sqlx::query_as::<_, (Option<serde_json::Value>,)>(
+            r#"
+            SELECT text_changes
+            FROM layer_entries
+            WHERE tenant_id = $1 AND layer_id = $2 AND path = $3
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_id)
+        .bind(path)
+        .fetch_optional(self.pool)
+        .await?;
+
+        match row.and_then(|(value,)| value) {
+            Some(value) => Ok(Some(TextChanges::from_json(&value).ok_or_else(|| {
+                anyhow::anyhow!("Malformed text_changes for {} in layer {}", path, layer_id)
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Layer that was current for the tenant at `at`, from the switch history.
+    pub async fn layer_at_time(
+        &self,
+        tenant_id: TenantId,
+        at: DateTime<Utc>,
+    ) -> Result<Option<LayerId>> {
         let layer_id = sqlx::query_as::<_, (LayerId,)>(
             r#"
-            SELECT current_layer_id
-            FROM tenant_current_layer
-            WHERE tenant_id = $1
+            SELECT layer_id
+            FROM tenant_current_layer_history
+            WHERE tenant_id = $1 AND switched_at <= $2
+            ORDER BY switched_at DESC, history_id DESC
+            LIMIT 1
             "#,
         )
         .bind(tenant_id)
+        .bind(at)
         .fetch_optional(self.pool)
         .await?
         .map(|row| row.0);
@@ -233,27 +469,275 @@ impl<'a> LayerRepository for LayerOperations<'a> {
         Ok(layer_id)
     }
 
-    async fn set_current_layer(&self, tenant_id: TenantId, layer_id: LayerId) -> Result<()> {
-        sqlx::query(
+    /// Find the layer nearest to `layer_id` along its chain that recorded a
+    /// change to `inode_id`, within a caller-managed transaction. That layer
+    /// holds the inode's current text content, if it is a text file.
+    pub async fn last_change_in_chain_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+        inode_id: InodeId,
+    ) -> Result<Option<LayerId>> {
+        let layer = sqlx::query_scalar::<_, LayerId>(
             r#"
-            INSERT INTO tenant_current_layer (tenant_id, current_layer_id)
-            VALUES ($1, $2)
-            ON CONFLICT (tenant_id)
-            DO UPDATE SET current_layer_id = $2, updated_at = CURRENT_TIMESTAMP
+            WITH RECURSIVE layer_chain AS (
+                SELECT layer_id, parent_layer_id, 0 AS depth
+                FROM layers
+                WHERE layer_id = $2 AND tenant_id = $1
+
+                UNION ALL
+
+                SELECT l.layer_id, l.parent_layer_id, lc.depth + 1
+                FROM layers l
+                INNER JOIN layer_chain lc ON l.layer_id = lc.parent_layer_id
+                WHERE l.tenant_id = $1
+            )
+            SELECT lc.layer_id
+            FROM layer_chain lc
+            INNER JOIN layer_entries e ON e.layer_id = lc.layer_id
+            WHERE e.tenant_id = $1 AND e.inode_id = $3
+            ORDER BY lc.depth
+            LIMIT 1
             "#,
         )
         .bind(tenant_id)
         .bind(layer_id)
-        .execute(self.pool)
+        .bind(inode_id)
+        .fetch_optional(&mut **tx)
         .await?;
 
-        tracing::info!(
-            tenant_id = %tenant_id,
-            layer_id = %layer_id,
-            "Set current layer for tenant"
-        );
+        Ok(layer)
+    }
 
-        Ok(())
+    /// Length of `inode_id`'s binary content as of `layer_id`: the file size
+    /// recorded by the nearest binary write along the layer's chain, within a
+    /// caller-managed transaction. `None` if no write there recorded one.
+    pub async fn last_file_size_in_chain_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+        inode_id: InodeId,
+    ) -> Result<Option<i64>> {
+        let size = sqlx::query_scalar::<_, i64>(
+            r#"
+            WITH RECURSIVE layer_chain AS (
+                SELECT layer_id, parent_layer_id, 0 AS depth
+                FROM layers
+                WHERE layer_id = $2 AND tenant_id = $1
+
+                UNION ALL
+
+                SELECT l.layer_id, l.parent_layer_id, lc.depth + 1
+                FROM layers l
+                INNER JOIN layer_chain lc ON l.layer_id = lc.parent_layer_id
+                WHERE l.tenant_id = $1
+            )
+            SELECT (e.block_changes->>'file_size')::BIGINT
+            FROM layer_chain lc
+            INNER JOIN layer_entries e ON e.layer_id = lc.layer_id
+            WHERE e.tenant_id = $1 AND e.inode_id = $3 AND e.block_changes ? 'file_size'
+            ORDER BY lc.depth
+            LIMIT 1
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_id)
+        .bind(inode_id)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        Ok(size)
+    }
+
+    /// Get the block-level changes recorded for a binary file in a layer.
+    pub async fn get_block_changes(
+        &self,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+        path: &str,
+    ) -> Result<Option<BlockChanges>> {
+        let row = sqlx::query_as::<_, (Option<serde_json::Value>,)>(
+            r#"
+            SELECT block_changes
+            FROM layer_entries
+            WHERE tenant_id = $1 AND layer_id = $2 AND path = $3
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_id)
+        .bind(path)
+        .fetch_optional(self.pool)
+        .await?;
+
+        match row.and_then(|(value,)| value) {
+            Some(value) => Ok(Some(BlockChanges::from_json(&value).ok_or_else(|| {
+                anyhow::anyhow!("Malformed block_changes for {} in layer {}", path, layer_id)
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    /// What deleting `layer_ids` would free, without deleting anything.
+    ///
+    /// Chunks and text blocks count only if no other layer uses them; text
+    /// blocks are shared between tenants, so other tenants are checked too.
+    pub async fn reclaimable_blocks(
+        &self,
+        tenant_id: TenantId,
+        layer_ids: &[LayerId],
+    ) -> Result<ReclaimableBlocks> {
+        let blocks = sqlx::query_as::<_, ReclaimableBlocks>(
+            r#"
+            WITH blocks AS (
+                SELECT COUNT(*) AS data_blocks, COALESCE(SUM(size), 0)::BIGINT AS data_bytes
+                FROM data_blocks
+                WHERE tenant_id = $1 AND layer_id = ANY($2)
+            ),
+            chunk_hashes AS (
+                SELECT content_hash FROM file_chunks
+                WHERE tenant_id = $1 AND layer_id = ANY($2)
+                EXCEPT
+                SELECT content_hash FROM file_chunks
+                WHERE tenant_id = $1 AND layer_id <> ALL($2)
+            ),
+            chunks AS (
+                SELECT COUNT(*) AS chunks, COALESCE(SUM(c.size), 0)::BIGINT AS chunk_bytes
+                FROM content_chunks c
+                JOIN chunk_hashes h ON h.content_hash = c.content_hash
+                WHERE c.tenant_id = $1
+            ),
+            dropped_lines AS (
+                SELECT block_id, COUNT(*) AS refs FROM text_line_map
+                WHERE tenant_id = $1 AND layer_id = ANY($2)
+                GROUP BY block_id
+            ),
+            texts AS (
+                SELECT COUNT(*) AS text_blocks, COALESCE(SUM(b.byte_size), 0)::BIGINT AS text_bytes
+                FROM text_blocks b
+                JOIN dropped_lines d ON d.block_id = b.block_id
+                -- Deleting the lines takes ref_count down by one each
+                WHERE b.ref_count <= d.refs
+                  AND NOT EXISTS (
+                      SELECT 1 FROM text_line_map m
+                      WHERE m.block_id = b.block_id
+                        AND (m.tenant_id <> $1 OR m.layer_id <> ALL($2))
+                  )
+            )
+            SELECT * FROM blocks, chunks, texts
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_ids)
+        .fetch_one(self.pool)
+        .await?;
+
+        Ok(blocks)
+    }
+}
+
+#[async_trait]
+impl<'a> LayerRepository for LayerOperations<'a> {
+    async fn create(&self, input: CreateLayerInput) -> Result<Layer> {
+        Self::create_with(self.pool, input).await
+    }
+
+    async fn get(&self, tenant_id: TenantId, layer_id: LayerId) -> Result<Option<Layer>> {
+        Self::get_with(self.pool, tenant_id, layer_id).await
+    }
+
+    async fn list(&self, tenant_id: TenantId) -> Result<Vec<Layer>> {
+        let layers = sqlx::query_as::<_, Layer>(
+            r#"
+            SELECT layer_id, tenant_id, parent_layer_id, layer_name, description,
+                   file_count, total_size, status, is_readonly, tags,
+                   created_at, created_by, mount_entry_id, is_working
+            FROM layers
+            WHERE tenant_id = $1
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(tenant_id)
+        .fetch_all(self.pool)
+        .await?;
+
+        Ok(layers)
+    }
+
+    async fn get_layer_chain(&self, tenant_id: TenantId, layer_id: LayerId) -> Result<Vec<Layer>> {
+        let layers = sqlx::query_as::<_, Layer>(
+            r#"
+            WITH RECURSIVE layer_chain AS (
+                SELECT layer_id, tenant_id, parent_layer_id, layer_name, description,
+                       file_count, total_size, status, is_readonly, tags,
+                       created_at, created_by, mount_entry_id, is_working, 0 as depth
+                FROM layers
+                WHERE layer_id = $2 AND tenant_id = $1
+
+                UNION ALL
+
+                SELECT l.layer_id, l.tenant_id, l.parent_layer_id, l.layer_name, l.description,
+                       l.file_count, l.total_size, l.status, l.is_readonly, l.tags,
+                       l.created_at, l.created_by, l.mount_entry_id, l.is_working, lc.depth + 1
+                FROM layers l
+                INNER JOIN layer_chain lc ON l.layer_id = lc.parent_layer_id
+                WHERE l.tenant_id = $1
+            )
+            SELECT layer_id, tenant_id, parent_layer_id, layer_name, description,
+                   file_count, total_size, status, is_readonly, tags,
+                   created_at, created_by, mount_entry_id, is_working
+            FROM layer_chain
+            ORDER BY depth
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_id)
+        .fetch_all(self.pool)
+        .await?;
+
+        Ok(layers)
+    }
+
+    async fn delete(&self, tenant_id: TenantId, layer_id: LayerId) -> Result<bool> {
+        Self::delete_with(self.pool, tenant_id, layer_id).await
+    }
+
+    async fn add_entry(&self, input: CreateLayerEntryInput) -> Result<LayerEntry> {
+        let mut tx = self.pool.begin().await?;
+        let entry = self.add_entry_in_tx(&mut tx, input).await?;
+        tx.commit().await?;
+        Ok(entry)
+    }
+
+    async fn list_entries(
+        &self,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+    ) -> Result<Vec<LayerEntry>> {
+        let entries = sqlx::query_as::<_, LayerEntry>(
+            r#"
+            SELECT entry_id, layer_id, tenant_id, inode_id, path,
+                   change_type, size_delta, text_changes, block_changes, created_at
+            FROM layer_entries
+            WHERE tenant_id = $1 AND layer_id = $2
+            ORDER BY created_at
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_id)
+        .fetch_all(self.pool)
+        .await?;
+
+        Ok(entries)
+    }
+
+    async fn get_current_layer(&self, tenant_id: TenantId) -> Result<Option<LayerId>> {
+        Self::get_current_layer_with(self.pool, tenant_id).await
+    }
+
+    async fn set_current_layer(&self, tenant_id: TenantId, layer_id: LayerId) -> Result<()> {
+        Self::set_current_layer_with(self.pool, tenant_id, layer_id).await
     }
 
     // Mount-level layer chains (Task 21)
@@ -320,20 +804,7 @@ impl<'a> LayerRepository for LayerOperations<'a> {
     }
 
     async fn get_working_layer(&self, mount_entry_id: Uuid) -> Result<Option<Layer>> {
-        let layer = sqlx::query_as::<_, Layer>(
-            r#"
-            SELECT layer_id, tenant_id, parent_layer_id, layer_name, description,
-                   file_count, total_size, status, is_readonly, tags,
-                   created_at, created_by, mount_entry_id, is_working
-            FROM layers
-            WHERE mount_entry_id = $1 AND is_working = true
-            "#,
-        )
-        .bind(mount_entry_id)
-        .fetch_optional(self.pool)
-        .await?;
-
-        Ok(layer)
+        Self::get_working_layer_with(self.pool, mount_entry_id).await
     }
 
     async fn create_snapshot(
@@ -342,50 +813,10 @@ impl<'a> LayerRepository for LayerOperations<'a> {
         name: &str,
         description: Option<String>,
     ) -> Result<Layer> {
-        // Get current working layer
-        let working_layer = self
-            .get_working_layer(mount_entry_id)
-            .await?
-            .ok_or_else(|| anyhow::anyhow!("Working layer not found for mount"))?;
-
-        // Set working layer to snapshot (is_working = false)
-        sqlx::query(
-            r#"
-            UPDATE layers
-            SET is_working = false,
-                layer_name = $2,
-                description = $3
-            WHERE layer_id = $1
-            "#,
-        )
-        .bind(working_layer.layer_id)
-        .bind(name)
-        .bind(description.as_deref())
-        .execute(self.pool)
-        .await?;
-
-        // Create new working layer
-        let new_working_layer = self
-            .create(CreateLayerInput {
-                tenant_id: working_layer.tenant_id,
-                parent_layer_id: Some(working_layer.layer_id),
-                layer_name: format!("working-{}", mount_entry_id),
-                description: Some("Working layer".to_string()),
-                tags: None,
-                created_by: "system".to_string(),
-                mount_entry_id: Some(mount_entry_id),
-                is_working: true,
-            })
-            .await?;
-
-        tracing::info!(
-            mount_entry_id = %mount_entry_id,
-            snapshot_name = %name,
-            old_working_layer_id = %working_layer.layer_id,
-            new_working_layer_id = %new_working_layer.layer_id,
-            "Created snapshot for mount"
-        );
-
+        let mut tx = self.pool.begin().await?;
+        let new_working_layer =
+            Self::snapshot_in_tx(&mut tx, mount_entry_id, name, description).await?;
+        tx.commit().await?;
         Ok(new_working_layer)
     }
 
@@ -398,76 +829,66 @@ impl<'a> LayerRepository for LayerOperations<'a> {
     ) -> Result<Vec<crate::composition::SnapshotResult>> {
         use crate::composition::SnapshotResult;
 
-        let mut results = Vec::new();
+        // All mounts snapshot in one transaction, or none do
+        let mut tx = self.pool.begin().await?;
 
-        // For now, process sequentially (could be optimized with transactions)
+        // Resolve and lock every mount before changing anything, so all bad
+        // names are reported together
+        let mut mounts = Vec::with_capacity(mount_names.len());
+        let mut missing = Vec::new();
         for mount_name in mount_names {
-            // Get mount entry
-            let mount_result = sqlx::query_as::<_, (Uuid, Uuid)>(
-                "SELECT mount_entry_id, current_layer_id FROM mount_entries WHERE tenant_id = $1 AND name = $2"
+            let mount_entry_id = sqlx::query_scalar::<_, Uuid>(
+                r#"
+                SELECT mount_entry_id FROM mount_entries
+                WHERE tenant_id = $1 AND name = $2
+                FOR UPDATE
+                "#,
             )
             .bind(tenant_id)
             .bind(mount_name)
-            .fetch_optional(self.pool)
-            .await;
-
-            match mount_result {
-                Ok(Some((mount_entry_id, _current_layer_id))) => {
-                    // Check if working layer has changes
-                    if skip_unchanged {
-                        let working_layer = self.get_working_layer(mount_entry_id).await?;
-                        if let Some(layer) = working_layer
-                            && layer.file_count == 0
-                        {
-                            results.push(SnapshotResult {
-                                mount_name: mount_name.clone(),
-                                layer_id: None,
-                                skipped: true,
-                                reason: Some("No changes".to_string()),
-                            });
-                            continue;
-                        }
-                    }
-
-                    // Create snapshot
-                    match self.create_snapshot(mount_entry_id, name, None).await {
-                        Ok(new_layer) => {
-                            results.push(SnapshotResult {
-                                mount_name: mount_name.clone(),
-                                layer_id: Some(new_layer.layer_id),
-                                skipped: false,
-                                reason: None,
-                            });
-                        }
-                        Err(e) => {
-                            results.push(SnapshotResult {
-                                mount_name: mount_name.clone(),
-                                layer_id: None,
-                                skipped: true,
-                                reason: Some(format!("Error: {}", e)),
-                            });
-                        }
-                    }
-                }
-                Ok(None) => {
-                    results.push(SnapshotResult {
-                        mount_name: mount_name.clone(),
-                        layer_id: None,
-                        skipped: true,
-                        reason: Some("Mount not found".to_string()),
-                    });
-                }
-                Err(e) => {
-                    results.push(SnapshotResult {
-                        mount_name: mount_name.clone(),
-                        layer_id: None,
-                        skipped: true,
-                        reason: Some(format!("Database error: {}", e)),
-                    });
-                }
+            .fetch_optional(&mut *tx)
+            .await?;
+            match mount_entry_id {
+                Some(mount_entry_id) => mounts.push((mount_name, mount_entry_id)),
+                None => missing.push(mount_name.as_str()),
             }
         }
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Snapshot '{}' not taken: mounts not found: {}",
+                name,
+                missing.join(", ")
+            ));
+        }
+
+        let mut results = Vec::with_capacity(mounts.len());
+        for (mount_name, mount_entry_id) in mounts {
+            if skip_unchanged
+                && let Some(layer) = Self::get_working_layer_with(&mut *tx, mount_entry_id).await?
+                && layer.file_count == 0
+            {
+                results.push(SnapshotResult {
+                    mount_name: mount_name.clone(),
+                    layer_id: None,
+                    skipped: true,
+                    reason: Some("No changes".to_string()),
+                });
+                continue;
+            }
+
+            let new_layer =
+                Self::snapshot_in_tx(&mut tx, mount_entry_id, name, None).await.with_context(
+                    || format!("Snapshot '{}' not taken: mount '{}' failed", name, mount_name),
+                )?;
+            results.push(SnapshotResult {
+                mount_name: mount_name.clone(),
+                layer_id: Some(new_layer.layer_id),
+                skipped: false,
+                reason: None,
+            });
+        }
 
+        tx.commit().await?;
         Ok(results)
     }
 }
@@ -536,6 +957,7 @@ mod tests {
             change_type: ChangeType::Add,
             size_delta: Some(1024),
             text_changes: None,
+            block_changes: None,
         };
 
         assert_eq!(input.path, "/test.txt");
@@ -553,6 +975,7 @@ mod tests {
             change_type: ChangeType::Add,
             size_delta: Some(100),
             text_changes: None,
+            block_changes: None,
         };
 
         let modify_entry =
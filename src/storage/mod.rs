@@ -1,26 +1,35 @@
 pub mod audit;
 pub mod block;
+pub mod chunk;
 pub mod inode;
 pub mod layer;
 pub mod models;
 pub mod mount_entry;
+pub mod notify;
 pub mod pool;
 pub mod published_mount;
 pub mod tenant;
 pub mod text;
 pub mod traits;
+pub mod usage;
 
-pub use audit::AuditLogOperations;
+pub use audit::{
+    AUDIT_RECORD_SCHEMA_VERSION, AuditExportFormat, AuditFilter, AuditLogOperations, AuditRecord,
+    AuditWindow,
+};
 pub use block::BlockOperations;
+pub use chunk::ChunkOperations;
 pub use inode::InodeOperations;
 pub use layer::LayerOperations;
 pub use models::*;
 pub use mount_entry::PgMountEntryRepository;
-pub use pool::{DatabasePool, DatabaseTransaction};
-pub use published_mount::PgPublishedMountRepository;
+pub use notify::{INVALIDATE_CHANNEL, Invalidation, InvalidationListener, NotifyOperations};
+pub use pool::{DatabasePool, DatabaseTransaction, WriteSession, begin_snapshot};
+pub use published_mount::{PgPublishedMountRepository, PublishedMountError};
 pub use tenant::TenantOperations;
 pub use text::TextBlockOperations;
 pub use traits::{
     AuditLogRepository, BlockRepository, InodeRepository, LayerRepository, MountEntryRepository,
-    PublishedMountRepository, TenantRepository, TextBlockRepository,
+    PublishedMountRepository, TenantRepository, TextBlockRepository, UsageRepository,
 };
+pub use usage::UsageOperations;
@@ -9,15 +9,41 @@ pub struct Tenant {
     pub tenant_id: TenantId,
     pub tenant_name: String,
     pub root_inode_id: InodeId,
+    /// Mode of files created without one, before `umask`
+    pub default_file_mode: i32,
+    /// Mode of directories created without one, before `umask`
+    pub default_dir_mode: i32,
+    /// Permission bits cleared from every new file and directory
+    pub umask: i32,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+impl Tenant {
+    /// Mode for a new file: `requested` if given, else the default, less `umask`
+    pub fn file_mode(&self, requested: Option<i32>) -> i32 {
+        requested.unwrap_or(self.default_file_mode) & 0o7777 & !self.umask
+    }
+
+    /// Mode for a new directory: `requested` if given, else the default, less `umask`
+    pub fn dir_mode(&self, requested: Option<i32>) -> i32 {
+        requested.unwrap_or(self.default_dir_mode) & 0o7777 & !self.umask
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateTenantInput {
     pub tenant_name: String,
 }
 
+/// Change a tenant's mode policy; `None` leaves a setting as it is
+#[derive(Debug, Clone, Default)]
+pub struct UpdateTenantModesInput {
+    pub default_file_mode: Option<i32>,
+    pub default_dir_mode: Option<i32>,
+    pub umask: Option<i32>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "varchar", rename_all = "lowercase")]
 pub enum InodeType {
@@ -74,6 +100,9 @@ pub struct DataBlock {
     pub size: i32,
     pub content_hash: String,
     pub created_at: DateTime<Utc>,
+    /// Layer that stored this version of the block; `None` for blocks that
+    /// predate layered storage, which every layer sees.
+    pub layer_id: Option<LayerId>,
 }
 
 #[derive(Debug, Clone)]
@@ -82,6 +111,56 @@ pub struct CreateBlockInput {
     pub inode_id: InodeId,
     pub block_index: i32,
     pub data: Vec<u8>,
+    pub layer_id: Option<LayerId>,
+}
+
+/// One entry of a layer's chunk map for a content-defined chunked file
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct FileChunk {
+    pub tenant_id: TenantId,
+    pub inode_id: InodeId,
+    pub layer_id: LayerId,
+    pub chunk_index: i32,
+    pub chunk_offset: i64,
+    pub size: i32,
+    pub content_hash: String,
+}
+
+/// Storage held by one tenant.
+///
+/// Logical bytes are what the tenant's files reference across all layers;
+/// physical bytes are what is actually stored for them after deduplication.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct TenantUsage {
+    pub tenant_id: TenantId,
+    pub tenant_name: String,
+    pub layers: i64,
+    pub files: i64,
+    pub logical_bytes: i64,
+    pub physical_bytes: i64,
+}
+
+/// Storage a single layer contributes itself, excluding what it inherits.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct LayerUsage {
+    pub layer_id: LayerId,
+    pub layer_name: String,
+    /// Files added or modified in this layer.
+    pub own_files: i64,
+    /// Block, chunk and text bytes the layer's own file versions reference.
+    pub own_bytes: i64,
+}
+
+/// Storage accounting across tenants.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StorageUsage {
+    pub tenants: i64,
+    pub layers: i64,
+    pub files: i64,
+    pub logical_bytes: i64,
+    /// Text blocks shared between tenants are counted once.
+    pub physical_bytes: i64,
+    pub dedup_savings_bytes: i64,
 }
 
 // ============================================================================
@@ -110,6 +189,8 @@ pub struct AuditLog {
     pub metadata: Option<serde_json::Value>,
     pub created_at: DateTime<Utc>,
     pub log_date: chrono::NaiveDate,
+    /// Agent the caller acted for, when the mount or API request named one.
+    pub agent_id: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -131,6 +212,7 @@ pub struct CreateAuditLogInput {
     pub is_native_mount: bool,
     pub native_source_path: Option<String>,
     pub metadata: Option<serde_json::Value>,
+    pub agent_id: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -196,6 +278,18 @@ pub struct Layer {
     pub is_working: bool,
 }
 
+/// Storage that deleting a set of layers frees: fixed-size blocks go with
+/// the layers, chunks and text blocks used by nothing else at the next GC.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct ReclaimableBlocks {
+    pub data_blocks: i64,
+    pub data_bytes: i64,
+    pub chunks: i64,
+    pub chunk_bytes: i64,
+    pub text_blocks: i64,
+    pub text_bytes: i64,
+}
+
 #[derive(Debug, Clone)]
 pub struct CreateLayerInput {
     pub tenant_id: TenantId,
@@ -220,6 +314,7 @@ pub struct LayerEntry {
     pub change_type: ChangeType,
     pub size_delta: Option<i64>,
     pub text_changes: Option<serde_json::Value>,
+    pub block_changes: Option<serde_json::Value>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -232,6 +327,7 @@ pub struct CreateLayerEntryInput {
     pub change_type: ChangeType,
     pub size_delta: Option<i64>,
     pub text_changes: Option<serde_json::Value>,
+    pub block_changes: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -377,8 +473,13 @@ mod tests {
     fn test_create_block_input() {
         let tenant_id = uuid::Uuid::new_v4();
         let data = vec![1, 2, 3, 4, 5];
-        let input =
-            CreateBlockInput { tenant_id, inode_id: 42, block_index: 0, data: data.clone() };
+        let input = CreateBlockInput {
+            tenant_id,
+            inode_id: 42,
+            block_index: 0,
+            data: data.clone(),
+            layer_id: None,
+        };
         assert_eq!(input.inode_id, 42);
         assert_eq!(input.block_index, 0);
         assert_eq!(input.data, data);
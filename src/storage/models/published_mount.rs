@@ -28,7 +28,8 @@ pub enum PublishScope {
 #[derive(Debug, Clone, PartialEq)]
 pub struct PublishedMount {
     pub publish_id: Uuid,
-    pub mount_entry_id: Uuid,
+    /// Mount entry published; `None` for a layer published directly
+    pub mount_entry_id: Option<Uuid>,
     pub tenant_id: Uuid,
     pub publish_name: String,
     pub description: Option<String>,
@@ -48,6 +49,16 @@ pub struct PublishMountInput {
     pub scope: PublishScope,
 }
 
+/// Input for publishing one of a tenant's layers directly
+#[derive(Debug, Clone)]
+pub struct PublishLayerInput {
+    pub tenant_id: Uuid,
+    pub layer_id: Uuid,
+    pub publish_name: String,
+    pub description: Option<String>,
+    pub scope: PublishScope,
+}
+
 /// Input for updating publish info
 #[derive(Debug, Clone, Default)]
 pub struct UpdatePublishInput {
@@ -65,7 +76,7 @@ pub struct PublishedMountFilter {
 /// Resolved published mount (with actual layer_id for working_layer types)
 #[derive(Debug, Clone)]
 pub struct ResolvedPublished {
-    pub mount_entry_id: Uuid,
+    pub mount_entry_id: Option<Uuid>,
     pub owner_tenant_id: Uuid,
     pub layer_id: Uuid,
     pub is_working_layer: bool,
@@ -159,7 +170,7 @@ mod tests {
     #[test]
     fn test_resolved_published_creation() {
         let resolved = ResolvedPublished {
-            mount_entry_id: Uuid::new_v4(),
+            mount_entry_id: Some(Uuid::new_v4()),
             owner_tenant_id: Uuid::new_v4(),
             layer_id: Uuid::new_v4(),
             is_working_layer: true,
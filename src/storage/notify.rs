@@ -0,0 +1,114 @@
+//! Telling other processes about changes with Postgres LISTEN/NOTIFY.
+//!
+//! Every process caches some of what it has seen (missing paths, attributes).
+//! Changes are announced on `INVALIDATE_CHANNEL` with a `<tenant>:<path>`
+//! payload, so processes serving the same tenant can drop what went stale.
+
+use anyhow::Result;
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+use tracing::warn;
+
+use crate::types::TenantId;
+
+/// Channel changes are announced on
+pub const INVALIDATE_CHANNEL: &str = "tarbox_invalidate";
+
+/// A path of a tenant that some process changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invalidation {
+    pub tenant_id: TenantId,
+    pub path: String,
+    /// Server process id of the session that sent it
+    pub sender_pid: u32,
+}
+
+impl Invalidation {
+    /// Parse a `<tenant>:<path>` payload.
+    pub fn parse(payload: &str, sender_pid: u32) -> Option<Self> {
+        let (tenant, path) = payload.split_once(':')?;
+        let tenant_id = tenant.parse().ok()?;
+        Some(Self { tenant_id, path: path.to_string(), sender_pid })
+    }
+}
+
+fn payload(tenant_id: TenantId, path: &str) -> String {
+    format!("{}:{}", tenant_id, path)
+}
+
+pub struct NotifyOperations<'a> {
+    pool: &'a PgPool,
+}
+
+impl<'a> NotifyOperations<'a> {
+    pub fn new(pool: &'a PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Announce changes to `paths` of a tenant. `on_sender` is first given
+    /// the server process id they will be sent from, which listeners see as
+    /// `sender_pid`, so a process can recognise its own announcements.
+    pub async fn notify_invalidate(
+        &self,
+        tenant_id: TenantId,
+        paths: &[&str],
+        on_sender: impl FnOnce(u32),
+    ) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        let (pid,): (i32,) =
+            sqlx::query_as("SELECT pg_backend_pid()").fetch_one(&mut *conn).await?;
+        on_sender(pid as u32);
+
+        let payloads: Vec<String> = paths.iter().map(|path| payload(tenant_id, path)).collect();
+        sqlx::query("SELECT pg_notify($1, p) FROM unnest($2::text[]) AS p")
+            .bind(INVALIDATE_CHANNEL)
+            .bind(&payloads)
+            .execute(&mut *conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Receives invalidations from every process, on a connection of its own.
+pub struct InvalidationListener {
+    listener: PgListener,
+}
+
+impl InvalidationListener {
+    pub async fn connect(pool: &PgPool) -> Result<Self> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen(INVALIDATE_CHANNEL).await?;
+        Ok(Self { listener })
+    }
+
+    /// Wait for the next invalidation. Malformed payloads are skipped.
+    ///
+    /// If the connection drops, it is re-established; anything announced
+    /// meanwhile is missed.
+    pub async fn recv(&mut self) -> Result<Invalidation> {
+        loop {
+            let notification = self.listener.recv().await?;
+            match Invalidation::parse(notification.payload(), notification.process_id()) {
+                Some(invalidation) => return Ok(invalidation),
+                None => warn!(payload = notification.payload(), "Ignoring malformed invalidation"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_invalidation_payload_round_trip() {
+        let tenant_id = Uuid::new_v4();
+        let parsed = Invalidation::parse(&payload(tenant_id, "/a:b/c.txt"), 42).unwrap();
+        assert_eq!(parsed, Invalidation { tenant_id, path: "/a:b/c.txt".into(), sender_pid: 42 });
+
+        assert_eq!(Invalidation::parse("/no/tenant", 1), None);
+        assert_eq!(Invalidation::parse("not-a-uuid:/x", 1), None);
+    }
+}
@@ -2,14 +2,27 @@ use anyhow::{Result, anyhow};
 use async_trait::async_trait;
 use chrono::Utc;
 use sqlx::PgPool;
+use thiserror::Error;
 use uuid::Uuid;
 
 use super::models::published_mount::{
-    PublishMountInput, PublishScope, PublishTarget, PublishedMount, PublishedMountFilter,
-    ResolvedPublished, UpdatePublishInput,
+    PublishLayerInput, PublishMountInput, PublishScope, PublishTarget, PublishedMount,
+    PublishedMountFilter, ResolvedPublished, UpdatePublishInput,
 };
 use super::traits::PublishedMountRepository;
 
+/// Why a published mount could not be resolved for a tenant. Returned
+/// inside the `anyhow::Error` of `resolve_published`, for callers to tell
+/// refusals from lookups that went wrong.
+#[derive(Error, Debug)]
+pub enum PublishedMountError {
+    #[error("Published mount not found: {0}")]
+    NotFound(String),
+
+    #[error("Tenant does not have access to published mount '{0}'")]
+    AccessDenied(String),
+}
+
 pub struct PgPublishedMountRepository {
     pool: PgPool,
 }
@@ -24,7 +37,7 @@ impl PgPublishedMountRepository {
         use sqlx::Row;
 
         let publish_id: Uuid = row.try_get("publish_id")?;
-        let mount_entry_id: Uuid = row.try_get("mount_entry_id")?;
+        let mount_entry_id: Option<Uuid> = row.try_get("mount_entry_id")?;
         let tenant_id: Uuid = row.try_get("tenant_id")?;
         let publish_name: String = row.try_get("publish_name")?;
         let description: Option<String> = row.try_get("description")?;
@@ -128,7 +141,7 @@ impl PublishedMountRepository for PgPublishedMountRepository {
 
         Ok(PublishedMount {
             publish_id,
-            mount_entry_id: input.mount_entry_id,
+            mount_entry_id: Some(input.mount_entry_id),
             tenant_id: mount.0,
             publish_name: input.publish_name,
             description: input.description,
@@ -139,6 +152,106 @@ impl PublishedMountRepository for PgPublishedMountRepository {
         })
     }
 
+    async fn publish_layer(&self, input: PublishLayerInput) -> Result<PublishedMount> {
+        let mut tx = self.pool.begin().await?;
+
+        let layer: Option<(Uuid,)> =
+            sqlx::query_as("SELECT layer_id FROM layers WHERE tenant_id = $1 AND layer_id = $2")
+                .bind(input.tenant_id)
+                .bind(input.layer_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+        if layer.is_none() {
+            return Err(anyhow!("Layer not found: {}", input.layer_id));
+        }
+
+        // Lock the current-layer row so a concurrent checkpoint can't race us
+        let current: Option<(Uuid,)> = sqlx::query_as(
+            "SELECT current_layer_id FROM tenant_current_layer WHERE tenant_id = $1 FOR SHARE",
+        )
+        .bind(input.tenant_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if current.is_some_and(|(id,)| id == input.layer_id) {
+            return Err(anyhow!(
+                "Layer {} is the current layer, create a checkpoint before publishing it",
+                input.layer_id
+            ));
+        }
+
+        let taken: Option<(Uuid,)> =
+            sqlx::query_as("SELECT publish_id FROM published_mounts WHERE publish_name = $1")
+                .bind(&input.publish_name)
+                .fetch_optional(&mut *tx)
+                .await?;
+        if taken.is_some() {
+            return Err(anyhow!(
+                "PublishNameExists: '{}' is already published",
+                input.publish_name
+            ));
+        }
+
+        // Readers of a published layer must never see it change
+        sqlx::query("UPDATE layers SET is_readonly = true WHERE tenant_id = $1 AND layer_id = $2")
+            .bind(input.tenant_id)
+            .bind(input.layer_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let publish_id = Uuid::new_v4();
+        let now = Utc::now();
+
+        let (scope_str, allowed_tenants) = match &input.scope {
+            PublishScope::Public => ("public", None),
+            PublishScope::AllowList { tenants } => ("allow_list", Some(tenants.as_slice())),
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO published_mounts (
+                publish_id, mount_entry_id, tenant_id, publish_name, description,
+                target_type, layer_id, scope, allowed_tenants, created_at, updated_at
+            ) VALUES ($1, NULL, $2, $3, $4, 'layer', $5, $6, $7, $8, $9)
+            "#,
+        )
+        .bind(publish_id)
+        .bind(input.tenant_id)
+        .bind(&input.publish_name)
+        .bind(&input.description)
+        .bind(input.layer_id)
+        .bind(scope_str)
+        .bind(allowed_tenants)
+        .bind(now)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(PublishedMount {
+            publish_id,
+            mount_entry_id: None,
+            tenant_id: input.tenant_id,
+            publish_name: input.publish_name,
+            description: input.description,
+            target: PublishTarget::Layer(input.layer_id),
+            scope: input.scope,
+            created_at: now,
+            updated_at: now,
+        })
+    }
+
+    async fn unpublish_by_name(&self, tenant_id: Uuid, publish_name: &str) -> Result<bool> {
+        let result =
+            sqlx::query("DELETE FROM published_mounts WHERE tenant_id = $1 AND publish_name = $2")
+                .bind(tenant_id)
+                .bind(publish_name)
+                .execute(&self.pool)
+                .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
     async fn unpublish_mount(&self, mount_entry_id: Uuid) -> Result<bool> {
         let result = sqlx::query("DELETE FROM published_mounts WHERE mount_entry_id = $1")
             .bind(mount_entry_id)
@@ -273,7 +386,7 @@ impl PublishedMountRepository for PgPublishedMountRepository {
         let published = self
             .get_published_by_name(publish_name)
             .await?
-            .ok_or_else(|| anyhow!("Published mount not found: {}", publish_name))?;
+            .ok_or_else(|| PublishedMountError::NotFound(publish_name.to_string()))?;
 
         // Owner always has access
         if published.tenant_id == accessor_tenant_id {
@@ -330,22 +443,26 @@ impl PublishedMountRepository for PgPublishedMountRepository {
     ) -> Result<ResolvedPublished> {
         // Check access first
         if !self.check_access(publish_name, accessor_tenant_id).await? {
-            return Err(anyhow!("AccessDenied: tenant does not have access to '{}'", publish_name));
+            return Err(PublishedMountError::AccessDenied(publish_name.to_string()).into());
         }
 
         let published = self
             .get_published_by_name(publish_name)
             .await?
-            .ok_or_else(|| anyhow!("Published mount not found"))?;
+            .ok_or_else(|| PublishedMountError::NotFound(publish_name.to_string()))?;
 
         let layer_id = match &published.target {
             PublishTarget::Layer(id) => *id,
             PublishTarget::WorkingLayer => {
+                let mount_entry_id = published
+                    .mount_entry_id
+                    .ok_or_else(|| anyhow!("Working layer publish has no mount entry"))?;
+
                 // Get current working layer from mount_entry
                 let mount: (Option<Uuid>,) = sqlx::query_as(
                     "SELECT current_layer_id FROM mount_entries WHERE mount_entry_id = $1",
                 )
-                .bind(published.mount_entry_id)
+                .bind(mount_entry_id)
                 .fetch_optional(&self.pool)
                 .await?
                 .ok_or_else(|| anyhow!("Mount entry not found"))?;
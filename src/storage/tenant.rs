@@ -1,11 +1,11 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_trait::async_trait;
 use sqlx::PgPool;
 use uuid::Uuid;
 
 use crate::types::{InodeId, TenantId};
 
-use super::models::{CreateTenantInput, Tenant};
+use super::models::{CreateTenantInput, Tenant, UpdateTenantModesInput};
 use super::traits::TenantRepository;
 
 pub struct TenantOperations<'a> {
@@ -64,7 +64,8 @@ impl<'a> TenantRepository for TenantOperations<'a> {
             UPDATE tenants
             SET root_inode_id = $2
             WHERE tenant_id = $1
-            RETURNING tenant_id, tenant_name, root_inode_id, created_at, updated_at
+            RETURNING tenant_id, tenant_name, root_inode_id, default_file_mode, default_dir_mode,
+                      umask, created_at, updated_at
             "#,
         )
         .bind(tenant_id)
@@ -87,7 +88,8 @@ impl<'a> TenantRepository for TenantOperations<'a> {
     async fn get_by_id(&self, tenant_id: TenantId) -> Result<Option<Tenant>> {
         let tenant = sqlx::query_as::<_, Tenant>(
             r#"
-            SELECT tenant_id, tenant_name, root_inode_id, created_at, updated_at
+            SELECT tenant_id, tenant_name, root_inode_id, default_file_mode, default_dir_mode, umask,
+                   created_at, updated_at
             FROM tenants
             WHERE tenant_id = $1
             "#,
@@ -102,7 +104,8 @@ impl<'a> TenantRepository for TenantOperations<'a> {
     async fn get_by_name(&self, tenant_name: &str) -> Result<Option<Tenant>> {
         let tenant = sqlx::query_as::<_, Tenant>(
             r#"
-            SELECT tenant_id, tenant_name, root_inode_id, created_at, updated_at
+            SELECT tenant_id, tenant_name, root_inode_id, default_file_mode, default_dir_mode, umask,
+                   created_at, updated_at
             FROM tenants
             WHERE tenant_name = $1
             "#,
@@ -117,7 +120,8 @@ impl<'a> TenantRepository for TenantOperations<'a> {
     async fn list(&self) -> Result<Vec<Tenant>> {
         let tenants = sqlx::query_as::<_, Tenant>(
             r#"
-            SELECT tenant_id, tenant_name, root_inode_id, created_at, updated_at
+            SELECT tenant_id, tenant_name, root_inode_id, default_file_mode, default_dir_mode, umask,
+                   created_at, updated_at
             FROM tenants
             ORDER BY created_at DESC
             "#,
@@ -128,18 +132,106 @@ impl<'a> TenantRepository for TenantOperations<'a> {
         Ok(tenants)
     }
 
+    /// Delete the tenant and everything it owns.
+    ///
+    /// Tenant-scoped tables cascade from `tenants`; text blocks are shared,
+    /// so the ones only this tenant used are removed explicitly. Either all
+    /// of it goes or, on any error, none of it.
     async fn delete(&self, tenant_id: TenantId) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let block_ids: Vec<Uuid> =
+            sqlx::query_scalar("SELECT DISTINCT block_id FROM text_line_map WHERE tenant_id = $1")
+                .bind(tenant_id)
+                .fetch_all(&mut *tx)
+                .await?;
+
         let result = sqlx::query("DELETE FROM tenants WHERE tenant_id = $1")
             .bind(tenant_id)
-            .execute(self.pool)
-            .await?;
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to delete tenant {}", tenant_id))?;
+
+        // The line maps are gone, so ref counts are settled
+        let text_blocks = sqlx::query(
+            r#"
+            DELETE FROM text_blocks b
+            WHERE b.block_id = ANY($1)
+              AND b.ref_count = 0
+              AND NOT EXISTS (SELECT 1 FROM text_line_map m WHERE m.block_id = b.block_id)
+            "#,
+        )
+        .bind(&block_ids)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
 
         let deleted = result.rows_affected() > 0;
 
         if deleted {
-            tracing::info!(tenant_id = %tenant_id, "Deleted tenant");
+            tracing::info!(
+                tenant_id = %tenant_id,
+                text_blocks = text_blocks.rows_affected(),
+                "Deleted tenant"
+            );
         }
 
         Ok(deleted)
     }
+
+    async fn update_modes(
+        &self,
+        tenant_id: TenantId,
+        input: UpdateTenantModesInput,
+    ) -> Result<Option<Tenant>> {
+        let tenant = sqlx::query_as::<_, Tenant>(
+            r#"
+            UPDATE tenants
+            SET default_file_mode = COALESCE($2, default_file_mode),
+                default_dir_mode = COALESCE($3, default_dir_mode),
+                umask = COALESCE($4, umask)
+            WHERE tenant_id = $1
+            RETURNING tenant_id, tenant_name, root_inode_id, default_file_mode, default_dir_mode,
+                      umask, created_at, updated_at
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(input.default_file_mode)
+        .bind(input.default_dir_mode)
+        .bind(input.umask)
+        .fetch_optional(self.pool)
+        .await?;
+
+        Ok(tenant)
+    }
+
+    async fn rename(&self, tenant_id: TenantId, new_name: &str) -> Result<Option<Tenant>> {
+        let tenant = sqlx::query_as::<_, Tenant>(
+            r#"
+            UPDATE tenants
+            SET tenant_name = $2
+            WHERE tenant_id = $1
+            RETURNING tenant_id, tenant_name, root_inode_id, default_file_mode, default_dir_mode,
+                      umask, created_at, updated_at
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(new_name)
+        .fetch_optional(self.pool)
+        .await;
+
+        let tenant = match tenant {
+            Err(sqlx::Error::Database(db)) if db.code().as_deref() == Some("23505") => {
+                anyhow::bail!("Tenant name already in use: {}", new_name)
+            }
+            result => result?,
+        };
+
+        if let Some(tenant) = &tenant {
+            tracing::info!(tenant_id = %tenant_id, tenant_name = %tenant.tenant_name, "Renamed tenant");
+        }
+
+        Ok(tenant)
+    }
 }
@@ -1,6 +1,6 @@
 use anyhow::Result;
 use async_trait::async_trait;
-use sqlx::PgPool;
+use sqlx::{PgExecutor, PgPool};
 use uuid::Uuid;
 
 use crate::types::{BlockId, InodeId, LayerId, TenantId};
@@ -8,6 +8,7 @@ use crate::types::{BlockId, InodeId, LayerId, TenantId};
 use super::models::{
     CreateTextBlockInput, CreateTextMetadataInput, TextBlock, TextFileMetadata, TextLineMap,
 };
+use super::pool::DatabaseTransaction;
 use super::traits::TextBlockRepository;
 
 pub struct TextBlockOperations<'a> {
@@ -24,15 +25,153 @@ impl<'a> TextBlockOperations<'a> {
         let hash = blake3::hash(content.as_bytes());
         hash.to_hex().to_string()
     }
-}
 
-#[async_trait]
-impl<'a> TextBlockRepository for TextBlockOperations<'a> {
-    async fn create_block(&self, input: CreateTextBlockInput) -> Result<TextBlock> {
+    /// Delete text blocks no file references, returning how many were removed
+    /// and their total size.
+    ///
+    /// Blocks are shared between tenants, so this sweeps every tenant's
+    /// unreferenced blocks at once.
+    pub async fn delete_unreferenced(&self) -> Result<(i64, i64)> {
+        let removed = sqlx::query_as::<_, (i64, i64)>(
+            r#"
+            WITH removed AS (
+                DELETE FROM text_blocks b
+                WHERE b.ref_count = 0
+                  AND NOT EXISTS (SELECT 1 FROM text_line_map m WHERE m.block_id = b.block_id)
+                RETURNING byte_size
+            )
+            SELECT COUNT(*), COALESCE(SUM(byte_size), 0)::BIGINT FROM removed
+            "#,
+        )
+        .fetch_one(self.pool)
+        .await?;
+
+        Ok(removed)
+    }
+
+    /// Lines of the text files among `inode_ids` as `layer_id` sees them, as
+    /// `(inode_id, line_number, content)` with 0-based line numbers, ordered
+    /// by file and line.
+    ///
+    /// A file's text is read from the nearest layer along the chain that
+    /// changed it; files stored as binary there have no lines. If `needle` is
+    /// given, only lines containing it are returned, ignoring case if
+    /// `ignore_case` is set.
+    pub async fn search_lines_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+        inode_ids: &[InodeId],
+        needle: Option<&str>,
+        ignore_case: bool,
+    ) -> Result<Vec<(InodeId, i32, String)>> {
+        let lines = sqlx::query_as::<_, (InodeId, i32, String)>(
+            r#"
+            WITH RECURSIVE layer_chain AS (
+                SELECT layer_id, parent_layer_id, 0 AS depth
+                FROM layers
+                WHERE layer_id = $2 AND tenant_id = $1
+
+                UNION ALL
+
+                SELECT l.layer_id, l.parent_layer_id, lc.depth + 1
+                FROM layers l
+                INNER JOIN layer_chain lc ON l.layer_id = lc.parent_layer_id
+                WHERE l.tenant_id = $1
+            ),
+            text_layers AS (
+                SELECT DISTINCT ON (e.inode_id) e.inode_id, lc.layer_id
+                FROM layer_chain lc
+                INNER JOIN layer_entries e ON e.layer_id = lc.layer_id
+                WHERE e.tenant_id = $1 AND e.inode_id = ANY($3)
+                ORDER BY e.inode_id, lc.depth
+            )
+            SELECT m.inode_id, m.line_number, b.content
+            FROM text_layers t
+            INNER JOIN text_line_map m
+                ON m.tenant_id = $1 AND m.inode_id = t.inode_id AND m.layer_id = t.layer_id
+            INNER JOIN text_blocks b ON b.block_id = m.block_id
+            WHERE $4::text IS NULL
+               OR (NOT $5 AND strpos(b.content, $4) > 0)
+               OR ($5 AND strpos(lower(b.content), lower($4)) > 0)
+            ORDER BY m.inode_id, m.line_number
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_id)
+        .bind(inode_ids)
+        .bind(needle)
+        .bind(ignore_case)
+        .fetch_all(&mut **tx)
+        .await?;
+
+        Ok(lines)
+    }
+
+    /// Content of the text files among `inode_ids` as `layer_id` sees them,
+    /// as `(inode_id, content)`, each read from the nearest layer along the
+    /// chain that changed it. Files stored as binary there, or empty, are
+    /// left out.
+    pub async fn visible_texts_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        layer_id: LayerId,
+        inode_ids: &[InodeId],
+    ) -> Result<Vec<(InodeId, String)>> {
+        let texts = sqlx::query_as::<_, (InodeId, String)>(
+            r#"
+            WITH RECURSIVE layer_chain AS (
+                SELECT layer_id, parent_layer_id, 0 AS depth
+                FROM layers
+                WHERE layer_id = $2 AND tenant_id = $1
+
+                UNION ALL
+
+                SELECT l.layer_id, l.parent_layer_id, lc.depth + 1
+                FROM layers l
+                INNER JOIN layer_chain lc ON l.layer_id = lc.parent_layer_id
+                WHERE l.tenant_id = $1
+            ),
+            text_layers AS (
+                SELECT DISTINCT ON (e.inode_id) e.inode_id, lc.layer_id
+                FROM layer_chain lc
+                INNER JOIN layer_entries e ON e.layer_id = lc.layer_id
+                WHERE e.tenant_id = $1 AND e.inode_id = ANY($3)
+                ORDER BY e.inode_id, lc.depth
+            )
+            SELECT md.inode_id,
+                   string_agg(b.content, E'\n' ORDER BY m.line_number)
+                       || CASE WHEN md.has_trailing_newline THEN E'\n' ELSE '' END
+            FROM text_layers t
+            INNER JOIN text_file_metadata md
+                ON md.tenant_id = $1 AND md.inode_id = t.inode_id AND md.layer_id = t.layer_id
+            INNER JOIN text_line_map m
+                ON m.tenant_id = $1 AND m.inode_id = t.inode_id AND m.layer_id = t.layer_id
+            INNER JOIN text_blocks b ON b.block_id = m.block_id
+            GROUP BY md.inode_id, md.has_trailing_newline
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(layer_id)
+        .bind(inode_ids)
+        .fetch_all(&mut **tx)
+        .await?;
+
+        Ok(texts)
+    }
+
+    /// Create (or reuse) a text block as part of a caller-managed transaction.
+    pub async fn create_block_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        input: CreateTextBlockInput,
+    ) -> Result<TextBlock> {
         let content_hash = Self::compute_content_hash(&input.content);
 
         // Try to find existing block with same hash (deduplication)
-        if let Some(existing) = self.get_block_by_hash(&content_hash).await? {
+        if let Some(existing) = self.get_block_by_hash_in_tx(tx, &content_hash).await? {
             // Block already exists, just return it (ref_count will be incremented separately)
             tracing::debug!(
                 block_id = %existing.block_id,
@@ -62,7 +201,7 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
         .bind(line_count)
         .bind(byte_size)
         .bind(&input.encoding)
-        .fetch_one(self.pool)
+        .fetch_one(&mut **tx)
         .await?;
 
         tracing::debug!(
@@ -75,23 +214,12 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
         Ok(block)
     }
 
-    async fn get_block(&self, block_id: BlockId) -> Result<Option<TextBlock>> {
-        let block = sqlx::query_as::<_, TextBlock>(
-            r#"
-            SELECT block_id, content_hash, content, line_count, byte_size, encoding,
-                   ref_count, created_at, last_accessed_at
-            FROM text_blocks
-            WHERE block_id = $1
-            "#,
-        )
-        .bind(block_id)
-        .fetch_optional(self.pool)
-        .await?;
-
-        Ok(block)
-    }
-
-    async fn get_block_by_hash(&self, content_hash: &str) -> Result<Option<TextBlock>> {
+    /// Look up a text block by hash within a transaction, seeing its uncommitted blocks.
+    pub async fn get_block_by_hash_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        content_hash: &str,
+    ) -> Result<Option<TextBlock>> {
         let block = sqlx::query_as::<_, TextBlock>(
             r#"
             SELECT block_id, content_hash, content, line_count, byte_size, encoding,
@@ -101,13 +229,18 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
             "#,
         )
         .bind(content_hash)
-        .fetch_optional(self.pool)
+        .fetch_optional(&mut **tx)
         .await?;
 
         Ok(block)
     }
 
-    async fn increment_ref_count(&self, block_id: BlockId) -> Result<()> {
+    /// Increment a text block's ref count as part of a caller-managed transaction.
+    pub async fn increment_ref_count_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        block_id: BlockId,
+    ) -> Result<()> {
         sqlx::query(
             r#"
             UPDATE text_blocks
@@ -116,7 +249,7 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
             "#,
         )
         .bind(block_id)
-        .execute(self.pool)
+        .execute(&mut **tx)
         .await?;
 
         tracing::trace!(
@@ -127,30 +260,12 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
         Ok(())
     }
 
-    async fn decrement_ref_count(&self, block_id: BlockId) -> Result<i32> {
-        let new_count = sqlx::query_as::<_, (i32,)>(
-            r#"
-            UPDATE text_blocks
-            SET ref_count = GREATEST(ref_count - 1, 0)
-            WHERE block_id = $1
-            RETURNING ref_count
-            "#,
-        )
-        .bind(block_id)
-        .fetch_one(self.pool)
-        .await?
-        .0;
-
-        tracing::trace!(
-            block_id = %block_id,
-            new_count = new_count,
-            "Decremented text block ref_count"
-        );
-
-        Ok(new_count)
-    }
-
-    async fn create_metadata(&self, input: CreateTextMetadataInput) -> Result<TextFileMetadata> {
+    /// Create text file metadata as part of a caller-managed transaction.
+    pub async fn create_metadata_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        input: CreateTextMetadataInput,
+    ) -> Result<TextFileMetadata> {
         let metadata = sqlx::query_as::<_, TextFileMetadata>(
             r#"
             INSERT INTO text_file_metadata (
@@ -169,7 +284,7 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
         .bind(&input.encoding)
         .bind(&input.line_ending)
         .bind(input.has_trailing_newline)
-        .fetch_one(self.pool)
+        .fetch_one(&mut **tx)
         .await?;
 
         tracing::debug!(
@@ -183,31 +298,10 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
         Ok(metadata)
     }
 
-    async fn get_metadata(
-        &self,
-        tenant_id: TenantId,
-        inode_id: InodeId,
-        layer_id: LayerId,
-    ) -> Result<Option<TextFileMetadata>> {
-        let metadata = sqlx::query_as::<_, TextFileMetadata>(
-            r#"
-            SELECT tenant_id, inode_id, layer_id, total_lines, encoding,
-                   line_ending, has_trailing_newline, created_at
-            FROM text_file_metadata
-            WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3
-            "#,
-        )
-        .bind(tenant_id)
-        .bind(inode_id)
-        .bind(layer_id)
-        .fetch_optional(self.pool)
-        .await?;
-
-        Ok(metadata)
-    }
-
-    async fn create_line_mappings(
+    /// Create line mappings as part of a caller-managed transaction.
+    pub async fn create_line_mappings_in_tx(
         &self,
+        tx: &mut DatabaseTransaction<'_>,
         tenant_id: TenantId,
         inode_id: InodeId,
         layer_id: LayerId,
@@ -217,7 +311,6 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
             return Ok(0);
         }
 
-        let mut tx = self.pool.begin().await?;
         let mut inserted = 0u64;
 
         for (line_number, block_id, block_line_offset) in mappings {
@@ -235,14 +328,12 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
             .bind(line_number)
             .bind(block_id)
             .bind(block_line_offset)
-            .execute(&mut *tx)
+            .execute(&mut **tx)
             .await?;
 
             inserted += result.rows_affected();
         }
 
-        tx.commit().await?;
-
         tracing::debug!(
             tenant_id = %tenant_id,
             inode_id = inode_id,
@@ -254,8 +345,140 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
         Ok(inserted)
     }
 
-    async fn get_line_mappings(
+    /// Give `dst_inode_id` in `dst_layer_id` the same text content as the
+    /// source, referencing the source's blocks instead of copying them.
+    ///
+    /// Returns false if the source has no text content in `src_layer_id`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn copy_file_in_tx(
         &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        src_inode_id: InodeId,
+        src_layer_id: LayerId,
+        dst_inode_id: InodeId,
+        dst_layer_id: LayerId,
+    ) -> Result<bool> {
+        let copied = sqlx::query(
+            r#"
+            INSERT INTO text_file_metadata (
+                tenant_id, inode_id, layer_id, total_lines, encoding, line_ending,
+                has_trailing_newline
+            )
+            SELECT tenant_id, $4, $5, total_lines, encoding, line_ending, has_trailing_newline
+            FROM text_file_metadata
+            WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(src_inode_id)
+        .bind(src_layer_id)
+        .bind(dst_inode_id)
+        .bind(dst_layer_id)
+        .execute(&mut **tx)
+        .await?
+        .rows_affected();
+        if copied == 0 {
+            return Ok(false);
+        }
+
+        // The refcount trigger on text_line_map counts the new references
+        sqlx::query(
+            r#"
+            INSERT INTO text_line_map (
+                tenant_id, inode_id, layer_id, line_number, block_id, block_line_offset
+            )
+            SELECT tenant_id, $4, $5, line_number, block_id, block_line_offset
+            FROM text_line_map
+            WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(src_inode_id)
+        .bind(src_layer_id)
+        .bind(dst_inode_id)
+        .bind(dst_layer_id)
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(true)
+    }
+
+    /// Get a text block within a caller-managed transaction.
+    pub async fn get_block_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        block_id: BlockId,
+    ) -> Result<Option<TextBlock>> {
+        Self::get_block_with(&mut **tx, block_id).await
+    }
+
+    async fn get_block_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        block_id: BlockId,
+    ) -> Result<Option<TextBlock>> {
+        let block = sqlx::query_as::<_, TextBlock>(
+            r#"
+            SELECT block_id, content_hash, content, line_count, byte_size, encoding,
+                   ref_count, created_at, last_accessed_at
+            FROM text_blocks
+            WHERE block_id = $1
+            "#,
+        )
+        .bind(block_id)
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(block)
+    }
+
+    /// Get text file metadata within a caller-managed transaction.
+    pub async fn get_metadata_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Option<TextFileMetadata>> {
+        Self::get_metadata_with(&mut **tx, tenant_id, inode_id, layer_id).await
+    }
+
+    async fn get_metadata_with<'e, E: PgExecutor<'e>>(
+        executor: E,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Option<TextFileMetadata>> {
+        let metadata = sqlx::query_as::<_, TextFileMetadata>(
+            r#"
+            SELECT tenant_id, inode_id, layer_id, total_lines, encoding,
+                   line_ending, has_trailing_newline, created_at
+            FROM text_file_metadata
+            WHERE tenant_id = $1 AND inode_id = $2 AND layer_id = $3
+            "#,
+        )
+        .bind(tenant_id)
+        .bind(inode_id)
+        .bind(layer_id)
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(metadata)
+    }
+
+    /// Get line mappings within a caller-managed transaction.
+    pub async fn get_line_mappings_in_tx(
+        &self,
+        tx: &mut DatabaseTransaction<'_>,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Vec<TextLineMap>> {
+        Self::get_line_mappings_with(&mut **tx, tenant_id, inode_id, layer_id).await
+    }
+
+    async fn get_line_mappings_with<'e, E: PgExecutor<'e>>(
+        executor: E,
         tenant_id: TenantId,
         inode_id: InodeId,
         layer_id: LayerId,
@@ -271,13 +494,104 @@ impl<'a> TextBlockRepository for TextBlockOperations<'a> {
         .bind(tenant_id)
         .bind(inode_id)
         .bind(layer_id)
-        .fetch_all(self.pool)
+        .fetch_all(executor)
         .await?;
 
         Ok(mappings)
     }
 }
 
+#[async_trait]
+impl<'a> TextBlockRepository for TextBlockOperations<'a> {
+    async fn create_block(&self, input: CreateTextBlockInput) -> Result<TextBlock> {
+        let mut tx = self.pool.begin().await?;
+        let result = self.create_block_in_tx(&mut tx, input).await?;
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    async fn get_block(&self, block_id: BlockId) -> Result<Option<TextBlock>> {
+        Self::get_block_with(self.pool, block_id).await
+    }
+
+    async fn get_block_by_hash(&self, content_hash: &str) -> Result<Option<TextBlock>> {
+        let mut tx = self.pool.begin().await?;
+        let result = self.get_block_by_hash_in_tx(&mut tx, content_hash).await?;
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    async fn increment_ref_count(&self, block_id: BlockId) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let result = self.increment_ref_count_in_tx(&mut tx, block_id).await?;
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    async fn decrement_ref_count(&self, block_id: BlockId) -> Result<i32> {
+        let new_count = sqlx::query_as::<_, (i32,)>(
+            r#"
+            UPDATE text_blocks
+            SET ref_count = GREATEST(ref_count - 1, 0)
+            WHERE block_id = $1
+            RETURNING ref_count
+            "#,
+        )
+        .bind(block_id)
+        .fetch_one(self.pool)
+        .await?
+        .0;
+
+        tracing::trace!(
+            block_id = %block_id,
+            new_count = new_count,
+            "Decremented text block ref_count"
+        );
+
+        Ok(new_count)
+    }
+
+    async fn create_metadata(&self, input: CreateTextMetadataInput) -> Result<TextFileMetadata> {
+        let mut tx = self.pool.begin().await?;
+        let result = self.create_metadata_in_tx(&mut tx, input).await?;
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    async fn get_metadata(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Option<TextFileMetadata>> {
+        Self::get_metadata_with(self.pool, tenant_id, inode_id, layer_id).await
+    }
+
+    async fn create_line_mappings(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        mappings: Vec<(i32, BlockId, i32)>,
+    ) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let result = self
+            .create_line_mappings_in_tx(&mut tx, tenant_id, inode_id, layer_id, mappings)
+            .await?;
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    async fn get_line_mappings(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+    ) -> Result<Vec<TextLineMap>> {
+        Self::get_line_mappings_with(self.pool, tenant_id, inode_id, layer_id).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -10,7 +10,7 @@ use super::models::{
     AuditLog, AuditStats, CreateAuditLogInput, CreateBlockInput, CreateInodeInput,
     CreateLayerEntryInput, CreateLayerInput, CreateTenantInput, CreateTextBlockInput,
     CreateTextMetadataInput, DataBlock, Inode, Layer, LayerEntry, QueryAuditLogsInput, Tenant,
-    TextBlock, TextFileMetadata, TextLineMap, UpdateInodeInput,
+    TextBlock, TextFileMetadata, TextLineMap, UpdateInodeInput, UpdateTenantModesInput,
 };
 
 #[cfg_attr(any(test, feature = "mockall"), automock)]
@@ -21,6 +21,14 @@ pub trait TenantRepository: Send + Sync {
     async fn get_by_name(&self, tenant_name: &str) -> Result<Option<Tenant>>;
     async fn list(&self) -> Result<Vec<Tenant>>;
     async fn delete(&self, tenant_id: TenantId) -> Result<bool>;
+    /// Change how new entries' modes are chosen; `None` if there is no such tenant.
+    async fn update_modes(
+        &self,
+        tenant_id: TenantId,
+        input: UpdateTenantModesInput,
+    ) -> Result<Option<Tenant>>;
+    /// Give the tenant a new name; `None` if there is no such tenant.
+    async fn rename(&self, tenant_id: TenantId, new_name: &str) -> Result<Option<Tenant>>;
 }
 
 #[cfg_attr(any(test, feature = "mockall"), automock)]
@@ -56,6 +64,16 @@ pub trait BlockRepository: Send + Sync {
     ) -> Result<Option<DataBlock>>;
     async fn list(&self, tenant_id: TenantId, inode_id: InodeId) -> Result<Vec<DataBlock>>;
     async fn delete(&self, tenant_id: TenantId, inode_id: InodeId) -> Result<u64>;
+    /// Blocks `first..first + count` of an inode as seen from `layer_id`;
+    /// holes are left out.
+    async fn list_visible_range(
+        &self,
+        tenant_id: TenantId,
+        inode_id: InodeId,
+        layer_id: LayerId,
+        first: i32,
+        count: i32,
+    ) -> Result<Vec<DataBlock>>;
 }
 
 #[cfg_attr(any(test, feature = "mockall"), automock)]
@@ -147,6 +165,14 @@ pub trait TextBlockRepository: Send + Sync {
     ) -> Result<Vec<TextLineMap>>;
 }
 
+/// Database-wide usage, for reporting how much room the backing store has left.
+#[cfg_attr(any(test, feature = "mockall"), automock)]
+#[async_trait]
+pub trait UsageRepository: Send + Sync {
+    /// Bytes the database occupies on disk, across all tenants.
+    async fn database_size(&self) -> Result<i64>;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -211,8 +237,13 @@ mod tests {
     #[test]
     fn test_create_block_input_construction() {
         let tenant_id = Uuid::new_v4();
-        let input =
-            CreateBlockInput { tenant_id, inode_id: 123, block_index: 0, data: vec![1, 2, 3, 4] };
+        let input = CreateBlockInput {
+            tenant_id,
+            inode_id: 123,
+            block_index: 0,
+            data: vec![1, 2, 3, 4],
+            layer_id: None,
+        };
         assert_eq!(input.block_index, 0);
         assert_eq!(input.data.len(), 4);
         assert_eq!(input.inode_id, 123);
@@ -226,6 +257,9 @@ mod tests {
             tenant_id,
             tenant_name: "test".to_string(),
             root_inode_id: 1,
+            default_file_mode: 0o644,
+            default_dir_mode: 0o755,
+            umask: 0o022,
             created_at: now,
             updated_at: now,
         };
@@ -270,6 +304,7 @@ mod tests {
             data,
             content_hash: "hash123".to_string(),
             created_at: now,
+            layer_id: None,
         };
         assert_eq!(block.inode_id, 100);
         assert_eq!(block.data.len(), 4096);
@@ -283,7 +318,8 @@ mod tests {
 
 use super::models::mount_entry::{CreateMountEntry, MountEntry, UpdateMountEntry};
 use super::models::published_mount::{
-    PublishMountInput, PublishedMount, PublishedMountFilter, ResolvedPublished, UpdatePublishInput,
+    PublishLayerInput, PublishMountInput, PublishedMount, PublishedMountFilter, ResolvedPublished,
+    UpdatePublishInput,
 };
 use std::path::Path;
 use uuid::Uuid;
@@ -355,6 +391,12 @@ pub trait PublishedMountRepository: Send + Sync {
     /// Publish a mount
     async fn publish_mount(&self, input: PublishMountInput) -> Result<PublishedMount>;
 
+    /// Publish one of a tenant's layers directly, marking it read-only
+    async fn publish_layer(&self, input: PublishLayerInput) -> Result<PublishedMount>;
+
+    /// Remove a tenant's publish by name
+    async fn unpublish_by_name(&self, tenant_id: Uuid, publish_name: &str) -> Result<bool>;
+
     /// Unpublish a mount
     async fn unpublish_mount(&self, mount_entry_id: Uuid) -> Result<bool>;
 
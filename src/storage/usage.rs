@@ -0,0 +1,189 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use sqlx::PgPool;
+
+use crate::types::TenantId;
+
+use super::models::{LayerUsage, StorageUsage, TenantUsage};
+use super::traits::UsageRepository;
+
+/// Storage accounting over the block, chunk, text and layer tables.
+///
+/// Unlike the other operations this spans tenants, for operators; pass a
+/// tenant list to restrict it.
+pub struct UsageOperations<'a> {
+    pool: &'a PgPool,
+}
+
+impl<'a> UsageOperations<'a> {
+    pub fn new(pool: &'a PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Usage of each tenant, or of the given tenants, ordered by name.
+    pub async fn list_tenant_usage(
+        &self,
+        tenant_ids: Option<&[TenantId]>,
+    ) -> Result<Vec<TenantUsage>> {
+        let usage = sqlx::query_as::<_, TenantUsage>(
+            r#"
+            WITH t AS (
+                SELECT tenant_id, tenant_name FROM tenants
+                WHERE $1::uuid[] IS NULL OR tenant_id = ANY($1)
+            ),
+            layer_counts AS (
+                SELECT tenant_id, COUNT(*) AS n FROM layers
+                WHERE tenant_id IN (SELECT tenant_id FROM t) GROUP BY tenant_id
+            ),
+            file_counts AS (
+                SELECT tenant_id, COUNT(*) AS n FROM inodes
+                WHERE tenant_id IN (SELECT tenant_id FROM t) AND inode_type = 'file'
+                GROUP BY tenant_id
+            ),
+            blocks AS (
+                SELECT tenant_id, SUM(size) AS bytes FROM data_blocks
+                WHERE tenant_id IN (SELECT tenant_id FROM t) GROUP BY tenant_id
+            ),
+            chunk_refs AS (
+                SELECT tenant_id, SUM(size) AS bytes FROM file_chunks
+                WHERE tenant_id IN (SELECT tenant_id FROM t) GROUP BY tenant_id
+            ),
+            chunk_store AS (
+                SELECT tenant_id, SUM(size) AS bytes FROM content_chunks
+                WHERE tenant_id IN (SELECT tenant_id FROM t) GROUP BY tenant_id
+            ),
+            text_refs AS (
+                SELECT m.tenant_id, SUM(b.byte_size) AS bytes
+                FROM (
+                    SELECT DISTINCT tenant_id, inode_id, layer_id, block_id FROM text_line_map
+                    WHERE tenant_id IN (SELECT tenant_id FROM t)
+                ) m
+                INNER JOIN text_blocks b ON b.block_id = m.block_id
+                GROUP BY m.tenant_id
+            ),
+            text_store AS (
+                SELECT m.tenant_id, SUM(b.byte_size) AS bytes
+                FROM (
+                    SELECT DISTINCT tenant_id, block_id FROM text_line_map
+                    WHERE tenant_id IN (SELECT tenant_id FROM t)
+                ) m
+                INNER JOIN text_blocks b ON b.block_id = m.block_id
+                GROUP BY m.tenant_id
+            )
+            SELECT
+                t.tenant_id,
+                t.tenant_name,
+                COALESCE(lc.n, 0) AS layers,
+                COALESCE(fc.n, 0) AS files,
+                (COALESCE(bl.bytes, 0) + COALESCE(cr.bytes, 0) + COALESCE(tr.bytes, 0))::BIGINT
+                    AS logical_bytes,
+                (COALESCE(bl.bytes, 0) + COALESCE(cs.bytes, 0) + COALESCE(ts.bytes, 0))::BIGINT
+                    AS physical_bytes
+            FROM t
+            LEFT JOIN layer_counts lc ON lc.tenant_id = t.tenant_id
+            LEFT JOIN file_counts fc ON fc.tenant_id = t.tenant_id
+            LEFT JOIN blocks bl ON bl.tenant_id = t.tenant_id
+            LEFT JOIN chunk_refs cr ON cr.tenant_id = t.tenant_id
+            LEFT JOIN chunk_store cs ON cs.tenant_id = t.tenant_id
+            LEFT JOIN text_refs tr ON tr.tenant_id = t.tenant_id
+            LEFT JOIN text_store ts ON ts.tenant_id = t.tenant_id
+            ORDER BY t.tenant_name
+            "#,
+        )
+        .bind(tenant_ids)
+        .fetch_all(self.pool)
+        .await?;
+
+        Ok(usage)
+    }
+
+    /// What each of a tenant's layers stores itself, oldest layer first.
+    pub async fn list_layer_usage(&self, tenant_id: TenantId) -> Result<Vec<LayerUsage>> {
+        let usage = sqlx::query_as::<_, LayerUsage>(
+            r#"
+            WITH files AS (
+                SELECT layer_id, COUNT(*) AS n FROM layer_entries
+                WHERE tenant_id = $1 AND change_type <> 'delete' GROUP BY layer_id
+            ),
+            blocks AS (
+                SELECT layer_id, SUM(size) AS bytes FROM data_blocks
+                WHERE tenant_id = $1 AND layer_id IS NOT NULL GROUP BY layer_id
+            ),
+            chunks AS (
+                SELECT layer_id, SUM(size) AS bytes FROM file_chunks
+                WHERE tenant_id = $1 GROUP BY layer_id
+            ),
+            text AS (
+                SELECT m.layer_id, SUM(b.byte_size) AS bytes
+                FROM (
+                    SELECT DISTINCT inode_id, layer_id, block_id FROM text_line_map
+                    WHERE tenant_id = $1
+                ) m
+                INNER JOIN text_blocks b ON b.block_id = m.block_id
+                GROUP BY m.layer_id
+            )
+            SELECT
+                l.layer_id,
+                l.layer_name,
+                COALESCE(f.n, 0) AS own_files,
+                (COALESCE(bl.bytes, 0) + COALESCE(c.bytes, 0) + COALESCE(t.bytes, 0))::BIGINT
+                    AS own_bytes
+            FROM layers l
+            LEFT JOIN files f ON f.layer_id = l.layer_id
+            LEFT JOIN blocks bl ON bl.layer_id = l.layer_id
+            LEFT JOIN chunks c ON c.layer_id = l.layer_id
+            LEFT JOIN text t ON t.layer_id = l.layer_id
+            WHERE l.tenant_id = $1
+            ORDER BY l.created_at, l.layer_name
+            "#,
+        )
+        .bind(tenant_id)
+        .fetch_all(self.pool)
+        .await?;
+
+        Ok(usage)
+    }
+
+    /// Totals over all tenants, or over the given tenants.
+    pub async fn summary(&self, tenant_ids: Option<&[TenantId]>) -> Result<StorageUsage> {
+        let tenants = self.list_tenant_usage(tenant_ids).await?;
+
+        // Text blocks are global; a block used by several tenants is stored once
+        let (shared_text,) = sqlx::query_as::<_, (i64,)>(
+            r#"
+            WITH per_tenant AS (
+                SELECT DISTINCT tenant_id, block_id FROM text_line_map
+                WHERE $1::uuid[] IS NULL OR tenant_id = ANY($1)
+            )
+            SELECT COALESCE(SUM(b.byte_size * (u.tenants - 1)), 0)::BIGINT
+            FROM (SELECT block_id, COUNT(*) AS tenants FROM per_tenant GROUP BY block_id) u
+            INNER JOIN text_blocks b ON b.block_id = u.block_id
+            "#,
+        )
+        .bind(tenant_ids)
+        .fetch_one(self.pool)
+        .await?;
+
+        let mut summary = tenants.iter().fold(StorageUsage::default(), |mut acc, t| {
+            acc.tenants += 1;
+            acc.layers += t.layers;
+            acc.files += t.files;
+            acc.logical_bytes += t.logical_bytes;
+            acc.physical_bytes += t.physical_bytes;
+            acc
+        });
+        summary.physical_bytes -= shared_text;
+        summary.dedup_savings_bytes = summary.logical_bytes - summary.physical_bytes;
+        Ok(summary)
+    }
+}
+
+#[async_trait]
+impl<'a> UsageRepository for UsageOperations<'a> {
+    async fn database_size(&self) -> Result<i64> {
+        let size: i64 = sqlx::query_scalar("SELECT pg_database_size(current_database())")
+            .fetch_one(self.pool)
+            .await?;
+        Ok(size)
+    }
+}
@@ -0,0 +1,497 @@
+//! Consistency test harness.
+//!
+//! Runs a seeded, randomized sequence of filesystem operations in a scratch
+//! tenant and verifies storage invariants afterwards. Used by
+//! `tarbox selftest` to validate a deployment and as a regression tool.
+
+use anyhow::Result;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use tracing::{debug, info};
+
+use crate::fs::FileSystem;
+use crate::storage::{CreateTenantInput, InodeType, TenantOperations, TenantRepository};
+use crate::types::{InodeId, TenantId};
+
+/// Directory in the scratch tenant the operations are confined to
+const WORK_DIR: &str = "/selftest";
+
+/// Configuration for a consistency run.
+#[derive(Debug, Clone, Copy)]
+pub struct ConsistencyConfig {
+    /// Seed for the operation generator; the same seed replays the same sequence.
+    pub seed: u64,
+    /// Number of random operations to perform.
+    pub operations: usize,
+}
+
+impl Default for ConsistencyConfig {
+    fn default() -> Self {
+        Self { seed: 0x7a2b_0c5e, operations: 200 }
+    }
+}
+
+/// An invariant violation found during verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// Inode size does not match the length of its stored content.
+    SizeMismatch { path: String, inode_size: i64, stored_size: usize },
+    /// File content differs from the reference model.
+    ContentMismatch { path: String },
+    /// A path in the reference model is missing from the filesystem.
+    MissingPath { path: String },
+    /// The filesystem contains a path the reference model does not.
+    UnexpectedPath { path: String },
+    /// A path has a different type than the reference model expects.
+    TypeMismatch { path: String },
+    /// Data blocks or text metadata attached to an inode that is not a regular file.
+    OrphanedBlocks { inode_id: InodeId, count: i64 },
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::SizeMismatch { path, inode_size, stored_size } => write!(
+                f,
+                "size mismatch at {}: inode says {} bytes, stored {} bytes",
+                path, inode_size, stored_size
+            ),
+            Violation::ContentMismatch { path } => write!(f, "content mismatch at {}", path),
+            Violation::MissingPath { path } => write!(f, "missing path {}", path),
+            Violation::UnexpectedPath { path } => write!(f, "unexpected path {}", path),
+            Violation::TypeMismatch { path } => write!(f, "type mismatch at {}", path),
+            Violation::OrphanedBlocks { inode_id, count } => {
+                write!(f, "{} orphaned block(s) on non-file inode {}", count, inode_id)
+            }
+        }
+    }
+}
+
+/// Result of a consistency run.
+#[derive(Debug, Clone)]
+pub struct ConsistencyReport {
+    pub seed: u64,
+    pub operations_run: usize,
+    /// Name of the scratch tenant the operations were performed in.
+    pub scratch_tenant: String,
+    pub violations: Vec<Violation>,
+}
+
+impl ConsistencyReport {
+    /// Returns true if no invariant was violated.
+    pub fn is_consistent(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Run the consistency suite with the default configuration.
+pub async fn run_consistency_suite(
+    pool: &PgPool,
+    tenant_id: TenantId,
+) -> Result<ConsistencyReport> {
+    run_consistency_suite_with_config(pool, tenant_id, ConsistencyConfig::default()).await
+}
+
+/// Run the consistency suite.
+///
+/// Operations run in a scratch tenant of their own, so the tenant being
+/// checked gets no new files or layer entries. The scratch tenant is
+/// deleted afterwards unless the run found violations, in which case it is
+/// kept for inspection. Invariants are checked in both tenants, so
+/// pre-existing damage in `tenant_id` is reported too.
+pub async fn run_consistency_suite_with_config(
+    pool: &PgPool,
+    tenant_id: TenantId,
+    config: ConsistencyConfig,
+) -> Result<ConsistencyReport> {
+    let tenant_ops = TenantOperations::new(pool);
+    let scratch_tenant = format!("tarbox-selftest-{}", uuid::Uuid::new_v4().simple());
+    let scratch =
+        tenant_ops.create(CreateTenantInput { tenant_name: scratch_tenant.clone() }).await?;
+
+    info!(seed = config.seed, operations = config.operations, scratch_tenant = %scratch_tenant, "Running consistency suite");
+
+    let mut violations = match run_operations(pool, scratch.tenant_id, config).await {
+        Ok(violations) => violations,
+        Err(e) => {
+            tenant_ops.delete(scratch.tenant_id).await?;
+            return Err(e);
+        }
+    };
+    violations.extend(check_invariants(pool, tenant_id).await?);
+
+    if violations.is_empty() {
+        tenant_ops.delete(scratch.tenant_id).await?;
+    }
+
+    Ok(ConsistencyReport {
+        seed: config.seed,
+        operations_run: config.operations,
+        scratch_tenant,
+        violations,
+    })
+}
+
+/// Apply the generated operations in `tenant_id` and compare the result
+/// with the reference model and the tenant's invariants.
+async fn run_operations(
+    pool: &PgPool,
+    tenant_id: TenantId,
+    config: ConsistencyConfig,
+) -> Result<Vec<Violation>> {
+    let fs = FileSystem::new(pool, tenant_id).await?;
+    fs.create_directory(WORK_DIR).await?;
+
+    let mut rng = SplitMix64::new(config.seed);
+    let mut model = Model::default();
+
+    for step in 0..config.operations {
+        let op = model.next_operation(&mut rng, step);
+        debug!(step = step, op = ?op, "Consistency op");
+        apply(&fs, WORK_DIR, &op).await?;
+        model.apply(&op);
+    }
+
+    let mut violations = verify_model(&fs, WORK_DIR, &model).await?;
+    violations.extend(check_invariants(pool, tenant_id).await?);
+    Ok(violations)
+}
+
+/// Check model-independent invariants across the whole tenant.
+///
+/// Every regular file's inode size must match its stored content, and no
+/// non-file inode may own data blocks or text metadata.
+pub async fn check_invariants(pool: &PgPool, tenant_id: TenantId) -> Result<Vec<Violation>> {
+    let fs = FileSystem::new(pool, tenant_id).await?;
+    let mut violations = Vec::new();
+
+    let mut pending = vec!["/".to_string()];
+    while let Some(dir) = pending.pop() {
+        for child in fs.list_directory(&dir).await? {
+            let path = join(&dir, &child.name);
+            match child.inode_type {
+                InodeType::Dir => pending.push(path),
+                InodeType::File => {
+                    let data = fs.read_file(&path).await?;
+                    if data.len() as i64 != child.size {
+                        violations.push(Violation::SizeMismatch {
+                            path,
+                            inode_size: child.size,
+                            stored_size: data.len(),
+                        });
+                    }
+                }
+                InodeType::Symlink => {}
+            }
+        }
+    }
+
+    let orphans: Vec<(InodeId, i64)> = sqlx::query_as(
+        r#"
+        SELECT i.inode_id, COUNT(*)
+        FROM inodes i
+        JOIN (
+            SELECT tenant_id, inode_id FROM data_blocks WHERE tenant_id = $1
+            UNION ALL
+            SELECT tenant_id, inode_id FROM text_file_metadata WHERE tenant_id = $1
+        ) b ON b.tenant_id = i.tenant_id AND b.inode_id = i.inode_id
+        WHERE i.tenant_id = $1 AND i.inode_type <> 'file'
+        GROUP BY i.inode_id
+        ORDER BY i.inode_id
+        "#,
+    )
+    .bind(tenant_id)
+    .fetch_all(pool)
+    .await?;
+
+    violations.extend(
+        orphans.into_iter().map(|(inode_id, count)| Violation::OrphanedBlocks { inode_id, count }),
+    );
+
+    Ok(violations)
+}
+
+/// A single generated filesystem operation, relative to the work directory.
+#[derive(Debug, Clone)]
+enum Op {
+    CreateDir(String),
+    RemoveDir(String),
+    CreateFile(String, FileKind),
+    Write(String, Vec<u8>),
+    Delete(String),
+}
+
+/// Content kind of a generated file.
+///
+/// A file keeps the kind it was created with so the harness exercises text
+/// and binary storage without switching modes within one layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileKind {
+    Text,
+    Binary,
+}
+
+/// Reference model of the work directory.
+#[derive(Debug, Default)]
+struct Model {
+    dirs: BTreeMap<String, ()>,
+    files: BTreeMap<String, (FileKind, Vec<u8>)>,
+    next_name: usize,
+}
+
+impl Model {
+    fn next_operation(&mut self, rng: &mut SplitMix64, step: usize) -> Op {
+        loop {
+            match rng.below(10) {
+                0 | 1 => {
+                    let parent = self.pick_dir(rng);
+                    return Op::CreateDir(self.fresh_path(&parent, "d"));
+                }
+                2 => {
+                    let empty: Vec<&String> =
+                        self.dirs.keys().filter(|d| self.is_empty_dir(d)).collect();
+                    if !empty.is_empty() {
+                        let dir = empty[rng.below(empty.len())].clone();
+                        return Op::RemoveDir(dir);
+                    }
+                }
+                3 | 4 => {
+                    let parent = self.pick_dir(rng);
+                    let kind = if rng.below(2) == 0 { FileKind::Text } else { FileKind::Binary };
+                    return Op::CreateFile(self.fresh_path(&parent, "f"), kind);
+                }
+                5..=8 => {
+                    if !self.files.is_empty() {
+                        let idx = rng.below(self.files.len());
+                        let (path, (kind, _)) = self.files.iter().nth(idx).unwrap();
+                        return Op::Write(path.clone(), generate_content(rng, *kind, step));
+                    }
+                }
+                _ => {
+                    if !self.files.is_empty() {
+                        let idx = rng.below(self.files.len());
+                        return Op::Delete(self.files.keys().nth(idx).unwrap().clone());
+                    }
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, op: &Op) {
+        match op {
+            Op::CreateDir(path) => {
+                self.dirs.insert(path.clone(), ());
+            }
+            Op::RemoveDir(path) => {
+                self.dirs.remove(path);
+            }
+            Op::CreateFile(path, kind) => {
+                self.files.insert(path.clone(), (*kind, Vec::new()));
+            }
+            Op::Write(path, data) => {
+                if let Some(entry) = self.files.get_mut(path) {
+                    entry.1 = data.clone();
+                }
+            }
+            Op::Delete(path) => {
+                self.files.remove(path);
+            }
+        }
+    }
+
+    /// Pick an existing directory, or "" for the work directory itself.
+    fn pick_dir(&self, rng: &mut SplitMix64) -> String {
+        let idx = rng.below(self.dirs.len() + 1);
+        self.dirs.keys().nth(idx).cloned().unwrap_or_default()
+    }
+
+    fn fresh_path(&mut self, parent: &str, prefix: &str) -> String {
+        self.next_name += 1;
+        format!("{}/{}{}", parent, prefix, self.next_name)
+    }
+
+    fn is_empty_dir(&self, dir: &str) -> bool {
+        let prefix = format!("{}/", dir);
+        !self.dirs.keys().chain(self.files.keys()).any(|p| p.starts_with(&prefix))
+    }
+}
+
+async fn apply(fs: &FileSystem<'_>, work_dir: &str, op: &Op) -> Result<()> {
+    match op {
+        Op::CreateDir(path) => {
+            fs.create_directory(&format!("{}{}", work_dir, path)).await?;
+        }
+        Op::RemoveDir(path) => fs.remove_directory(&format!("{}{}", work_dir, path)).await?,
+        Op::CreateFile(path, _) => {
+            fs.create_file(&format!("{}{}", work_dir, path)).await?;
+        }
+        Op::Write(path, data) => fs.write_file(&format!("{}{}", work_dir, path), data).await?,
+        Op::Delete(path) => fs.delete_file(&format!("{}{}", work_dir, path)).await?,
+    }
+    Ok(())
+}
+
+/// Compare the visible tree under `work_dir` with the reference model.
+async fn verify_model(
+    fs: &FileSystem<'_>,
+    work_dir: &str,
+    model: &Model,
+) -> Result<Vec<Violation>> {
+    let mut violations = Vec::new();
+    let mut seen = BTreeMap::new();
+
+    let mut pending = vec![String::new()];
+    while let Some(dir) = pending.pop() {
+        for child in fs.list_directory(&format!("{}{}", work_dir, dir)).await? {
+            let rel = format!("{}/{}", dir, child.name);
+            if child.inode_type == InodeType::Dir {
+                pending.push(rel.clone());
+            }
+            seen.insert(rel, child.inode_type);
+        }
+    }
+
+    for path in model.dirs.keys() {
+        match seen.remove(path) {
+            Some(InodeType::Dir) => {}
+            Some(_) => violations.push(Violation::TypeMismatch { path: path.clone() }),
+            None => violations.push(Violation::MissingPath { path: path.clone() }),
+        }
+    }
+
+    for (path, (_, expected)) in &model.files {
+        match seen.remove(path) {
+            Some(InodeType::File) => {
+                let data = fs.read_file(&format!("{}{}", work_dir, path)).await?;
+                if &data != expected {
+                    violations.push(Violation::ContentMismatch { path: path.clone() });
+                }
+            }
+            Some(_) => violations.push(Violation::TypeMismatch { path: path.clone() }),
+            None => violations.push(Violation::MissingPath { path: path.clone() }),
+        }
+    }
+
+    violations.extend(seen.into_keys().map(|path| Violation::UnexpectedPath { path }));
+
+    Ok(violations)
+}
+
+fn generate_content(rng: &mut SplitMix64, kind: FileKind, step: usize) -> Vec<u8> {
+    match kind {
+        FileKind::Text => {
+            let lines = rng.below(20);
+            let mut text = String::new();
+            for i in 0..lines {
+                text.push_str(&format!(
+                    "step {} line {} value {}\n",
+                    step,
+                    i,
+                    rng.next_u64() % 1000
+                ));
+            }
+            text.into_bytes()
+        }
+        FileKind::Binary => {
+            // Up to three blocks; the leading NUL keeps it detected as binary
+            let len = 1 + rng.below(3 * 4096);
+            let mut data = vec![0u8; len];
+            for byte in data.iter_mut().skip(1) {
+                *byte = rng.next_u64() as u8;
+            }
+            data
+        }
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir == "/" { format!("/{}", name) } else { format!("{}/{}", dir, name) }
+}
+
+/// Small deterministic PRNG (SplitMix64) so runs replay from a seed.
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform-ish value in `0..n` (n must be non-zero).
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splitmix_is_deterministic() {
+        let mut a = SplitMix64::new(42);
+        let mut b = SplitMix64::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn test_model_sequence_replays_from_seed() {
+        let run = |seed| {
+            let mut rng = SplitMix64::new(seed);
+            let mut model = Model::default();
+            let mut ops = Vec::new();
+            for step in 0..50 {
+                let op = model.next_operation(&mut rng, step);
+                model.apply(&op);
+                ops.push(format!("{:?}", op));
+            }
+            ops
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn test_model_only_removes_empty_dirs() {
+        let mut rng = SplitMix64::new(1);
+        let mut model = Model::default();
+        for step in 0..500 {
+            let op = model.next_operation(&mut rng, step);
+            if let Op::RemoveDir(ref dir) = op {
+                assert!(model.is_empty_dir(dir));
+            }
+            model.apply(&op);
+        }
+    }
+
+    #[test]
+    fn test_generated_binary_content_is_binary() {
+        let mut rng = SplitMix64::new(3);
+        let data = generate_content(&mut rng, FileKind::Binary, 0);
+        assert!(!data.is_empty());
+        assert_eq!(data[0], 0);
+    }
+
+    #[test]
+    fn test_violation_display() {
+        let v = Violation::SizeMismatch { path: "/a".to_string(), inode_size: 3, stored_size: 2 };
+        assert_eq!(v.to_string(), "size mismatch at /a: inode says 3 bytes, stored 2 bytes");
+    }
+
+    #[test]
+    fn test_join() {
+        assert_eq!(join("/", "a"), "/a");
+        assert_eq!(join("/a", "b"), "/a/b");
+    }
+}
@@ -33,6 +33,31 @@ impl<'a> WasiAdapter<'a> {
         Self { fs, tenant_id, fd_table: Arc::new(Mutex::new(FdTable::new())), config }
     }
 
+    /// Create an adapter with the configured preopens registered as the first
+    /// descriptors, in order from fd 3.
+    pub async fn with_preopens(
+        fs: Arc<FileSystem<'a>>,
+        tenant_id: Uuid,
+        config: WasiConfig,
+    ) -> Result<Self, WasiError> {
+        let adapter = Self::new(fs, tenant_id, config);
+        for (guest_path, tarbox_path) in &adapter.config.preopens {
+            let stat = adapter.fs.stat(tarbox_path).await.map_err(WasiError::from)?;
+            if !matches!(stat.inode_type, InodeType::Dir) {
+                return Err(WasiError::NotDirectory);
+            }
+            let mut descriptor = FileDescriptor::new(
+                stat.inode_id,
+                tarbox_path.clone(),
+                OpenFlags::read_only(),
+                true,
+            );
+            descriptor.preopen = Some(guest_path.clone());
+            adapter.fd_table.lock().unwrap().allocate(descriptor);
+        }
+        Ok(adapter)
+    }
+
     /// Get the tenant ID
     pub fn tenant_id(&self) -> Uuid {
         self.tenant_id
@@ -47,9 +72,13 @@ impl<'a> WasiAdapter<'a> {
     ///
     /// This is a WASI-style open operation that returns a numeric fd.
     pub async fn fd_open(&self, path: &str, flags: OpenFlags) -> Result<u32, WasiError> {
-        // Resolve the path to get inode info
-        let stat: FsResult<Inode> = self.fs.stat(path).await;
-        let stat = stat.map_err(WasiError::from)?;
+        // Resolve the path to get inode info, creating the file if asked to
+        let stat = match self.fs.stat(path).await.map_err(WasiError::from) {
+            Err(WasiError::NotFound) if flags.create => {
+                self.fs.create_file(path).await.map_err(WasiError::from)?
+            }
+            result => result?,
+        };
 
         // Check if it's a directory
         let is_directory = matches!(stat.inode_type, InodeType::Dir);
@@ -63,6 +92,64 @@ impl<'a> WasiAdapter<'a> {
         Ok(fd)
     }
 
+    /// Open `path` relative to the directory descriptor `dirfd`
+    ///
+    /// Paths are confined to the directory: absolute paths and `..` that
+    /// would climb above it are refused.
+    pub async fn path_open(
+        &self,
+        dirfd: u32,
+        path: &str,
+        flags: OpenFlags,
+    ) -> Result<u32, WasiError> {
+        let full_path = self.resolve_at(dirfd, path)?;
+        self.fd_open(&full_path, flags).await
+    }
+
+    /// Tarbox path of `path` relative to the directory descriptor `dirfd`
+    fn resolve_at(&self, dirfd: u32, path: &str) -> Result<String, WasiError> {
+        let base = {
+            let table = self.fd_table.lock().unwrap();
+            let descriptor = table.get(dirfd)?;
+            if !descriptor.is_directory {
+                return Err(WasiError::NotDirectory);
+            }
+            descriptor.path.clone()
+        };
+
+        if path.starts_with('/') {
+            return Err(WasiError::PermissionDenied);
+        }
+        let mut components: Vec<&str> = Vec::new();
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop().ok_or(WasiError::PermissionDenied)?;
+                }
+                name => components.push(name),
+            }
+        }
+
+        let base = base.trim_end_matches('/');
+        Ok(match (components.is_empty(), base.is_empty()) {
+            (true, true) => "/".to_string(),
+            (true, false) => base.to_string(),
+            (false, _) => format!("{}/{}", base, components.join("/")),
+        })
+    }
+
+    /// Guest path of a preopened directory descriptor
+    pub fn fd_prestat_dir_name(&self, fd: u32) -> Result<String, WasiError> {
+        let table = self.fd_table.lock().unwrap();
+        table.get(fd)?.preopen.clone().ok_or(WasiError::BadFd)
+    }
+
+    /// Tarbox inode a descriptor refers to
+    pub fn fd_inode(&self, fd: u32) -> Result<i64, WasiError> {
+        Ok(self.fd_table.lock().unwrap().get(fd)?.inode_id)
+    }
+
     /// Read from a file descriptor
     pub async fn fd_read(&self, fd: u32, buf: &mut [u8]) -> Result<usize, WasiError> {
         // Get file descriptor
@@ -166,6 +253,20 @@ impl<'a> WasiAdapter<'a> {
         })
     }
 
+    /// Stat `path` relative to the directory descriptor `fd`
+    pub async fn path_filestat_get(&self, fd: u32, path: &str) -> Result<Filestat, WasiError> {
+        let full_path = self.resolve_at(fd, path)?;
+        let inode = self.fs.stat(&full_path).await.map_err(WasiError::from)?;
+        Ok(Filestat::from_inode(&inode))
+    }
+
+    /// Stat the file or directory an open descriptor refers to
+    pub async fn fd_filestat_get(&self, fd: u32) -> Result<Filestat, WasiError> {
+        let path = self.fd_table.lock().unwrap().get(fd)?.path.clone();
+        let inode = self.fs.stat(&path).await.map_err(WasiError::from)?;
+        Ok(Filestat::from_inode(&inode))
+    }
+
     /// Create a directory
     pub async fn path_create_directory(&self, path: &str) -> Result<(), WasiError> {
         let result: FsResult<Inode> = self.fs.create_directory(path).await;
@@ -225,6 +326,51 @@ impl<'a> WasiAdapter<'a> {
     }
 }
 
+/// WASI `filetype` values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Filetype {
+    Unknown = 0,
+    Directory = 3,
+    RegularFile = 4,
+    SymbolicLink = 7,
+}
+
+/// WASI `filestat`, as returned by `path_filestat_get` and `fd_filestat_get`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filestat {
+    pub dev: u64,
+    pub ino: u64,
+    pub filetype: Filetype,
+    pub nlink: u64,
+    pub size: u64,
+    /// Timestamps in nanoseconds since the Unix epoch
+    pub atim: u64,
+    pub mtim: u64,
+    pub ctim: u64,
+}
+
+impl Filestat {
+    fn from_inode(inode: &Inode) -> Self {
+        let nanos =
+            |t: chrono::DateTime<chrono::Utc>| t.timestamp_nanos_opt().unwrap_or(0).max(0) as u64;
+        Self {
+            dev: 0,
+            ino: inode.inode_id as u64,
+            filetype: match inode.inode_type {
+                InodeType::File => Filetype::RegularFile,
+                InodeType::Dir => Filetype::Directory,
+                InodeType::Symlink => Filetype::SymbolicLink,
+            },
+            nlink: 1,
+            size: inode.size as u64,
+            atim: nanos(inode.atime),
+            mtim: nanos(inode.mtime),
+            ctim: nanos(inode.ctime),
+        }
+    }
+}
+
 /// File stat information (WASI-compatible)
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileStat {
@@ -61,6 +61,10 @@ pub struct WasiConfig {
 
     /// Tenant ID to use
     pub tenant_id: Option<uuid::Uuid>,
+
+    /// Directories handed to the guest at startup, as (guest path, tarbox path)
+    #[serde(default)]
+    pub preopens: Vec<(String, String)>,
 }
 
 impl Default for WasiConfig {
@@ -74,6 +78,7 @@ impl Default for WasiConfig {
             cache_size_mb: 100,
             cache_ttl_secs: 300,
             tenant_id: None,
+            preopens: Vec::new(),
         }
     }
 }
@@ -127,6 +132,7 @@ impl WasiConfig {
             cache_size_mb,
             cache_ttl_secs,
             tenant_id,
+            preopens: Vec::new(),
         })
     }
 
@@ -141,6 +147,7 @@ impl WasiConfig {
             cache_size_mb: 100,
             cache_ttl_secs: 300,
             tenant_id: None,
+            preopens: Vec::new(),
         }
     }
 
@@ -155,6 +162,7 @@ impl WasiConfig {
             cache_size_mb: 100,
             cache_ttl_secs: 300,
             tenant_id: None,
+            preopens: Vec::new(),
         }
     }
 
@@ -175,6 +183,16 @@ impl WasiConfig {
         self.cache_ttl_secs = ttl_secs;
         self
     }
+
+    /// Expose the tarbox directory `tarbox_path` to the guest as `guest_path`
+    pub fn with_preopen(
+        mut self,
+        guest_path: impl Into<String>,
+        tarbox_path: impl Into<String>,
+    ) -> Self {
+        self.preopens.push((guest_path.into(), tarbox_path.into()));
+        self
+    }
 }
 
 #[cfg(test)]
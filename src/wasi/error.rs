@@ -22,6 +22,8 @@ pub enum WasiError {
     DirectoryNotEmpty,
     /// No space left on device
     NoSpaceLeft,
+    /// Too many levels of symbolic links
+    SymlinkLoop,
     /// Invalid file descriptor
     InvalidFd,
     /// Bad file number
@@ -45,6 +47,7 @@ impl fmt::Display for WasiError {
             WasiError::NotDirectory => write!(f, "Not a directory"),
             WasiError::DirectoryNotEmpty => write!(f, "Directory not empty"),
             WasiError::NoSpaceLeft => write!(f, "No space left on device"),
+            WasiError::SymlinkLoop => write!(f, "Too many levels of symbolic links"),
             WasiError::InvalidFd => write!(f, "Invalid file descriptor"),
             WasiError::BadFd => write!(f, "Bad file descriptor"),
             WasiError::FdNotOpen => write!(f, "File descriptor not open"),
@@ -67,6 +70,9 @@ impl From<FsError> for WasiError {
             FsError::InvalidPath(_) => WasiError::InvalidArgument,
             FsError::PathTooLong(_) => WasiError::InvalidArgument,
             FsError::FilenameTooLong(_) => WasiError::InvalidArgument,
+            FsError::InvalidPattern(_) => WasiError::InvalidArgument,
+            FsError::NoSpace(_) | FsError::QuotaExceeded(_) => WasiError::NoSpaceLeft,
+            FsError::SymlinkLoop(_) => WasiError::SymlinkLoop,
             FsError::Storage(_) => WasiError::IoError("Storage error".to_string()),
         }
     }
@@ -86,6 +92,7 @@ pub fn to_wasi_errno(err: &WasiError) -> u16 {
         WasiError::NotDirectory => 54,      // ENOTDIR
         WasiError::DirectoryNotEmpty => 66, // ENOTEMPTY
         WasiError::NoSpaceLeft => 51,       // ENOSPC
+        WasiError::SymlinkLoop => 32,       // ELOOP
         WasiError::InvalidFd => 8,          // EBADF
         WasiError::BadFd => 8,              // EBADF
         WasiError::FdNotOpen => 8,          // EBADF
@@ -157,6 +164,11 @@ mod tests {
         );
         assert_eq!(WasiError::from(FsError::PathTooLong(5000)), WasiError::InvalidArgument);
         assert_eq!(WasiError::from(FsError::FilenameTooLong(300)), WasiError::InvalidArgument);
+        assert_eq!(WasiError::from(FsError::NoSpace("/f".to_string())), WasiError::NoSpaceLeft);
+        assert_eq!(
+            WasiError::from(FsError::QuotaExceeded("/f".to_string())),
+            WasiError::NoSpaceLeft
+        );
     }
 
     #[test]
@@ -71,12 +71,14 @@ pub struct FileDescriptor {
     pub position: u64,
     /// Is this a directory?
     pub is_directory: bool,
+    /// Guest path, if the runtime preopened this directory
+    pub preopen: Option<String>,
 }
 
 impl FileDescriptor {
     /// Create a new file descriptor
     pub fn new(inode_id: i64, path: String, flags: OpenFlags, is_directory: bool) -> Self {
-        Self { inode_id, path, flags, position: 0, is_directory }
+        Self { inode_id, path, flags, position: 0, is_directory, preopen: None }
     }
 
     /// Check if the descriptor allows reading
@@ -8,7 +8,7 @@ pub mod config;
 pub mod error;
 pub mod fd_table;
 
-pub use adapter::WasiAdapter;
+pub use adapter::{Filestat, Filetype, WasiAdapter};
 pub use config::{DbMode, WasiConfig};
 pub use error::{WasiError, to_wasi_errno};
 pub use fd_table::{FdTable, FileDescriptor, OpenFlags};